@@ -0,0 +1,613 @@
+use crate::config;
+use crate::metrics::Metrics;
+use parking_lot::Mutex;
+use serde::Deserialize;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite};
+use tokio::sync::mpsc;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The access log file could not be opened
+    Io(String, std::io::Error),
+    /// The template format was picked, but no template was given
+    NoTemplate,
+    /// The template refers to a field that doesn't exist
+    UnknownField(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(path, err) => write!(f, "could not open access log {}: {}", path, err),
+            Error::NoTemplate => write!(f, "the template access log format needs a template"),
+            Error::UnknownField(name) => write!(f, "unknown access log field ${}", name),
+        }
+    }
+}
+
+/// How each line of the access log is laid out.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Format {
+    /// The Combined Log Format used by Apache and nginx, followed by the number of bytes received
+    /// from the client, the upstream, the upstream latency and the total latency
+    #[default]
+    Combined,
+    /// A JSON object per line
+    Json,
+    /// A template with `$field` placeholders, such as "$client_ip $status $total_latency"
+    Template,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "combined" => Ok(Format::Combined),
+            "json" => Ok(Format::Json),
+            "template" => Ok(Format::Template),
+            _ => Err(format!(
+                "unknown access log format \"{}\" (expected combined, json or template)",
+                s
+            )),
+        }
+    }
+}
+
+/// A value that can be filled into a template.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Field {
+    ClientIp,
+    /// When the request started arriving, e.g. 10/Oct/2000:13:55:36 +0000
+    Time,
+    /// The same, in RFC 3339 format, e.g. 2000-10-10T13:55:36Z
+    TimeIso,
+    Request,
+    Method,
+    Path,
+    Protocol,
+    Host,
+    Status,
+    BytesIn,
+    BytesOut,
+    Upstream,
+    UpstreamLatency,
+    TotalLatency,
+    Referer,
+    UserAgent,
+}
+
+impl Field {
+    fn from_name(name: &str) -> Option<Field> {
+        Some(match name {
+            "client_ip" => Field::ClientIp,
+            "time" => Field::Time,
+            "time_iso" => Field::TimeIso,
+            "request" => Field::Request,
+            "method" => Field::Method,
+            "path" => Field::Path,
+            "protocol" => Field::Protocol,
+            "host" => Field::Host,
+            "status" => Field::Status,
+            "bytes_in" => Field::BytesIn,
+            "bytes_out" => Field::BytesOut,
+            "upstream" => Field::Upstream,
+            "upstream_latency" => Field::UpstreamLatency,
+            "total_latency" => Field::TotalLatency,
+            "referer" => Field::Referer,
+            "user_agent" => Field::UserAgent,
+            _ => return None,
+        })
+    }
+}
+
+/// A piece of a parsed template.
+#[derive(Clone, Debug)]
+enum Piece {
+    Literal(String),
+    Field(Field),
+}
+
+/// Splits a template into literal text and `$field` placeholders. A `$` that isn't followed by a
+/// field name is kept as it is.
+fn parse_template(template: &str) -> Result<Vec<Piece>, Error> {
+    let mut pieces = Vec::new();
+    let mut literal = String::new();
+    let mut rest = template;
+    while let Some(idx) = rest.find('$') {
+        literal.push_str(&rest[..idx]);
+        rest = &rest[idx + 1..];
+        let name_len = rest
+            .find(|c: char| !(c.is_ascii_lowercase() || c == '_'))
+            .unwrap_or(rest.len());
+        if name_len == 0 {
+            literal.push('$');
+            continue;
+        }
+        let field = Field::from_name(&rest[..name_len])
+            .ok_or_else(|| Error::UnknownField(rest[..name_len].to_string()))?;
+        if !literal.is_empty() {
+            pieces.push(Piece::Literal(std::mem::take(&mut literal)));
+        }
+        pieces.push(Piece::Field(field));
+        rest = &rest[name_len..];
+    }
+    literal.push_str(rest);
+    if !literal.is_empty() {
+        pieces.push(Piece::Literal(literal));
+    }
+    Ok(pieces)
+}
+
+/// What is known about a request so far, to be written to the access log once it is answered.
+pub struct Entry {
+    client_ip: String,
+    started: Instant,
+    time: SystemTime,
+    /// Byte counts of the client connection when the request started
+    bytes_read: u64,
+    bytes_written: u64,
+    method: Option<String>,
+    path: Option<String>,
+    protocol: Option<String>,
+    host: Option<String>,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    /// Upstream that answered the request (the last one tried, if it was retried)
+    pub upstream: Option<String>,
+    /// Time from sending the request to the upstream to getting back the response headers
+    pub upstream_latency: Option<Duration>,
+}
+
+impl Entry {
+    /// Starts the entry for a request that is starting to arrive on `client_conn`.
+    pub fn new<S>(client_ip: &str, client_conn: &CountingStream<S>) -> Entry {
+        Entry {
+            client_ip: client_ip.to_string(),
+            started: Instant::now(),
+            time: SystemTime::now(),
+            bytes_read: client_conn.bytes_read,
+            bytes_written: client_conn.bytes_written,
+            method: None,
+            path: None,
+            protocol: None,
+            host: None,
+            referer: None,
+            user_agent: None,
+            upstream: None,
+            upstream_latency: None,
+        }
+    }
+
+    pub fn client_ip(&self) -> &str {
+        &self.client_ip
+    }
+
+    /// Replaces the client IP the entry was started with, once the request shows that it was
+    /// relayed for someone else by a trusted proxy.
+    pub fn set_client_ip(&mut self, client_ip: &str) {
+        self.client_ip = client_ip.to_string();
+    }
+
+    /// Fills in the details of the request, once its headers have been read.
+    pub fn set_request(&mut self, request: &http::Request<Vec<u8>>) {
+        let header = |name: &str| {
+            request
+                .headers()
+                .get(name)
+                .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned())
+        };
+        self.method = Some(request.method().to_string());
+        self.path = Some(request.uri().to_string());
+        self.protocol = Some(format!("{:?}", request.version()));
+        self.host = header("host");
+        self.referer = header("referer");
+        self.user_agent = header("user-agent");
+    }
+}
+
+/// An entry along with how the request turned out.
+struct Record<'a> {
+    entry: &'a Entry,
+    status: u16,
+    bytes_in: u64,
+    bytes_out: u64,
+    total_latency: Duration,
+}
+
+impl Record<'_> {
+    /// Returns the value of `field` as text, with "-" standing in for values that aren't known.
+    fn field(&self, field: Field) -> String {
+        let entry = self.entry;
+        let text = |value: &Option<String>| value.clone().unwrap_or_else(|| "-".to_string());
+        match field {
+            Field::ClientIp => entry.client_ip.clone(),
+            Field::Time => format_clf_time(entry.time),
+            Field::TimeIso => format_iso_time(entry.time),
+            Field::Request => match (&entry.method, &entry.path, &entry.protocol) {
+                (Some(method), Some(path), Some(protocol)) => {
+                    format!("{} {} {}", method, path, protocol)
+                }
+                _ => "-".to_string(),
+            },
+            Field::Method => text(&entry.method),
+            Field::Path => text(&entry.path),
+            Field::Protocol => text(&entry.protocol),
+            Field::Host => text(&entry.host),
+            Field::Status => self.status.to_string(),
+            Field::BytesIn => self.bytes_in.to_string(),
+            Field::BytesOut => self.bytes_out.to_string(),
+            Field::Upstream => text(&entry.upstream),
+            Field::UpstreamLatency => entry
+                .upstream_latency
+                .map(format_seconds)
+                .unwrap_or_else(|| "-".to_string()),
+            Field::TotalLatency => format_seconds(self.total_latency),
+            Field::Referer => text(&entry.referer),
+            Field::UserAgent => text(&entry.user_agent),
+        }
+    }
+
+    fn to_combined(&self) -> String {
+        format!(
+            "{} - - [{}] \"{}\" {} {} \"{}\" \"{}\" {} \"{}\" {} {}",
+            self.field(Field::ClientIp),
+            self.field(Field::Time),
+            escape_quoted(&self.field(Field::Request)),
+            self.status,
+            self.bytes_out,
+            escape_quoted(&self.field(Field::Referer)),
+            escape_quoted(&self.field(Field::UserAgent)),
+            self.bytes_in,
+            self.field(Field::Upstream),
+            self.field(Field::UpstreamLatency),
+            self.field(Field::TotalLatency),
+        )
+    }
+
+    fn to_json(&self) -> String {
+        let entry = self.entry;
+        serde_json::json!({
+            "time": format_iso_time(entry.time),
+            "client_ip": entry.client_ip,
+            "method": entry.method,
+            "path": entry.path,
+            "protocol": entry.protocol,
+            "host": entry.host,
+            "status": self.status,
+            "bytes_in": self.bytes_in,
+            "bytes_out": self.bytes_out,
+            "upstream": entry.upstream,
+            "upstream_latency": entry.upstream_latency.map(|latency| latency.as_secs_f64()),
+            "total_latency": self.total_latency.as_secs_f64(),
+            "referer": entry.referer,
+            "user_agent": entry.user_agent,
+        })
+        .to_string()
+    }
+
+    fn to_template(&self, pieces: &[Piece]) -> String {
+        pieces
+            .iter()
+            .map(|piece| match piece {
+                Piece::Literal(text) => text.clone(),
+                Piece::Field(field) => self.field(*field),
+            })
+            .collect()
+    }
+}
+
+/// Where the access log goes, and how its lines are laid out.
+struct Output {
+    path: String,
+    format: Format,
+    template: Vec<Piece>,
+}
+
+/// Most messages that may be waiting for the writer task at once.
+const QUEUE_LENGTH: usize = 10_000;
+
+/// What the writer task is asked to do, in the order it was asked.
+enum Message {
+    /// Write to this file from now on, or stop writing if there is none
+    Open(Option<(String, File)>),
+    /// Write this line to the current file
+    Line(String),
+}
+
+/// Writes a line per answered request to the access log file, if one is configured. The lines
+/// are handed to a writer task, so requests never wait on the disk. If the disk stalls and the
+/// writer falls QUEUE_LENGTH messages behind, further lines are dropped (and counted in the
+/// metrics) rather than piling up in memory.
+pub struct AccessLog {
+    output: Mutex<Option<Output>>,
+    sender: Mutex<mpsc::Sender<Message>>,
+    metrics: Arc<Metrics>,
+}
+
+impl AccessLog {
+    pub fn new(metrics: Arc<Metrics>) -> AccessLog {
+        let (sender, receiver) = mpsc::channel(QUEUE_LENGTH);
+        tokio::spawn(write_lines(receiver));
+        AccessLog {
+            output: Mutex::new(None),
+            sender: Mutex::new(sender),
+            metrics,
+        }
+    }
+
+    /// Queues a message that mustn't be dropped, waiting for room if the writer has fallen behind.
+    async fn send(&self, message: Message) {
+        let mut sender = self.sender.lock().clone();
+        let _ = sender.send(message).await;
+    }
+
+    /// Switches to the given settings, opening the log file (for appending) if there is one.
+    /// Lines recorded while the switch is under way may still go to the old file.
+    pub async fn load(&self, settings: &config::AccessLog) -> Result<(), Error> {
+        let path = match &settings.path {
+            Some(path) => path,
+            None => {
+                *self.output.lock() = None;
+                self.send(Message::Open(None)).await;
+                return Ok(());
+            }
+        };
+        let template = match (settings.format, &settings.template) {
+            (Format::Template, Some(template)) => parse_template(template)?,
+            (Format::Template, None) => return Err(Error::NoTemplate),
+            _ => Vec::new(),
+        };
+        let file = open(path)?;
+        *self.output.lock() = Some(Output {
+            path: path.clone(),
+            format: settings.format,
+            template,
+        });
+        self.send(Message::Open(Some((path.clone(), file)))).await;
+        Ok(())
+    }
+
+    /// Opens the log file again, e.g. after it has been moved away by log rotation. If it can't
+    /// be opened, the old file is kept.
+    pub async fn reopen(&self) -> Result<(), Error> {
+        let path = match self.output.lock().as_ref() {
+            Some(output) => output.path.clone(),
+            None => return Ok(()),
+        };
+        let file = open(&path)?;
+        self.send(Message::Open(Some((path, file)))).await;
+        Ok(())
+    }
+
+    /// Queues the line for a request that has been answered with `status`. `client_conn` is the
+    /// connection the request came in on, which tells how many bytes went back and forth.
+    pub fn record<S>(
+        &self,
+        entry: &Entry,
+        status: http::StatusCode,
+        client_conn: &CountingStream<S>,
+    ) {
+        let output = self.output.lock();
+        let output = match output.as_ref() {
+            Some(output) => output,
+            None => return,
+        };
+        let record = Record {
+            entry,
+            status: status.as_u16(),
+            bytes_in: client_conn.bytes_read - entry.bytes_read,
+            bytes_out: client_conn.bytes_written - entry.bytes_written,
+            total_latency: entry.started.elapsed(),
+        };
+        let mut line = match output.format {
+            Format::Combined => record.to_combined(),
+            Format::Json => record.to_json(),
+            Format::Template => record.to_template(&output.template),
+        };
+        line.push('\n');
+        // Sent while the lock is held, so the line can't end up in a file opened after it
+        if self.sender.lock().try_send(Message::Line(line)).is_err() {
+            self.metrics.record_access_log_dropped();
+        }
+    }
+}
+
+/// Carries out the messages sent by AccessLog. Whatever has queued up is written in one go on a
+/// blocking thread, and flushed once the queue is empty.
+async fn write_lines(mut receiver: mpsc::Receiver<Message>) {
+    let mut output: Option<(String, BufWriter<File>)> = None;
+    while let Some(message) = receiver.recv().await {
+        let mut batch = vec![message];
+        while let Ok(message) = receiver.try_recv() {
+            batch.push(message);
+        }
+        output = tokio::task::spawn_blocking(move || write_batch(output, batch))
+            .await
+            .unwrap_or_else(|err| {
+                log::error!("Access log writer failed: {}", err);
+                None
+            });
+    }
+}
+
+/// Writes a batch of messages to `output`, flushes it, and returns the file to keep writing to.
+fn write_batch(
+    mut output: Option<(String, BufWriter<File>)>,
+    batch: Vec<Message>,
+) -> Option<(String, BufWriter<File>)> {
+    for message in batch {
+        match message {
+            Message::Open(file) => {
+                if let Some((path, mut writer)) = output.take() {
+                    if let Err(err) = writer.flush() {
+                        log::error!("Failed to write to access log {}: {}", path, err);
+                    }
+                }
+                output = file.map(|(path, file)| (path, BufWriter::new(file)));
+            }
+            Message::Line(line) => {
+                if let Some((path, writer)) = output.as_mut() {
+                    if let Err(err) = writer.write_all(line.as_bytes()) {
+                        log::error!("Failed to write to access log {}: {}", path, err);
+                    }
+                }
+            }
+        }
+    }
+    if let Some((path, writer)) = output.as_mut() {
+        if let Err(err) = writer.flush() {
+            log::error!("Failed to write to access log {}: {}", path, err);
+        }
+    }
+    output
+}
+
+fn open(path: &str) -> Result<File, Error> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|err| Error::Io(path.to_string(), err))
+}
+
+/// Formats a duration in seconds, to the millisecond.
+fn format_seconds(duration: Duration) -> String {
+    format!("{:.3}", duration.as_secs_f64())
+}
+
+/// Escapes quotes, backslashes and control characters so a value can go between double quotes.
+fn escape_quoted(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Splits a time into its UTC date and time of day: (year, month, day, hour, minute, second).
+fn utc_parts(time: SystemTime) -> (i64, u32, u32, u64, u64, u64) {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+    let (days, secs_of_day) = ((secs / 86400) as i64, secs % 86400);
+    // Howard Hinnant's days-to-civil algorithm
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day, secs_of_day / 3600, secs_of_day / 60 % 60, secs_of_day % 60)
+}
+
+/// Formats a time the way the Common Log Format does, e.g. 10/Oct/2000:13:55:36 +0000.
+fn format_clf_time(time: SystemTime) -> String {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let (year, month, day, hour, minute, second) = utc_parts(time);
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        hour,
+        minute,
+        second
+    )
+}
+
+/// Formats a time in RFC 3339 format, e.g. 2000-10-10T13:55:36Z.
+fn format_iso_time(time: SystemTime) -> String {
+    let (year, month, day, hour, minute, second) = utc_parts(time);
+    format!(
+        "{}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year, month, day, hour, minute, second
+    )
+}
+
+/// Wraps a client connection to count the bytes read from and written to it, for the bytes in and
+/// out of each access log line.
+pub struct CountingStream<S> {
+    inner: S,
+    bytes_read: u64,
+    bytes_written: u64,
+}
+
+impl<S> CountingStream<S> {
+    pub fn new(inner: S) -> CountingStream<S> {
+        CountingStream {
+            inner,
+            bytes_read: 0,
+            bytes_written: 0,
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for CountingStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            self.bytes_read += n as u64;
+        }
+        result
+    }
+}
+
+impl<S: AsyncBufRead + Unpin> AsyncBufRead for CountingStream<S> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<&[u8]>> {
+        Pin::new(&mut self.get_mut().inner).poll_fill_buf(cx)
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        self.bytes_read += amt as u64;
+        Pin::new(&mut self.inner).consume(amt)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CountingStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            self.bytes_written += n as u64;
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,445 @@
+use crate::{body, config, request, response, timeout, ProxyState};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::pin::Pin;
+use tokio::io::{AsyncBufRead, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::stream::StreamExt;
+use tokio::sync::oneshot;
+
+/// Largest request body the admin API accepts
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// An upstream as listed by GET /upstreams.
+#[derive(Serialize)]
+struct UpstreamStatus {
+    address: String,
+    weight: usize,
+    /// Pool the upstream belongs to, or null for the top-level upstreams
+    pool: Option<String>,
+    /// "live", "dead" or "draining"
+    state: &'static str,
+    /// Where the upstream's passive health check circuit breaker stands: "closed", "open"
+    /// (ejected) or "half-open"
+    circuit: &'static str,
+    /// Client connections currently assigned to the upstream
+    connections: usize,
+    /// Requests currently being proxied to the upstream
+    in_flight: usize,
+}
+
+/// Accepts connections on the admin listener until it fails or `stop` fires, and returns the
+/// listener. The admin listener is kept separate from the proxy listeners so it can be bound to an
+/// address clients can't reach.
+pub async fn serve(
+    mut listener: TcpListener,
+    mut stop: oneshot::Receiver<()>,
+    state: &ProxyState,
+) -> TcpListener {
+    loop {
+        let stream = tokio::select! {
+            stream = listener.next() => stream,
+            _ = &mut stop => break,
+        };
+        match stream {
+            Some(Ok(stream)) => {
+                let state_copy = state.clone();
+                tokio::spawn(async move {
+                    handle_connection(stream, &state_copy).await;
+                });
+            }
+            _ => break,
+        }
+    }
+    listener
+}
+
+/// Answers admin requests on a connection until the client hangs up. The client and idle timeouts
+/// apply just as they do on client connections, so nobody can hold admin connections open without
+/// sending anything (which they could otherwise do before ever showing a token).
+async fn handle_connection(conn: TcpStream, state: &ProxyState) {
+    // Buffered, so a request's body is read no further than its end
+    let mut conn = BufReader::new(conn);
+    let mut first_request = true;
+    loop {
+        let timeouts = state.config().timeouts.clone();
+        if !first_request {
+            let next_request = std::future::poll_fn(|cx| {
+                Pin::new(&mut conn)
+                    .poll_fill_buf(cx)
+                    .map(|result| result.map(|_| ()))
+            });
+            if timeout::limit(timeout::from_secs(timeouts.idle), next_request)
+                .await
+                .is_none()
+            {
+                log::debug!("Closing idle admin connection");
+                return;
+            }
+        }
+        first_request = false;
+
+        let header_timeout = timeout::from_secs(timeouts.header_read);
+        let result = timeout::limit(header_timeout, request::read_from_stream(&mut conn)).await;
+        let (request, request_length) = match result {
+            Some(Ok(request)) => request,
+            Some(Err(request::Error::IncompleteRequest(0)))
+            | Some(Err(request::Error::ConnectionError(_))) => {
+                return;
+            }
+            Some(Err(error)) => {
+                log::debug!("Error parsing admin request: {:?}", error);
+                let response = response::make_http_error(http::StatusCode::BAD_REQUEST);
+                let _ = response::write_to_stream(&response, &mut conn).await;
+                return;
+            }
+            None => {
+                log::debug!("Timed out waiting for an admin request");
+                let response = response::make_http_error(http::StatusCode::REQUEST_TIMEOUT);
+                let _ = response::write_to_stream(&response, &mut conn).await;
+                return;
+            }
+        };
+        // Admin request bodies are small JSON documents, so they are read into memory. Insist on a
+        // Content-Length so we know up front whether the body is too big
+        let mut request_body = Vec::new();
+        let error_status = match request_length {
+            body::Length::Empty => None,
+            body::Length::ContentLength(len) if len <= MAX_BODY_SIZE => {
+                let body_timeout = timeout::from_secs(timeouts.body_read);
+                let mut client_body = timeout::ReadTimeout::new(&mut conn, body_timeout);
+                match body::copy(&mut client_body, request_length, &mut request_body).await {
+                    Ok(()) => None,
+                    Err(body::Error::ReadError(error)) if timeout::is_timeout(&error) => {
+                        log::debug!("Timed out waiting for an admin request body");
+                        Some(http::StatusCode::REQUEST_TIMEOUT)
+                    }
+                    Err(error) => {
+                        log::debug!("Error reading admin request body: {:?}", error);
+                        return;
+                    }
+                }
+            }
+            body::Length::ContentLength(_) => Some(http::StatusCode::PAYLOAD_TOO_LARGE),
+            _ => Some(http::StatusCode::LENGTH_REQUIRED),
+        };
+        if let Some(status) = error_status {
+            let response = response::make_http_error(status);
+            let _ = response::write_to_stream(&response, &mut conn).await;
+            return;
+        }
+
+        let response = route(&request, &request_body, state).await;
+        log::debug!(
+            "Admin: {} <- {}",
+            request::format_request_line(&request),
+            response::format_response_line(&response)
+        );
+        if let Err(error) = response::write_to_stream(&response, &mut conn).await {
+            log::warn!("Failed to send admin response: {}", error);
+            return;
+        }
+    }
+}
+
+/// Picks the endpoint for a request:
+///
+/// * GET /metrics: Prometheus metrics
+/// * GET /upstreams: list the upstreams with their state
+/// * POST /upstreams: add an upstream (`{"address": "host:port", "weight": 1}`)
+/// * POST /upstreams/{address}/drain: stop sending new requests to an upstream, and remove it once
+///   the requests it is serving have finished
+/// * DELETE /upstreams/{address}: remove an upstream right away
+///
+/// The /upstreams endpoints require an `Authorization: Bearer <admin token>` header.
+async fn route(
+    request: &http::Request<Vec<u8>>,
+    request_body: &[u8],
+    state: &ProxyState,
+) -> http::Response<Vec<u8>> {
+    let path = request.uri().path();
+    if path == "/metrics" {
+        return match *request.method() {
+            http::Method::GET => metrics(state).await,
+            _ => response::make_http_error(http::StatusCode::METHOD_NOT_ALLOWED),
+        };
+    }
+    let upstream_path = match path.strip_prefix("/upstreams") {
+        Some(rest) => rest,
+        None => return response::make_http_error(http::StatusCode::NOT_FOUND),
+    };
+    if let Some(response) = check_authorization(request, state) {
+        return response;
+    }
+
+    let method = request.method();
+    if upstream_path.is_empty() {
+        return match *method {
+            http::Method::GET => list_upstreams(state).await,
+            http::Method::POST => add_upstream(request_body, state).await,
+            _ => response::make_http_error(http::StatusCode::METHOD_NOT_ALLOWED),
+        };
+    }
+    let address = match upstream_path.strip_prefix('/') {
+        Some(address) if !address.is_empty() => address,
+        _ => return response::make_http_error(http::StatusCode::NOT_FOUND),
+    };
+    if let Some(address) = address.strip_suffix("/drain") {
+        return match *method {
+            http::Method::POST => drain_upstream(address, state).await,
+            _ => response::make_http_error(http::StatusCode::METHOD_NOT_ALLOWED),
+        };
+    }
+    match *method {
+        http::Method::DELETE => remove_upstream(address, state).await,
+        _ => response::make_http_error(http::StatusCode::METHOD_NOT_ALLOWED),
+    }
+}
+
+/// Checks the request's bearer token against the configured admin token. Returns the response to
+/// send back if the request isn't allowed through.
+fn check_authorization(
+    request: &http::Request<Vec<u8>>,
+    state: &ProxyState,
+) -> Option<http::Response<Vec<u8>>> {
+    let token = match &state.config().admin_token {
+        Some(token) => token.clone(),
+        None => {
+            return Some(make_text_response(
+                http::StatusCode::FORBIDDEN,
+                "The upstream API is disabled; start balancebeam with --admin-token to enable it",
+            ))
+        }
+    };
+    let presented = request
+        .headers()
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match presented {
+        Some(presented) if constant_time_eq(presented.as_bytes(), token.as_bytes()) => None,
+        _ => {
+            let mut response = response::make_http_error(http::StatusCode::UNAUTHORIZED);
+            response.headers_mut().insert(
+                "WWW-Authenticate",
+                http::HeaderValue::from_static("Bearer realm=\"balancebeam\""),
+            );
+            Some(response)
+        }
+    }
+}
+
+/// Compares two byte strings in time that only depends on their lengths, so the token can't be
+/// guessed one byte at a time by timing the responses.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Serves GET /upstreams.
+async fn list_upstreams(state: &ProxyState) -> http::Response<Vec<u8>> {
+    let config = state.config();
+    let mut upstreams = Vec::new();
+    {
+        let upstream_addresses = state.upstream_addresses.lock().await;
+        let dead_upstream_addresses = state.dead_upstream_addresses.lock().await;
+        let draining_upstream_addresses = state.draining_upstream_addresses.lock().await;
+        let outstanding = state.balancer.outstanding();
+        let lists = [
+            ("live", &*upstream_addresses),
+            ("dead", &*dead_upstream_addresses),
+            ("draining", &*draining_upstream_addresses),
+        ];
+        for (upstream_state, addresses) in lists.iter() {
+            for address in addresses.iter() {
+                upstreams.push(UpstreamStatus {
+                    address: address.clone(),
+                    weight: state.balancer.weight(address),
+                    pool: config.pool_of(address).map(str::to_string),
+                    state: upstream_state,
+                    circuit: state.circuit_breaker.state(address),
+                    connections: outstanding.get(address).copied().unwrap_or(0),
+                    in_flight: state.balancer.in_flight(address),
+                });
+            }
+        }
+    }
+    make_json_response(http::StatusCode::OK, &upstreams)
+}
+
+/// Serves POST /upstreams. New upstreams start out live; if they turn out not to be, the next
+/// failed connection or health check marks them dead.
+async fn add_upstream(request_body: &[u8], state: &ProxyState) -> http::Response<Vec<u8>> {
+    let upstream: config::Upstream = match serde_json::from_slice(request_body) {
+        Ok(upstream) => upstream,
+        Err(err) => {
+            let message = format!("Invalid upstream: {}", err);
+            return make_text_response(http::StatusCode::BAD_REQUEST, &message);
+        }
+    };
+    if !(1..=config::MAX_WEIGHT).contains(&upstream.weight) {
+        let message = format!("Weight must be between 1 and {}", config::MAX_WEIGHT);
+        return make_text_response(http::StatusCode::BAD_REQUEST, &message);
+    }
+    let valid_port = upstream
+        .address
+        .rsplit_once(':')
+        .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok());
+    if !valid_port {
+        return make_text_response(
+            http::StatusCode::BAD_REQUEST,
+            "Address must be of the form host:port",
+        );
+    }
+
+    let mut upstream_addresses = state.upstream_addresses.lock().await;
+    let dead_upstream_addresses = state.dead_upstream_addresses.lock().await;
+    let draining_upstream_addresses = state.draining_upstream_addresses.lock().await;
+    let exists = upstream_addresses
+        .iter()
+        .chain(dead_upstream_addresses.iter())
+        .chain(draining_upstream_addresses.iter())
+        .any(|addr| *addr == upstream.address);
+    if exists {
+        return make_text_response(http::StatusCode::CONFLICT, "Upstream already exists");
+    }
+    if let Err(err) = state.upstream_tls.add(&upstream) {
+        let message = format!("Invalid upstream TLS settings: {}", err);
+        return make_text_response(http::StatusCode::BAD_REQUEST, &message);
+    }
+    state.balancer.set_weight(&upstream.address, upstream.weight);
+    upstream_addresses.push(upstream.address.clone());
+    state.health_history.forget(&upstream.address);
+    remove_address(
+        &mut *state.removed_upstream_addresses.lock().await,
+        &upstream.address,
+    );
+    log::info!("Added upstream {} through the admin API", upstream.address);
+    make_json_response(
+        http::StatusCode::CREATED,
+        &UpstreamStatus {
+            circuit: state.circuit_breaker.state(&upstream.address),
+            // Upstreams go to the top-level list unless they were in a pool before being removed
+            pool: state.config().pool_of(&upstream.address).map(str::to_string),
+            address: upstream.address,
+            weight: upstream.weight,
+            state: "live",
+            connections: 0,
+            in_flight: 0,
+        },
+    )
+}
+
+/// Serves POST /upstreams/{address}/drain.
+async fn drain_upstream(address: &str, state: &ProxyState) -> http::Response<Vec<u8>> {
+    {
+        let mut upstream_addresses = state.upstream_addresses.lock().await;
+        let mut dead_upstream_addresses = state.dead_upstream_addresses.lock().await;
+        let mut draining_upstream_addresses = state.draining_upstream_addresses.lock().await;
+        if !draining_upstream_addresses.iter().any(|addr| addr == address) {
+            if !remove_address(&mut upstream_addresses, address)
+                && !remove_address(&mut dead_upstream_addresses, address)
+            {
+                return response::make_http_error(http::StatusCode::NOT_FOUND);
+            }
+            draining_upstream_addresses.push(address.to_string());
+            state.health_history.forget(address);
+            log::info!("Draining upstream {}", address);
+        }
+    }
+    // Idle connections won't be needed again
+    state.pool.remove_upstream(address);
+    make_text_response(http::StatusCode::ACCEPTED, "Draining")
+}
+
+/// Serves DELETE /upstreams/{address}. Requests the upstream is still serving are allowed to
+/// finish, since they hold their own connections; client connections assigned to the upstream
+/// move to another one with their next request.
+async fn remove_upstream(address: &str, state: &ProxyState) -> http::Response<Vec<u8>> {
+    {
+        let mut upstream_addresses = state.upstream_addresses.lock().await;
+        let mut dead_upstream_addresses = state.dead_upstream_addresses.lock().await;
+        let mut draining_upstream_addresses = state.draining_upstream_addresses.lock().await;
+        let removed = remove_address(&mut upstream_addresses, address)
+            | remove_address(&mut dead_upstream_addresses, address)
+            | remove_address(&mut draining_upstream_addresses, address);
+        if !removed {
+            return response::make_http_error(http::StatusCode::NOT_FOUND);
+        }
+        state
+            .removed_upstream_addresses
+            .lock()
+            .await
+            .push(address.to_string());
+    }
+    state.pool.remove_upstream(address);
+    state.health_history.forget(address);
+    log::info!("Removed upstream {} through the admin API", address);
+    http::Response::builder()
+        .status(http::StatusCode::NO_CONTENT)
+        .version(http::Version::HTTP_11)
+        .body(Vec::new())
+        .unwrap()
+}
+
+/// Removes draining upstreams that no longer have any requests in flight.
+pub async fn remove_drained_upstreams(state: &ProxyState) {
+    let mut draining_upstream_addresses = state.draining_upstream_addresses.lock().await;
+    let mut removed_upstream_addresses = state.removed_upstream_addresses.lock().await;
+    draining_upstream_addresses.retain(|address| {
+        if state.balancer.in_flight(address) > 0 {
+            return true;
+        }
+        log::info!("Upstream {} has finished draining", address);
+        state.pool.remove_upstream(address);
+        removed_upstream_addresses.push(address.clone());
+        false
+    });
+}
+
+/// Removes `address` from `addresses`, returning whether it was there.
+fn remove_address(addresses: &mut Vec<String>, address: &str) -> bool {
+    let len = addresses.len();
+    addresses.retain(|addr| addr != address);
+    addresses.len() != len
+}
+
+fn make_text_response(status: http::StatusCode, message: &str) -> http::Response<Vec<u8>> {
+    make_response(status, "text/plain", format!("{}\n", message).into_bytes())
+}
+
+fn make_json_response<T: Serialize>(
+    status: http::StatusCode,
+    value: &T,
+) -> http::Response<Vec<u8>> {
+    make_response(status, "application/json", serde_json::to_vec(value).unwrap())
+}
+
+fn make_response(
+    status: http::StatusCode,
+    content_type: &str,
+    body: Vec<u8>,
+) -> http::Response<Vec<u8>> {
+    http::Response::builder()
+        .status(status)
+        .header("Content-Type", content_type)
+        .header("Content-Length", body.len().to_string())
+        .version(http::Version::HTTP_11)
+        .body(body)
+        .unwrap()
+}
+
+/// Serves GET /metrics.
+async fn metrics(state: &ProxyState) -> http::Response<Vec<u8>> {
+    let (live_upstreams, dead_upstreams) = {
+        let upstream_addresses = state.upstream_addresses.lock().await;
+        let dead_upstream_addresses = state.dead_upstream_addresses.lock().await;
+        (upstream_addresses.clone(), dead_upstream_addresses.clone())
+    };
+    let upstream_connections: BTreeMap<String, usize> =
+        state.balancer.outstanding().into_iter().collect();
+    let body = state
+        .metrics
+        .render(&live_upstreams, &dead_upstreams, &upstream_connections)
+        .into_bytes();
+    make_response(http::StatusCode::OK, "text/plain; version=0.0.4", body)
+}
@@ -0,0 +1,249 @@
+use crate::config;
+use parking_lot::Mutex;
+use serde::Deserialize;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Number of points each unit of weight puts on the hash ring. More points spread the keys more
+/// evenly between upstreams.
+const POINTS_PER_WEIGHT: usize = 100;
+
+/// Number of rings kept around. Each pool has its own set of upstreams (and so its own ring), and
+/// the set changes as upstreams die and come back.
+const MAX_RINGS: usize = 16;
+
+/// How requests from the same client (or session) are kept on the same upstream.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Mode {
+    /// No affinity; every new connection (or request) is balanced on its own
+    #[default]
+    None,
+    /// The first response carries a cookie naming the upstream it came from, and requests that
+    /// send the cookie back go to the same upstream
+    Cookie,
+    /// Requests are placed on a hash ring by client IP (or by the value of a header), so the same
+    /// key keeps going to the same upstream. Adding or removing an upstream only moves the keys
+    /// that belonged to it
+    ConsistentHash,
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Mode::None),
+            "cookie" => Ok(Mode::Cookie),
+            "consistent-hash" => Ok(Mode::ConsistentHash),
+            _ => Err(format!(
+                "unknown affinity mode \"{}\" (expected none, cookie or consistent-hash)",
+                s
+            )),
+        }
+    }
+}
+
+/// What a request says about which upstream it should go to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Target {
+    /// Nothing; the balancing strategy decides
+    Any,
+    /// The upstream whose cookie value (see `cookie_value`) this is
+    Cookie(String),
+    /// Whichever upstream this key lands on in the hash ring
+    Hash(u64),
+}
+
+impl Target {
+    /// Works out the target of `request`, sent by `client_ip`.
+    pub fn of(
+        request: &http::Request<Vec<u8>>,
+        client_ip: &str,
+        settings: &config::Affinity,
+    ) -> Target {
+        match settings.mode {
+            Mode::None => Target::Any,
+            Mode::Cookie => match find_cookie(request, &settings.cookie_name) {
+                Some(value) => Target::Cookie(value.to_string()),
+                None => Target::Any,
+            },
+            Mode::ConsistentHash => match &settings.hash_header {
+                Some(header) => match request.headers().get(header.as_str()) {
+                    Some(value) => Target::Hash(hash(value.as_bytes())),
+                    None => Target::Any,
+                },
+                None => Target::Hash(hash(client_ip.as_bytes())),
+            },
+        }
+    }
+
+    /// Works out the target of a raw TCP connection from `client_ip`. There are no headers or
+    /// cookies to go by, so only consistent hashing on the client IP applies.
+    pub fn of_connection(client_ip: &str, settings: &config::Affinity) -> Target {
+        match settings.mode {
+            Mode::ConsistentHash => Target::Hash(hash(client_ip.as_bytes())),
+            _ => Target::Any,
+        }
+    }
+
+    /// Returns true if a request with this target shouldn't go to `upstream`, which was picked for
+    /// an earlier request with target `previous`.
+    pub fn moves_from(&self, upstream: &str, previous: &Target) -> bool {
+        match self {
+            Target::Any => false,
+            Target::Cookie(value) => *value != cookie_value(upstream),
+            Target::Hash(_) => self != previous,
+        }
+    }
+}
+
+/// Returns the value of the cookie called `name`, if the request carries one.
+fn find_cookie<'a>(request: &'a http::Request<Vec<u8>>, name: &str) -> Option<&'a str> {
+    request
+        .headers()
+        .get_all("cookie")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(cookie_name, _)| *cookie_name == name)
+        .map(|(_, value)| value)
+}
+
+/// Returns the cookie value that names `upstream`. This is a hash of its address, so clients
+/// don't learn the addresses of the upstreams.
+pub fn cookie_value(upstream: &str) -> String {
+    format!("{:016x}", hash(upstream.as_bytes()))
+}
+
+/// Returns the Set-Cookie header value that ties the client to `upstream`, if cookie affinity is
+/// on and the request (with target `target`) didn't already carry that cookie.
+pub fn cookie_to_set(
+    upstream: &str,
+    target: &Target,
+    settings: &config::Affinity,
+) -> Option<String> {
+    let value = cookie_value(upstream);
+    match target {
+        _ if settings.mode != Mode::Cookie => None,
+        Target::Cookie(current) if *current == value => None,
+        _ => Some(format!("{}={}; Path=/; HttpOnly", settings.cookie_name, value)),
+    }
+}
+
+/// 64-bit FNV-1a followed by a finalizer that spreads similar inputs (such as "host:port#1" and
+/// "host:port#2") across the whole range. Unlike the standard library's hasher, this gives the same
+/// result in every build, so cookies stay valid across restarts and upgrades.
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in bytes {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+/// Points on a hash ring, sorted, each naming the index of the upstream it belongs to.
+struct Ring {
+    /// Upstreams (and their weights) the ring was built for, sorted by name
+    upstreams: Vec<(String, usize)>,
+    points: Vec<(u64, usize)>,
+}
+
+impl Ring {
+    fn new(upstreams: Vec<(String, usize)>) -> Ring {
+        let mut points = Vec::new();
+        // Weights are capped when upstreams are configured; a stray one is clamped rather than
+        // allowed to overflow or make for a huge ring
+        let max_points = config::MAX_WEIGHT * POINTS_PER_WEIGHT;
+        for (idx, (upstream, weight)) in upstreams.iter().enumerate() {
+            let n_points = weight
+                .checked_mul(POINTS_PER_WEIGHT)
+                .map_or(max_points, |n| n.min(max_points));
+            for point in 0..n_points {
+                points.push((hash(format!("{}#{}", upstream, point).as_bytes()), idx));
+            }
+        }
+        points.sort_unstable();
+        Ring { upstreams, points }
+    }
+
+    /// Returns the name of the upstream owning the first point at or after `key`
+    fn lookup(&self, key: u64) -> &str {
+        let idx = self.points.partition_point(|(point, _)| *point < key);
+        // Past the last point, wrap around to the first
+        &self.upstreams[self.points[idx % self.points.len()].1].0
+    }
+}
+
+/// Consistent hashing over whichever upstreams are available at the time. Each upstream owns
+/// points on a ring (in proportion to its weight), and a key goes to the upstream owning the
+/// first point at or after the key's hash. Rings are kept for the most recently used sets of
+/// upstreams, since those rarely change; they are keyed on the sorted set of upstreams and
+/// weights, so an upstream that fails and recovers picks its old ring back up.
+pub struct HashRing {
+    /// Least recently used first. The lock is only held to look rings up and swap them in, never
+    /// while building one.
+    rings: Mutex<Vec<Arc<Ring>>>,
+}
+
+impl HashRing {
+    pub fn new() -> HashRing {
+        HashRing {
+            rings: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Picks the upstream that `key` lands on and returns its index. `upstreams` must not be
+    /// empty.
+    pub fn select(
+        &self,
+        upstreams: &[String],
+        weight: impl Fn(&str) -> usize,
+        key: u64,
+    ) -> usize {
+        assert!(!upstreams.is_empty(), "no upstreams to select from");
+        let mut members: Vec<(String, usize)> = upstreams
+            .iter()
+            .map(|upstream| (upstream.clone(), weight(upstream).max(1)))
+            .collect();
+        members.sort_unstable();
+        let ring = match self.cached(&members) {
+            Some(ring) => ring,
+            None => self.insert(Arc::new(Ring::new(members))),
+        };
+        let selected = ring.lookup(key);
+        upstreams
+            .iter()
+            .position(|upstream| upstream == selected)
+            .expect("ring built from these upstreams")
+    }
+
+    /// Returns the ring built for `members`, if there is one, and marks it most recently used
+    fn cached(&self, members: &[(String, usize)]) -> Option<Arc<Ring>> {
+        let mut rings = self.rings.lock();
+        let idx = rings.iter().position(|ring| ring.upstreams == members)?;
+        let ring = rings.remove(idx);
+        rings.push(ring.clone());
+        Some(ring)
+    }
+
+    /// Adds a freshly built ring, evicting the least recently used one if the cache is full. If
+    /// another request built the same ring in the meantime, that one is kept and returned.
+    fn insert(&self, ring: Arc<Ring>) -> Arc<Ring> {
+        let mut rings = self.rings.lock();
+        if let Some(existing) = rings.iter().find(|other| other.upstreams == ring.upstreams) {
+            return existing.clone();
+        }
+        if rings.len() >= MAX_RINGS {
+            rings.remove(0);
+        }
+        rings.push(ring.clone());
+        ring
+    }
+}
@@ -0,0 +1,234 @@
+use parking_lot::Mutex;
+use rand::{Rng, SeedableRng};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// The algorithm used to pick which upstream server a new connection (or, with per-request
+/// balancing, a new request) is sent to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Strategy {
+    /// Pick a uniformly random live upstream
+    Random,
+    /// Cycle through the live upstreams in order
+    RoundRobin,
+    /// Cycle through the live upstreams, picking each one in proportion to its weight. This uses
+    /// the "smooth" variant of the algorithm (as in nginx), so heavy upstreams are interleaved with
+    /// light ones instead of receiving their share in one burst.
+    WeightedRoundRobin,
+    /// Pick the live upstream with the fewest outstanding connections. Ties are broken in
+    /// round-robin order.
+    LeastConnections,
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "random" => Ok(Strategy::Random),
+            "round-robin" => Ok(Strategy::RoundRobin),
+            "weighted-round-robin" => Ok(Strategy::WeightedRoundRobin),
+            "least-connections" => Ok(Strategy::LeastConnections),
+            _ => Err(format!(
+                "unknown balancing strategy \"{}\" (expected random, round-robin, \
+                weighted-round-robin or least-connections)",
+                s
+            )),
+        }
+    }
+}
+
+/// Parses an `--upstream` argument of the form `host:port` or `host:port=weight`. Upstreams
+/// without an explicit weight get a weight of 1.
+pub fn parse_upstream(arg: &str) -> Result<(String, usize), String> {
+    match arg.rsplit_once('=') {
+        Some((address, weight)) => {
+            let weight = weight
+                .parse::<usize>()
+                .map_err(|_| format!("invalid weight in upstream \"{}\"", arg))?;
+            if weight == 0 {
+                return Err(format!("weight of upstream \"{}\" must be positive", arg));
+            }
+            Ok((address.to_string(), weight))
+        }
+        None => Ok((arg.to_string(), 1)),
+    }
+}
+
+/// Selects upstreams according to a Strategy and keeps whatever bookkeeping the strategy needs
+/// (round-robin position, smooth weights, outstanding connection counts).
+pub struct Balancer {
+    strategy: Mutex<Strategy>,
+    /// Weight of each upstream (only used by WeightedRoundRobin). Missing entries count as 1
+    weights: Mutex<HashMap<String, usize>>,
+    /// Position in the rotation for RoundRobin, and tie-breaker for LeastConnections
+    next_index: AtomicUsize,
+    /// Running "current weight" of each upstream for WeightedRoundRobin
+    current_weights: Mutex<HashMap<String, isize>>,
+    /// Number of connections currently open to each upstream
+    outstanding: Mutex<HashMap<String, usize>>,
+    /// Number of requests currently being proxied to each upstream
+    in_flight: Mutex<HashMap<String, usize>>,
+}
+
+impl Balancer {
+    pub fn new(strategy: Strategy, weights: HashMap<String, usize>) -> Balancer {
+        Balancer {
+            strategy: Mutex::new(strategy),
+            weights: Mutex::new(weights),
+            next_index: AtomicUsize::new(0),
+            current_weights: Mutex::new(HashMap::new()),
+            outstanding: Mutex::new(HashMap::new()),
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Replaces the upstream weights, e.g. after the configuration file has been reloaded.
+    pub fn set_weights(&self, weights: HashMap<String, usize>) {
+        *self.weights.lock() = weights;
+        self.current_weights.lock().clear();
+    }
+
+    /// Switches to another strategy, e.g. after the configuration file has been reloaded. The
+    /// connection counts carry over, so least-connections picks up where the others left off.
+    pub fn set_strategy(&self, strategy: Strategy) {
+        *self.strategy.lock() = strategy;
+        self.current_weights.lock().clear();
+    }
+
+    /// Returns the weight of `upstream`.
+    pub fn weight(&self, upstream: &str) -> usize {
+        self.weights.lock().get(upstream).copied().unwrap_or(1)
+    }
+
+    /// Sets the weight of a single upstream, e.g. one added through the admin API.
+    pub fn set_weight(&self, upstream: &str, weight: usize) {
+        self.weights.lock().insert(upstream.to_string(), weight);
+        self.current_weights.lock().clear();
+    }
+
+    /// Picks one of the given upstreams and returns its index. `upstreams` must not be empty.
+    pub fn select(&self, upstreams: &[String]) -> usize {
+        assert!(!upstreams.is_empty(), "no upstreams to select from");
+        let strategy = *self.strategy.lock();
+        match strategy {
+            Strategy::Random => {
+                let mut rng = rand::rngs::StdRng::from_entropy();
+                rng.gen_range(0, upstreams.len())
+            }
+            Strategy::RoundRobin => {
+                self.next_index.fetch_add(1, Ordering::Relaxed) % upstreams.len()
+            }
+            Strategy::WeightedRoundRobin => {
+                let weights = self.weights.lock();
+                let mut current_weights = self.current_weights.lock();
+                let mut total = 0;
+                let mut best = 0;
+                let mut best_weight = isize::MIN;
+                for (idx, upstream) in upstreams.iter().enumerate() {
+                    let weight = weights.get(upstream).copied().unwrap_or(1) as isize;
+                    let current = current_weights.entry(upstream.clone()).or_insert(0);
+                    *current += weight;
+                    total += weight;
+                    if *current > best_weight {
+                        best = idx;
+                        best_weight = *current;
+                    }
+                }
+                *current_weights.get_mut(&upstreams[best]).unwrap() -= total;
+                best
+            }
+            Strategy::LeastConnections => {
+                let outstanding = self.outstanding.lock();
+                let start = self.next_index.fetch_add(1, Ordering::Relaxed);
+                (0..upstreams.len())
+                    .map(|offset| (start + offset) % upstreams.len())
+                    .min_by_key(|idx| outstanding.get(&upstreams[*idx]).copied().unwrap_or(0))
+                    .unwrap()
+            }
+        }
+    }
+
+    /// Returns the number of outstanding connections to each upstream that has any.
+    pub fn outstanding(&self) -> HashMap<String, usize> {
+        self.outstanding.lock().clone()
+    }
+
+    /// Returns the number of requests currently being proxied to `upstream`.
+    pub fn in_flight(&self, upstream: &str) -> usize {
+        self.in_flight.lock().get(upstream).copied().unwrap_or(0)
+    }
+
+    /// Records a new outstanding connection to `upstream`. The connection is counted until the
+    /// returned guard is dropped.
+    pub fn track(self: &Arc<Self>, upstream: &str) -> ConnectionGuard {
+        *self
+            .outstanding
+            .lock()
+            .entry(upstream.to_string())
+            .or_insert(0) += 1;
+        ConnectionGuard {
+            balancer: self.clone(),
+            upstream: upstream.to_string(),
+        }
+    }
+
+    /// Records a request being proxied to `upstream`. The request is counted until the returned
+    /// guard is dropped.
+    pub fn track_request(self: &Arc<Self>, upstream: &str) -> RequestGuard {
+        *self
+            .in_flight
+            .lock()
+            .entry(upstream.to_string())
+            .or_insert(0) += 1;
+        RequestGuard {
+            balancer: self.clone(),
+            upstream: upstream.to_string(),
+        }
+    }
+}
+
+/// Decrements the count for `upstream`, forgetting upstreams whose count drops to zero.
+fn release(counts: &Mutex<HashMap<String, usize>>, upstream: &str) {
+    let mut counts = counts.lock();
+    if let Some(count) = counts.get_mut(upstream) {
+        *count -= 1;
+        if *count == 0 {
+            counts.remove(upstream);
+        }
+    }
+}
+
+/// Keeps a connection to an upstream counted as outstanding for as long as it is alive.
+pub struct ConnectionGuard {
+    balancer: Arc<Balancer>,
+    upstream: String,
+}
+
+impl ConnectionGuard {
+    pub fn upstream(&self) -> &str {
+        &self.upstream
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        release(&self.balancer.outstanding, &self.upstream);
+    }
+}
+
+/// Keeps a request to an upstream counted as in flight until it has been answered.
+pub struct RequestGuard {
+    balancer: Arc<Balancer>,
+    upstream: String,
+}
+
+impl Drop for RequestGuard {
+    fn drop(&mut self) {
+        release(&self.balancer.in_flight, &self.upstream);
+    }
+}
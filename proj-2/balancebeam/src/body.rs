@@ -0,0 +1,175 @@
+use crate::chunked;
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+
+/// Size of the buffer used to move body bytes from one connection to the other. This bounds how
+/// much of a body is in memory at a time, no matter how large the body is.
+const BUFFER_SIZE: usize = 8192;
+
+// The payloads are only read through the Debug impl when errors are logged
+#[allow(dead_code, clippy::enum_variant_names)]
+#[derive(Debug)]
+pub enum Error {
+    /// The sender hung up before the whole body arrived
+    Incomplete,
+    /// The sender sent more body bytes than the Content-Length header announced
+    ContentLengthMismatch,
+    /// A chunk-size line, chunk terminator or trailer is not valid
+    MalformedChunkedBody,
+    /// Encountered an I/O error when reading from the sender
+    ReadError(std::io::Error),
+    /// Encountered an I/O error when writing to the receiver
+    WriteError(std::io::Error),
+}
+
+/// How the end of a message body is found.
+#[allow(clippy::enum_variant_names)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Length {
+    /// The message has no body
+    Empty,
+    /// The body is exactly this many bytes long
+    ContentLength(usize),
+    /// The body is sent in chunks, ending with a zero-length chunk and (optionally) trailers
+    Chunked,
+    /// The body continues until the sender closes the connection (only allowed for responses)
+    UntilClose,
+}
+
+impl Length {
+    /// Returns true if the message has a body that still has to be forwarded.
+    pub fn has_body(&self) -> bool {
+        *self != Length::Empty && *self != Length::ContentLength(0)
+    }
+}
+
+/// Copies a message body from `src` to `dst` without holding more than a small buffer of it in
+/// memory; each piece is written out before the next one is read, so a slow receiver slows down
+/// the sender as well. Only the body is taken out of `src`, so whatever follows it (such as the
+/// next request on a keep-alive connection) is left there to be read. Chunked bodies are checked
+/// and re-framed chunk by chunk, trailers included. `dst` is flushed at the end, so anything
+/// buffered along the way (such as the headers written before the body, on a TLS connection) is
+/// sent.
+pub async fn copy<R, W>(src: &mut R, length: Length, dst: &mut W) -> Result<(), Error>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    copy_body(src, length, dst, true).await?;
+    dst.flush().await.map_err(Error::WriteError)
+}
+
+/// Like `copy`, for a body read from a connection without a buffer of its own (such as one to an
+/// upstream). `already_read` holds any bytes of the body that were read from `src` together with
+/// the headers. There is nowhere to leave anything that follows the body, so the sender must not
+/// have sent anything more.
+pub async fn copy_unbuffered<R, W>(
+    src: &mut R,
+    already_read: &[u8],
+    length: Length,
+    dst: &mut W,
+) -> Result<(), Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    copy_unbuffered_body(src, already_read, length, dst, true).await?;
+    dst.flush().await.map_err(Error::WriteError)
+}
+
+/// Like `copy_unbuffered`, but a chunked body comes out as just the data in its chunks, for a
+/// receiver that marks where the body ends in its own way (such as an HTTP/2 stream).
+pub async fn copy_decoded<R, W>(
+    src: &mut R,
+    already_read: &[u8],
+    length: Length,
+    dst: &mut W,
+) -> Result<(), Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    copy_unbuffered_body(src, already_read, length, dst, false).await?;
+    dst.flush().await.map_err(Error::WriteError)
+}
+
+async fn copy_unbuffered_body<R, W>(
+    src: &mut R,
+    already_read: &[u8],
+    length: Length,
+    dst: &mut W,
+    reframe_chunks: bool,
+) -> Result<(), Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut src = BufReader::new(already_read.chain(src));
+    copy_body(&mut src, length, dst, reframe_chunks).await?;
+    let (unread, _) = src.get_ref().get_ref();
+    if !src.buffer().is_empty() || !unread.is_empty() {
+        // Anything after the body would be the start of another message, which we can't hand back
+        // to the caller
+        return Err(match length {
+            Length::Chunked => Error::MalformedChunkedBody,
+            _ => Error::ContentLengthMismatch,
+        });
+    }
+    Ok(())
+}
+
+async fn copy_body<R, W>(
+    src: &mut R,
+    length: Length,
+    dst: &mut W,
+    reframe_chunks: bool,
+) -> Result<(), Error>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    match length {
+        Length::Empty => Ok(()),
+        Length::ContentLength(content_length) => copy_exact(src, content_length, dst).await,
+        Length::Chunked if reframe_chunks => chunked::copy_body(src, dst).await,
+        Length::Chunked => chunked::decode_body(src, dst).await,
+        Length::UntilClose => {
+            let mut buffer = [0_u8; BUFFER_SIZE];
+            loop {
+                let bytes_read = src.read(&mut buffer).await.map_err(Error::ReadError)?;
+                if bytes_read == 0 {
+                    return Ok(());
+                }
+                dst.write_all(&buffer[..bytes_read])
+                    .await
+                    .map_err(Error::WriteError)?;
+                // The body may trickle in for a long time, so pass on each piece as it comes
+                dst.flush().await.map_err(Error::WriteError)?;
+            }
+        }
+    }
+}
+
+/// Copies exactly `len` bytes from `src` to `dst`. If `src` is buffered, anything after them stays
+/// in its buffer.
+pub async fn copy_exact<R, W>(src: &mut R, mut len: usize, dst: &mut W) -> Result<(), Error>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buffer = [0_u8; BUFFER_SIZE];
+    while len > 0 {
+        let to_read = std::cmp::min(len, BUFFER_SIZE);
+        let bytes_read = src
+            .read(&mut buffer[..to_read])
+            .await
+            .map_err(Error::ReadError)?;
+        if bytes_read == 0 {
+            return Err(Error::Incomplete);
+        }
+        dst.write_all(&buffer[..bytes_read])
+            .await
+            .map_err(Error::WriteError)?;
+        len -= bytes_read;
+    }
+    Ok(())
+}
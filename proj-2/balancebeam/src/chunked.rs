@@ -0,0 +1,141 @@
+use crate::body::{self, Error};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Maximum length of a chunk-size line or trailer line
+const MAX_LINE_SIZE: usize = 8000;
+const MAX_NUM_TRAILERS: usize = 32;
+
+/// Returns true if the final transfer coding in the Transfer-Encoding header is "chunked".
+pub fn is_chunked(headers: &http::HeaderMap) -> bool {
+    headers
+        .get_all("transfer-encoding")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .last()
+        .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"))
+}
+
+/// Reads a CRLF-terminated line from a chunked body and returns it without the CRLF. Nothing past
+/// the end of the line is taken out of `src`.
+async fn read_line<R: AsyncBufRead + Unpin>(src: &mut R) -> Result<Vec<u8>, Error> {
+    let mut line = Vec::new();
+    (&mut *src)
+        .take(MAX_LINE_SIZE as u64 + 2)
+        .read_until(b'\n', &mut line)
+        .await
+        .map_err(Error::ReadError)?;
+    if !line.ends_with(b"\r\n") {
+        // A bare LF, or a line too long to be read in full, is malformed; anything else means the
+        // stream ended partway through the line
+        if line.ends_with(b"\n") || line.len() > MAX_LINE_SIZE {
+            return Err(Error::MalformedChunkedBody);
+        }
+        return Err(Error::Incomplete);
+    }
+    line.truncate(line.len() - 2);
+    Ok(line)
+}
+
+/// Parses the size out of a chunk-size line, ignoring any chunk extensions.
+fn parse_chunk_size(line: &[u8]) -> Result<usize, Error> {
+    let line = std::str::from_utf8(line).map_err(|_| Error::MalformedChunkedBody)?;
+    let size = line.split(';').next().unwrap_or("").trim();
+    // from_str_radix would also accept a leading sign, which isn't valid here
+    if size.is_empty() || !size.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return Err(Error::MalformedChunkedBody);
+    }
+    usize::from_str_radix(size, 16).map_err(|_| Error::MalformedChunkedBody)
+}
+
+/// Parses a "Name: value" trailer line.
+fn parse_trailer(line: &[u8]) -> Result<(http::HeaderName, http::HeaderValue), Error> {
+    let colon = line
+        .iter()
+        .position(|byte| *byte == b':')
+        .ok_or(Error::MalformedChunkedBody)?;
+    let name = http::HeaderName::from_bytes(&line[..colon])
+        .map_err(|_| Error::MalformedChunkedBody)?;
+    let value = http::HeaderValue::from_bytes(line[colon + 1..].trim_ascii())
+        .map_err(|_| Error::MalformedChunkedBody)?;
+    Ok((name, value))
+}
+
+/// Copies a chunked body from `src` to `dst` one chunk at a time. Chunk extensions are dropped;
+/// chunk sizes and trailers are validated before being passed on, so a malformed body is never
+/// forwarded as-is. Reading stops at the end of the body, so whatever follows it stays in `src`.
+pub async fn copy_body<R, W>(src: &mut R, dst: &mut W) -> Result<(), Error>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    transfer(src, dst, true).await
+}
+
+/// Like `copy_body`, but only the data in the chunks is written to `dst`, for a receiver that
+/// marks where the body ends in its own way (such as an HTTP/2 stream). The body is validated all
+/// the same; trailers are dropped.
+pub async fn decode_body<R, W>(src: &mut R, dst: &mut W) -> Result<(), Error>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    transfer(src, dst, false).await
+}
+
+/// Reads a chunked body from `src`, writing it to `dst` with its chunked framing if `reframe` is
+/// true and as plain data otherwise.
+async fn transfer<R, W>(src: &mut R, dst: &mut W, reframe: bool) -> Result<(), Error>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    loop {
+        let size = parse_chunk_size(&read_line(src).await?)?;
+        if reframe {
+            dst.write_all(format!("{:x}\r\n", size).as_bytes())
+                .await
+                .map_err(Error::WriteError)?;
+        }
+        if size == 0 {
+            break;
+        }
+        body::copy_exact(src, size, dst).await?;
+        if !read_line(src).await?.is_empty() {
+            // Each chunk must be followed immediately by CRLF
+            return Err(Error::MalformedChunkedBody);
+        }
+        if reframe {
+            dst.write_all(b"\r\n").await.map_err(Error::WriteError)?;
+        }
+        // Chunks may be sent as events happen, so pass each one on right away
+        dst.flush().await.map_err(Error::WriteError)?;
+    }
+
+    let mut num_trailers = 0;
+    loop {
+        let line = read_line(src).await?;
+        if line.is_empty() {
+            break;
+        }
+        num_trailers += 1;
+        if num_trailers > MAX_NUM_TRAILERS {
+            return Err(Error::MalformedChunkedBody);
+        }
+        let (name, value) = parse_trailer(&line)?;
+        if !reframe {
+            continue;
+        }
+        dst.write_all(format!("{}: ", name).as_bytes())
+            .await
+            .map_err(Error::WriteError)?;
+        dst.write_all(value.as_bytes())
+            .await
+            .map_err(Error::WriteError)?;
+        dst.write_all(b"\r\n").await.map_err(Error::WriteError)?;
+    }
+    if reframe {
+        dst.write_all(b"\r\n").await.map_err(Error::WriteError)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,255 @@
+use crate::config;
+use parking_lot::Mutex;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// Width of the buckets that requests are counted in for the error rate, so the window slides
+/// forward a bucket at a time instead of remembering every request.
+const BUCKET_WIDTH: Duration = Duration::from_secs(1);
+
+/// Where an upstream's circuit breaker stands.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    /// Requests flow normally while their outcomes are watched
+    Closed,
+    /// The upstream has been ejected, and gets no requests until `until`
+    Open { until: Instant },
+    /// The ejection is over. A single trial request is let through to decide whether the upstream
+    /// has recovered; `trial_started` is set once some connection has claimed the trial
+    HalfOpen { trial_started: Option<Instant> },
+}
+
+/// Number of requests (and how many of them failed) that finished within one bucket.
+struct Bucket {
+    start: Instant,
+    requests: usize,
+    failures: usize,
+}
+
+/// Passive health bookkeeping for a single upstream.
+struct UpstreamHealth {
+    state: State,
+    consecutive_failures: usize,
+    /// Request counts over the error rate window, oldest first
+    buckets: VecDeque<Bucket>,
+    /// Number of back-to-back ejections, which decides how long the next one lasts
+    ejections: u32,
+    /// When the circuit last closed. An upstream that stays healthy for `max_ejection_time` has
+    /// its back-off reset
+    closed_since: Instant,
+}
+
+impl UpstreamHealth {
+    fn new(now: Instant) -> UpstreamHealth {
+        UpstreamHealth {
+            state: State::Closed,
+            consecutive_failures: 0,
+            buckets: VecDeque::new(),
+            ejections: 0,
+            closed_since: now,
+        }
+    }
+
+    /// Moves an expired ejection on to the half-open state.
+    fn advance(&mut self, now: Instant) {
+        if let State::Open { until } = self.state {
+            if now >= until {
+                self.state = State::HalfOpen {
+                    trial_started: None,
+                };
+            }
+        }
+    }
+
+    /// Returns true if a request may be sent to the upstream. A half-open upstream is available if
+    /// no trial request is under way, or the one that is has been going on for so long (longer
+    /// than the base ejection time) that it is presumed lost.
+    fn is_available(&self, settings: &config::PassiveHealthCheck, now: Instant) -> bool {
+        match self.state {
+            State::Closed => true,
+            State::Open { until } => now >= until,
+            State::HalfOpen { trial_started } => trial_started.is_none_or(|started| {
+                now.duration_since(started) >= Duration::from_secs(settings.base_ejection_time)
+            }),
+        }
+    }
+
+    fn record(&mut self, success: bool, now: Instant) {
+        match self.buckets.back() {
+            Some(bucket) if now.duration_since(bucket.start) < BUCKET_WIDTH => {}
+            _ => self.buckets.push_back(Bucket {
+                start: now,
+                requests: 0,
+                failures: 0,
+            }),
+        }
+        let bucket = self.buckets.back_mut().unwrap();
+        bucket.requests += 1;
+        if success {
+            self.consecutive_failures = 0;
+        } else {
+            bucket.failures += 1;
+            self.consecutive_failures += 1;
+        }
+    }
+
+    /// Returns the fraction of requests that failed within the window, provided there were enough
+    /// of them to go by.
+    fn error_rate(&mut self, settings: &config::PassiveHealthCheck, now: Instant) -> Option<f64> {
+        let window = Duration::from_secs(settings.window);
+        while let Some(bucket) = self.buckets.front() {
+            if now.duration_since(bucket.start) < window {
+                break;
+            }
+            self.buckets.pop_front();
+        }
+        let requests: usize = self.buckets.iter().map(|bucket| bucket.requests).sum();
+        let failures: usize = self.buckets.iter().map(|bucket| bucket.failures).sum();
+        if requests == 0 || requests < settings.min_requests {
+            return None;
+        }
+        Some(failures as f64 / requests as f64)
+    }
+
+    /// Ejects the upstream for the base ejection time, doubled for every ejection since it was
+    /// last healthy for a while, up to the maximum ejection time. Returns how long it's out for.
+    fn eject(&mut self, settings: &config::PassiveHealthCheck, now: Instant) -> Duration {
+        let max_ejection_time = Duration::from_secs(settings.max_ejection_time);
+        let ejection_time = Duration::from_secs(settings.base_ejection_time)
+            .checked_mul(2_u32.saturating_pow(self.ejections))
+            .map_or(max_ejection_time, |time| time.min(max_ejection_time));
+        self.state = State::Open {
+            until: now + ejection_time,
+        };
+        self.ejections = self.ejections.saturating_add(1);
+        self.consecutive_failures = 0;
+        self.buckets.clear();
+        ejection_time
+    }
+}
+
+/// Watches the outcome of proxied requests, and ejects upstreams that keep failing (too many
+/// failures in a row, or too high an error rate) even though they accept connections. Ejected
+/// upstreams are let back in through a half-open state: once the ejection is over, one trial
+/// request is sent, and the upstream is back for good if it succeeds or ejected for twice as long
+/// if it fails.
+pub struct CircuitBreaker {
+    upstreams: Mutex<HashMap<String, UpstreamHealth>>,
+}
+
+impl CircuitBreaker {
+    pub fn new() -> CircuitBreaker {
+        CircuitBreaker {
+            upstreams: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns true if a new request may be sent to `upstream`. This doesn't claim anything; call
+    /// `admit` once the upstream has actually been picked.
+    pub fn is_available(&self, upstream: &str, settings: &config::PassiveHealthCheck) -> bool {
+        match self.upstreams.lock().get(upstream) {
+            Some(health) => health.is_available(settings, Instant::now()),
+            None => true,
+        }
+    }
+
+    /// Lets a request through to `upstream` if its circuit allows it. If the upstream is
+    /// half-open, this claims the single trial request, and other callers are turned away until
+    /// its outcome has been recorded.
+    pub fn admit(&self, upstream: &str, settings: &config::PassiveHealthCheck) -> bool {
+        let now = Instant::now();
+        let mut upstreams = self.upstreams.lock();
+        let health = match upstreams.get_mut(upstream) {
+            Some(health) => health,
+            None => return true,
+        };
+        health.advance(now);
+        if !health.is_available(settings, now) {
+            return false;
+        }
+        if let State::HalfOpen { .. } = health.state {
+            log::info!("Sending a trial request to ejected upstream {}", upstream);
+            health.state = State::HalfOpen {
+                trial_started: Some(now),
+            };
+        }
+        true
+    }
+
+    /// Records whether a request to `upstream` succeeded, ejecting or restoring the upstream as
+    /// needed. Returns true if the upstream was ejected.
+    pub fn record(
+        &self,
+        upstream: &str,
+        success: bool,
+        settings: &config::PassiveHealthCheck,
+    ) -> bool {
+        if !settings.is_enabled() {
+            return false;
+        }
+        let now = Instant::now();
+        let mut upstreams = self.upstreams.lock();
+        let health = upstreams
+            .entry(upstream.to_string())
+            .or_insert_with(|| UpstreamHealth::new(now));
+        health.advance(now);
+        match health.state {
+            // Requests that were already under way when the upstream was ejected don't count
+            State::Open { .. } => false,
+            State::HalfOpen { .. } if success => {
+                log::info!("Upstream {} passed its trial request; restoring it", upstream);
+                health.state = State::Closed;
+                health.closed_since = now;
+                false
+            }
+            State::HalfOpen { .. } => {
+                let ejection_time = health.eject(settings, now);
+                log::warn!(
+                    "Upstream {} failed its trial request; ejecting it for {:?}",
+                    upstream,
+                    ejection_time
+                );
+                true
+            }
+            State::Closed => {
+                if now.duration_since(health.closed_since)
+                    >= Duration::from_secs(settings.max_ejection_time)
+                {
+                    health.ejections = 0;
+                }
+                health.record(success, now);
+                let error_rate = health.error_rate(settings, now);
+                let too_many_failures = settings.consecutive_failures > 0
+                    && health.consecutive_failures >= settings.consecutive_failures;
+                let error_rate_too_high = settings.error_rate > 0.0
+                    && error_rate.is_some_and(|rate| rate >= settings.error_rate);
+                if !too_many_failures && !error_rate_too_high {
+                    return false;
+                }
+                let ejection_time = health.eject(settings, now);
+                log::warn!(
+                    "Upstream {} is failing requests; ejecting it for {:?}",
+                    upstream,
+                    ejection_time
+                );
+                true
+            }
+        }
+    }
+
+    /// Describes where the circuit for `upstream` stands ("closed", "open" or "half-open").
+    pub fn state(&self, upstream: &str) -> &'static str {
+        let mut upstreams = self.upstreams.lock();
+        match upstreams.get_mut(upstream) {
+            Some(health) => {
+                health.advance(Instant::now());
+                match health.state {
+                    State::Closed => "closed",
+                    State::Open { .. } => "open",
+                    State::HalfOpen { .. } => "half-open",
+                }
+            }
+            None => "closed",
+        }
+    }
+}
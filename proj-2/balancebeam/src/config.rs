@@ -0,0 +1,669 @@
+use crate::access_log;
+use crate::affinity;
+use crate::balancer::Strategy;
+use crate::cidr::Cidr;
+use crate::forwarding;
+use crate::proxy_protocol;
+use crate::rate_limit;
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The configuration file could not be read
+    Io(std::io::Error),
+    /// The configuration file is not valid TOML, or doesn't match the expected layout
+    Toml(toml::de::Error),
+    /// The configuration file is not valid YAML, or doesn't match the expected layout
+    Yaml(serde_yaml::Error),
+    /// The file extension is neither .toml, .yaml nor .yml
+    UnknownFormat(String),
+    /// The configuration doesn't contain any upstream servers
+    NoUpstreams,
+    /// The configuration doesn't contain any addresses to listen on
+    NoListeners,
+    /// An upstream was given a weight of 0 or more than MAX_WEIGHT
+    InvalidWeight(String),
+    /// The passive health check error rate threshold is not between 0 and 1
+    InvalidErrorRate(f64),
+    /// A health check threshold is 0
+    InvalidThreshold,
+    /// There are TLS listeners, but no certificate to serve on them
+    NoCertificates,
+    /// An upstream pool has no upstreams in it
+    EmptyPool(String),
+    /// Two upstream pools have the same name
+    DuplicatePool(String),
+    /// A route or TCP listener sends requests to a pool that doesn't exist
+    UnknownPool(String),
+    /// An upstream is listed more than once, possibly in different pools
+    DuplicateUpstream(String),
+    /// The retry budget ratio is negative
+    InvalidRetryBudget(f64),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "could not read configuration file: {}", err),
+            Error::Toml(err) => write!(f, "invalid TOML configuration: {}", err),
+            Error::Yaml(err) => write!(f, "invalid YAML configuration: {}", err),
+            Error::UnknownFormat(path) => write!(
+                f,
+                "don't know how to parse {} (expected a .toml, .yaml or .yml file)",
+                path
+            ),
+            Error::NoUpstreams => write!(f, "at least one upstream server must be specified"),
+            Error::NoListeners => write!(f, "at least one listener address must be specified"),
+            Error::InvalidWeight(address) => {
+                write!(f, "weight of upstream {} must be between 1 and {}", address, MAX_WEIGHT)
+            }
+            Error::InvalidErrorRate(rate) => {
+                write!(f, "error rate threshold {} must be between 0 and 1", rate)
+            }
+            Error::InvalidThreshold => write!(f, "health check thresholds must be positive"),
+            Error::NoCertificates => {
+                write!(f, "at least one certificate must be specified for TLS listeners")
+            }
+            Error::EmptyPool(name) => write!(f, "upstream pool {} has no upstreams", name),
+            Error::DuplicatePool(name) => write!(f, "upstream pool {} is defined twice", name),
+            Error::UnknownPool(name) => write!(f, "unknown pool {}", name),
+            Error::DuplicateUpstream(address) => {
+                write!(f, "upstream {} is listed more than once", address)
+            }
+            Error::InvalidRetryBudget(ratio) => {
+                write!(f, "retry budget ratio {} must not be negative", ratio)
+            }
+        }
+    }
+}
+
+/// Largest weight an upstream may have. Consistent hashing gives every upstream a number of ring
+/// points proportional to its weight, so unbounded weights would make for unbounded rings.
+pub const MAX_WEIGHT: usize = 1000;
+
+/// An upstream server and its weight for weighted balancing strategies.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Upstream {
+    pub address: String,
+    #[serde(default = "default_weight")]
+    pub weight: usize,
+    /// Connect to the upstream over TLS with these settings, instead of plain TCP
+    #[serde(default)]
+    pub tls: Option<UpstreamTls>,
+    /// Start every connection to the upstream with a PROXY protocol header of this version, so
+    /// that it knows which client the connection is for
+    #[serde(default)]
+    pub proxy_protocol: Option<proxy_protocol::Version>,
+}
+
+fn default_weight() -> usize {
+    1
+}
+
+/// Settings for connecting to an upstream over TLS. Both requests and health checks use them.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamTls {
+    /// PEM file with the CA certificates the upstream's certificate must chain up to (the
+    /// Mozilla root certificates if not given)
+    pub ca: Option<String>,
+    /// PEM file with a client certificate chain to present, for upstreams that require mutual
+    /// TLS
+    pub cert: Option<String>,
+    /// PEM file with the private key for `cert`
+    pub key: Option<String>,
+    /// Name to ask for in the handshake (SNI) and to verify the certificate against, if not the
+    /// host in the upstream's address. Required for upstreams addressed by IP
+    pub server_name: Option<String>,
+    /// Check that the upstream's certificate is valid for the server name. The certificate chain
+    /// is verified either way
+    #[serde(default = "default_verify_hostname")]
+    pub verify_hostname: bool,
+}
+
+fn default_verify_hostname() -> bool {
+    true
+}
+
+/// A named group of upstreams that routes can send requests to.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamPool {
+    pub name: String,
+    pub upstreams: Vec<Upstream>,
+}
+
+/// Sends the requests that match all of the given conditions to an upstream pool. Requests that
+/// no route matches go to the top-level upstreams.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Route {
+    /// Name of the pool to send matching requests to
+    pub pool: String,
+    /// Host header the request must carry, e.g. "example.com" or "*.example.com" (the port and
+    /// case don't matter)
+    pub host: Option<String>,
+    /// Prefix the request path must start with
+    pub path_prefix: Option<String>,
+    /// Regular expression the request path must match
+    #[serde(default, deserialize_with = "deserialize_regex")]
+    pub path_regex: Option<Regex>,
+    /// Methods the request must use one of (any method if empty)
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// Headers the request must carry, with these exact values
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Replaces the part of the path matched by `path_prefix` or `path_regex` (which may refer to
+    /// capture groups, e.g. "$1"), or the whole path if neither is given
+    pub rewrite: Option<String>,
+}
+
+fn deserialize_regex<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Regex>, D::Error> {
+    let pattern = String::deserialize(deserializer)?;
+    Regex::new(&pattern).map(Some).map_err(serde::de::Error::custom)
+}
+
+/// A certificate chain and private key (both PEM files) to serve on the TLS listeners.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Certificate {
+    pub cert: String,
+    pub key: String,
+    /// Server names (SNI) to serve this certificate for, e.g. "example.com" or "*.example.com"
+    #[serde(default)]
+    pub hostnames: Vec<String>,
+}
+
+/// Settings for terminating HTTPS.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Tls {
+    /// IP/port pairs to accept HTTPS connections on
+    pub listeners: Vec<String>,
+    /// Certificates to pick from based on the server name the client asks for. The first one is
+    /// used for clients that don't ask for a name, or ask for one no certificate is listed for
+    pub certificates: Vec<Certificate>,
+}
+
+/// A listener that balances raw TCP connections (e.g. to databases) instead of HTTP requests.
+/// Each connection is relayed as it is to an upstream. Upstreams that only TCP listeners use are
+/// health checked by connecting to them rather than with HTTP requests, so a listener for servers
+/// that don't speak HTTP should name a pool of its own.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct TcpListener {
+    /// IP/port to accept connections on
+    pub bind: String,
+    /// Name of the pool to relay connections to (the top-level upstreams if not given)
+    pub pool: Option<String>,
+}
+
+/// Settings for raw TCP proxying.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Tcp {
+    pub listeners: Vec<TcpListener>,
+}
+
+/// Settings for acting as an HTTP CONNECT forward proxy, which opens a tunnel to a destination
+/// of the client's choosing instead of to one of the upstreams.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Connect {
+    /// Destinations that CONNECT requests may ask for, as "host:port". The host may start with
+    /// "*." to match any single label, and the port may be "*". CONNECT requests are refused if
+    /// this is empty
+    pub allow: Vec<String>,
+}
+
+/// Settings for accepting connections relayed by another (layer 4) proxy.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxyProtocol {
+    /// Addresses of the proxies in front of balancebeam, e.g. "10.0.0.0/8". Connections from them
+    /// must start with a PROXY protocol header (v1 or v2), and the client address in it is used
+    /// instead of the proxy's. Connections from anywhere else are taken as they are
+    pub trusted: Vec<Cidr>,
+}
+
+/// Settings for telling upstreams where requests came from.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Forwarding {
+    /// Addresses of the HTTP proxies in front of balancebeam, e.g. "10.0.0.0/8". The
+    /// X-Forwarded-* and Forwarded headers are only passed on from them (and stripped from
+    /// everyone else), and the client address is taken from them for rate limiting and logging
+    pub trusted_proxies: Vec<Cidr>,
+    /// The header the trusted proxies record the client in. Only that one is read, since the
+    /// proxies pass the other on from the client unchecked
+    pub header: forwarding::Header,
+}
+
+/// Settings for active health checks.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthCheck {
+    /// Perform active health checks on this interval (in seconds)
+    pub interval: usize,
+    /// Path to send request to for active health checks
+    pub path: String,
+    /// Give up on a health check request after this many seconds (0 = once the next check is due)
+    pub timeout: u64,
+    /// Number of health checks in a row a dead upstream must pass to be marked live
+    pub healthy_threshold: usize,
+    /// Number of health checks in a row a live upstream must fail to be marked dead
+    pub unhealthy_threshold: usize,
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        HealthCheck {
+            interval: 10,
+            path: "/".to_string(),
+            timeout: 5,
+            healthy_threshold: 1,
+            unhealthy_threshold: 2,
+        }
+    }
+}
+
+/// Settings for passive health checks, which eject upstreams based on how the requests proxied
+/// to them turn out.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PassiveHealthCheck {
+    /// Eject an upstream after this many failed requests in a row (0 = never)
+    pub consecutive_failures: usize,
+    /// Eject an upstream once this fraction of its requests within the window have failed
+    /// (0 = never)
+    pub error_rate: f64,
+    /// Minimum number of requests within the window before the error rate is looked at
+    pub min_requests: usize,
+    /// Length of the window that the error rate is measured over (in seconds)
+    pub window: u64,
+    /// How long an upstream is ejected for the first time (in seconds). Each ejection that
+    /// follows soon after doubles this
+    pub base_ejection_time: u64,
+    /// Longest an upstream is ever ejected for (in seconds)
+    pub max_ejection_time: u64,
+}
+
+impl PassiveHealthCheck {
+    /// Returns true if either of the thresholds is turned on.
+    pub fn is_enabled(&self) -> bool {
+        self.consecutive_failures > 0 || self.error_rate > 0.0
+    }
+}
+
+impl Default for PassiveHealthCheck {
+    fn default() -> Self {
+        PassiveHealthCheck {
+            consecutive_failures: 5,
+            error_rate: 0.5,
+            min_requests: 20,
+            window: 30,
+            base_ejection_time: 30,
+            max_ejection_time: 300,
+        }
+    }
+}
+
+/// Settings for per-client rate limiting.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimit {
+    /// Maximum number of requests to accept per IP per minute (0 = unlimited)
+    pub max_requests_per_minute: usize,
+    /// How requests are counted against the limit
+    pub algorithm: rate_limit::Algorithm,
+    /// Largest burst of requests a client may send at once with the token-bucket algorithm
+    /// (0 = one minute's worth of requests)
+    pub burst: usize,
+}
+
+/// Time limits for talking to clients and upstreams (in seconds, 0 = no limit).
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timeouts {
+    /// Longest a client may take to send a request's headers (and the TLS handshake, on HTTPS
+    /// listeners) before it gets a 408
+    pub header_read: u64,
+    /// Longest a client may go without sending any more of a request body before it gets a 408
+    pub body_read: u64,
+    /// Longest to wait for a connection to an upstream to be set up, TLS handshake included
+    pub upstream_connect: u64,
+    /// Longest to wait for an upstream's response headers (after which the client gets a 504), or
+    /// for the next piece of its response body
+    pub upstream_response: u64,
+    /// Longest a keep-alive connection from a client may sit idle between requests
+    pub idle: u64,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            header_read: 10,
+            body_read: 30,
+            upstream_connect: 5,
+            upstream_response: 60,
+            idle: 60,
+        }
+    }
+}
+
+/// Settings for retrying requests that failed on their way to or from an upstream. Only
+/// idempotent requests are retried, and only if their body (if any) is small enough to be held on
+/// to; each retry goes to an upstream that hasn't been tried yet.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Retry {
+    /// Number of times a failed request is retried (0 = never)
+    pub attempts: usize,
+    /// Methods to retry besides GET, HEAD, PUT and DELETE
+    pub methods: Vec<String>,
+    /// Give up on an attempt that hasn't received the response headers after this many seconds,
+    /// and retry it elsewhere (0 = no limit)
+    pub per_try_timeout: u64,
+    /// Longest wait before the first retry (in milliseconds). The actual wait is picked at random
+    /// up to this, and the limit doubles for each retry after that
+    pub backoff_base: u64,
+    /// Longest wait before any retry (in milliseconds)
+    pub backoff_max: u64,
+    /// Retries may add at most this fraction on top of the requests sent in the last 10 seconds
+    pub budget_ratio: f64,
+    /// Retries per second that are allowed on top of the ratio, so retries still work when there
+    /// is little traffic
+    pub budget_min_per_second: usize,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Retry {
+            attempts: 2,
+            methods: Vec::new(),
+            per_try_timeout: 0,
+            backoff_base: 25,
+            backoff_max: 250,
+            budget_ratio: 0.2,
+            budget_min_per_second: 3,
+        }
+    }
+}
+
+/// Settings for the access log, which gets a line for every request that is answered.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessLog {
+    /// File to append the access log to (no access log if not given). Send balancebeam SIGUSR1 to
+    /// have it reopen the file after rotating it
+    pub path: Option<String>,
+    pub format: access_log::Format,
+    /// Layout of each line with the template format, e.g. "$client_ip $request $status"
+    pub template: Option<String>,
+}
+
+/// Settings for keeping a client's requests on the same upstream.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Affinity {
+    pub mode: affinity::Mode,
+    /// Name of the cookie that names the upstream, with the cookie mode
+    pub cookie_name: String,
+    /// Header whose value is hashed with the consistent-hash mode, instead of the client IP.
+    /// Requests without the header are balanced as usual
+    pub hash_header: Option<String>,
+}
+
+impl Default for Affinity {
+    fn default() -> Self {
+        Affinity {
+            mode: affinity::Mode::None,
+            cookie_name: "balancebeam_upstream".to_string(),
+            hash_header: None,
+        }
+    }
+}
+
+/// Settings for pooling idle keep-alive connections to upstreams.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Pool {
+    /// Maximum number of idle connections kept open to each upstream (0 = no pooling)
+    pub max_idle_per_upstream: usize,
+    /// Close idle connections after this many seconds
+    pub idle_timeout: u64,
+}
+
+impl Default for Pool {
+    fn default() -> Self {
+        Pool {
+            max_idle_per_upstream: 8,
+            idle_timeout: 60,
+        }
+    }
+}
+
+/// All of balancebeam's settings, built either from the command line alone or from a
+/// configuration file layered on top of the command line.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// IP/port pairs to accept client connections on
+    pub listeners: Vec<String>,
+    /// IP/port to serve the admin endpoints (e.g. /metrics) on, if any
+    pub admin_bind: Option<String>,
+    /// Bearer token that admin API requests changing the upstreams must carry. The upstream API
+    /// is disabled if this isn't set
+    pub admin_token: Option<String>,
+    /// How to pick an upstream for each connection
+    pub balancing_strategy: Strategy,
+    /// Pick an upstream for every request, instead of once per client connection
+    pub per_request_balancing: bool,
+    pub affinity: Affinity,
+    /// How long (in seconds) to wait for open connections to finish when shutting down
+    pub shutdown_timeout: u64,
+    /// Upstream servers to forward requests to when no route says otherwise
+    pub upstreams: Vec<Upstream>,
+    /// Named groups of upstreams for routes to pick from
+    pub pools: Vec<UpstreamPool>,
+    /// Rules for sending requests to pools, tried in order
+    pub routes: Vec<Route>,
+    pub tls: Tls,
+    pub tcp: Tcp,
+    pub connect: Connect,
+    pub proxy_protocol: ProxyProtocol,
+    pub forwarding: Forwarding,
+    pub health_check: HealthCheck,
+    pub passive_health_check: PassiveHealthCheck,
+    pub rate_limit: RateLimit,
+    pub retry: Retry,
+    pub timeouts: Timeouts,
+    pub pool: Pool,
+    pub access_log: AccessLog,
+}
+
+/// The layout of a configuration file. Every top-level section is optional; sections that are
+/// left out keep the value they were given on the command line, while fields left out of a section
+/// that *is* present get their usual defaults.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    listeners: Option<Vec<String>>,
+    admin_bind: Option<String>,
+    admin_token: Option<String>,
+    balancing_strategy: Option<Strategy>,
+    per_request_balancing: Option<bool>,
+    affinity: Option<Affinity>,
+    shutdown_timeout: Option<u64>,
+    upstreams: Option<Vec<Upstream>>,
+    pools: Option<Vec<UpstreamPool>>,
+    routes: Option<Vec<Route>>,
+    tls: Option<Tls>,
+    tcp: Option<Tcp>,
+    connect: Option<Connect>,
+    proxy_protocol: Option<ProxyProtocol>,
+    forwarding: Option<Forwarding>,
+    health_check: Option<HealthCheck>,
+    passive_health_check: Option<PassiveHealthCheck>,
+    rate_limit: Option<RateLimit>,
+    retry: Option<Retry>,
+    timeouts: Option<Timeouts>,
+    pool: Option<Pool>,
+    access_log: Option<AccessLog>,
+}
+
+impl Config {
+    /// Reads the configuration file at `path` (TOML or YAML, depending on the extension) and
+    /// layers it on top of `base`, which holds the settings from the command line.
+    pub fn load(path: &str, base: &Config) -> Result<Config, Error> {
+        let contents = std::fs::read_to_string(path).map_err(Error::Io)?;
+        let file: ConfigFile = if path.ends_with(".toml") {
+            toml::from_str(&contents).map_err(Error::Toml)?
+        } else if path.ends_with(".yaml") || path.ends_with(".yml") {
+            serde_yaml::from_str(&contents).map_err(Error::Yaml)?
+        } else {
+            return Err(Error::UnknownFormat(path.to_string()));
+        };
+
+        let config = Config {
+            listeners: file.listeners.unwrap_or_else(|| base.listeners.clone()),
+            admin_bind: file.admin_bind.or_else(|| base.admin_bind.clone()),
+            admin_token: file.admin_token.or_else(|| base.admin_token.clone()),
+            balancing_strategy: file.balancing_strategy.unwrap_or(base.balancing_strategy),
+            per_request_balancing: file
+                .per_request_balancing
+                .unwrap_or(base.per_request_balancing),
+            affinity: file.affinity.unwrap_or_else(|| base.affinity.clone()),
+            shutdown_timeout: file.shutdown_timeout.unwrap_or(base.shutdown_timeout),
+            upstreams: file.upstreams.unwrap_or_else(|| base.upstreams.clone()),
+            pools: file.pools.unwrap_or_else(|| base.pools.clone()),
+            routes: file.routes.unwrap_or_else(|| base.routes.clone()),
+            tls: file.tls.unwrap_or_else(|| base.tls.clone()),
+            tcp: file.tcp.unwrap_or_else(|| base.tcp.clone()),
+            connect: file.connect.unwrap_or_else(|| base.connect.clone()),
+            proxy_protocol: file
+                .proxy_protocol
+                .unwrap_or_else(|| base.proxy_protocol.clone()),
+            forwarding: file.forwarding.unwrap_or_else(|| base.forwarding.clone()),
+            health_check: file.health_check.unwrap_or_else(|| base.health_check.clone()),
+            passive_health_check: file
+                .passive_health_check
+                .unwrap_or_else(|| base.passive_health_check.clone()),
+            rate_limit: file.rate_limit.unwrap_or_else(|| base.rate_limit.clone()),
+            retry: file.retry.unwrap_or_else(|| base.retry.clone()),
+            timeouts: file.timeouts.unwrap_or_else(|| base.timeouts.clone()),
+            pool: file.pool.unwrap_or_else(|| base.pool.clone()),
+            access_log: file.access_log.unwrap_or_else(|| base.access_log.clone()),
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Makes sure the configuration is usable before balancebeam starts (or switches to) it.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.listeners.is_empty()
+            && self.tls.listeners.is_empty()
+            && self.tcp.listeners.is_empty()
+        {
+            return Err(Error::NoListeners);
+        }
+        if !self.tls.listeners.is_empty() && self.tls.certificates.is_empty() {
+            return Err(Error::NoCertificates);
+        }
+        if self.upstreams.is_empty() && self.pools.is_empty() {
+            return Err(Error::NoUpstreams);
+        }
+        let mut pool_names = HashSet::new();
+        for pool in &self.pools {
+            if pool.upstreams.is_empty() {
+                return Err(Error::EmptyPool(pool.name.clone()));
+            }
+            if !pool_names.insert(pool.name.as_str()) {
+                return Err(Error::DuplicatePool(pool.name.clone()));
+            }
+        }
+        if let Some(route) = self.routes.iter().find(|route| !pool_names.contains(&*route.pool)) {
+            return Err(Error::UnknownPool(route.pool.clone()));
+        }
+        for listener in &self.tcp.listeners {
+            match &listener.pool {
+                Some(pool) if !pool_names.contains(pool.as_str()) => {
+                    return Err(Error::UnknownPool(pool.clone()));
+                }
+                _ => {}
+            }
+        }
+        let mut addresses = HashSet::new();
+        for upstream in self.all_upstreams() {
+            if !(1..=MAX_WEIGHT).contains(&upstream.weight) {
+                return Err(Error::InvalidWeight(upstream.address.clone()));
+            }
+            if !addresses.insert(upstream.address.as_str()) {
+                return Err(Error::DuplicateUpstream(upstream.address.clone()));
+            }
+        }
+        if self.health_check.healthy_threshold == 0 || self.health_check.unhealthy_threshold == 0 {
+            return Err(Error::InvalidThreshold);
+        }
+        let error_rate = self.passive_health_check.error_rate;
+        if !(0.0..=1.0).contains(&error_rate) {
+            return Err(Error::InvalidErrorRate(error_rate));
+        }
+        let budget_ratio = self.retry.budget_ratio;
+        if budget_ratio.is_nan() || budget_ratio < 0.0 {
+            return Err(Error::InvalidRetryBudget(budget_ratio));
+        }
+        Ok(())
+    }
+
+    /// Returns the top-level upstreams followed by the upstreams in every pool.
+    pub fn all_upstreams(&self) -> impl Iterator<Item = &Upstream> {
+        self.upstreams
+            .iter()
+            .chain(self.pools.iter().flat_map(|pool| pool.upstreams.iter()))
+    }
+
+    pub fn upstream_addresses(&self) -> Vec<String> {
+        self.all_upstreams()
+            .map(|upstream| upstream.address.clone())
+            .collect()
+    }
+
+    pub fn upstream_weights(&self) -> HashMap<String, usize> {
+        self.all_upstreams()
+            .map(|upstream| (upstream.address.clone(), upstream.weight))
+            .collect()
+    }
+
+    /// Returns true if `upstream` only takes raw TCP connections from a TCP listener, in which
+    /// case it may not speak HTTP at all. Upstreams that HTTP requests can also be sent to (the
+    /// top-level upstreams when there are HTTP listeners, and pools that routes name) speak HTTP.
+    pub fn is_tcp_upstream(&self, upstream: &str) -> bool {
+        let pool = self.pool_of(upstream);
+        let tcp = self
+            .tcp
+            .listeners
+            .iter()
+            .any(|listener| listener.pool.as_deref() == pool);
+        let http = match pool {
+            None => !self.listeners.is_empty() || !self.tls.listeners.is_empty(),
+            Some(pool) => self.routes.iter().any(|route| route.pool == pool),
+        };
+        tcp && !http
+    }
+
+    /// Returns the name of the pool `upstream` belongs to, or None if it is one of the top-level
+    /// upstreams (or was added through the admin API).
+    pub fn pool_of(&self, upstream: &str) -> Option<&str> {
+        self.pools
+            .iter()
+            .find(|pool| pool.upstreams.iter().any(|u| u.address == upstream))
+            .map(|pool| pool.name.as_str())
+    }
+}
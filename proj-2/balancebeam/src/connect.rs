@@ -0,0 +1,24 @@
+use crate::routing;
+
+/// Returns the destination ("host:port") of a CONNECT request, or None if its target isn't a
+/// host and port.
+pub fn destination(request: &http::Request<Vec<u8>>) -> Option<String> {
+    let authority = request.uri().authority()?;
+    let port = authority.port_u16()?;
+    Some(format!("{}:{}", authority.host().to_ascii_lowercase(), port))
+}
+
+/// Returns true if `destination` ("host:port") matches one of the patterns in `allow`.
+pub fn is_allowed(destination: &str, allow: &[String]) -> bool {
+    let (host, port) = match destination.rsplit_once(':') {
+        Some(parts) => parts,
+        None => return false,
+    };
+    allow.iter().any(|pattern| match pattern.rsplit_once(':') {
+        Some((host_pattern, port_pattern)) => {
+            (port_pattern == "*" || port_pattern == port)
+                && routing::host_matches(host_pattern, host)
+        }
+        None => false,
+    })
+}
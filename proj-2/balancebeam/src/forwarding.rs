@@ -0,0 +1,163 @@
+use crate::cidr;
+use crate::config;
+use crate::request;
+use serde::Deserialize;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+/// Headers that say where a request came from before it reached us. They are only passed on
+/// from trusted proxies; anyone else could put whatever they like in them.
+const FORWARDING_HEADERS: [&str; 4] = [
+    "forwarded",
+    "x-forwarded-for",
+    "x-forwarded-host",
+    "x-forwarded-proto",
+];
+
+/// The header the trusted proxies record each hop's client in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Header {
+    /// X-Forwarded-For, a list of addresses
+    #[default]
+    XForwardedFor,
+    /// Forwarded (RFC 7239), whose elements name each hop's client in a "for" parameter
+    Forwarded,
+}
+
+impl FromStr for Header {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "x-forwarded-for" => Ok(Header::XForwardedFor),
+            "forwarded" => Ok(Header::Forwarded),
+            _ => Err(format!(
+                "unknown forwarding header \"{}\" (expected x-forwarded-for or forwarded)",
+                s
+            )),
+        }
+    }
+}
+
+/// Works out which client `request` really comes from, given that it arrived from `peer`. If
+/// `peer` is a trusted proxy, the addresses the proxies have recorded in the header they write
+/// (the other one could have come from anyone) are followed back from the most recent one for as
+/// long as they name trusted proxies; the first address that doesn't is the client.
+pub fn client_ip(
+    request: &http::Request<Vec<u8>>,
+    peer: IpAddr,
+    settings: &config::Forwarding,
+) -> IpAddr {
+    let mut client = peer;
+    for hop in forwarded_for(request, settings.header).iter().rev() {
+        if !cidr::contains_any(&settings.trusted_proxies, client) {
+            break;
+        }
+        // A proxy that hides its client ("unknown" or an obfuscated name) is as far back as we
+        // can go
+        match parse_node(hop) {
+            Some(ip) => client = ip,
+            None => break,
+        }
+    }
+    client
+}
+
+/// Tells the upstream where `request` came from, given that it arrived from `peer` with
+/// `scheme` ("http" or "https"). Our hop is added to X-Forwarded-For and Forwarded (RFC 7239),
+/// and X-Forwarded-Proto and X-Forwarded-Host are filled in unless a trusted proxy already did.
+/// Whatever anyone else claimed in these headers is thrown away first.
+pub fn add_headers(
+    request: &mut http::Request<Vec<u8>>,
+    peer: IpAddr,
+    scheme: &'static str,
+    settings: &config::Forwarding,
+) {
+    if !cidr::contains_any(&settings.trusted_proxies, peer) {
+        for name in FORWARDING_HEADERS.iter() {
+            request.headers_mut().remove(*name);
+        }
+    }
+    request::extend_header_value(request, "x-forwarded-for", &peer.to_string());
+    let headers = request.headers_mut();
+    if !headers.contains_key("x-forwarded-proto") {
+        headers.insert("x-forwarded-proto", http::HeaderValue::from_static(scheme));
+    }
+    let host = headers.get("host").cloned();
+    if let Some(host) = &host {
+        if !headers.contains_key("x-forwarded-host") {
+            headers.insert("x-forwarded-host", host.clone());
+        }
+    }
+
+    // IPv6 addresses have to be bracketed and quoted, since they're full of colons
+    let mut element = match peer {
+        IpAddr::V4(ip) => format!("for={}", ip),
+        IpAddr::V6(ip) => format!("for=\"[{}]\"", ip),
+    };
+    if let Some(host) = host.as_ref().and_then(|host| host.to_str().ok()) {
+        element += &format!(";host={}", quote_if_needed(host));
+    }
+    element += &format!(";proto={}", scheme);
+    request::extend_header_value(request, "forwarded", &element);
+}
+
+/// Returns the client addresses the proxies in front of us have recorded in `header`, oldest
+/// first.
+fn forwarded_for(request: &http::Request<Vec<u8>>, header: Header) -> Vec<String> {
+    let values = |name: &str| -> Vec<String> {
+        request
+            .headers()
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|item| item.trim().to_string())
+            .collect()
+    };
+    if header == Header::XForwardedFor {
+        return values("x-forwarded-for");
+    }
+    // Each element of Forwarded is a hop, made up of ";"-separated parameters
+    values("forwarded")
+        .iter()
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.split_once('=')?;
+                if key.trim().eq_ignore_ascii_case("for") {
+                    Some(value.trim().to_string())
+                } else {
+                    None
+                }
+            })
+        })
+        .collect()
+}
+
+/// Parses a node as found in X-Forwarded-For or Forwarded: an IP address, possibly quoted,
+/// bracketed (for IPv6) or followed by a port.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim_matches('"');
+    if let Ok(ip) = node.parse() {
+        return Some(ip);
+    }
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(addr.ip());
+    }
+    node.strip_prefix('[')?.strip_suffix(']')?.parse().ok()
+}
+
+/// Returns `value` as a Forwarded parameter value: as it is if it is a valid token, or as a
+/// quoted string otherwise (e.g. a host with a port).
+fn quote_if_needed(value: &str) -> String {
+    let is_token = !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c));
+    if is_token {
+        value.to_string()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
@@ -0,0 +1,199 @@
+use crate::{config, request, response, timeout, tls, ProxyState};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Number of probes in a row that an upstream has passed or failed.
+#[derive(Default)]
+struct Streak {
+    successes: usize,
+    failures: usize,
+}
+
+/// Remembers recent probe results for each upstream, so that an upstream only moves between the
+/// live and dead lists after passing or failing several probes in a row instead of flapping on a
+/// single flaky one.
+pub struct HealthHistory {
+    streaks: Mutex<HashMap<String, Streak>>,
+}
+
+impl HealthHistory {
+    pub fn new() -> HealthHistory {
+        HealthHistory {
+            streaks: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Forgets the probe results for `upstream`. This is called whenever something other than
+    /// the health checks moves an upstream in or out of rotation, so that it has to earn its way
+    /// back with a fresh streak.
+    pub fn forget(&self, upstream: &str) {
+        self.streaks.lock().remove(upstream);
+    }
+
+    /// Records the outcome of a probe of `upstream`, which is currently live if `live` is true,
+    /// and returns whether it should be live from now on.
+    fn record(
+        &self,
+        upstream: &str,
+        live: bool,
+        passed: bool,
+        settings: &config::HealthCheck,
+    ) -> bool {
+        let mut streaks = self.streaks.lock();
+        let streak = streaks.entry(upstream.to_string()).or_default();
+        if passed {
+            streak.successes += 1;
+            streak.failures = 0;
+        } else {
+            streak.failures += 1;
+            streak.successes = 0;
+        }
+        let now_live = if live {
+            streak.failures < settings.unhealthy_threshold
+        } else {
+            streak.successes >= settings.healthy_threshold
+        };
+        if now_live != live {
+            streaks.remove(upstream);
+        }
+        now_live
+    }
+}
+
+/// Probes every live and dead upstream at the same time, then moves upstreams between the live
+/// and dead lists according to the results. The lists aren't locked while the probes are under
+/// way, so a slow upstream doesn't hold up client connections; upstreams that were added, drained
+/// or removed in the meantime are left alone.
+pub async fn perform_health_check(state: &ProxyState) {
+    let config = state.config();
+    let settings = config.health_check.clone();
+    let addresses: Vec<String> = {
+        let upstream_addresses = state.upstream_addresses.lock().await;
+        let dead_upstream_addresses = state.dead_upstream_addresses.lock().await;
+        upstream_addresses
+            .iter()
+            .chain(dead_upstream_addresses.iter())
+            .cloned()
+            .collect()
+    };
+
+    let probes: Vec<_> = addresses
+        .into_iter()
+        .map(|addr| {
+            let settings = settings.clone();
+            let connector = state.upstream_tls.clone();
+            let connect_only = config.is_tcp_upstream(&addr);
+            tokio::spawn(async move {
+                let passed = probe(&addr, &connector, &settings, connect_only).await;
+                (addr, passed)
+            })
+        })
+        .collect();
+    let mut results = HashMap::new();
+    for probe in probes {
+        if let Ok((addr, passed)) = probe.await {
+            results.insert(addr, passed);
+        }
+    }
+
+    let mut upstream_addresses = state.upstream_addresses.lock().await;
+    let mut dead_upstream_addresses = state.dead_upstream_addresses.lock().await;
+    let mut failed = Vec::new();
+    let mut revived = Vec::new();
+    upstream_addresses.retain(|addr| match results.get(addr) {
+        Some(&passed) if !state.health_history.record(addr, true, passed, &settings) => {
+            failed.push(addr.clone());
+            false
+        }
+        _ => true,
+    });
+    dead_upstream_addresses.retain(|addr| match results.get(addr) {
+        Some(&passed) if state.health_history.record(addr, false, passed, &settings) => {
+            revived.push(addr.clone());
+            false
+        }
+        _ => true,
+    });
+    for addr in &failed {
+        log::warn!("Upstream {} failed its health checks; marking it dead", addr);
+        state.pool.remove_upstream(addr);
+    }
+    for addr in &revived {
+        log::info!("Upstream {} passed its health checks; marking it live", addr);
+    }
+    upstream_addresses.append(&mut revived);
+    dead_upstream_addresses.append(&mut failed);
+    log::debug!(
+        "Health checks done; live upstreams: {:?}, dead upstreams: {:?}",
+        *upstream_addresses,
+        *dead_upstream_addresses
+    );
+}
+
+/// Sends a health check request to `addr`, and returns true if it answered with a successful
+/// status within the timeout. The request goes over TLS if the upstream is configured for it, so
+/// a broken certificate fails the health check too. With `connect_only`, for upstreams that take
+/// raw TCP connections and may not speak HTTP, getting connected is enough to pass.
+async fn probe(
+    addr: &str,
+    connector: &Arc<tls::UpstreamConnector>,
+    settings: &config::HealthCheck,
+    connect_only: bool,
+) -> bool {
+    let probe = async {
+        if connect_only {
+            connector
+                .connect(addr, None)
+                .await
+                .map(|_| http::StatusCode::OK)
+                .map_err(response::Error::ConnectionError)
+        } else {
+            send_probe(addr, connector, &settings.path).await
+        }
+    };
+    // Without a timeout of its own, a probe still mustn't outlast the check interval: the round
+    // waits for every probe, so an upstream that never answers would hold up all later rounds
+    let limit = if settings.timeout > 0 {
+        settings.timeout
+    } else {
+        (settings.interval as u64).max(1)
+    };
+    match timeout::limit(timeout::from_secs(limit), probe).await {
+        Some(Ok(status)) if !status.is_client_error() && !status.is_server_error() => true,
+        Some(Ok(status)) => {
+            log::warn!("Upstream {} answered its health check with {}", addr, status);
+            false
+        }
+        Some(Err(error)) => {
+            log::warn!("Health check of upstream {} failed: {:?}", addr, error);
+            false
+        }
+        None => {
+            log::warn!("Health check of upstream {} timed out after {}s", addr, limit);
+            false
+        }
+    }
+}
+
+async fn send_probe(
+    addr: &str,
+    connector: &tls::UpstreamConnector,
+    path: &str,
+) -> Result<http::StatusCode, response::Error> {
+    let mut conn = connector
+        .connect(addr, None)
+        .await
+        .map_err(response::Error::ConnectionError)?;
+    let request = http::Request::builder()
+        .method(http::Method::GET)
+        .uri(path)
+        .header("Host", addr)
+        .body(Vec::new())
+        .unwrap();
+    request::write_to_stream(&request, &mut conn)
+        .await
+        .map_err(response::Error::ConnectionError)?;
+    let (response, _) = response::read_from_stream(&mut conn, request.method()).await?;
+    Ok(response.status())
+}
@@ -0,0 +1,346 @@
+use crate::body;
+use bytes::{Buf, Bytes};
+use h2::server::{Connection, SendResponse};
+use h2::{RecvStream, SendStream};
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::Session;
+use tokio_rustls::server::TlsStream;
+
+/// What a client that speaks HTTP/2 without negotiating it first (h2c with prior knowledge) sends
+/// before anything else.
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// Most streams a client may have open at once on one connection.
+const MAX_CONCURRENT_STREAMS: u32 = 100;
+
+/// Headers that only mean something for a single HTTP/1.1 connection, which HTTP/2 doesn't allow.
+const CONNECTION_HEADERS: [&str; 5] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Removes the headers that only mean something for a single connection: the ones above, and any
+/// that the Connection header names (RFC 9113 section 8.2.2).
+fn remove_connection_headers(headers: &mut http::HeaderMap) {
+    let named: Vec<String> = headers
+        .get_all("connection")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .collect();
+    for name in named.iter().map(String::as_str).chain(CONNECTION_HEADERS) {
+        headers.remove(name);
+    }
+}
+
+/// Reads the start of what a client sends on a cleartext connection, and returns true if it is the
+/// HTTP/2 connection preface. Reading stops as soon as the data stops matching the preface, which
+/// for an HTTP/1.1 client is after the first read. Whatever was read is replayed by the returned
+/// stream, so either protocol can take the connection from the top.
+pub async fn detect_preface(mut stream: TcpStream) -> io::Result<(bool, Rewound<TcpStream>)> {
+    let mut buffer = vec![0_u8; PREFACE.len()];
+    let mut bytes_read = 0;
+    let is_preface = loop {
+        let n = stream.read(&mut buffer[bytes_read..]).await?;
+        bytes_read += n;
+        if n == 0 || buffer[..bytes_read] != PREFACE[..bytes_read] {
+            break false;
+        }
+        if bytes_read == PREFACE.len() {
+            break true;
+        }
+    };
+    buffer.truncate(bytes_read);
+    Ok((is_preface, Rewound::new(stream, buffer)))
+}
+
+/// Wraps a connection that some bytes have already been read from, and gives those bytes back
+/// before reading anything more from the connection.
+pub struct Rewound<S> {
+    inner: S,
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+impl<S> Rewound<S> {
+    pub fn new(inner: S, buffer: Vec<u8>) -> Rewound<S> {
+        Rewound {
+            inner,
+            buffer,
+            position: 0,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewound<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.position < self.buffer.len() {
+            let n = buf.len().min(self.buffer.len() - self.position);
+            buf[..n].copy_from_slice(&self.buffer[self.position..self.position + n]);
+            self.position += n;
+            return Poll::Ready(Ok(n));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewound<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Returns true if the client picked HTTP/2 through ALPN during the TLS handshake.
+pub fn is_negotiated(stream: &TlsStream<TcpStream>) -> bool {
+    stream.get_ref().1.get_alpn_protocol() == Some(b"h2")
+}
+
+/// Performs the server side of the HTTP/2 handshake on a client connection.
+pub async fn handshake<S>(stream: S) -> Result<Connection<S, Bytes>, h2::Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    h2::server::Builder::new()
+        .max_concurrent_streams(MAX_CONCURRENT_STREAMS)
+        .handshake(stream)
+        .await
+}
+
+/// One request on an HTTP/2 connection, which can be read and written like a connection of its
+/// own: reading gives the request body, framed the way it was described to the upstream, and
+/// writing sends response body data once the response headers are out. Flow control is left to
+/// the reader and writer: the client may only send more of the body as it is read, and writes wait
+/// until the client is ready to take more of the response.
+pub struct Exchange {
+    request_body: RecvStream,
+    /// Whether the request body is passed on in chunked framing, since the client didn't say how
+    /// long it is
+    chunked: bool,
+    /// Request body bytes that have been received but not read yet
+    pending: Bytes,
+    finished: bool,
+    respond: SendResponse<Bytes>,
+    response_body: Option<SendStream<Bytes>>,
+}
+
+impl Exchange {
+    /// Turns the headers of an HTTP/2 request into the HTTP/1.1 form the upstreams expect: the
+    /// authority becomes the Host header, the target is just the path, connection-specific headers
+    /// are dropped, and a body of unknown length is chunked. The version is left as HTTP/2, for the
+    /// caller to log before switching it over.
+    pub fn new(
+        request: http::Request<RecvStream>,
+        respond: SendResponse<Bytes>,
+    ) -> (http::Request<Vec<u8>>, body::Length, Exchange) {
+        let (mut parts, request_body) = request.into_parts();
+        if let Some(authority) = parts.uri.authority() {
+            if let Ok(host) = http::HeaderValue::from_str(authority.as_str()) {
+                parts.headers.insert("host", host);
+            }
+        }
+        let path = parts
+            .uri
+            .path_and_query()
+            .map_or("/", |path| path.as_str())
+            .parse()
+            .unwrap_or_else(|_| http::Uri::from_static("/"));
+        parts.uri = path;
+        remove_connection_headers(&mut parts.headers);
+
+        // HTTP/2 clients may send each cookie in a header of its own, which HTTP/1.1 doesn't allow
+        let cookies: Vec<&str> = parts
+            .headers
+            .get_all("cookie")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .collect();
+        if cookies.len() > 1 {
+            if let Ok(cookie) = http::HeaderValue::from_str(&cookies.join("; ")) {
+                parts.headers.insert("cookie", cookie);
+            }
+        }
+
+        let content_length = parts
+            .headers
+            .get("content-length")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok());
+        let length = match content_length {
+            _ if request_body.is_end_stream() => body::Length::Empty,
+            Some(len) => body::Length::ContentLength(len),
+            None => {
+                parts.headers.insert(
+                    "transfer-encoding",
+                    http::HeaderValue::from_static("chunked"),
+                );
+                body::Length::Chunked
+            }
+        };
+        let exchange = Exchange {
+            request_body,
+            chunked: length == body::Length::Chunked,
+            pending: Bytes::new(),
+            finished: false,
+            respond,
+            response_body: None,
+        };
+        (http::Request::from_parts(parts, Vec::new()), length, exchange)
+    }
+
+    /// Sends the response headers, leaving out the ones HTTP/2 doesn't allow. If `end_of_stream` is
+    /// true, there is no body to follow and the stream ends right away.
+    pub fn send_headers(
+        &mut self,
+        response: &http::Response<Vec<u8>>,
+        end_of_stream: bool,
+    ) -> io::Result<()> {
+        let mut head = http::Response::new(());
+        *head.status_mut() = response.status();
+        *head.version_mut() = http::Version::HTTP_2;
+        *head.headers_mut() = response.headers().clone();
+        remove_connection_headers(head.headers_mut());
+        let response_body = self
+            .respond
+            .send_response(head, end_of_stream)
+            .map_err(io::Error::other)?;
+        if !end_of_stream {
+            self.response_body = Some(response_body);
+        }
+        Ok(())
+    }
+}
+
+/// Encodes the last chunk of a chunked body, carrying `trailers`.
+fn last_chunk(mut trailers: http::HeaderMap) -> Vec<u8> {
+    remove_connection_headers(&mut trailers);
+    let mut chunk = b"0\r\n".to_vec();
+    for (name, value) in trailers.iter() {
+        chunk.extend_from_slice(name.as_str().as_bytes());
+        chunk.extend_from_slice(b": ");
+        chunk.extend_from_slice(value.as_bytes());
+        chunk.extend_from_slice(b"\r\n");
+    }
+    chunk.extend_from_slice(b"\r\n");
+    chunk
+}
+
+impl AsyncBufRead for Exchange {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        while this.pending.is_empty() && !this.finished {
+            match ready!(this.request_body.poll_data(cx)) {
+                Some(Ok(data)) => {
+                    // The client gets to send this much more once it has been read
+                    let _ = this.request_body.flow_control().release_capacity(data.len());
+                    this.pending = if this.chunked && !data.is_empty() {
+                        let mut chunk = format!("{:x}\r\n", data.len()).into_bytes();
+                        chunk.extend_from_slice(&data);
+                        chunk.extend_from_slice(b"\r\n");
+                        Bytes::from(chunk)
+                    } else {
+                        data
+                    };
+                }
+                Some(Err(error)) => return Poll::Ready(Err(io::Error::other(error))),
+                None if this.chunked => {
+                    // Trailers, if the client sent any, go in the last chunk. (With a known length
+                    // there's nowhere to put them, so they're dropped.)
+                    let trailers = match ready!(this.request_body.poll_trailers(cx)) {
+                        Ok(trailers) => trailers.unwrap_or_default(),
+                        Err(error) => return Poll::Ready(Err(io::Error::other(error))),
+                    };
+                    this.finished = true;
+                    this.pending = Bytes::from(last_chunk(trailers));
+                }
+                None => this.finished = true,
+            }
+        }
+        Poll::Ready(Ok(&this.pending))
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        self.get_mut().pending.advance(amt);
+    }
+}
+
+impl AsyncRead for Exchange {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let pending = ready!(self.as_mut().poll_fill_buf(cx))?;
+        let n = std::cmp::min(buf.len(), pending.len());
+        buf[..n].copy_from_slice(&pending[..n]);
+        self.consume(n);
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl AsyncWrite for Exchange {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let response_body = match self.get_mut().response_body.as_mut() {
+            Some(response_body) => response_body,
+            None => return Poll::Ready(Err(io::ErrorKind::NotConnected.into())),
+        };
+        response_body.reserve_capacity(buf.len());
+        loop {
+            let capacity = response_body.capacity();
+            if capacity > 0 {
+                let n = std::cmp::min(capacity, buf.len());
+                response_body
+                    .send_data(Bytes::copy_from_slice(&buf[..n]), false)
+                    .map_err(io::Error::other)?;
+                return Poll::Ready(Ok(n));
+            }
+            match ready!(response_body.poll_capacity(cx)) {
+                Some(Ok(_)) => {}
+                Some(Err(error)) => return Poll::Ready(Err(io::Error::other(error))),
+                None => return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Data is sent by the task driving the connection as soon as it has been handed over
+        Poll::Ready(Ok(()))
+    }
+
+    /// Ends the response body.
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let Some(mut response_body) = self.get_mut().response_body.take() {
+            response_body
+                .send_data(Bytes::new(), true)
+                .map_err(io::Error::other)?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
@@ -0,0 +1,178 @@
+use crate::config::Config;
+use crate::{admin, ListenerKind, ProxyState};
+use std::collections::HashMap;
+use std::os::unix::io::{AsRawFd, RawFd};
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+/// A listening socket that a task of its own is accepting connections on.
+struct Running {
+    bind: String,
+    fd: RawFd,
+    /// Tells the task to stop accepting connections (as does dropping it)
+    stop: oneshot::Sender<()>,
+    /// Hands the socket back once the task has stopped
+    task: JoinHandle<TcpListener>,
+}
+
+impl Running {
+    /// Stops accepting connections and returns the socket, which is still open. Connections that
+    /// were already accepted carry on.
+    async fn stop(self) -> Option<TcpListener> {
+        let _ = self.stop.send(());
+        self.task.await.ok()
+    }
+}
+
+/// The sockets that client and admin connections are accepted on. Reloading the configuration
+/// opens the ones it adds and closes the ones it takes away.
+pub struct Listeners {
+    clients: Vec<(ListenerKind, Running)>,
+    admin: Option<Running>,
+}
+
+impl Listeners {
+    pub fn new() -> Listeners {
+        Listeners {
+            clients: Vec::new(),
+            admin: None,
+        }
+    }
+
+    /// Starts accepting client connections of the given kind on `listener`.
+    pub fn serve(
+        &mut self,
+        bind: &str,
+        listener: TcpListener,
+        kind: ListenerKind,
+        state: &ProxyState,
+    ) {
+        log::info!("Listening for {} on {}", kind.describe(), bind);
+        let (stop, stopped) = oneshot::channel();
+        let fd = listener.as_raw_fd();
+        let state = state.clone();
+        let task_kind = kind.clone();
+        let task =
+            tokio::spawn(async move { crate::serve(listener, task_kind, stopped, &state).await });
+        let bind = bind.to_string();
+        self.clients.push((kind, Running { bind, fd, stop, task }));
+    }
+
+    /// Starts answering admin requests on `listener`, in place of any admin listener there was.
+    pub fn serve_admin(&mut self, bind: &str, listener: TcpListener, state: &ProxyState) {
+        log::info!("Listening for admin requests on {}", bind);
+        let (stop, stopped) = oneshot::channel();
+        let fd = listener.as_raw_fd();
+        let state = state.clone();
+        let task = tokio::spawn(async move { admin::serve(listener, stopped, &state).await });
+        let bind = bind.to_string();
+        self.admin = Some(Running { bind, fd, stop, task });
+    }
+
+    /// Returns true if there is a listener on `bind` already.
+    pub fn is_listening(&self, bind: &str) -> bool {
+        self.all().any(|running| running.bind == bind)
+    }
+
+    /// Returns the address and file descriptor of every listener, to hand over to an upgraded
+    /// process.
+    pub fn fds(&self) -> Vec<(String, RawFd)> {
+        self.all()
+            .map(|running| (running.bind.clone(), running.fd))
+            .collect()
+    }
+
+    /// Waits for the client listeners to stop accepting connections once shutdown has started.
+    /// (The admin listener stays up until the process exits.)
+    pub async fn wait_stopped(&mut self) {
+        for (_, running) in self.clients.drain(..) {
+            let _ = running.task.await;
+        }
+    }
+
+    /// Brings the listeners in line with `config`. Sockets that are still wanted (even for another
+    /// kind of connection) are kept open, and the rest are closed; `bound` holds the sockets opened
+    /// for addresses that weren't being listened on before.
+    pub async fn update(
+        &mut self,
+        config: &Config,
+        mut bound: HashMap<String, TcpListener>,
+        state: &ProxyState,
+    ) {
+        let wanted = wanted(config);
+        let mut kept = Vec::new();
+        for (kind, running) in self.clients.drain(..) {
+            if wanted.contains(&(running.bind.clone(), kind.clone())) {
+                kept.push((kind, running));
+                continue;
+            }
+            let bind = running.bind.clone();
+            if let Some(listener) = running.stop().await {
+                log::info!("No longer listening for {} on {}", kind.describe(), bind);
+                bound.insert(bind, listener);
+            }
+        }
+        self.clients = kept;
+        if let Some(running) = self.admin.take() {
+            if config.admin_bind.as_ref() == Some(&running.bind) {
+                self.admin = Some(running);
+            } else {
+                let bind = running.bind.clone();
+                if let Some(listener) = running.stop().await {
+                    log::info!("No longer listening for admin requests on {}", bind);
+                    bound.insert(bind, listener);
+                }
+            }
+        }
+
+        for (bind, kind) in wanted {
+            let is_running = self
+                .clients
+                .iter()
+                .any(|(running_kind, running)| running.bind == bind && *running_kind == kind);
+            if let (false, Some(listener)) = (is_running, bound.remove(&bind)) {
+                self.serve(&bind, listener, kind, state);
+            }
+        }
+        if let (None, Some(bind)) = (&self.admin, &config.admin_bind) {
+            if let Some(listener) = bound.remove(bind) {
+                self.serve_admin(bind, listener, state);
+            }
+        }
+        // Whatever is left over is closed as it is dropped
+    }
+
+    fn all(&self) -> impl Iterator<Item = &Running> {
+        self.clients
+            .iter()
+            .map(|(_, running)| running)
+            .chain(self.admin.iter())
+    }
+}
+
+/// Returns the address and kind of every client listener that `config` asks for.
+pub fn wanted(config: &Config) -> Vec<(String, ListenerKind)> {
+    let binds = config
+        .listeners
+        .iter()
+        .map(|bind| (bind.clone(), ListenerKind::Http));
+    let tls_binds = config
+        .tls
+        .listeners
+        .iter()
+        .map(|bind| (bind.clone(), ListenerKind::Https));
+    let tcp_binds = config
+        .tcp
+        .listeners
+        .iter()
+        .map(|listener| (listener.bind.clone(), ListenerKind::Tcp(listener.pool.clone())));
+    binds.chain(tls_binds).chain(tcp_binds).collect()
+}
+
+/// Returns every address that `config` asks to listen on, client and admin alike.
+pub fn binds(config: &Config) -> Vec<String> {
+    let mut binds: Vec<String> = wanted(config).into_iter().map(|(bind, _)| bind).collect();
+    binds.extend(config.admin_bind.clone());
+    binds
+}
@@ -1,30 +1,137 @@
+mod access_log;
+mod admin;
+mod affinity;
+mod balancer;
+mod body;
+mod chunked;
+mod cidr;
+mod circuit_breaker;
+mod config;
+mod connect;
+mod forwarding;
+mod health_check;
+mod http2;
+mod listeners;
+mod metrics;
+mod pool;
+mod proxy_protocol;
+mod rate_limit;
 mod request;
 mod response;
+mod retry;
+mod routing;
+mod shutdown;
+mod timeout;
+mod tls;
+mod tunnel;
+mod upgrade;
 
 use clap::Parser;
-use rand::{Rng, SeedableRng};
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::{stream::StreamExt};
-use tokio::sync::Mutex;
-use std::time::Duration;
+use tokio::sync::{oneshot, Mutex};
+use access_log::CountingStream;
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::time::{Duration, Instant};
 use tokio::time::delay_for;
 use async_std::sync::Arc;
-use std::io::{Error, ErrorKind};
-use std::collections::HashMap;
+use std::io::Error;
 
 /// Contains information parsed from the command-line invocation of balancebeam. The Clap macros
 /// provide a fancy way to automatically construct a command-line argument parser.
 #[derive(Parser, Debug)]
 #[clap(about = "Fun with load balancing")]
 struct CmdOptions {
+    #[clap(short, long)]
+    /// TOML or YAML file to read settings from. Sections present in the file override the
+    /// corresponding command-line options, and the file is re-read on SIGHUP
+    config: Option<String>,
+
     #[clap(short, long, default_value = "0.0.0.0:1100")]
     /// IP/port to bind to
     bind: String,
 
+    #[clap(long)]
+    /// IP/port to accept HTTPS connections on (requires --tls-cert and --tls-key)
+    tls_bind: Vec<String>,
+
+    #[clap(long)]
+    /// IP/port to accept raw TCP connections on, which are relayed to the upstreams as they are.
+    /// The upstreams are still health checked over HTTP, since HTTP requests go to them too
+    tcp_bind: Vec<String>,
+
+    #[clap(long)]
+    /// Destination (host:port, where the host may start with "*." and the port may be "*") that
+    /// HTTP CONNECT requests may open a tunnel to. CONNECT is refused if none are given
+    connect_allow: Vec<String>,
+
+    #[clap(long)]
+    /// Address block (e.g. "10.0.0.0/8") of a proxy in front of balancebeam. Connections from it
+    /// must start with a PROXY protocol header saying where the client really is
+    proxy_protocol_trusted: Vec<cidr::Cidr>,
+
+    #[clap(long)]
+    /// Send a PROXY protocol header (v1, v2) at the start of every connection to the upstreams
+    upstream_proxy_protocol: Option<proxy_protocol::Version>,
+
+    #[clap(long)]
+    /// Address block (e.g. "10.0.0.0/8") of an HTTP proxy in front of balancebeam, whose
+    /// X-Forwarded-For and Forwarded headers are trusted to say who the client is
+    trusted_proxy: Vec<cidr::Cidr>,
+
+    #[clap(long, default_value = "x-forwarded-for")]
+    /// Header the trusted proxies record the client in (x-forwarded-for, forwarded)
+    trusted_proxy_header: forwarding::Header,
+
+    #[clap(long)]
+    /// PEM file with the certificate chain to serve on the HTTPS listeners; re-read on SIGHUP
+    tls_cert: Option<String>,
+
+    #[clap(long)]
+    /// PEM file with the private key for --tls-cert
+    tls_key: Option<String>,
+
+    #[clap(long)]
+    /// IP/port to serve the admin endpoints (e.g. Prometheus metrics on /metrics) on
+    admin_bind: Option<String>,
+
+    #[clap(long)]
+    /// Bearer token required by the admin API for listing, adding, draining and removing
+    /// upstreams (the upstream API is disabled without one)
+    admin_token: Option<String>,
+
     #[clap(short, long)]
-    /// Upstream host to forward requests to
+    /// Upstream host to forward requests to, optionally with a weight (host:port=weight)
     upstream: Vec<String>,
 
+    #[clap(long, default_value = "random")]
+    /// How to pick an upstream for each connection (random, round-robin, weighted-round-robin,
+    /// least-connections)
+    balancing_strategy: balancer::Strategy,
+
+    #[clap(long)]
+    /// Pick an upstream for every request, instead of once per client connection
+    per_request_balancing: bool,
+
+    #[clap(long, default_value = "none")]
+    /// How to keep a client's requests on the same upstream (none, cookie, consistent-hash)
+    affinity: affinity::Mode,
+
+    #[clap(long, default_value = "balancebeam_upstream")]
+    /// Name of the cookie that names the upstream, with cookie affinity
+    affinity_cookie_name: String,
+
+    #[clap(long)]
+    /// Header to hash with consistent-hash affinity, instead of the client IP
+    affinity_hash_header: Option<String>,
+
+    #[clap(long, default_value = "30")]
+    /// How long (in seconds) to wait for open connections to finish on SIGINT or SIGTERM
+    shutdown_timeout: u64,
+
     #[clap(long, default_value = "10")]
     /// Perform active health checks on this interval (in seconds)
     active_health_check_interval: usize,
@@ -33,32 +140,304 @@ struct CmdOptions {
     /// Path to send request to for active health checks
     active_health_check_path: String,
 
+    #[clap(long, default_value = "5")]
+    /// Give up on an active health check request after this many seconds (0 = once the next check
+    /// is due)
+    active_health_check_timeout: u64,
+
+    #[clap(long, default_value = "1")]
+    /// Number of active health checks in a row a dead upstream must pass to be marked live
+    active_health_check_healthy_threshold: usize,
+
+    #[clap(long, default_value = "2")]
+    /// Number of active health checks in a row a live upstream must fail to be marked dead
+    active_health_check_unhealthy_threshold: usize,
+
+    #[clap(long, default_value = "5")]
+    /// Eject an upstream after this many failed requests in a row (0 = never)
+    passive_health_check_consecutive_failures: usize,
+
+    #[clap(long, default_value = "0.5")]
+    /// Eject an upstream once this fraction of its recent requests have failed (0 = never)
+    passive_health_check_error_rate: f64,
+
+    #[clap(long, default_value = "20")]
+    /// Minimum number of recent requests before the error rate is looked at
+    passive_health_check_min_requests: usize,
+
+    #[clap(long, default_value = "30")]
+    /// Length of the window (in seconds) that the error rate is measured over
+    passive_health_check_window: u64,
+
+    #[clap(long, default_value = "30")]
+    /// How long (in seconds) an upstream is first ejected for; repeated ejections double this
+    passive_health_check_base_ejection_time: u64,
+
+    #[clap(long, default_value = "300")]
+    /// Longest (in seconds) an upstream is ever ejected for
+    passive_health_check_max_ejection_time: u64,
+
     #[clap(long, default_value = "0")]
     /// Maximum number of requests to accept per IP per minute (0 = unlimited)
     max_requests_per_minute: usize,
+
+    #[clap(long, default_value = "sliding-window")]
+    /// How requests are counted against the rate limit (sliding-window, token-bucket)
+    rate_limit_algorithm: rate_limit::Algorithm,
+
+    #[clap(long, default_value = "0")]
+    /// Largest burst of requests a client may send at once with the token-bucket algorithm
+    /// (0 = one minute's worth of requests)
+    rate_limit_burst: usize,
+
+    #[clap(long, default_value = "2")]
+    /// Number of times a failed idempotent request is retried on another upstream (0 = never)
+    retry_attempts: usize,
+
+    #[clap(long)]
+    /// Method to retry besides GET, HEAD, PUT and DELETE
+    retry_method: Vec<String>,
+
+    #[clap(long, default_value = "0")]
+    /// Retry an attempt that hasn't received the response headers after this many seconds
+    /// (0 = no limit)
+    retry_per_try_timeout: u64,
+
+    #[clap(long, default_value = "25")]
+    /// Longest wait (in milliseconds) before the first retry; doubles for each retry after that
+    retry_backoff_base: u64,
+
+    #[clap(long, default_value = "250")]
+    /// Longest wait (in milliseconds) before any retry
+    retry_backoff_max: u64,
+
+    #[clap(long, default_value = "0.2")]
+    /// Fraction of recent requests that may be retried
+    retry_budget_ratio: f64,
+
+    #[clap(long, default_value = "3")]
+    /// Retries per second allowed on top of the budget ratio
+    retry_budget_min_per_second: usize,
+
+    #[clap(long, default_value = "10")]
+    /// Seconds a client may take to send a request's headers (0 = no limit)
+    header_read_timeout: u64,
+
+    #[clap(long, default_value = "30")]
+    /// Seconds a client may go without sending any more of a request body (0 = no limit)
+    body_read_timeout: u64,
+
+    #[clap(long, default_value = "5")]
+    /// Seconds to wait for a connection to an upstream to be set up (0 = no limit)
+    upstream_connect_timeout: u64,
+
+    #[clap(long, default_value = "60")]
+    /// Seconds to wait for an upstream's response headers, or the next piece of its response
+    /// body (0 = no limit)
+    upstream_response_timeout: u64,
+
+    #[clap(long, default_value = "60")]
+    /// Seconds a keep-alive client connection may sit idle between requests (0 = no limit)
+    idle_timeout: u64,
+
+    #[clap(long, default_value = "8")]
+    /// Maximum number of idle keep-alive connections to keep open to each upstream (0 = no pooling)
+    pool_max_idle_per_upstream: usize,
+
+    #[clap(long, default_value = "60")]
+    /// Close idle upstream connections after this many seconds
+    pool_idle_timeout: u64,
+
+    #[clap(long)]
+    /// File to write a line per request to; reopened on SIGUSR1 for log rotation
+    access_log: Option<String>,
+
+    #[clap(long, default_value = "combined")]
+    /// Layout of the access log (combined, json, template)
+    access_log_format: access_log::Format,
+
+    #[clap(long)]
+    /// Layout of each access log line with the template format, e.g. "$client_ip $status"
+    access_log_template: Option<String>,
 }
 
-/// Contains information about the state of balancebeam (e.g. what servers we are currently proxying
-/// to, what servers have failed, rate limiting counts, etc.)
-///
-/// You should add fields to this struct in later milestones.
+impl CmdOptions {
+    /// Builds a Config out of the command-line options alone.
+    fn to_config(&self) -> Result<config::Config, String> {
+        let mut upstreams = Vec::new();
+        for upstream in &self.upstream {
+            let (address, weight) = balancer::parse_upstream(upstream)?;
+            upstreams.push(config::Upstream {
+                address,
+                weight,
+                tls: None,
+                proxy_protocol: self.upstream_proxy_protocol,
+            });
+        }
+        let certificates = match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => vec![config::Certificate {
+                cert: cert.clone(),
+                key: key.clone(),
+                hostnames: Vec::new(),
+            }],
+            (None, None) => Vec::new(),
+            _ => return Err("--tls-cert and --tls-key must be given together".to_string()),
+        };
+        Ok(config::Config {
+            listeners: vec![self.bind.clone()],
+            admin_bind: self.admin_bind.clone(),
+            admin_token: self.admin_token.clone(),
+            balancing_strategy: self.balancing_strategy,
+            per_request_balancing: self.per_request_balancing,
+            affinity: config::Affinity {
+                mode: self.affinity,
+                cookie_name: self.affinity_cookie_name.clone(),
+                hash_header: self.affinity_hash_header.clone(),
+            },
+            shutdown_timeout: self.shutdown_timeout,
+            upstreams,
+            pools: Vec::new(),
+            routes: Vec::new(),
+            tls: config::Tls {
+                listeners: self.tls_bind.clone(),
+                certificates,
+            },
+            tcp: config::Tcp {
+                listeners: self
+                    .tcp_bind
+                    .iter()
+                    .map(|bind| config::TcpListener {
+                        bind: bind.clone(),
+                        pool: None,
+                    })
+                    .collect(),
+            },
+            connect: config::Connect {
+                allow: self.connect_allow.clone(),
+            },
+            proxy_protocol: config::ProxyProtocol {
+                trusted: self.proxy_protocol_trusted.clone(),
+            },
+            forwarding: config::Forwarding {
+                trusted_proxies: self.trusted_proxy.clone(),
+                header: self.trusted_proxy_header,
+            },
+            health_check: config::HealthCheck {
+                interval: self.active_health_check_interval,
+                path: self.active_health_check_path.clone(),
+                timeout: self.active_health_check_timeout,
+                healthy_threshold: self.active_health_check_healthy_threshold,
+                unhealthy_threshold: self.active_health_check_unhealthy_threshold,
+            },
+            passive_health_check: config::PassiveHealthCheck {
+                consecutive_failures: self.passive_health_check_consecutive_failures,
+                error_rate: self.passive_health_check_error_rate,
+                min_requests: self.passive_health_check_min_requests,
+                window: self.passive_health_check_window,
+                base_ejection_time: self.passive_health_check_base_ejection_time,
+                max_ejection_time: self.passive_health_check_max_ejection_time,
+            },
+            rate_limit: config::RateLimit {
+                max_requests_per_minute: self.max_requests_per_minute,
+                algorithm: self.rate_limit_algorithm,
+                burst: self.rate_limit_burst,
+            },
+            retry: config::Retry {
+                attempts: self.retry_attempts,
+                methods: self.retry_method.clone(),
+                per_try_timeout: self.retry_per_try_timeout,
+                backoff_base: self.retry_backoff_base,
+                backoff_max: self.retry_backoff_max,
+                budget_ratio: self.retry_budget_ratio,
+                budget_min_per_second: self.retry_budget_min_per_second,
+            },
+            timeouts: config::Timeouts {
+                header_read: self.header_read_timeout,
+                body_read: self.body_read_timeout,
+                upstream_connect: self.upstream_connect_timeout,
+                upstream_response: self.upstream_response_timeout,
+                idle: self.idle_timeout,
+            },
+            pool: config::Pool {
+                max_idle_per_upstream: self.pool_max_idle_per_upstream,
+                idle_timeout: self.pool_idle_timeout,
+            },
+            access_log: config::AccessLog {
+                path: self.access_log.clone(),
+                format: self.access_log_format,
+                template: self.access_log_template.clone(),
+            },
+        })
+    }
+}
+
+/// State shared by every connection and background task: settings, upstreams and their health.
 #[derive(Clone)]
 struct ProxyState {
-    /// How frequently we check whether upstream servers are alive (Milestone 4)
-    #[allow(dead_code)]
-    active_health_check_interval: usize,
-    /// Where we should send requests when doing active health checks (Milestone 4)
-    #[allow(dead_code)]
-    active_health_check_path: String,
-    /// Maximum number of requests an individual IP can make in a minute (Milestone 5)
-    #[allow(dead_code)]
-    max_requests_per_minute: usize,
+    /// Current settings. Reloading the configuration file swaps in a new Config as a whole, so
+    /// readers always see a consistent set of values
+    config: Arc<parking_lot::RwLock<Arc<config::Config>>>,
     /// Addresses of servers that we are proxying to
     upstream_addresses: Arc<Mutex<Vec<String>>>,
     /// Addresses of servers that are not available
     dead_upstream_addresses: Arc<Mutex<Vec<String>>>,
-    /// Count of attemps per window
-    count_map: Arc<Mutex<HashMap<String, usize>>>,
+    /// Addresses of servers that are being drained through the admin API. They get no new
+    /// requests, and are removed once the requests they are still serving have finished
+    draining_upstream_addresses: Arc<Mutex<Vec<String>>>,
+    /// Addresses of servers that have been removed (or finished draining) through the admin API
+    /// and haven't been added back since
+    removed_upstream_addresses: Arc<Mutex<Vec<String>>>,
+    /// Per-client request counts for rate limiting
+    rate_limiter: Arc<rate_limit::RateLimiter>,
+    /// Picks which upstream each new connection goes to
+    balancer: Arc<balancer::Balancer>,
+    /// Maps clients to upstreams for consistent-hash affinity
+    hash_ring: Arc<affinity::HashRing>,
+    /// Recent active health check results, so upstreams don't flap between live and dead
+    health_history: Arc<health_check::HealthHistory>,
+    /// Ejects upstreams whose requests keep failing
+    circuit_breaker: Arc<circuit_breaker::CircuitBreaker>,
+    /// Keeps retries of failed requests from piling too much load onto the upstreams
+    retry_budget: Arc<retry::Budget>,
+    /// Idle keep-alive connections to upstreams, shared by all clients
+    pool: Arc<pool::Pool>,
+    /// Certificates served on the TLS listeners
+    certificates: Arc<tls::CertificateStore>,
+    /// Opens connections to upstreams, over TLS for those configured for it
+    upstream_tls: Arc<tls::UpstreamConnector>,
+    /// Request counts, latencies, etc. for the admin /metrics endpoint
+    metrics: Arc<metrics::Metrics>,
+    /// Gets a line for every request that is answered
+    access_log: Arc<access_log::AccessLog>,
+    /// Tells listeners and client connections when to wind down
+    shutdown: Arc<shutdown::Shutdown>,
+    /// The sockets that client and admin connections are accepted on
+    listeners: Arc<Mutex<listeners::Listeners>>,
+}
+
+impl ProxyState {
+    /// Returns a snapshot of the current settings.
+    fn config(&self) -> Arc<config::Config> {
+        self.config.read().clone()
+    }
+
+    /// Returns true if `upstream` is being drained or has been removed through the admin API, so
+    /// client connections assigned to it should move elsewhere.
+    async fn is_retired_upstream(&self, upstream: &str) -> bool {
+        let draining_upstream_addresses = self.draining_upstream_addresses.lock().await;
+        let removed_upstream_addresses = self.removed_upstream_addresses.lock().await;
+        draining_upstream_addresses.iter().any(|addr| addr == upstream)
+            || removed_upstream_addresses.iter().any(|addr| addr == upstream)
+    }
+
+    /// Tells the circuit breaker whether a request to `upstream` succeeded. An upstream that gets
+    /// ejected starts over on its health check streak, just like one that failover marks dead.
+    fn record_upstream_result(&self, upstream: &str, success: bool) {
+        let settings = &self.config().passive_health_check;
+        if self.circuit_breaker.record(upstream, success, settings) {
+            self.health_history.forget(upstream);
+        }
+    }
 }
 
 #[tokio::main]
@@ -66,43 +445,107 @@ async fn main() {
     // Initialize the logging library. You can print log messages using the `log` macros:
     // https://docs.rs/log/0.4.8/log/ You are welcome to continue using print! statements; this
     // just looks a little prettier.
-    if let Err(_) = std::env::var("RUST_LOG") {
+    if std::env::var("RUST_LOG").is_err() {
         std::env::set_var("RUST_LOG", "debug");
     }
     pretty_env_logger::init();
 
-    // Parse the command line arguments passed to this program
+    // Parse the command line arguments passed to this program, then layer the configuration file
+    // (if any) on top of them
     let options = CmdOptions::parse();
-    if options.upstream.len() < 1 {
-        log::error!("At least one upstream server must be specified using the --upstream option.");
-        std::process::exit(1);
-    }
-
-    // Start listening for connections
-    let mut listener = match TcpListener::bind(&options.bind).await {
-        Ok(listener) => listener,
+    let cmd_config = match options.to_config() {
+        Ok(config) => config,
         Err(err) => {
-            log::error!("Could not bind to {}: {}", options.bind, err);
+            log::error!("{}", err);
             std::process::exit(1);
         }
     };
-    log::info!("Listening for requests on {}", options.bind);
+    let config = match &options.config {
+        Some(path) => config::Config::load(path, &cmd_config),
+        None => cmd_config.validate().map(|_| cmd_config.clone()),
+    };
+    let config = match config {
+        Ok(config) => config,
+        Err(err) => {
+            log::error!("{}", err);
+            std::process::exit(1);
+        }
+    };
+
+    let certificates = Arc::new(tls::CertificateStore::new());
+    if let Err(err) = certificates.load(&config.tls.certificates) {
+        log::error!("{}", err);
+        std::process::exit(1);
+    }
+    let upstream_tls = Arc::new(tls::UpstreamConnector::new());
+    if let Err(err) = upstream_tls.load(config.all_upstreams()) {
+        log::error!("{}", err);
+        std::process::exit(1);
+    }
+    let metrics = Arc::new(metrics::Metrics::new());
+    let access_log = Arc::new(access_log::AccessLog::new(metrics.clone()));
+    if let Err(err) = access_log.load(&config.access_log).await {
+        log::error!("{}", err);
+        std::process::exit(1);
+    }
+
+    // Start listening for connections, on the sockets handed over by the previous process if this
+    // is an upgrade
+    let mut inherited = upgrade::Inherited::from_env();
+    let mut listeners = Vec::new();
+    for (bind, kind) in listeners::wanted(&config) {
+        let listener = match upgrade::bind(&bind, &mut inherited).await {
+            Ok(listener) => listener,
+            Err(err) => {
+                log::error!("Could not bind to {}: {}", bind, err);
+                std::process::exit(1);
+            }
+        };
+        listeners.push((bind, listener, kind));
+    }
+    let admin_listener = match &config.admin_bind {
+        Some(bind) => match upgrade::bind(bind, &mut inherited).await {
+            Ok(listener) => Some((bind.clone(), listener)),
+            Err(err) => {
+                log::error!("Could not bind admin listener to {}: {}", bind, err);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    inherited.close_unused();
 
     // Handle incoming connections
     let state = ProxyState {
-        upstream_addresses: Arc::new(Mutex::new(options.upstream)),
+        upstream_addresses: Arc::new(Mutex::new(config.upstream_addresses())),
         dead_upstream_addresses: Arc::new(Mutex::new(Vec::new())),
-        active_health_check_interval: options.active_health_check_interval,
-        active_health_check_path: options.active_health_check_path,
-        max_requests_per_minute: options.max_requests_per_minute,
-        count_map: Arc::new(Mutex::new(HashMap::new())),
+        draining_upstream_addresses: Arc::new(Mutex::new(Vec::new())),
+        removed_upstream_addresses: Arc::new(Mutex::new(Vec::new())),
+        rate_limiter: Arc::new(rate_limit::RateLimiter::new()),
+        balancer: Arc::new(balancer::Balancer::new(
+            config.balancing_strategy,
+            config.upstream_weights(),
+        )),
+        hash_ring: Arc::new(affinity::HashRing::new()),
+        health_history: Arc::new(health_check::HealthHistory::new()),
+        circuit_breaker: Arc::new(circuit_breaker::CircuitBreaker::new()),
+        retry_budget: Arc::new(retry::Budget::new()),
+        pool: Arc::new(pool::Pool::new()),
+        certificates,
+        upstream_tls,
+        metrics,
+        access_log,
+        shutdown: Arc::new(shutdown::Shutdown::new()),
+        listeners: Arc::new(Mutex::new(listeners::Listeners::new())),
+        config: Arc::new(parking_lot::RwLock::new(Arc::new(config))),
     };
 
     let state_copy = state.clone();
     tokio::spawn(async move {
         loop {
-            delay_for(Duration::from_secs(state_copy.active_health_check_interval as u64)).await;
-            perform_health_check(&state_copy).await;
+            let interval = state_copy.config().health_check.interval;
+            delay_for(Duration::from_secs(interval as u64)).await;
+            health_check::perform_health_check(&state_copy).await;
         }
     });
 
@@ -114,97 +557,1069 @@ async fn main() {
         }
     });
 
-    while let Some(stream) = listener.next().await {
-        match stream {
-            Ok(mut stream) => {
-                // We could short-circuit the process if the client runs out of budget
-                if state.max_requests_per_minute > 0 {
-                    let state_copy = state.clone();
-                    {
-                        let mut count_map = state_copy.count_map.lock().await;
-                        let ip_addr = stream.peer_addr().unwrap().ip().to_string();
-                        if !count_map.contains_key(&ip_addr) {
-                            count_map.insert(ip_addr.clone(), 1);
-                        } else {
-                            if count_map[&ip_addr] >= state_copy.max_requests_per_minute {
-                                let response = response::make_http_error(http::StatusCode::TOO_MANY_REQUESTS);
-                                response::write_to_stream(&response, &mut stream).await.unwrap();
-                                continue;
-                            } else {
-                                *count_map.get_mut(&ip_addr).unwrap() += 1;
-                            }
+    let state_copy = state.clone();
+    tokio::spawn(async move {
+        loop {
+            delay_for(Duration::from_secs(1)).await;
+            state_copy.pool.evict(&state_copy.config().pool).await;
+        }
+    });
+
+    let state_copy = state.clone();
+    tokio::spawn(async move {
+        loop {
+            delay_for(Duration::from_secs(1)).await;
+            admin::remove_drained_upstreams(&state_copy).await;
+        }
+    });
+
+    // On SIGHUP, re-read the configuration file, or without one, the certificates given on the
+    // command line (so renewed certificates can be picked up either way)
+    let state_copy = state.clone();
+    let config_path = options.config.clone();
+    let mut hangups = signal(SignalKind::hangup()).expect("Failed to install SIGHUP handler");
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            match &config_path {
+                Some(path) => reload_config(&state_copy, path, &cmd_config).await,
+                None => reload_certificates(&state_copy, &cmd_config),
+            }
+        }
+    });
+
+    // On SIGUSR1, reopen the access log, so it can be rotated by moving it away first
+    let state_copy = state.clone();
+    let mut reopens =
+        signal(SignalKind::user_defined1()).expect("Failed to install SIGUSR1 handler");
+    tokio::spawn(async move {
+        while reopens.recv().await.is_some() {
+            match state_copy.access_log.reopen().await {
+                Ok(()) => log::info!("Reopened access log"),
+                Err(err) => log::error!("Failed to reopen access log: {}", err),
+            }
+        }
+    });
+
+    if let Some((bind, listener)) = admin_listener {
+        state.listeners.lock().await.serve_admin(&bind, listener, &state);
+    }
+
+    // Install the handlers before accepting anything, so a signal is never missed
+    let mut interrupts = signal(SignalKind::interrupt()).expect("Failed to install SIGINT handler");
+    let mut terminations =
+        signal(SignalKind::terminate()).expect("Failed to install SIGTERM handler");
+
+    for (bind, listener, kind) in listeners {
+        state.listeners.lock().await.serve(&bind, listener, kind, &state);
+    }
+
+    // On SIGUSR2, start a fresh copy of the executable (which may have been replaced with a new
+    // version) on the same sockets. It sends us SIGTERM once it is accepting connections, and we
+    // then shut down as usual
+    let state_copy = state.clone();
+    let mut upgrades =
+        signal(SignalKind::user_defined2()).expect("Failed to install SIGUSR2 handler");
+    tokio::spawn(async move {
+        while upgrades.recv().await.is_some() {
+            if state_copy.shutdown.is_started() {
+                log::warn!("Ignoring SIGUSR2, since we are already shutting down");
+                continue;
+            }
+            // (Reloading the configuration may have changed the listeners since startup)
+            let listener_fds = state_copy.listeners.lock().await.fds();
+            match upgrade::spawn_successor(&listener_fds) {
+                Ok(mut child) => {
+                    log::info!("Handed listeners over to process {}", child.id());
+                    // Further upgrades are only tried if this one fails to start
+                    match (&mut child).await {
+                        Ok(status) => log::error!("Process {} exited: {}", child.id(), status),
+                        Err(err) => {
+                            log::error!("Failed to wait for process {}: {}", child.id(), err)
                         }
                     }
                 }
+                Err(err) => log::error!("Failed to start a new balancebeam process: {}", err),
+            }
+        }
+    });
+    inherited.notify_parent();
+
+    let signal_name = tokio::select! {
+        _ = interrupts.recv() => "SIGINT",
+        _ = terminations.recv() => "SIGTERM",
+    };
+    let timeout = state.config().shutdown_timeout;
+    log::info!(
+        "Received {}; no longer accepting connections, and waiting up to {} seconds for {} open \
+        connections to finish",
+        signal_name,
+        timeout,
+        state.shutdown.open_connections()
+    );
+    state.shutdown.start();
+    state.listeners.lock().await.wait_stopped().await;
+    if !state.shutdown.drain(Duration::from_secs(timeout)).await {
+        log::warn!(
+            "{} connections still open after {} seconds; exiting anyway",
+            state.shutdown.open_connections(),
+            timeout
+        );
+        std::process::exit(1);
+    }
+    log::info!("All connections finished; exiting");
+}
+
+/// What the connections accepted on a listener carry.
+#[derive(Clone, PartialEq)]
+enum ListenerKind {
+    Http,
+    /// HTTPS, with a certificate from the store picked for each client
+    Https,
+    /// Raw TCP, relayed to an upstream in this pool (or to the top-level upstreams if None)
+    Tcp(Option<String>),
+}
+
+impl ListenerKind {
+    fn describe(&self) -> &'static str {
+        match self {
+            ListenerKind::Http => "HTTP requests",
+            ListenerKind::Https => "HTTPS requests",
+            ListenerKind::Tcp(_) => "TCP connections",
+        }
+    }
+}
+
+/// Accepts client connections on `listener` until shutdown starts or `stop` fires, handing each
+/// one off to its own task. Returns the listener, which is still open.
+async fn serve(
+    mut listener: TcpListener,
+    kind: ListenerKind,
+    mut stop: oneshot::Receiver<()>,
+    state: &ProxyState,
+) -> TcpListener {
+    let acceptor = state.certificates.acceptor();
+    loop {
+        let stream = tokio::select! {
+            stream = listener.next() => stream,
+            _ = state.shutdown.started() => break,
+            _ = &mut stop => break,
+        };
+        match stream {
+            Some(Ok(stream)) => {
                 let state_copy = state.clone();
+                let kind = kind.clone();
+                let acceptor = acceptor.clone();
+                let connection_guard = state.shutdown.track_connection();
                 // Handle the connection!
                 tokio::spawn(async move {
-                    handle_connection(stream, &state_copy).await;
+                    let _connection_guard = connection_guard;
+                    let mut stream = stream;
+                    let peer_ip = match stream.peer_addr() {
+                        Ok(addr) => addr.ip(),
+                        Err(_) => return,
+                    };
+                    let config = state_copy.config();
+                    let handshake_timeout = timeout::from_secs(config.timeouts.header_read);
+                    // Connections relayed by a trusted proxy say who the client really is before
+                    // anything else, even the TLS handshake
+                    let trusted = &config.proxy_protocol.trusted;
+                    let accepted = proxy_protocol::accept(&mut stream, trusted);
+                    let client = match timeout::limit(handshake_timeout, accepted).await {
+                        Some(Ok(client)) => client,
+                        Some(Err(err)) => {
+                            log::info!("Closing connection from {}: {}", peer_ip, err);
+                            return;
+                        }
+                        None => {
+                            log::info!("Timed out waiting for a PROXY header from {}", peer_ip);
+                            return;
+                        }
+                    };
+                    let client_ip = client.source.ip().to_string();
+                    match kind {
+                        ListenerKind::Https => match timeout::io(
+                            handshake_timeout,
+                            acceptor.accept(stream),
+                        )
+                        .await
+                        {
+                            Ok(stream) if http2::is_negotiated(&stream) => {
+                                handle_http2_connection(stream, client, "https", &state_copy).await
+                            }
+                            Ok(mut stream) => {
+                                handle_connection(&mut stream, client, "https", &state_copy).await;
+                                // Let the client know the connection wasn't cut off halfway
+                                let _ = stream.shutdown().await;
+                            }
+                            Err(err) => {
+                                log::info!("TLS handshake with {} failed: {}", client_ip, err)
+                            }
+                        },
+                        ListenerKind::Http => {
+                            // HTTP/2 clients on a cleartext connection just start speaking it
+                            let preface = http2::detect_preface(stream);
+                            match timeout::limit(handshake_timeout, preface).await {
+                                Some(Ok((true, stream))) => {
+                                    handle_http2_connection(stream, client, "http", &state_copy)
+                                        .await
+                                }
+                                Some(Ok((false, stream))) => {
+                                    handle_connection(stream, client, "http", &state_copy).await
+                                }
+                                Some(Err(err)) => {
+                                    log::info!("Error reading from {}: {}", client_ip, err)
+                                }
+                                None => {
+                                    log::info!("Timed out waiting for a request from {}", client_ip)
+                                }
+                            }
+                        }
+                        ListenerKind::Tcp(pool) => {
+                            handle_tcp_connection(stream, client, pool.as_deref(), &state_copy)
+                                .await
+                        }
+                    }
                 });
-            },
-            Err(_) => { break; } 
+            }
+            Some(Err(err)) => {
+                // Most likely out of file descriptors. Give connections a moment to close rather
+                // than spin
+                log::error!("Failed to accept connection: {}", err);
+                delay_for(Duration::from_millis(100)).await;
+            }
+            None => break,
         }
     }
+    listener
+}
 
-    println!("some error wwwå");
+/// Re-reads the configuration file and swaps the new settings into `state`. Client connections
+/// that are already open are left alone and keep talking to the upstream they were assigned. The
+/// upstreams in the file replace any that were added or removed through the admin API. Listeners
+/// the file adds are opened and listeners it drops are closed, though connections they already
+/// accepted carry on.
+async fn reload_config(state: &ProxyState, path: &str, cmd_config: &config::Config) {
+    log::info!("Reloading configuration from {}", path);
+    let new_config = match config::Config::load(path, cmd_config) {
+        Ok(config) => config,
+        Err(err) => {
+            log::error!("Failed to reload configuration, keeping the old one: {}", err);
+            return;
+        }
+    };
+    // New addresses are bound before anything else changes, so the old configuration stays in
+    // place if one of them can't be
+    let mut listeners = state.listeners.lock().await;
+    let mut bound = HashMap::new();
+    for bind in listeners::binds(&new_config) {
+        if listeners.is_listening(&bind) || bound.contains_key(&bind) {
+            continue;
+        }
+        match TcpListener::bind(bind.as_str()).await {
+            Ok(listener) => {
+                bound.insert(bind, listener);
+            }
+            Err(err) => {
+                log::error!("Could not bind to {}, keeping the old configuration: {}", bind, err);
+                return;
+            }
+        }
+    }
+    // The certificate files are re-read even if their paths haven't changed, so renewed
+    // certificates can be picked up
+    if let Err(err) = state.certificates.load(&new_config.tls.certificates) {
+        log::error!("Failed to reload certificates, keeping the old configuration: {}", err);
+        return;
+    }
+    if let Err(err) = state.upstream_tls.load(new_config.all_upstreams()) {
+        log::error!(
+            "Failed to reload upstream TLS settings, keeping the old configuration: {}",
+            err
+        );
+        return;
+    }
+    if let Err(err) = state.access_log.load(&new_config.access_log).await {
+        log::error!("Failed to reload access log settings, keeping the old configuration: {}", err);
+        return;
+    }
+    listeners.update(&new_config, bound, state).await;
+    drop(listeners);
+
+    // Hold the upstream locks while swapping so no connection sees a half-updated list. Upstreams
+    // that are currently dead stay dead until the next health check says otherwise, and upstreams
+    // that are being drained finish draining; new upstreams start out alive.
+    let mut upstream_addresses = state.upstream_addresses.lock().await;
+    let mut dead_upstream_addresses = state.dead_upstream_addresses.lock().await;
+    let draining_upstream_addresses = state.draining_upstream_addresses.lock().await;
+    let mut removed_upstream_addresses = state.removed_upstream_addresses.lock().await;
+    removed_upstream_addresses.retain(|addr| !new_config.upstream_addresses().contains(addr));
+    let (dead, alive): (Vec<String>, Vec<String>) = new_config
+        .upstream_addresses()
+        .into_iter()
+        .filter(|addr| !draining_upstream_addresses.contains(addr))
+        .partition(|addr| dead_upstream_addresses.contains(addr));
+    for addr in upstream_addresses.iter().chain(dead_upstream_addresses.iter()) {
+        if !alive.contains(addr) {
+            state.pool.remove_upstream(addr);
+        }
+        if !alive.contains(addr) && !dead.contains(addr) {
+            state.health_history.forget(addr);
+        }
+    }
+    *upstream_addresses = alive;
+    *dead_upstream_addresses = dead;
+    state.balancer.set_weights(new_config.upstream_weights());
+    state.balancer.set_strategy(new_config.balancing_strategy);
+    *state.config.write() = Arc::new(new_config);
+    log::info!("Configuration reloaded; upstreams are now {:?}", *upstream_addresses);
+}
+
+/// Re-reads the certificate files given on the command line, for when there is no configuration
+/// file to reload. The old certificates are kept if the new ones can't be loaded.
+fn reload_certificates(state: &ProxyState, cmd_config: &config::Config) {
+    log::info!("Reloading certificates");
+    match state.certificates.load(&cmd_config.tls.certificates) {
+        Ok(()) => log::info!("Certificates reloaded"),
+        Err(err) => log::error!("Failed to reload certificates, keeping the old ones: {}", err),
+    }
 }
 
-async fn connect_to_upstream(state: &ProxyState) -> Result<TcpStream, std::io::Error> {
+/// Picks a live upstream from `pool` (or from the top-level upstreams if None) and connects to it,
+/// leaving out the upstreams in `exclude`. The upstream that `target` asks for is picked if it is
+/// available. If no upstream can be reached, the error from the last one tried is returned.
+async fn connect_to_upstream(
+    state: &ProxyState,
+    pool: Option<&str>,
+    target: &affinity::Target,
+    exclude: &[String],
+    client: &proxy_protocol::Addresses,
+) -> Result<(pool::Connection, balancer::ConnectionGuard), std::io::Error> {
+    let mut last_error = None;
     loop {
-        // connect to random upstream
-        let mut rng = rand::rngs::StdRng::from_entropy(); 
-        let mut upstream_addresses = state.upstream_addresses.lock().await;
-        if upstream_addresses.len() == 0 {
-            return Err(Error::new(ErrorKind::Other, "empty upstream available"));
-        }
-        let upstream_idx = rng.gen_range(0, upstream_addresses.len());
-        let upstream_ip = &upstream_addresses[upstream_idx];
-        match TcpStream::connect(upstream_ip).await {
-            Ok(stream) => return Ok(stream),
+        let config = state.config();
+        // pick an upstream using the balancing strategy, skipping any that passive health checks
+        // have ejected. If every live upstream has been ejected, ignore the ejections rather than
+        // turn everyone away.
+        let upstream_ip = {
+            let upstream_addresses: Vec<String> = state
+                .upstream_addresses
+                .lock()
+                .await
+                .iter()
+                .filter(|addr| config.pool_of(addr) == pool && !exclude.contains(addr))
+                .cloned()
+                .collect();
+            if upstream_addresses.is_empty() {
+                return Err(last_error.unwrap_or_else(|| Error::other("empty upstream available")));
+            }
+            let available: Vec<String> = upstream_addresses
+                .iter()
+                .filter(|addr| {
+                    state
+                        .circuit_breaker
+                        .is_available(addr, &config.passive_health_check)
+                })
+                .cloned()
+                .collect();
+            if available.is_empty() {
+                pick_upstream(state, &upstream_addresses, target)
+            } else {
+                let upstream_ip = pick_upstream(state, &available, target);
+                // Someone else may have claimed the upstream's half-open trial in the meantime
+                if !state
+                    .circuit_breaker
+                    .admit(&upstream_ip, &config.passive_health_check)
+                {
+                    continue;
+                }
+                upstream_ip
+            }
+        };
+        let connect_timeout = timeout::from_secs(config.timeouts.upstream_connect);
+        let conn = state
+            .pool
+            .connect(&upstream_ip, &state.upstream_tls, &config.pool, client);
+        match timeout::io(connect_timeout, conn).await {
+            Ok(conn) => return Ok((conn, state.balancer.track(&upstream_ip))),
             Err(err) => {
                 log::error!("Failed to connect to upstream {}: {}", upstream_ip, err);
+                state.record_upstream_result(&upstream_ip, false);
+                last_error = Some(err);
             },
         }
 
-        // TODO: implement failover (milestone 3)
-        // update dead upstream addresses and remove it from upstream addresses
-        let mut dead_upstream_addresses = state.dead_upstream_addresses.lock().await;
-        // log::error!("{:?}, {:?}", dead_upstream_addresses, upstream_addresses);
-        let addr = upstream_addresses[upstream_idx].clone();
-        dead_upstream_addresses.push(addr);
-        upstream_addresses.remove(upstream_idx);
+        // failover: stop sending connections to the upstream until a health check revives it
+        mark_upstream_dead(state, &upstream_ip).await;
     }
 }
 
-async fn send_response(client_conn: &mut TcpStream, response: &http::Response<Vec<u8>>) {
-    let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
-    log::info!("{} <- {}", client_ip, response::format_response_line(&response));
-    if let Err(error) = response::write_to_stream(&response, client_conn).await {
+/// Picks one of `upstreams` (which must not be empty) for a request with affinity target
+/// `target`, falling back to the balancing strategy if the target isn't among them.
+fn pick_upstream(state: &ProxyState, upstreams: &[String], target: &affinity::Target) -> String {
+    match target {
+        affinity::Target::Any => {}
+        affinity::Target::Cookie(value) => {
+            if let Some(upstream) = upstreams
+                .iter()
+                .find(|upstream| affinity::cookie_value(upstream) == *value)
+            {
+                return upstream.clone();
+            }
+        }
+        affinity::Target::Hash(key) => {
+            let weight = |upstream: &str| state.balancer.weight(upstream);
+            return upstreams[state.hash_ring.select(upstreams, weight, *key)].clone();
+        }
+    }
+    upstreams[state.balancer.select(upstreams)].clone()
+}
+
+/// Moves an upstream from the live list to the dead list (if it is still live) and closes any
+/// pooled connections to it. Its health check streak from while it was live is thrown away, so it
+/// needs the full number of passing probes to come back.
+async fn mark_upstream_dead(state: &ProxyState, upstream_ip: &str) {
+    let mut upstream_addresses = state.upstream_addresses.lock().await;
+    let mut dead_upstream_addresses = state.dead_upstream_addresses.lock().await;
+    if let Some(idx) = upstream_addresses.iter().position(|addr| addr == upstream_ip) {
+        upstream_addresses.remove(idx);
+        dead_upstream_addresses.push(upstream_ip.to_string());
+        state.health_history.forget(upstream_ip);
+    }
+    state.pool.remove_upstream(upstream_ip);
+}
+
+async fn send_response<S: AsyncWrite + Unpin>(
+    state: &ProxyState,
+    client_conn: &mut CountingStream<S>,
+    entry: &access_log::Entry,
+    response: &http::Response<Vec<u8>>,
+) {
+    state.metrics.record_response(response.status());
+    log::info!("{} <- {}", entry.client_ip(), response::format_response_line(response));
+    if let Err(error) = response::write_to_stream(response, client_conn).await {
         log::warn!("Failed to send response to client: {}", error);
-        return;
     }
+    state.access_log.record(entry, response.status(), client_conn);
 }
 
-async fn handle_connection(mut client_conn: TcpStream, state: &ProxyState) {
-    let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
-    log::info!("Connection received from {}", client_ip);
+/// Works out what to tell a client whose request body couldn't be read: 408 if it was too slow to
+/// send it, and 400 if it was malformed. Returns None if the client is no longer listening.
+fn request_body_error_status(
+    entry: &access_log::Entry,
+    error: body::Error,
+) -> Option<http::StatusCode> {
+    match error {
+        body::Error::ReadError(io_err) if timeout::is_timeout(&io_err) => {
+            log::info!("Timed out waiting for the request body from {}", entry.client_ip());
+            Some(http::StatusCode::REQUEST_TIMEOUT)
+        }
+        body::Error::ReadError(io_err) => {
+            log::info!("Error reading request body from client stream: {}", io_err);
+            None
+        }
+        error => {
+            log::debug!("Error reading request body: {:?}", error);
+            Some(http::StatusCode::BAD_REQUEST)
+        }
+    }
+}
 
-    // Open a connection to a random destination server
-    let mut upstream_conn = match connect_to_upstream(state).await {
-        Ok(stream) => stream,
-        Err(_error) => {
-            let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-            send_response(&mut client_conn, &response).await;
-            return;
+/// Tells an HTTP/1.1 client that asked for 100-continue to go ahead and send the request body.
+async fn send_continue<S: AsyncWrite + Unpin>(client_conn: &mut S) -> Result<(), std::io::Error> {
+    let response = http::Response::builder()
+        .status(http::StatusCode::CONTINUE)
+        .version(http::Version::HTTP_11)
+        .body(Vec::new())
+        .unwrap();
+    response::write_to_stream(&response, client_conn).await
+}
+
+/// Makes the response for a request that no upstream answered: 504 if the upstream took too long,
+/// and 502 if it failed in some other way.
+fn gateway_error(timed_out: bool) -> http::Response<Vec<u8>> {
+    response::make_http_error(if timed_out {
+        http::StatusCode::GATEWAY_TIMEOUT
+    } else {
+        http::StatusCode::BAD_GATEWAY
+    })
+}
+
+/// Returns true if `error` means the upstream took too long to answer.
+fn is_upstream_timeout(error: &response::Error) -> bool {
+    matches!(error, response::Error::ConnectionError(err) if timeout::is_timeout(err))
+}
+
+/// Why a request couldn't be proxied.
+enum ForwardError {
+    /// The client sent a malformed request body, or hung up while sending it
+    Client(body::Error),
+    /// The upstream couldn't be reached, or failed before sending back the response headers
+    Upstream(response::Error),
+}
+
+/// Writes a request to an upstream connection, streams the request body over from the client, and
+/// reads back the response headers, within the given time limits. Whatever part of the body has
+/// already been read into the request is sent first.
+async fn forward_request<S: AsyncBufRead + Unpin>(
+    request: &http::Request<Vec<u8>>,
+    request_length: body::Length,
+    client_conn: &mut S,
+    upstream_conn: &mut tls::UpstreamStream,
+    timeouts: &config::Timeouts,
+) -> Result<(http::Response<Vec<u8>>, body::Length), ForwardError> {
+    request::write_headers(request, upstream_conn)
+        .await
+        .map_err(|err| ForwardError::Upstream(response::Error::ConnectionError(err)))?;
+    let body_timeout = timeout::from_secs(timeouts.body_read);
+    let client_body = timeout::ReadTimeout::new(client_conn, body_timeout);
+    let mut client_body = request.body().as_slice().chain(client_body);
+    body::copy(&mut client_body, request_length, upstream_conn)
+        .await
+        .map_err(|err| match err {
+            body::Error::WriteError(err) => {
+                ForwardError::Upstream(response::Error::ConnectionError(err))
+            }
+            err => ForwardError::Client(err),
+        })?;
+    log::debug!("Forwarded request to server");
+    let response_timeout = timeout::from_secs(timeouts.upstream_response);
+    timeout::limit(response_timeout, response::read_from_stream(upstream_conn, request.method()))
+        .await
+        .unwrap_or_else(|| Err(response::Error::ConnectionError(timeout::timed_out())))
+        .map_err(ForwardError::Upstream)
+}
+
+/// Sends a request to `upstream_ip` and returns the response headers, along with the connection
+/// the response body can be read from. `upstream_conn` is used if given; otherwise a connection is
+/// taken from the pool. If a reused connection turns out to have been closed by the upstream
+/// before it answered, an idempotent request without a body is retried once on a brand new
+/// connection. (A request body can't be retried, since it is streamed straight from the client,
+/// and the upstream may have acted on a non-idempotent request before closing the connection.)
+async fn send_to_upstream<S: AsyncBufRead + Unpin>(
+    state: &ProxyState,
+    upstream_ip: &str,
+    upstream_conn: Option<pool::Connection>,
+    request: &http::Request<Vec<u8>>,
+    request_length: body::Length,
+    client_conn: &mut S,
+    client: &proxy_protocol::Addresses,
+) -> Result<(http::Response<Vec<u8>>, body::Length, tls::UpstreamStream), ForwardError> {
+    let config = state.config();
+    let connect_timeout = timeout::from_secs(config.timeouts.upstream_connect);
+    let mut conn = match upstream_conn {
+        Some(conn) => conn,
+        None => {
+            let conn = state
+                .pool
+                .connect(upstream_ip, &state.upstream_tls, &config.pool, client);
+            timeout::io(connect_timeout, conn)
+                .await
+                .map_err(|err| ForwardError::Upstream(response::Error::ConnectionError(err)))?
+        }
+    };
+    let timeouts = &config.timeouts;
+    let result =
+        forward_request(request, request_length, client_conn, &mut conn.stream, timeouts).await;
+    let (response, response_length) = match result {
+        Ok(response) => response,
+        // (A timeout means the upstream is slow, not that the connection had gone stale)
+        Err(ForwardError::Upstream(error))
+            if conn.reused
+                && !request_length.has_body()
+                && retry::is_idempotent(request.method(), &config.retry)
+                && !is_upstream_timeout(&error)
+                && matches!(
+                    error,
+                    response::Error::IncompleteResponse | response::Error::ConnectionError(_)
+                ) =>
+        {
+            log::debug!("Pooled connection to {} was closed; retrying on a new one", upstream_ip);
+            let stream = state.upstream_tls.connect(upstream_ip, Some(client));
+            conn.stream = timeout::io(connect_timeout, stream)
+                .await
+                .map_err(|err| ForwardError::Upstream(response::Error::ConnectionError(err)))?;
+            forward_request(request, request_length, client_conn, &mut conn.stream, timeouts)
+                .await?
         }
+        Err(error) => return Err(error),
+    };
+    Ok((response, response_length, conn.stream))
+}
+
+/// Reads the request body from the client into the request, if the body is small enough to hold
+/// on to, so that the request can be sent again should it fail. Returns false if the body is too
+/// large (or chunked), in which case it is left to be streamed to the upstream.
+async fn buffer_body<S: AsyncRead + Unpin>(
+    request: &mut http::Request<Vec<u8>>,
+    request_length: body::Length,
+    client_conn: &mut S,
+) -> Result<bool, body::Error> {
+    match request_length {
+        body::Length::Empty => Ok(true),
+        body::Length::ContentLength(len) if len <= retry::MAX_BODY_SIZE => {
+            body::copy_exact(client_conn, len, request.body_mut()).await?;
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
+
+/// The response headers an upstream sent back, along with the connection to read the body from.
+struct Answered {
+    response: http::Response<Vec<u8>>,
+    response_length: body::Length,
+    upstream_stream: tls::UpstreamStream,
+    /// Keeps the request counted against the upstream that answered until it is dropped
+    _in_flight: balancer::RequestGuard,
+}
+
+/// Sends a request to the upstream that `guard` is for (over `upstream_conn` if given, or a pooled
+/// connection otherwise) and reads back the response headers. If that fails and the request is
+/// `replayable`, it is sent on to upstreams in `pool` that haven't been tried yet, within the retry
+/// settings and budget; `guard` is moved over to whichever upstream is tried last.
+#[allow(clippy::too_many_arguments)]
+async fn send_with_retries<S: AsyncBufRead + Unpin>(
+    state: &ProxyState,
+    entry: &mut access_log::Entry,
+    request: &http::Request<Vec<u8>>,
+    request_length: body::Length,
+    client_conn: &mut S,
+    replayable: bool,
+    pool: Option<&str>,
+    target: &affinity::Target,
+    guard: &mut balancer::ConnectionGuard,
+    mut upstream_conn: Option<pool::Connection>,
+    client: &proxy_protocol::Addresses,
+) -> Result<Answered, ForwardError> {
+    let config = state.config();
+    let retry_settings = &config.retry;
+    let mut upstream_ip = guard.upstream().to_string();
+    log::info!(
+        "{} -> {}: {}",
+        entry.client_ip(),
+        upstream_ip,
+        request::format_request_line(request)
+    );
+    entry.upstream = Some(upstream_ip.clone());
+    state.metrics.record_upstream_request(&upstream_ip);
+    state.retry_budget.record_request();
+    let mut in_flight = state.balancer.track_request(&upstream_ip);
+
+    let mut tried = Vec::new();
+    let mut retries = 0;
+    loop {
+        let attempt_started = Instant::now();
+        let attempt = send_to_upstream(
+            state,
+            &upstream_ip,
+            upstream_conn.take(),
+            request,
+            request_length,
+            client_conn,
+            client,
+        );
+        // Only replayable requests have their whole body in hand, so the time limit can't end up
+        // counting time spent waiting for the client
+        let per_try_timeout = if replayable {
+            timeout::from_secs(retry_settings.per_try_timeout)
+        } else {
+            None
+        };
+        let result = timeout::limit(per_try_timeout, attempt).await.unwrap_or_else(|| {
+            Err(ForwardError::Upstream(response::Error::ConnectionError(
+                timeout::timed_out(),
+            )))
+        });
+        let error = match result {
+            Ok((response, response_length, upstream_stream)) => {
+                entry.upstream_latency = Some(attempt_started.elapsed());
+                return Ok(Answered {
+                    response,
+                    response_length,
+                    upstream_stream,
+                    _in_flight: in_flight,
+                });
+            }
+            Err(ForwardError::Client(error)) => return Err(ForwardError::Client(error)),
+            Err(ForwardError::Upstream(error)) => error,
+        };
+        log::error!("Error forwarding request to upstream {}: {:?}", upstream_ip, error);
+        state.record_upstream_result(&upstream_ip, false);
+        tried.push(upstream_ip.clone());
+
+        let next = if !replayable || retries >= retry_settings.attempts {
+            None
+        } else if !state.retry_budget.try_retry(retry_settings) {
+            log::warn!(
+                "Retry budget exhausted; not retrying request from {}",
+                entry.client_ip()
+            );
+            None
+        } else {
+            retries += 1;
+            delay_for(retry::backoff(retries as u32, retry_settings)).await;
+            connect_to_upstream(state, pool, target, &tried, client).await.ok()
+        };
+        let (conn, next_guard) = match next {
+            Some(next) => next,
+            None => return Err(ForwardError::Upstream(error)),
+        };
+        upstream_ip = next_guard.upstream().to_string();
+        upstream_conn = Some(conn);
+        entry.upstream = Some(upstream_ip.clone());
+        log::info!(
+            "{} -> {}: retrying {}",
+            entry.client_ip(),
+            upstream_ip,
+            request::format_request_line(request)
+        );
+        state.metrics.record_retry();
+        state.metrics.record_upstream_request(&upstream_ip);
+        in_flight = state.balancer.track_request(&upstream_ip);
+        *guard = next_guard;
+    }
+}
+
+/// With cookie affinity, ties the client to the upstream that answered.
+fn add_affinity_cookie(
+    response: &mut http::Response<Vec<u8>>,
+    upstream_ip: &str,
+    target: &affinity::Target,
+    settings: &config::Affinity,
+) {
+    if let Some(cookie) = affinity::cookie_to_set(upstream_ip, target, settings) {
+        if let Ok(cookie) = http::HeaderValue::from_str(&cookie) {
+            response.headers_mut().append("set-cookie", cookie);
+        }
+    }
+}
+
+/// Records how long an upstream took over a request and whether it did its part. Server errors
+/// count against the upstream, and so does a response body that it didn't finish sending properly
+/// (but not a client that went away while it was being sent).
+fn record_outcome(
+    state: &ProxyState,
+    upstream_ip: &str,
+    response: &http::Response<Vec<u8>>,
+    result: &Result<(), body::Error>,
+    started: Instant,
+) {
+    state
+        .metrics
+        .record_request_duration(upstream_ip, started.elapsed());
+    let upstream_failed = response.status().is_server_error()
+        || matches!(result, Err(ref error) if !matches!(error, body::Error::WriteError(_)));
+    state.record_upstream_result(upstream_ip, !upstream_failed);
+}
+
+/// Sends the response headers to the client, followed by the body streamed over from the
+/// upstream. The request is written to the access log once the response is out (or has failed).
+async fn forward_response<S: AsyncWrite + Unpin>(
+    state: &ProxyState,
+    response: &http::Response<Vec<u8>>,
+    response_length: body::Length,
+    upstream_conn: &mut tls::UpstreamStream,
+    client_conn: &mut CountingStream<S>,
+    entry: &access_log::Entry,
+) -> Result<(), body::Error> {
+    state.metrics.record_response(response.status());
+    log::info!("{} <- {}", entry.client_ip(), response::format_response_line(response));
+    let result = match response::write_headers(response, client_conn).await {
+        Ok(()) => {
+            let response_timeout = timeout::from_secs(state.config().timeouts.upstream_response);
+            let mut upstream_body = timeout::ReadTimeout::new(upstream_conn, response_timeout);
+            let body = response.body();
+            body::copy_unbuffered(&mut upstream_body, body, response_length, client_conn).await
+        }
+        Err(error) => Err(body::Error::WriteError(error)),
+    };
+    state.access_log.record(entry, response.status(), client_conn);
+    result
+}
+
+/// The upstream that a client connection's requests go to, unless they are balanced one by one.
+struct Pinned {
+    /// The upstream, with a connection to it if one is ready for the next request
+    upstream: Option<(Option<pool::Connection>, balancer::ConnectionGuard)>,
+    /// Who the upstream was picked for, with session affinity
+    target: affinity::Target,
+}
+
+/// An upstream's answer to a request, ready to be forwarded to the client.
+struct Proxied {
+    answered: Answered,
+    upstream_ip: String,
+    /// When the request was sent, for the request duration metric
+    started: Instant,
+    /// With per-request balancing, keeps the request counted against its upstream (for
+    /// least-connections) until it is dropped
+    _connection: Option<balancer::ConnectionGuard>,
+}
+
+/// What became of a request that was handed to `proxy_request`.
+enum Outcome {
+    /// An upstream answered
+    Answered(Proxied),
+    /// The request is a CONNECT request, which is up to the transport to deal with
+    Connect,
+    /// The request was turned away, with `response` to tell the client why (unless it is no longer
+    /// listening). The connection can go on to the next request if `keep_alive` is true; otherwise
+    /// it should be closed, rather than read the rest of a request body just to throw it away.
+    Rejected {
+        response: Option<http::Response<Vec<u8>>>,
+        keep_alive: bool,
+    },
+}
+
+/// Proxies a request whose headers have been read from `client_conn`, whatever protocol the client
+/// speaks: checks the client's rate limit, routes the request, sends it on to an upstream (over the
+/// pinned one if given, and retrying where it can) and reads back the response headers. Reading the
+/// request and writing the response are left to the caller.
+#[allow(clippy::too_many_arguments)]
+async fn proxy_request<S>(
+    state: &ProxyState,
+    client_conn: &mut CountingStream<S>,
+    entry: &mut access_log::Entry,
+    request: &mut http::Request<Vec<u8>>,
+    request_length: body::Length,
+    client: &proxy_protocol::Addresses,
+    scheme: &'static str,
+    mut pinned: Option<&mut Pinned>,
+) -> Outcome
+where
+    S: AsyncBufRead + AsyncWrite + Unpin,
+{
+    // A request with a body that isn't going ahead leaves the rest of the body unread
+    let rejected = |response| Outcome::Rejected {
+        response: Some(response),
+        keep_alive: !request_length.has_body(),
+    };
+
+    // Check the client's budget for every request, not just when it connects, so clients can't get
+    // around the limit by sending all their requests over one keep-alive connection
+    let config = state.config();
+    if !state.rate_limiter.check(entry.client_ip(), &config.rate_limit) {
+        log::info!("Rate limiting {}", entry.client_ip());
+        state.metrics.record_rate_limited();
+        return rejected(response::make_http_error(http::StatusCode::TOO_MANY_REQUESTS));
+    }
+
+    // A CONNECT request asks for a tunnel to a destination of the client's choosing, rather than
+    // being balanced across the upstreams
+    if request.method() == http::Method::CONNECT {
+        return Outcome::Connect;
+    }
+
+    // Work out which pool the request goes to, and rewrite its path if its route says so
+    let route = routing::find_route(&config.routes, request);
+    let pool = route.map(|route| route.pool.as_str());
+    let target = affinity::Target::of(request, entry.client_ip(), &config.affinity);
+    if let Some(route) = route {
+        if let Err(error) = routing::rewrite_path(route, request) {
+            log::error!(
+                "Failed to rewrite {} for pool {}: {}",
+                request::format_request_line(request),
+                route.pool,
+                error
+            );
+            return rejected(response::make_http_error(http::StatusCode::INTERNAL_SERVER_ERROR));
+        }
+    }
+
+    // The request is going ahead, so a client waiting for the go-ahead can send its body now
+    if request::take_expect_continue(request) && request_length.has_body() {
+        if let Err(error) = send_continue(client_conn).await {
+            log::info!("Error writing to client {}: {}", entry.client_ip(), error);
+            return Outcome::Rejected {
+                response: None,
+                keep_alive: false,
+            };
+        }
+    }
+
+    // Idempotent requests can be retried on another upstream if they fail, as long as their body
+    // can be sent again too
+    let retry_settings = &config.retry;
+    let replayable = if retry_settings.attempts > 0
+        && retry::is_idempotent(request.method(), retry_settings)
+    {
+        let body_timeout = timeout::from_secs(config.timeouts.body_read);
+        let mut client_body = timeout::ReadTimeout::new(&mut *client_conn, body_timeout);
+        match buffer_body(request, request_length, &mut client_body).await {
+            Ok(replayable) => replayable,
+            Err(error) => {
+                return Outcome::Rejected {
+                    response: request_body_error_status(entry, error)
+                        .map(response::make_http_error),
+                    keep_alive: false,
+                }
+            }
+        }
+    } else {
+        false
+    };
+
+    if let Some(pinned) = pinned.as_deref_mut() {
+        // If the upstream the connection is pinned to has been drained or removed through the
+        // admin API, or ejected by passive health checks, move the connection over to another
+        // upstream. The same goes if the request belongs to another pool, or session affinity ties
+        // it to another upstream. (The first request goes out on the connection made when the
+        // upstream was picked, which has already been admitted.)
+        if let Some((conn, guard)) = &pinned.upstream {
+            let ejected = conn.is_none()
+                && !state
+                    .circuit_breaker
+                    .admit(guard.upstream(), &config.passive_health_check);
+            if ejected || state.is_retired_upstream(guard.upstream()).await {
+                log::info!("{} is no longer in use; picking a new upstream", guard.upstream());
+                // Release the old upstream first so it isn't counted by least-connections
+                pinned.upstream.take();
+            } else if config.pool_of(guard.upstream()) != pool
+                || target.moves_from(guard.upstream(), &pinned.target)
+            {
+                log::debug!("Request is routed away from {}", guard.upstream());
+                pinned.upstream.take();
+            }
+        }
+        if pinned.upstream.is_none() {
+            match connect_to_upstream(state, pool, &target, &[], client).await {
+                Ok((conn, guard)) => {
+                    pinned.upstream = Some((Some(conn), guard));
+                    pinned.target = target.clone();
+                }
+                Err(error) => return rejected(gateway_error(timeout::is_timeout(&error))),
+            }
+        }
+    }
+
+    // Work out where this request goes. Without a pinned upstream, the request is balanced on its
+    // own, and its guard is handed back along with the response
+    let mut request_guard = None;
+    let (guard, upstream_conn) = match pinned.and_then(|pinned| pinned.upstream.as_mut()) {
+        Some((conn, guard)) => (guard, conn.take()),
+        None => match connect_to_upstream(state, pool, &target, &[], client).await {
+            Ok((conn, guard)) => (request_guard.get_or_insert(guard), Some(conn)),
+            Err(error) => return rejected(gateway_error(timeout::is_timeout(&error))),
+        },
+    };
+    forwarding::add_headers(request, client.source.ip(), scheme, &config.forwarding);
+
+    // Forward the request to the server and read its response headers. (A pinned connection moves
+    // over to whichever upstream ends up answering.)
+    let started = Instant::now();
+    let result = send_with_retries(
+        state,
+        entry,
+        request,
+        request_length,
+        client_conn,
+        replayable,
+        pool,
+        &target,
+        guard,
+        upstream_conn,
+        client,
+    )
+    .await;
+    let mut answered = match result {
+        Ok(answered) => answered,
+        Err(ForwardError::Client(error)) => {
+            return Outcome::Rejected {
+                response: request_body_error_status(entry, error).map(response::make_http_error),
+                keep_alive: false,
+            }
+        }
+        Err(ForwardError::Upstream(error)) => {
+            return Outcome::Rejected {
+                response: Some(gateway_error(is_upstream_timeout(&error))),
+                keep_alive: false,
+            }
+        }
+    };
+    let upstream_ip = guard.upstream().to_string();
+    add_affinity_cookie(&mut answered.response, &upstream_ip, &target, &config.affinity);
+    Outcome::Answered(Proxied {
+        answered,
+        upstream_ip,
+        started,
+        _connection: request_guard,
+    })
+}
+
+/// Proxies requests from a client connection until the client hangs up. `scheme` is "https" if
+/// the client connected over TLS and "http" otherwise.
+async fn handle_connection<S>(
+    client_conn: S,
+    client: proxy_protocol::Addresses,
+    scheme: &'static str,
+    state: &ProxyState,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let client_ip = client.source.ip().to_string();
+    log::info!("Connection received from {}", client_ip);
+    let _connection_guard = state.metrics.track_client_connection();
+    // Buffered, so we can wait for the next request to start without consuming any of it, and
+    // counted, so the access log can tell how much each request sent and received
+    let mut client_conn = CountingStream::new(BufReader::new(client_conn));
+
+    // Unless every request is balanced on its own, the connection is pinned to a destination
+    // server, picked once the first request has arrived (a CONNECT request never needs one, and
+    // with routes or session affinity, where requests go depends on the requests themselves). The
+    // first request uses the connection made then; later requests borrow pooled connections to the
+    // same server.
+    let mut pinned = Pinned {
+        upstream: None,
+        target: affinity::Target::Any,
     };
-    let upstream_ip = upstream_conn.peer_addr().unwrap().ip().to_string();
 
     // The client may now send us one or more requests. Keep trying to read requests until the
     // client hangs up or we get an error.
+    let mut first_request = true;
     loop {
-        // Read a request from the client
-        let mut request = match request::read_from_stream(&mut client_conn).await {
+        // Once shutdown has started, the connection is closed as soon as it is idle: right away if
+        // it's waiting for the next request, or once the current one has been answered. The first
+        // request is always waited for, since the client connected expecting an answer (and, when
+        // listeners are handed over in an upgrade, couldn't have known which process took it)
+        if !first_request {
+            if state.shutdown.is_started() {
+                log::debug!("Closing connection from {} for shutdown", client_ip);
+                return;
+            }
+            let next_request = std::future::poll_fn(|cx| {
+                Pin::new(&mut client_conn)
+                    .poll_fill_buf(cx)
+                    .map(|result| result.map(|_| ()))
+            });
+            let idle_timeout = timeout::from_secs(state.config().timeouts.idle);
+            tokio::select! {
+                // A closed connection or an error shows up again when the request is read below
+                _ = next_request => {}
+                _ = state.shutdown.started() => continue,
+                _ = timeout::expired(idle_timeout) => {
+                    log::debug!("Closing idle connection from {}", client_ip);
+                    return;
+                }
+            }
+        }
+        first_request = false;
+        let mut entry = access_log::Entry::new(&client_ip, &client_conn);
+
+        // Read a request from the client. Its body (if any) is still waiting to be read, and is
+        // streamed over to the upstream once we know where the request goes. A client that takes
+        // too long to send the headers is cut off, so it can't tie the connection up forever
+        let header_timeout = timeout::from_secs(state.config().timeouts.header_read);
+        let result = timeout::limit(header_timeout, request::read_from_stream(&mut client_conn));
+        let result = match result.await {
+            Some(result) => result,
+            None => {
+                log::info!("Timed out waiting for a request from {}", client_ip);
+                let response = response::make_http_error(http::StatusCode::REQUEST_TIMEOUT);
+                send_response(state, &mut client_conn, &entry, &response).await;
+                return;
+            }
+        };
+        let (mut request, request_length) = match result {
             Ok(request) => request,
             // Handle case where client closed connection and is no longer sending requests
             Err(request::Error::IncompleteRequest(0)) => {
@@ -222,109 +1637,471 @@ async fn handle_connection(mut client_conn: TcpStream, state: &ProxyState) {
                     request::Error::IncompleteRequest(_)
                     | request::Error::MalformedRequest(_)
                     | request::Error::InvalidContentLength
-                    | request::Error::ContentLengthMismatch => http::StatusCode::BAD_REQUEST,
-                    request::Error::RequestBodyTooLarge => http::StatusCode::PAYLOAD_TOO_LARGE,
+                    | request::Error::ConflictingBodyLength
+                    | request::Error::InvalidTransferEncoding => http::StatusCode::BAD_REQUEST,
                     request::Error::ConnectionError(_) => http::StatusCode::SERVICE_UNAVAILABLE,
                 });
-                send_response(&mut client_conn, &response).await;
+                send_response(state, &mut client_conn, &entry, &response).await;
                 continue;
             }
         };
-        log::info!(
-            "{} -> {}: {}",
-            client_ip,
+        // Logged as the client sent it, before any route rewrites it
+        entry.set_request(&request);
+        // Behind trusted proxies, the request is from whoever they say it is from
+        let forwarding = &state.config().forwarding;
+        let request_ip = forwarding::client_ip(&request, client.source.ip(), forwarding);
+        entry.set_client_ip(&request_ip.to_string());
+
+        let pinned = if state.config().per_request_balancing {
+            None
+        } else {
+            Some(&mut pinned)
+        };
+        let outcome = proxy_request(
+            state,
+            &mut client_conn,
+            &mut entry,
+            &mut request,
+            request_length,
+            &client,
+            scheme,
+            pinned,
+        )
+        .await;
+        let Proxied {
+            answered:
+                Answered {
+                    mut response,
+                    response_length,
+                    mut upstream_stream,
+                    _in_flight,
+                },
             upstream_ip,
-            request::format_request_line(&request)
-        );
+            started,
+            _connection,
+        } = match outcome {
+            Outcome::Answered(proxied) => proxied,
+            // The tunnel takes over the connection
+            Outcome::Connect => {
+                handle_connect_request(state, &mut client_conn, &mut entry, &request).await;
+                return;
+            }
+            Outcome::Rejected {
+                response,
+                keep_alive,
+            } => {
+                if let Some(response) = response {
+                    send_response(state, &mut client_conn, &entry, &response).await;
+                }
+                if keep_alive {
+                    continue;
+                }
+                return;
+            }
+        };
 
-        // Add X-Forwarded-For header so that the upstream server knows the client's IP address.
-        // (We're the ones connecting directly to the upstream server, so without this header, the
-        // upstream server will only know our IP, not the client's.)
-        request::extend_header_value(&mut request, "x-forwarded-for", &client_ip);
+        // A protocol switch (e.g. a WebSocket handshake) turns the connection into a tunnel once
+        // the response is out. Anything the upstream sent after the response headers already
+        // belongs to the new protocol, so it is passed on as part of the tunnel
+        let upgraded = tunnel::is_upgrade(&request, &response);
+        let tunnel_data = if upgraded {
+            std::mem::take(response.body_mut())
+        } else {
+            Vec::new()
+        };
 
-        // Forward the request to the server
-        if let Err(error) = request::write_to_stream(&request, &mut upstream_conn).await {
-            log::error!("Failed to send request to upstream {}: {}", upstream_ip, error);
-            let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-            send_response(&mut client_conn, &response).await;
-            return;
+        // Let the client know not to send any more requests if we're about to close the connection
+        // (an upgraded connection won't carry any more requests anyway)
+        if state.shutdown.is_started() && !upgraded {
+            response
+                .headers_mut()
+                .insert("connection", http::HeaderValue::from_static("close"));
         }
-        log::debug!("Forwarded request to server");
 
-        // Read the server's response
-        let response = match response::read_from_stream(&mut upstream_conn, request.method()).await {
-            Ok(response) => response,
+        // Forward the response to the client. Once the headers are out, there is no way to tell
+        // the client that something went wrong other than hanging up
+        let result = forward_response(
+            state,
+            &response,
+            response_length,
+            &mut upstream_stream,
+            &mut client_conn,
+            &entry,
+        )
+        .await;
+        record_outcome(state, &upstream_ip, &response, &result, started);
+        match result {
+            Ok(()) => log::debug!("Forwarded response to client"),
+            Err(body::Error::WriteError(error)) => {
+                log::warn!("Failed to send response to client: {}", error);
+                return;
+            }
             Err(error) => {
-                log::error!("Error reading response from server: {:?}", error);
-                let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-                send_response(&mut client_conn, &response).await;
+                log::error!(
+                    "Error reading response body from upstream {}: {:?}",
+                    upstream_ip,
+                    error
+                );
                 return;
             }
-        };
-        // Forward the response to the client
-        send_response(&mut client_conn, &response).await;
-        log::debug!("Forwarded response to client");
+        }
+        if upgraded {
+            // The connection stays open (and counted) until either side is done with it, or it
+            // sits idle for too long
+            log::info!("{} <-> {}: switched protocols", client_ip, upstream_ip);
+            let idle_timeout = timeout::from_secs(state.config().timeouts.idle);
+            let result =
+                tunnel::relay(&mut client_conn, &mut upstream_stream, &tunnel_data, idle_timeout)
+                    .await;
+            match result {
+                Ok(()) => log::debug!("Upgraded connection from {} closed", client_ip),
+                Err(error) if timeout::is_timeout(&error) => {
+                    log::debug!("Closing idle upgraded connection from {}", client_ip)
+                }
+                Err(error) => log::info!("Error relaying upgraded connection: {}", error),
+            }
+            return;
+        }
+        if pool::can_reuse(&request, &response) {
+            let settings = &state.config().pool;
+            state
+                .pool
+                .checkin(&upstream_ip, upstream_stream, &state.upstream_tls, settings);
+        }
+        if response_length == body::Length::UntilClose {
+            // The client can only tell where the body ended because the connection was closed
+            return;
+        }
     }
 }
 
-async fn perform_health_check(state: &ProxyState) {
-    let mut dead_upstream_addresses = state.dead_upstream_addresses.lock().await;
-    let mut upstream_addresses = state.upstream_addresses.lock().await;
-    // merge two vector into 1
-    dead_upstream_addresses.append(&mut upstream_addresses);
-    upstream_addresses.clear();
-    let mut new_dead_addresses = Vec::new();
-
-    for addr in dead_upstream_addresses.iter() {
-        // try to connect to a upstream server
-        let mut conn = match TcpStream::connect(addr).await {
-            Ok(stream) => stream,
-            Err(err) => {
-                log::error!("Failed to connect to upstream {}: {}", addr, err);
-                new_dead_addresses.push(true);
-                continue;
-            },
-        };
+/// Relays a raw TCP connection to an upstream from `pool` (or from the top-level upstreams if
+/// None), until both sides are done with it or it sits idle for too long.
+async fn handle_tcp_connection(
+    mut client_conn: TcpStream,
+    client: proxy_protocol::Addresses,
+    pool: Option<&str>,
+    state: &ProxyState,
+) {
+    let client_ip = client.source.ip().to_string();
+    log::info!("TCP connection received from {}", client_ip);
+    let _connection_guard = state.metrics.track_client_connection();
+    let config = state.config();
+    let target = affinity::Target::of_connection(&client_ip, &config.affinity);
+    let upstream = connect_to_upstream(state, pool, &target, &[], &client).await;
+    let (mut upstream_conn, guard) = match upstream {
+        Ok(upstream) => upstream,
+        Err(error) => {
+            log::error!("No upstream available for TCP connection from {}: {}", client_ip, error);
+            return;
+        }
+    };
+    let upstream_ip = guard.upstream();
+    log::info!("{} -> {}: relaying TCP connection", client_ip, upstream_ip);
+    // Getting connected is all an upstream has to do to count as working
+    state.record_upstream_result(upstream_ip, true);
+    state.metrics.record_upstream_request(upstream_ip);
+    let _in_flight = state.balancer.track_request(upstream_ip);
+    let started = Instant::now();
+    let idle_timeout = timeout::from_secs(config.timeouts.idle);
+    let result =
+        tunnel::relay(&mut client_conn, &mut upstream_conn.stream, &[], idle_timeout).await;
+    state
+        .metrics
+        .record_request_duration(upstream_ip, started.elapsed());
+    match result {
+        Ok(()) => log::debug!("TCP connection from {} closed", client_ip),
+        Err(error) if timeout::is_timeout(&error) => {
+            log::debug!("Closing idle TCP connection from {}", client_ip)
+        }
+        Err(error) => log::info!("Error relaying TCP connection from {}: {}", client_ip, error),
+    }
+}
 
-        // build a request to health check path
-        let request = http::Request::builder()
-            .method(http::Method::GET)
-            .uri(state.active_health_check_path.clone())
-            .header("Host", addr)
-            .body(Vec::<u8>::new())
-            .unwrap();
-
-        if let Err(error) = request::write_to_stream(&request, &mut conn).await {
-            log::error!("Failed to send request to upstream {}: {}", addr, error);
-            new_dead_addresses.push(true);
-            continue;
+/// Opens a tunnel to the destination of a CONNECT request, if the allow-list lets the client go
+/// there, and relays bytes between the two until both sides are done with it.
+async fn handle_connect_request<S>(
+    state: &ProxyState,
+    client_conn: &mut CountingStream<S>,
+    entry: &mut access_log::Entry,
+    request: &http::Request<Vec<u8>>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let config = state.config();
+    let status = match connect::destination(request) {
+        _ if config.connect.allow.is_empty() => Some(http::StatusCode::METHOD_NOT_ALLOWED),
+        None => Some(http::StatusCode::BAD_REQUEST),
+        Some(destination) if !connect::is_allowed(&destination, &config.connect.allow) => {
+            log::info!("{} may not CONNECT to {}", entry.client_ip(), destination);
+            Some(http::StatusCode::FORBIDDEN)
         }
+        Some(destination) => {
+            entry.upstream = Some(destination);
+            None
+        }
+    };
+    if let Some(status) = status {
+        let response = response::make_http_error(status);
+        send_response(state, client_conn, entry, &response).await;
+        return;
+    }
+    let destination = entry.upstream.clone().unwrap_or_default();
 
-        // Read the server's response
-        let response = match response::read_from_stream(&mut conn, request.method()).await {
-            Ok(response) => response,
+    let connect_timeout = timeout::from_secs(config.timeouts.upstream_connect);
+    let started = Instant::now();
+    let mut upstream_conn =
+        match timeout::io(connect_timeout, TcpStream::connect(destination.as_str())).await {
+            Ok(conn) => conn,
             Err(error) => {
-                log::error!("Error reading response from server: {:?}", error);
-                new_dead_addresses.push(true);
+                log::error!("Failed to connect to CONNECT destination {}: {}", destination, error);
+                let response = gateway_error(timeout::is_timeout(&error));
+                send_response(state, client_conn, entry, &response).await;
                 return;
             }
         };
+    entry.upstream_latency = Some(started.elapsed());
+    let response = http::Response::builder()
+        .status(http::StatusCode::OK)
+        .version(http::Version::HTTP_11)
+        .body(Vec::new())
+        .unwrap();
+    send_response(state, client_conn, entry, &response).await;
 
-        // the response is errorneous
-        if response.status().as_u16() > 400 {
-            new_dead_addresses.push(true);
-        } else {
-            new_dead_addresses.push(false);
-            upstream_addresses.push(addr.to_string());
+    log::info!("{} <-> {}: tunneling", entry.client_ip(), destination);
+    let idle_timeout = timeout::from_secs(config.timeouts.idle);
+    // Anything the client sent after the request headers is already meant for the destination, and
+    // is still waiting to be read from the client connection
+    let result = tunnel::relay(client_conn, &mut upstream_conn, &[], idle_timeout).await;
+    match result {
+        Ok(()) => log::debug!("Tunnel to {} closed", destination),
+        Err(error) if timeout::is_timeout(&error) => {
+            log::debug!("Closing idle tunnel to {}", destination)
+        }
+        Err(error) => log::info!("Error relaying tunnel to {}: {}", destination, error),
+    }
+}
+
+/// Serves an HTTP/2 connection until the client hangs up, handing each stream (request) off to a
+/// task of its own so they run side by side. `scheme` is as for `handle_connection`.
+async fn handle_http2_connection<S>(
+    client_conn: S,
+    client: proxy_protocol::Addresses,
+    scheme: &'static str,
+    state: &ProxyState,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let client_ip = client.source.ip().to_string();
+    log::info!("HTTP/2 connection received from {}", client_ip);
+    let _connection_guard = state.metrics.track_client_connection();
+    let header_timeout = timeout::from_secs(state.config().timeouts.header_read);
+    let mut connection = match timeout::limit(header_timeout, http2::handshake(client_conn)).await
+    {
+        Some(Ok(connection)) => connection,
+        Some(Err(error)) => {
+            log::info!("HTTP/2 handshake with {} failed: {}", client_ip, error);
+            return;
+        }
+        None => {
+            log::info!("Timed out waiting for the HTTP/2 handshake from {}", client_ip);
+            return;
+        }
+    };
+
+    // Every stream task holds a clone, so the connection can tell whether any are still open.
+    // Streams only make progress while the connection is polled, so it is served until the client
+    // is gone, even once it has been asked to wind down
+    let open_streams = Arc::new(());
+    let mut closing = false;
+    loop {
+        let idle_timeout = timeout::from_secs(state.config().timeouts.idle);
+        tokio::select! {
+            stream = connection.accept() => match stream {
+                Some(Ok((request, respond))) => {
+                    let state = state.clone();
+                    let stream_guard = open_streams.clone();
+                    tokio::spawn(async move {
+                        let _stream_guard = stream_guard;
+                        handle_http2_stream(request, respond, &client, scheme, &state).await;
+                    });
+                }
+                Some(Err(error)) => {
+                    log::info!("Error on HTTP/2 connection from {}: {}", client_ip, error);
+                    return;
+                }
+                None => {
+                    log::debug!("HTTP/2 connection from {} closed", client_ip);
+                    return;
+                }
+            },
+            // The client is told not to start any more streams; the ones already open are answered
+            _ = state.shutdown.started(), if !closing => {
+                log::debug!("Closing HTTP/2 connection from {} for shutdown", client_ip);
+                connection.graceful_shutdown();
+                closing = true;
+            }
+            _ = timeout::expired(idle_timeout), if !closing => {
+                if Arc::strong_count(&open_streams) == 1 {
+                    log::debug!("Closing idle HTTP/2 connection from {}", client_ip);
+                    connection.graceful_shutdown();
+                    closing = true;
+                }
+            }
         }
     }
+}
 
-    let mut iter = new_dead_addresses.iter();
-    dead_upstream_addresses.retain(|_| *iter.next().unwrap());
-    println!("{:?}, {:?}", upstream_addresses, dead_upstream_addresses);
+/// Proxies one request from an HTTP/2 connection to an HTTP/1.1 upstream. Each stream is balanced
+/// on its own, since streams from one connection are in flight at the same time.
+async fn handle_http2_stream(
+    request: http::Request<h2::RecvStream>,
+    respond: h2::server::SendResponse<bytes::Bytes>,
+    client: &proxy_protocol::Addresses,
+    scheme: &'static str,
+    state: &ProxyState,
+) {
+    let (mut request, request_length, exchange) = http2::Exchange::new(request, respond);
+    let forwarding = &state.config().forwarding;
+    let client_ip = &forwarding::client_ip(&request, client.source.ip(), forwarding).to_string();
+    let mut client_conn = CountingStream::new(exchange);
+    let mut entry = access_log::Entry::new(client_ip, &client_conn);
+    // Logged as the client sent it; the upstreams only speak HTTP/1.1
+    entry.set_request(&request);
+    *request.version_mut() = http::Version::HTTP_11;
+    // There's no way to send an interim response on the stream, so the client is left to its own
+    // timer before it sends the body. Taking the expectation off here keeps it from being answered
+    // in the stream's data, or passed on to the upstream
+    request::take_expect_continue(&mut request);
+
+    let outcome = proxy_request(
+        state,
+        &mut client_conn,
+        &mut entry,
+        &mut request,
+        request_length,
+        client,
+        scheme,
+        None,
+    )
+    .await;
+    let Proxied {
+        answered:
+            Answered {
+                response,
+                response_length,
+                mut upstream_stream,
+                _in_flight,
+            },
+        upstream_ip,
+        started,
+        _connection,
+    } = match outcome {
+        Outcome::Answered(proxied) => proxied,
+        // A CONNECT tunnel over HTTP/2 would run inside the stream rather than take over the
+        // connection, which isn't supported
+        Outcome::Connect => {
+            let response = response::make_http_error(http::StatusCode::NOT_IMPLEMENTED);
+            send_http2_response(state, &mut client_conn, &entry, &response).await;
+            return;
+        }
+        Outcome::Rejected { response, .. } => {
+            if let Some(response) = response {
+                send_http2_response(state, &mut client_conn, &entry, &response).await;
+            }
+            return;
+        }
+    };
+
+    let result = forward_http2_response(
+        state,
+        &response,
+        response_length,
+        &mut upstream_stream,
+        &mut client_conn,
+        &entry,
+    )
+    .await;
+    record_outcome(state, &upstream_ip, &response, &result, started);
+    match result {
+        Ok(()) => log::debug!("Forwarded response to client"),
+        Err(body::Error::WriteError(error)) => {
+            log::warn!("Failed to send response to client: {}", error);
+            return;
+        }
+        Err(error) => {
+            log::error!(
+                "Error reading response body from upstream {}: {:?}",
+                upstream_ip,
+                error
+            );
+            return;
+        }
+    }
+    if pool::can_reuse(&request, &response) {
+        let settings = &state.config().pool;
+        state
+            .pool
+            .checkin(&upstream_ip, upstream_stream, &state.upstream_tls, settings);
+    }
+}
+
+/// Answers an HTTP/2 request with a response held in memory (such as one made by make_http_error).
+async fn send_http2_response(
+    state: &ProxyState,
+    client_conn: &mut CountingStream<http2::Exchange>,
+    entry: &access_log::Entry,
+    response: &http::Response<Vec<u8>>,
+) {
+    state.metrics.record_response(response.status());
+    log::info!("{} <- {}", entry.client_ip(), response::format_response_line(response));
+    let end_of_stream = response.body().is_empty();
+    let result = match client_conn.get_mut().send_headers(response, end_of_stream) {
+        Ok(()) if end_of_stream => Ok(()),
+        Ok(()) => match client_conn.write_all(response.body()).await {
+            Ok(()) => client_conn.shutdown().await,
+            Err(error) => Err(error),
+        },
+        Err(error) => Err(error),
+    };
+    if let Err(error) = result {
+        log::warn!("Failed to send response to client: {}", error);
+    }
+    state.access_log.record(entry, response.status(), client_conn);
+}
+
+/// Like `forward_response`, for a client on an HTTP/2 connection. A chunked body is passed on as
+/// plain data, since the stream marks where the body ends, and the stream is ended afterwards.
+async fn forward_http2_response(
+    state: &ProxyState,
+    response: &http::Response<Vec<u8>>,
+    response_length: body::Length,
+    upstream_conn: &mut tls::UpstreamStream,
+    client_conn: &mut CountingStream<http2::Exchange>,
+    entry: &access_log::Entry,
+) -> Result<(), body::Error> {
+    state.metrics.record_response(response.status());
+    log::info!("{} <- {}", entry.client_ip(), response::format_response_line(response));
+    let end_of_stream = !response_length.has_body();
+    let result = match client_conn.get_mut().send_headers(response, end_of_stream) {
+        Ok(()) if end_of_stream => Ok(()),
+        Ok(()) => {
+            let response_timeout = timeout::from_secs(state.config().timeouts.upstream_response);
+            let mut upstream_body = timeout::ReadTimeout::new(upstream_conn, response_timeout);
+            let body = response.body();
+            let result =
+                body::copy_decoded(&mut upstream_body, body, response_length, client_conn).await;
+            match result {
+                Ok(()) => client_conn.shutdown().await.map_err(body::Error::WriteError),
+                Err(error) => Err(error),
+            }
+        }
+        Err(error) => Err(body::Error::WriteError(error)),
+    };
+    state.access_log.record(entry, response.status(), client_conn);
+    result
 }
 
 async fn rate_limiting_refresh(state: &ProxyState) {
-    let mut count_map = state.count_map.lock().await;
-    count_map.clear();
+    state.rate_limiter.prune(&state.config().rate_limit);
 }
@@ -0,0 +1,275 @@
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Upper bounds (in seconds) of the buckets of the request duration histogram
+const DURATION_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// A Prometheus histogram of request durations for a single upstream.
+#[derive(Default)]
+struct Histogram {
+    /// Number of observations that fell into each bucket (not cumulative; that's done when the
+    /// histogram is rendered)
+    buckets: [u64; DURATION_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        if let Some(idx) = DURATION_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[idx] += 1;
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+}
+
+/// Counters and gauges describing what balancebeam has been up to, exported in the Prometheus
+/// text format on the admin listener's /metrics endpoint.
+pub struct Metrics {
+    /// Number of requests forwarded to each upstream
+    upstream_requests: Mutex<BTreeMap<String, u64>>,
+    /// Time from reading each request to finishing its response, per upstream
+    request_durations: Mutex<BTreeMap<String, Histogram>>,
+    /// Number of responses sent to clients by status class ("2xx", "5xx", ...), including errors
+    /// generated by balancebeam itself
+    responses: Mutex<BTreeMap<String, u64>>,
+    /// Number of requests rejected by the rate limiter
+    rate_limited: AtomicUsize,
+    /// Number of failed requests that were sent again to another upstream
+    retries: AtomicUsize,
+    /// Number of access log lines dropped because the writer had fallen too far behind
+    access_log_dropped: AtomicUsize,
+    /// Number of client connections currently open
+    client_connections: Arc<AtomicUsize>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            upstream_requests: Mutex::new(BTreeMap::new()),
+            request_durations: Mutex::new(BTreeMap::new()),
+            responses: Mutex::new(BTreeMap::new()),
+            rate_limited: AtomicUsize::new(0),
+            retries: AtomicUsize::new(0),
+            access_log_dropped: AtomicUsize::new(0),
+            client_connections: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Records a request being forwarded to `upstream`.
+    pub fn record_upstream_request(&self, upstream: &str) {
+        *self
+            .upstream_requests
+            .lock()
+            .entry(upstream.to_string())
+            .or_insert(0) += 1;
+    }
+
+    /// Records how long it took to proxy a request to `upstream`, response body included.
+    pub fn record_request_duration(&self, upstream: &str, duration: Duration) {
+        self.request_durations
+            .lock()
+            .entry(upstream.to_string())
+            .or_default()
+            .observe(duration.as_secs_f64());
+    }
+
+    /// Records a response sent to a client.
+    pub fn record_response(&self, status: http::StatusCode) {
+        let class = format!("{}xx", status.as_u16() / 100);
+        *self.responses.lock().entry(class).or_insert(0) += 1;
+    }
+
+    pub fn record_rate_limited(&self) {
+        self.rate_limited.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_retry(&self) {
+        self.retries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_access_log_dropped(&self) {
+        self.access_log_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a client connection as open until the returned guard is dropped.
+    pub fn track_client_connection(&self) -> ClientConnectionGuard {
+        self.client_connections.fetch_add(1, Ordering::Relaxed);
+        ClientConnectionGuard {
+            client_connections: self.client_connections.clone(),
+        }
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format. The live/dead upstream sets
+    /// and the number of open connections to each upstream are owned by other parts of
+    /// balancebeam, so they are passed in.
+    pub fn render(
+        &self,
+        live_upstreams: &[String],
+        dead_upstreams: &[String],
+        upstream_connections: &BTreeMap<String, usize>,
+    ) -> String {
+        let mut out = String::new();
+
+        write_header(
+            &mut out,
+            "balancebeam_upstream_requests_total",
+            "counter",
+            "Requests forwarded to each upstream",
+        );
+        for (upstream, count) in self.upstream_requests.lock().iter() {
+            write_sample(&mut out, "balancebeam_upstream_requests_total", upstream, *count);
+        }
+
+        write_header(
+            &mut out,
+            "balancebeam_responses_total",
+            "counter",
+            "Responses sent to clients, by status class",
+        );
+        for (class, count) in self.responses.lock().iter() {
+            let _ = writeln!(out, "balancebeam_responses_total{{class=\"{}\"}} {}", class, count);
+        }
+
+        write_header(
+            &mut out,
+            "balancebeam_request_duration_seconds",
+            "histogram",
+            "Time taken to proxy a request to each upstream, response body included",
+        );
+        for (upstream, histogram) in self.request_durations.lock().iter() {
+            let upstream = escape_label(upstream);
+            let mut cumulative = 0;
+            for (bound, count) in DURATION_BUCKETS.iter().zip(histogram.buckets.iter()) {
+                cumulative += count;
+                let _ = writeln!(
+                    out,
+                    "balancebeam_request_duration_seconds_bucket{{upstream=\"{}\",le=\"{}\"}} {}",
+                    upstream, bound, cumulative
+                );
+            }
+            let _ = writeln!(
+                out,
+                "balancebeam_request_duration_seconds_bucket{{upstream=\"{}\",le=\"+Inf\"}} {}",
+                upstream, histogram.count
+            );
+            let _ = writeln!(
+                out,
+                "balancebeam_request_duration_seconds_sum{{upstream=\"{}\"}} {}",
+                upstream, histogram.sum
+            );
+            let _ = writeln!(
+                out,
+                "balancebeam_request_duration_seconds_count{{upstream=\"{}\"}} {}",
+                upstream, histogram.count
+            );
+        }
+
+        write_header(
+            &mut out,
+            "balancebeam_upstream_up",
+            "gauge",
+            "Whether each upstream is currently live (1) or dead (0)",
+        );
+        for upstream in live_upstreams {
+            write_sample(&mut out, "balancebeam_upstream_up", upstream, 1);
+        }
+        for upstream in dead_upstreams {
+            write_sample(&mut out, "balancebeam_upstream_up", upstream, 0);
+        }
+
+        write_header(
+            &mut out,
+            "balancebeam_upstream_connections",
+            "gauge",
+            "Connections currently open to each upstream on behalf of clients",
+        );
+        for (upstream, count) in upstream_connections {
+            write_sample(&mut out, "balancebeam_upstream_connections", upstream, *count);
+        }
+
+        write_header(
+            &mut out,
+            "balancebeam_rate_limited_total",
+            "counter",
+            "Requests rejected by the rate limiter",
+        );
+        let _ = writeln!(
+            out,
+            "balancebeam_rate_limited_total {}",
+            self.rate_limited.load(Ordering::Relaxed)
+        );
+
+        write_header(
+            &mut out,
+            "balancebeam_retries_total",
+            "counter",
+            "Failed requests that were retried on another upstream",
+        );
+        let _ = writeln!(
+            out,
+            "balancebeam_retries_total {}",
+            self.retries.load(Ordering::Relaxed)
+        );
+
+        write_header(
+            &mut out,
+            "balancebeam_access_log_dropped_total",
+            "counter",
+            "Access log lines dropped because writing them fell too far behind",
+        );
+        let _ = writeln!(
+            out,
+            "balancebeam_access_log_dropped_total {}",
+            self.access_log_dropped.load(Ordering::Relaxed)
+        );
+
+        write_header(
+            &mut out,
+            "balancebeam_client_connections",
+            "gauge",
+            "Client connections currently open",
+        );
+        let _ = writeln!(
+            out,
+            "balancebeam_client_connections {}",
+            self.client_connections.load(Ordering::Relaxed)
+        );
+
+        out
+    }
+}
+
+/// Keeps a client connection counted as open for as long as it is alive.
+pub struct ClientConnectionGuard {
+    client_connections: Arc<AtomicUsize>,
+}
+
+impl Drop for ClientConnectionGuard {
+    fn drop(&mut self) {
+        self.client_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Writes a sample labelled with an upstream address.
+fn write_sample<T: std::fmt::Display>(out: &mut String, name: &str, upstream: &str, value: T) {
+    let _ = writeln!(out, "{}{{upstream=\"{}\"}} {}", name, escape_label(upstream), value);
+}
+
+/// Escapes a label value as required by the text format.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
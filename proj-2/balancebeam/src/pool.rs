@@ -0,0 +1,166 @@
+use crate::chunked;
+use crate::config;
+use crate::proxy_protocol;
+use crate::tls::{UpstreamConnector, UpstreamStream};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// A connection to an upstream server, either freshly opened or borrowed from the pool.
+pub struct Connection {
+    pub stream: UpstreamStream,
+    /// True if the connection already served a request before it was handed out. The upstream may
+    /// have closed such a connection just as we picked it up, so a failure on the first write or
+    /// read doesn't necessarily mean the upstream is down.
+    pub reused: bool,
+}
+
+/// A connection sitting in the pool, waiting for the next request.
+struct IdleConnection {
+    stream: UpstreamStream,
+    idle_since: Instant,
+}
+
+/// Keeps idle keep-alive connections to each upstream so that requests don't have to pay for a new
+/// TCP (and TLS) handshake every time.
+pub struct Pool {
+    idle: Mutex<HashMap<String, Vec<IdleConnection>>>,
+}
+
+impl Pool {
+    pub fn new() -> Pool {
+        Pool {
+            idle: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes an idle connection to `upstream` out of the pool, closing any that have timed out or
+    /// been closed by the upstream along the way. Returns None if no usable connection is left.
+    pub async fn checkout(&self, upstream: &str, settings: &config::Pool) -> Option<Connection> {
+        let idle_timeout = Duration::from_secs(settings.idle_timeout);
+        loop {
+            // Most recently used connections are the least likely to have been closed
+            let idle = self.idle.lock().get_mut(upstream).and_then(|idle| idle.pop())?;
+            if idle.idle_since.elapsed() >= idle_timeout {
+                continue;
+            }
+            let mut stream = idle.stream;
+            if is_alive(&mut stream).await {
+                return Some(Connection {
+                    stream,
+                    reused: true,
+                });
+            }
+            log::debug!("Discarding pooled connection to {} closed by the upstream", upstream);
+        }
+    }
+
+    /// Returns an idle connection to `upstream` if there is one, or opens a new one for `client`
+    /// otherwise. Connections to upstreams that take the PROXY protocol are always new, since
+    /// they are tied to the client they were opened for.
+    pub async fn connect(
+        &self,
+        upstream: &str,
+        connector: &UpstreamConnector,
+        settings: &config::Pool,
+        client: &proxy_protocol::Addresses,
+    ) -> Result<Connection, std::io::Error> {
+        if !connector.sends_proxy_protocol(upstream) {
+            if let Some(conn) = self.checkout(upstream, settings).await {
+                return Ok(conn);
+            }
+        }
+        Ok(Connection {
+            stream: connector.connect(upstream, Some(client)).await?,
+            reused: false,
+        })
+    }
+
+    /// Hands a connection back once its response has been fully read. The connection is closed
+    /// instead if the pool for `upstream` is already full, or if the upstream takes the PROXY
+    /// protocol.
+    pub fn checkin(
+        &self,
+        upstream: &str,
+        stream: UpstreamStream,
+        connector: &UpstreamConnector,
+        settings: &config::Pool,
+    ) {
+        if connector.sends_proxy_protocol(upstream) {
+            return;
+        }
+        let mut idle = self.idle.lock();
+        let idle = idle.entry(upstream.to_string()).or_default();
+        if idle.len() < settings.max_idle_per_upstream {
+            idle.push(IdleConnection {
+                stream,
+                idle_since: Instant::now(),
+            });
+        }
+    }
+
+    /// Closes every idle connection to `upstream`, e.g. because it has been marked dead.
+    pub fn remove_upstream(&self, upstream: &str) {
+        self.idle.lock().remove(upstream);
+    }
+
+    /// Closes idle connections that have timed out or that the upstream has hung up on.
+    pub async fn evict(&self, settings: &config::Pool) {
+        let idle_timeout = Duration::from_secs(settings.idle_timeout);
+        // Take the connections out while we probe them so the lock isn't held across an await.
+        // Anything checked in meanwhile is kept as well.
+        let pooled: Vec<(String, Vec<IdleConnection>)> = self.idle.lock().drain().collect();
+        for (upstream, connections) in pooled {
+            let mut survivors = Vec::new();
+            for mut idle in connections {
+                if idle.idle_since.elapsed() < idle_timeout && is_alive(&mut idle.stream).await {
+                    survivors.push(idle);
+                }
+            }
+            let mut idle = self.idle.lock();
+            let idle = idle.entry(upstream).or_default();
+            // Drop the oldest connections if the pool filled up meanwhile, and keep the most
+            // recently used ones at the end, where checkout looks first
+            let excess =
+                (survivors.len() + idle.len()).saturating_sub(settings.max_idle_per_upstream);
+            survivors.drain(..excess.min(survivors.len()));
+            survivors.append(idle);
+            *idle = survivors;
+        }
+    }
+}
+
+/// Checks that an idle connection is still open. An idle upstream connection should have nothing
+/// to read; if it is readable, the upstream either closed it or sent bytes we never asked for, and
+/// it isn't safe to send another request over it. (For a TLS connection, this includes a
+/// close_notify alert.)
+async fn is_alive(stream: &mut UpstreamStream) -> bool {
+    let mut buffer = [0_u8; 1];
+    tokio::time::timeout(Duration::from_secs(0), stream.tcp_mut().peek(&mut buffer))
+        .await
+        .is_err()
+}
+
+/// Returns true if the upstream connection can carry another request after this exchange: both
+/// sides must be willing to keep it alive, the response must be a final one (after a 1xx response,
+/// more is still to come on the connection), and the response body must have had a definite length
+/// or been chunked (otherwise it was delimited by the upstream closing the connection).
+pub fn can_reuse(request: &http::Request<Vec<u8>>, response: &http::Response<Vec<u8>>) -> bool {
+    let wants_close = |headers: &http::HeaderMap| {
+        headers
+            .get_all("connection")
+            .iter()
+            .any(|value| value.to_str().is_ok_and(|v| v.eq_ignore_ascii_case("close")))
+    };
+    if response.status().is_informational() {
+        return false;
+    }
+    let has_body = !(request.method() == http::Method::HEAD
+        || response.status() == http::StatusCode::NO_CONTENT
+        || response.status() == http::StatusCode::NOT_MODIFIED);
+    !wants_close(request.headers())
+        && !wants_close(response.headers())
+        && (!has_body
+            || response.headers().contains_key("content-length")
+            || chunked::is_chunked(response.headers()))
+}
@@ -0,0 +1,371 @@
+use crate::cidr::{self, Cidr};
+use serde::Deserialize;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::TcpStream;
+
+/// What a version 1 (text) header starts with.
+const V1_PREFIX: &[u8] = b"PROXY ";
+
+/// Longest a version 1 header can be, including the CRLF at the end.
+const V1_MAX_LEN: usize = 107;
+
+/// What a version 2 (binary) header starts with.
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Version 2 commands: LOCAL is sent for connections the proxy opens on its own behalf (such as
+/// health checks), PROXY for connections relayed for a client.
+const V2_LOCAL: u8 = 0x20;
+const V2_PROXY: u8 = 0x21;
+
+/// Version 2 address families over TCP.
+const V2_TCP4: u8 = 0x11;
+const V2_TCP6: u8 = 0x21;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Encountered an I/O error when reading the header
+    Io(io::Error),
+    /// The connection didn't start with a valid PROXY protocol header
+    Invalid(&'static str),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "error reading PROXY protocol header: {}", err),
+            Error::Invalid(reason) => write!(f, "invalid PROXY protocol header: {}", reason),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+/// The version of the PROXY protocol header to send to an upstream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Version {
+    /// The human-readable text header
+    V1,
+    /// The binary header
+    V2,
+}
+
+impl FromStr for Version {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "v1" => Ok(Version::V1),
+            "v2" => Ok(Version::V2),
+            _ => Err(format!(
+                "unknown PROXY protocol version \"{}\" (expected v1 or v2)",
+                s
+            )),
+        }
+    }
+}
+
+/// Where a client connection really comes from and which address it was made to, which differ
+/// from the addresses of the TCP connection when it was relayed by a proxy in front of us.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Addresses {
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+}
+
+/// Works out the addresses of a client connection that was just accepted. Connections from a
+/// `trusted` proxy must start with a PROXY protocol header (of either version), which is read off
+/// the stream and says where the client really is. Connections from anywhere else are taken at
+/// face value, without reading anything.
+pub async fn accept(stream: &mut TcpStream, trusted: &[Cidr]) -> Result<Addresses, Error> {
+    let actual = Addresses {
+        source: stream.peer_addr()?,
+        destination: stream.local_addr()?,
+    };
+    if !cidr::contains_any(trusted, actual.source.ip()) {
+        return Ok(actual);
+    }
+    // Proxies send LOCAL and UNKNOWN for connections of their own, which come from the proxy itself
+    Ok(read_header(stream).await?.unwrap_or(actual))
+}
+
+/// Reads a PROXY protocol header of either version off the start of `stream`. Returns None if the
+/// header doesn't name a client.
+async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<Addresses>, Error> {
+    let mut start = [0_u8; V1_PREFIX.len()];
+    stream.read_exact(&mut start).await?;
+    if start == V1_PREFIX {
+        read_v1(stream).await
+    } else if start == V2_SIGNATURE[..start.len()] {
+        read_v2(stream).await
+    } else {
+        Err(Error::Invalid("missing header"))
+    }
+}
+
+/// Reads the rest of a version 1 header, after "PROXY ". Returns None for UNKNOWN connections.
+async fn read_v1<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<Addresses>, Error> {
+    // Read a byte at a time, so that nothing after the header is taken off the stream
+    let mut line = Vec::new();
+    while !line.ends_with(b"\r\n") {
+        if V1_PREFIX.len() + line.len() >= V1_MAX_LEN {
+            return Err(Error::Invalid("header too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+    line.truncate(line.len() - 2);
+    let line = std::str::from_utf8(&line).map_err(|_| Error::Invalid("header is not text"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields[0] {
+        "UNKNOWN" => return Ok(None),
+        "TCP4" | "TCP6" if fields.len() == 5 => {}
+        _ => return Err(Error::Invalid("unsupported protocol")),
+    }
+    let ip = |field: &str| -> Result<IpAddr, Error> {
+        let ip = field.parse().map_err(|_| Error::Invalid("bad address"))?;
+        match ip {
+            IpAddr::V4(_) if fields[0] == "TCP4" => Ok(ip),
+            IpAddr::V6(_) if fields[0] == "TCP6" => Ok(ip),
+            _ => Err(Error::Invalid("address doesn't match the protocol")),
+        }
+    };
+    let port = |field: &str| -> Result<u16, Error> {
+        field.parse().map_err(|_| Error::Invalid("bad port"))
+    };
+    Ok(Some(Addresses {
+        source: SocketAddr::new(ip(fields[1])?, port(fields[3])?),
+        destination: SocketAddr::new(ip(fields[2])?, port(fields[4])?),
+    }))
+}
+
+/// Reads the rest of a version 2 header, after the start of the signature. Returns None for
+/// LOCAL connections and for address families other than TCP over IPv4 or IPv6.
+async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<Addresses>, Error> {
+    let mut rest = [0_u8; 10];
+    stream.read_exact(&mut rest).await?;
+    if rest[..6] != V2_SIGNATURE[V1_PREFIX.len()..] {
+        return Err(Error::Invalid("missing header"));
+    }
+    let (command, family) = (rest[6], rest[7]);
+    let mut payload = vec![0_u8; u16::from_be_bytes([rest[8], rest[9]]) as usize];
+    stream.read_exact(&mut payload).await?;
+    match command {
+        V2_LOCAL => return Ok(None),
+        V2_PROXY => {}
+        _ => return Err(Error::Invalid("unsupported version or command")),
+    }
+    // Anything past the addresses is TLVs, which we don't need
+    let port = |at: usize| u16::from_be_bytes([payload[at], payload[at + 1]]);
+    match family {
+        V2_TCP4 if payload.len() >= 12 => {
+            let ip = |at: usize| {
+                let mut octets = [0_u8; 4];
+                octets.copy_from_slice(&payload[at..at + 4]);
+                IpAddr::V4(Ipv4Addr::from(octets))
+            };
+            Ok(Some(Addresses {
+                source: SocketAddr::new(ip(0), port(8)),
+                destination: SocketAddr::new(ip(4), port(10)),
+            }))
+        }
+        V2_TCP6 if payload.len() >= 36 => {
+            let ip = |at: usize| {
+                let mut octets = [0_u8; 16];
+                octets.copy_from_slice(&payload[at..at + 16]);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            };
+            Ok(Some(Addresses {
+                source: SocketAddr::new(ip(0), port(32)),
+                destination: SocketAddr::new(ip(16), port(34)),
+            }))
+        }
+        V2_TCP4 | V2_TCP6 => Err(Error::Invalid("addresses cut short")),
+        _ => Ok(None),
+    }
+}
+
+/// Builds the header that tells an upstream about `client`. With None, the header says the
+/// connection is balancebeam's own (e.g. a health check).
+pub fn encode(version: Version, client: Option<&Addresses>) -> Vec<u8> {
+    // Both addresses have to be of the same family, so map IPv4 into IPv6 if they're mixed
+    let client = client.map(|client| match (client.source, client.destination) {
+        (SocketAddr::V4(_), SocketAddr::V4(_)) | (SocketAddr::V6(_), SocketAddr::V6(_)) => *client,
+        (source, destination) => Addresses {
+            source: to_ipv6(source),
+            destination: to_ipv6(destination),
+        },
+    });
+    match version {
+        Version::V1 => match client {
+            Some(client) => format!(
+                "PROXY {} {} {} {} {}\r\n",
+                if client.source.is_ipv4() { "TCP4" } else { "TCP6" },
+                client.source.ip(),
+                client.destination.ip(),
+                client.source.port(),
+                client.destination.port()
+            )
+            .into_bytes(),
+            None => b"PROXY UNKNOWN\r\n".to_vec(),
+        },
+        Version::V2 => {
+            let mut header = V2_SIGNATURE.to_vec();
+            let mut payload = Vec::new();
+            match client {
+                Some(client) => {
+                    header.push(V2_PROXY);
+                    header.push(if client.source.is_ipv4() { V2_TCP4 } else { V2_TCP6 });
+                    payload.extend_from_slice(&octets(client.source.ip()));
+                    payload.extend_from_slice(&octets(client.destination.ip()));
+                    payload.extend_from_slice(&client.source.port().to_be_bytes());
+                    payload.extend_from_slice(&client.destination.port().to_be_bytes());
+                }
+                None => header.extend_from_slice(&[V2_LOCAL, 0]),
+            }
+            header.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+            header.extend_from_slice(&payload);
+            header
+        }
+    }
+}
+
+/// Returns `addr` with its IP as an IPv6 address, mapping IPv4 into IPv6 if need be.
+fn to_ipv6(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) => SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), addr.port()),
+        IpAddr::V6(_) => addr,
+    }
+}
+
+/// Returns the bytes of `ip` in network order.
+fn octets(ip: IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads a header off the start of `input`, and returns what it said along with whatever was
+    /// left on the stream after it.
+    async fn read(input: &[u8]) -> (Result<Option<Addresses>, Error>, Vec<u8>) {
+        let mut stream = input;
+        let result = read_header(&mut stream).await;
+        (result, stream.to_vec())
+    }
+
+    fn v2_header(command: u8, family: u8, payload: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend_from_slice(&[command, family]);
+        header.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        header.extend_from_slice(payload);
+        header
+    }
+
+    fn addresses(source: &str, destination: &str) -> Addresses {
+        Addresses {
+            source: source.parse().unwrap(),
+            destination: destination.parse().unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn v1_reads_addresses_and_leaves_the_rest() {
+        let (result, rest) = read(b"PROXY TCP4 203.0.113.7 10.0.0.1 4711 80\r\nGET").await;
+        let expected = addresses("203.0.113.7:4711", "10.0.0.1:80");
+        assert_eq!(result.unwrap(), Some(expected));
+        assert_eq!(rest, b"GET");
+    }
+
+    #[tokio::test]
+    async fn v1_rejects_header_over_limit() {
+        let mut input = b"PROXY TCP4 ".to_vec();
+        input.resize(V1_MAX_LEN + 10, b'1');
+        input.extend_from_slice(b"\r\n");
+        let (result, _) = read(&input).await;
+        assert!(matches!(result, Err(Error::Invalid("header too long"))));
+    }
+
+    #[tokio::test]
+    async fn v1_unknown_names_no_client() {
+        let (result, rest) = read(b"PROXY UNKNOWN\r\nGET").await;
+        assert_eq!(result.unwrap(), None);
+        assert_eq!(rest, b"GET");
+        // Whatever follows UNKNOWN is ignored
+        let (result, _) = read(b"PROXY UNKNOWN ::1 ::1 1 2\r\n").await;
+        assert_eq!(result.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn v1_rejects_address_family_mismatch() {
+        let (result, _) = read(b"PROXY TCP4 2001:db8::1 10.0.0.1 4711 80\r\n").await;
+        assert!(matches!(
+            result,
+            Err(Error::Invalid("address doesn't match the protocol"))
+        ));
+        let (result, _) = read(b"PROXY TCP6 203.0.113.7 ::1 4711 80\r\n").await;
+        assert!(matches!(
+            result,
+            Err(Error::Invalid("address doesn't match the protocol"))
+        ));
+    }
+
+    #[tokio::test]
+    async fn v2_rejects_truncated_addresses() {
+        // The length covers the bytes sent, but they're too few for two IPv4 addresses and ports
+        let (result, _) = read(&v2_header(V2_PROXY, V2_TCP4, &[203, 0, 113, 7])).await;
+        assert!(matches!(result, Err(Error::Invalid("addresses cut short"))));
+        // The length promises more than the stream holds
+        let mut input = v2_header(V2_PROXY, V2_TCP6, &[0; 36]);
+        input.truncate(input.len() - 10);
+        let (result, _) = read(&input).await;
+        assert!(matches!(result, Err(Error::Io(_))));
+    }
+
+    #[tokio::test]
+    async fn v2_local_skips_its_payload() {
+        let payload = [203, 0, 113, 7, 10, 0, 0, 1, 0, 1, 0, 2];
+        let mut input = v2_header(V2_LOCAL, V2_TCP4, &payload);
+        input.extend_from_slice(b"GET");
+        let (result, rest) = read(&input).await;
+        assert_eq!(result.unwrap(), None);
+        assert_eq!(rest, b"GET");
+    }
+
+    #[tokio::test]
+    async fn v2_rejects_unsupported_command() {
+        let (result, _) = read(&v2_header(0x22, V2_TCP4, &[0; 12])).await;
+        assert!(matches!(
+            result,
+            Err(Error::Invalid("unsupported version or command"))
+        ));
+    }
+
+    #[tokio::test]
+    async fn encode_round_trips() {
+        let same = addresses("203.0.113.7:4711", "10.0.0.1:80");
+        let mixed = addresses("203.0.113.7:4711", "[2001:db8::1]:443");
+        // Mixed families come back with the IPv4 address mapped into IPv6
+        let mapped = addresses("[::ffff:203.0.113.7]:4711", "[2001:db8::1]:443");
+        for version in [Version::V1, Version::V2].iter() {
+            let (result, _) = read(&encode(*version, Some(&same))).await;
+            assert_eq!(result.unwrap(), Some(same));
+            let (result, _) = read(&encode(*version, Some(&mixed))).await;
+            assert_eq!(result.unwrap(), Some(mapped));
+            let (result, rest) = read(&encode(*version, None)).await;
+            assert_eq!(result.unwrap(), None);
+            assert!(rest.is_empty());
+        }
+    }
+}
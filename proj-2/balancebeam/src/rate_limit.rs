@@ -0,0 +1,296 @@
+use crate::config;
+use parking_lot::Mutex;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// Length of the window that `max_requests_per_minute` applies to.
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// The algorithm used to decide whether a client has exceeded its request budget.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Algorithm {
+    /// Sliding-window counter: the count from the previous one-minute window is weighted by how
+    /// much of it still overlaps the last minute and added to the count for the current window.
+    /// Unlike a fixed window, this doesn't let a client send twice the limit across a boundary.
+    #[default]
+    SlidingWindow,
+    /// Token bucket: each client has a bucket of `burst` tokens that refills at
+    /// `max_requests_per_minute` tokens per minute, and every request takes one token.
+    TokenBucket,
+}
+
+impl FromStr for Algorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sliding-window" => Ok(Algorithm::SlidingWindow),
+            "token-bucket" => Ok(Algorithm::TokenBucket),
+            _ => Err(format!(
+                "unknown rate limiting algorithm \"{}\" (expected sliding-window or token-bucket)",
+                s
+            )),
+        }
+    }
+}
+
+/// Rate limiting bookkeeping for a single client IP.
+enum ClientState {
+    SlidingWindow {
+        window_start: Instant,
+        previous_count: usize,
+        current_count: usize,
+    },
+    TokenBucket {
+        tokens: f64,
+        last_refill: Instant,
+    },
+}
+
+impl ClientState {
+    fn new(settings: &config::RateLimit, now: Instant) -> ClientState {
+        match settings.algorithm {
+            Algorithm::SlidingWindow => ClientState::SlidingWindow {
+                window_start: now,
+                previous_count: 0,
+                current_count: 0,
+            },
+            Algorithm::TokenBucket => ClientState::TokenBucket {
+                tokens: bucket_capacity(settings),
+                last_refill: now,
+            },
+        }
+    }
+
+    fn algorithm(&self) -> Algorithm {
+        match self {
+            ClientState::SlidingWindow { .. } => Algorithm::SlidingWindow,
+            ClientState::TokenBucket { .. } => Algorithm::TokenBucket,
+        }
+    }
+
+    /// Brings the state up to date with the current time: rolls the sliding window forward, or
+    /// adds the tokens that have accumulated since the last refill.
+    fn advance(&mut self, settings: &config::RateLimit, now: Instant) {
+        match self {
+            ClientState::SlidingWindow {
+                window_start,
+                previous_count,
+                current_count,
+            } => {
+                let elapsed = now.duration_since(*window_start);
+                if elapsed >= WINDOW {
+                    let windows_passed = (elapsed.as_nanos() / WINDOW.as_nanos()) as u32;
+                    *previous_count = if windows_passed == 1 { *current_count } else { 0 };
+                    *current_count = 0;
+                    *window_start += WINDOW * windows_passed;
+                }
+            }
+            ClientState::TokenBucket {
+                tokens,
+                last_refill,
+            } => {
+                let refill_rate =
+                    settings.max_requests_per_minute as f64 / WINDOW.as_secs_f64();
+                let elapsed = now.duration_since(*last_refill).as_secs_f64();
+                *tokens = (*tokens + elapsed * refill_rate).min(bucket_capacity(settings));
+                *last_refill = now;
+            }
+        }
+    }
+
+    /// Tries to spend one request from the client's budget. Returns false if the budget is used up.
+    fn try_acquire(&mut self, settings: &config::RateLimit, now: Instant) -> bool {
+        match self {
+            ClientState::SlidingWindow {
+                window_start,
+                previous_count,
+                current_count,
+            } => {
+                let overlap = 1.0 - now.duration_since(*window_start).as_secs_f64()
+                    / WINDOW.as_secs_f64();
+                let estimate = *previous_count as f64 * overlap + *current_count as f64;
+                if estimate + 1.0 > settings.max_requests_per_minute as f64 {
+                    return false;
+                }
+                *current_count += 1;
+                true
+            }
+            ClientState::TokenBucket { tokens, .. } => {
+                if *tokens < 1.0 {
+                    return false;
+                }
+                *tokens -= 1.0;
+                true
+            }
+        }
+    }
+
+    /// Returns true if forgetting this client would not change any future decision. The state
+    /// must have been brought up to date with `advance` first.
+    fn is_idle(&self, settings: &config::RateLimit) -> bool {
+        match self {
+            ClientState::SlidingWindow {
+                previous_count,
+                current_count,
+                ..
+            } => *previous_count == 0 && *current_count == 0,
+            ClientState::TokenBucket { tokens, .. } => *tokens >= bucket_capacity(settings),
+        }
+    }
+}
+
+/// Maximum number of tokens a client can save up, i.e. the largest burst of requests it may send
+/// at once. A burst of 0 means the bucket holds one minute's worth of requests.
+fn bucket_capacity(settings: &config::RateLimit) -> f64 {
+    if settings.burst > 0 {
+        settings.burst as f64
+    } else {
+        settings.max_requests_per_minute as f64
+    }
+}
+
+/// Tracks how many requests each client IP has made and decides whether more are allowed.
+pub struct RateLimiter {
+    clients: Mutex<HashMap<String, ClientState>>,
+}
+
+impl RateLimiter {
+    pub fn new() -> RateLimiter {
+        RateLimiter {
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records a request from `client_ip` and returns whether it should be let through.
+    pub fn check(&self, client_ip: &str, settings: &config::RateLimit) -> bool {
+        if settings.max_requests_per_minute == 0 {
+            return true;
+        }
+        let now = Instant::now();
+        let mut clients = self.clients.lock();
+        let state = clients
+            .entry(client_ip.to_string())
+            .or_insert_with(|| ClientState::new(settings, now));
+        // Start over if the algorithm was switched by a configuration reload
+        if state.algorithm() != settings.algorithm {
+            *state = ClientState::new(settings, now);
+        }
+        state.advance(settings, now);
+        state.try_acquire(settings, now)
+    }
+
+    /// Forgets clients that haven't sent anything recently, so the map doesn't grow forever.
+    pub fn prune(&self, settings: &config::RateLimit) {
+        let now = Instant::now();
+        self.clients.lock().retain(|_, state| {
+            state.advance(settings, now);
+            state.algorithm() == settings.algorithm && !state.is_idle(settings)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sliding_window_settings() -> config::RateLimit {
+        config::RateLimit {
+            max_requests_per_minute: 10,
+            ..Default::default()
+        }
+    }
+
+    fn token_bucket_settings() -> config::RateLimit {
+        config::RateLimit {
+            max_requests_per_minute: 60,
+            algorithm: Algorithm::TokenBucket,
+            burst: 5,
+        }
+    }
+
+    /// Sends `requests` requests from one client at `now`, the way `RateLimiter::check` does, and
+    /// returns how many of them were let through.
+    fn send(
+        state: &mut ClientState,
+        settings: &config::RateLimit,
+        now: Instant,
+        requests: usize,
+    ) -> usize {
+        (0..requests)
+            .filter(|_| {
+                state.advance(settings, now);
+                state.try_acquire(settings, now)
+            })
+            .count()
+    }
+
+    #[test]
+    fn sliding_window_weights_previous_window() {
+        let settings = sliding_window_settings();
+        let start = Instant::now();
+        let mut state = ClientState::new(&settings, start);
+        assert_eq!(send(&mut state, &settings, start, 11), 10);
+        // Just after the rollover, the previous window still counts almost in full, so a client
+        // can't send another full minute's worth right away
+        let rolled_over = start + WINDOW + Duration::from_millis(100);
+        assert_eq!(send(&mut state, &settings, rolled_over, 1), 0);
+        // Halfway through the next window, half of the previous window's requests still count
+        let halfway = start + WINDOW + WINDOW / 2;
+        assert_eq!(send(&mut state, &settings, halfway, 10), 5);
+        // Two windows on, nothing from the first one counts any more
+        let later = start + WINDOW * 3;
+        assert_eq!(send(&mut state, &settings, later, 11), 10);
+    }
+
+    #[test]
+    fn token_bucket_allows_burst_then_refills_at_rate() {
+        let settings = token_bucket_settings();
+        let start = Instant::now();
+        let mut state = ClientState::new(&settings, start);
+        assert_eq!(send(&mut state, &settings, start, 6), 5);
+        // 60 requests per minute is one token a second
+        let almost = start + Duration::from_millis(999);
+        assert_eq!(send(&mut state, &settings, almost, 1), 0);
+        let one_second = start + Duration::from_secs(1);
+        assert_eq!(send(&mut state, &settings, one_second, 2), 1);
+        let four_seconds = start + Duration::from_secs(4);
+        assert_eq!(send(&mut state, &settings, four_seconds, 4), 3);
+        // Tokens don't pile up beyond the burst size
+        let much_later = start + Duration::from_secs(600);
+        assert_eq!(send(&mut state, &settings, much_later, 10), 5);
+    }
+
+    /// Moves every client's sliding window back in time, as if `by` had passed since it started.
+    fn rewind(limiter: &RateLimiter, by: Duration) {
+        for state in limiter.clients.lock().values_mut() {
+            if let ClientState::SlidingWindow { window_start, .. } = state {
+                *window_start -= by;
+            }
+        }
+    }
+
+    #[test]
+    fn prune_keeps_recent_sliding_window_clients() {
+        let settings = sliding_window_settings();
+        let limiter = RateLimiter::new();
+        assert!(limiter.check("10.0.0.1", &settings));
+        rewind(&limiter, WINDOW);
+        limiter.prune(&settings);
+        // The request still counts towards the previous window
+        assert_eq!(limiter.clients.lock().len(), 1);
+    }
+
+    #[test]
+    fn prune_forgets_sliding_window_clients_after_two_windows() {
+        let settings = sliding_window_settings();
+        let limiter = RateLimiter::new();
+        assert!(limiter.check("10.0.0.1", &settings));
+        rewind(&limiter, WINDOW * 2);
+        limiter.prune(&settings);
+        assert!(limiter.clients.lock().is_empty());
+    }
+}
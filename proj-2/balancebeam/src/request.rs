@@ -1,11 +1,15 @@
-use std::cmp::min;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use crate::body;
+use crate::chunked;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Poll};
+use tokio::io::{AsyncBufRead, AsyncWrite, AsyncWriteExt};
 
 const MAX_HEADERS_SIZE: usize = 8000;
-const MAX_BODY_SIZE: usize = 10000000;
 const MAX_NUM_HEADERS: usize = 32;
 
+// The payloads are only read through the Debug impl when errors are logged
+#[allow(dead_code, clippy::enum_variant_names)]
 #[derive(Debug)]
 pub enum Error {
     /// Client hung up before sending a complete request. IncompleteRequest contains the number of
@@ -15,69 +19,79 @@ pub enum Error {
     MalformedRequest(httparse::Error),
     /// The Content-Length header is present, but does not contain a valid numeric value
     InvalidContentLength,
-    /// The Content-Length header does not match the size of the request body that was sent
-    ContentLengthMismatch,
-    /// The request body is bigger than MAX_BODY_SIZE
-    RequestBodyTooLarge,
+    /// The request has both Transfer-Encoding and Content-Length headers, or Content-Length headers
+    /// with different values (either of which could be used to smuggle requests)
+    ConflictingBodyLength,
+    /// The request has a Transfer-Encoding header whose last coding isn't chunked, so there is no
+    /// telling where its body ends
+    InvalidTransferEncoding,
     /// Encountered an I/O error when reading/writing a TcpStream
     ConnectionError(std::io::Error),
 }
 
 /// Extracts the Content-Length header value from the provided request. Returns Ok(Some(usize)) if
 /// the Content-Length is present and valid, Ok(None) if Content-Length is not present, or
-/// Err(Error) if Content-Length is present but invalid.
-///
-/// You won't need to touch this function.
+/// Err(Error) if Content-Length is present but invalid. A request may repeat the header, but only
+/// with the same value each time; otherwise the upstream might pick a different one than we do.
 fn get_content_length(request: &http::Request<Vec<u8>>) -> Result<Option<usize>, Error> {
-    // Look for content-length header
-    if let Some(header_value) = request.headers().get("content-length") {
-        // If it exists, parse it as a usize (or return InvalidContentLength if it can't be parsed as such)
-        Ok(Some(
-            header_value
-                .to_str()
-                .or(Err(Error::InvalidContentLength))?
-                .parse::<usize>()
-                .or(Err(Error::InvalidContentLength))?,
-        ))
-    } else {
-        // If it doesn't exist, return None
-        Ok(None)
+    let mut content_length = None;
+    for header_value in request.headers().get_all("content-length") {
+        // Parse each value as a usize (or return InvalidContentLength if it can't be parsed as such)
+        let value = header_value
+            .to_str()
+            .or(Err(Error::InvalidContentLength))?
+            .parse::<usize>()
+            .or(Err(Error::InvalidContentLength))?;
+        if content_length.is_some_and(|content_length| content_length != value) {
+            return Err(Error::ConflictingBodyLength);
+        }
+        content_length = Some(value);
     }
+    Ok(content_length)
 }
 
 /// This function appends to a header value (adding a new header if the header is not already
 /// present). This is used to add the client's IP address to the end of the X-Forwarded-For list,
-/// or to add a new X-Forwarded-For header if one is not already present.
-///
-/// You won't need to touch this function.
+/// or to add a new X-Forwarded-For header if one is not already present. If the header appears
+/// more than once, the values are joined into one list, in order.
 pub fn extend_header_value(
     request: &mut http::Request<Vec<u8>>,
     name: &'static str,
     extend_value: &str,
 ) {
-    let new_value = match request.headers().get(name) {
-        Some(existing_value) => {
-            [existing_value.as_bytes(), b", ", extend_value.as_bytes()].concat()
-        }
-        None => extend_value.as_bytes().to_owned(),
-    };
+    let mut new_value = Vec::new();
+    for existing_value in request.headers().get_all(name) {
+        new_value.extend_from_slice(existing_value.as_bytes());
+        new_value.extend_from_slice(b", ");
+    }
+    new_value.extend_from_slice(extend_value.as_bytes());
     request
         .headers_mut()
         .insert(name, http::HeaderValue::from_bytes(&new_value).unwrap());
 }
 
+/// Removes the Expect header from the request, and returns true if it asked for 100-continue, i.e.
+/// the client is waiting to be told to go ahead before it sends the body. The upstream never sees
+/// the header: the body is read from the client before the upstream has a chance to answer, so
+/// balancebeam tells the client to go ahead itself.
+pub fn take_expect_continue(request: &mut http::Request<Vec<u8>>) -> bool {
+    request
+        .headers_mut()
+        .remove("expect")
+        .is_some_and(|value| value.as_bytes().eq_ignore_ascii_case(b"100-continue"))
+}
+
 /// Attempts to parse the data in the supplied buffer as an HTTP request. Returns one of the
 /// following:
 ///
 /// * If there is a complete and valid request in the buffer, returns Ok(Some(http::Request))
 /// * If there is an incomplete but valid-so-far request in the buffer, returns Ok(None)
 /// * If there is data in the buffer that is definitely not a valid HTTP request, returns Err(Error)
-///
-/// You won't need to touch this function.
+#[allow(clippy::type_complexity)]
 fn parse_request(buffer: &[u8]) -> Result<Option<(http::Request<Vec<u8>>, usize)>, Error> {
     let mut headers = [httparse::EMPTY_HEADER; MAX_NUM_HEADERS];
     let mut req = httparse::Request::new(&mut headers);
-    let res = req.parse(buffer).or_else(|err| Err(Error::MalformedRequest(err)))?;
+    let res = req.parse(buffer).map_err(Error::MalformedRequest)?;
 
     if let httparse::Status::Complete(len) = res {
         let mut request = http::Request::builder()
@@ -94,123 +108,112 @@ fn parse_request(buffer: &[u8]) -> Result<Option<(http::Request<Vec<u8>>, usize)
     }
 }
 
+/// Copies whatever `stream` has buffered (reading more into its buffer if it has nothing) onto the
+/// end of `buffer`, up to MAX_HEADERS_SIZE bytes in all, without taking it out of the stream.
+/// Returns the number of bytes copied, which is 0 if the stream has ended or `buffer` is full.
+async fn peek<S: AsyncBufRead + Unpin>(stream: &mut S, buffer: &mut Vec<u8>) -> io::Result<usize> {
+    std::future::poll_fn(|cx| {
+        let available = ready!(Pin::new(&mut *stream).poll_fill_buf(cx))?;
+        let len = std::cmp::min(available.len(), MAX_HEADERS_SIZE - buffer.len());
+        buffer.extend_from_slice(&available[..len]);
+        Poll::Ready(Ok(len))
+    })
+    .await
+}
+
 /// Reads an HTTP request from the provided stream, waiting until a complete set of headers is sent.
-/// This function only reads the request line and headers; the read_body function can subsequently
-/// be called in order to read the request body (for a POST request).
+/// This function only reads the request line and headers: whatever follows them (the body, or on a
+/// keep-alive connection, even the next request) is left in the stream's buffer.
 ///
 /// Returns Ok(http::Request) if a valid request is received, or Error if not.
-///
-/// You will need to modify this function in Milestone 2.
-async fn read_headers(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>, Error> {
+async fn read_headers<S: AsyncBufRead + Unpin>(
+    stream: &mut S,
+) -> Result<http::Request<Vec<u8>>, Error> {
     // Try reading the headers from the request. We may not receive all the headers in one shot
     // (e.g. we might receive the first few bytes of a request, and then the rest follows later).
     // Try parsing repeatedly until we read a valid HTTP request
-    let mut request_buffer = [0_u8; MAX_HEADERS_SIZE];
-    let mut bytes_read = 0;
+    let mut request_buffer = Vec::new();
     loop {
-        // Read bytes from the connection into the buffer, starting at position bytes_read
-        let new_bytes = stream
-            .read(&mut request_buffer[bytes_read..]).await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+        // Look at the next bytes from the connection, appending them to the buffer
+        let bytes_read = request_buffer.len();
+        let new_bytes = peek(stream, &mut request_buffer)
+            .await
+            .map_err(Error::ConnectionError)?;
         if new_bytes == 0 {
             // We didn't manage to read a complete request
             return Err(Error::IncompleteRequest(bytes_read));
         }
-        bytes_read += new_bytes;
 
-        // See if we've read a valid request so far
-        if let Some((mut request, headers_len)) = parse_request(&request_buffer[..bytes_read])? {
-            // We've read a complete set of headers. However, if this was a POST request, a request
-            // body might have been included as well, and we might have read part of the body out of
-            // the stream into header_buffer. We need to add those bytes to the Request body so that
-            // we don't lose them
-            request
-                .body_mut()
-                .extend_from_slice(&request_buffer[headers_len..bytes_read]);
+        // See if we've read a valid request so far. Only the bytes that belong to the headers are
+        // taken out of the stream
+        if let Some((request, headers_len)) = parse_request(&request_buffer)? {
+            Pin::new(&mut *stream).consume(headers_len - bytes_read);
             return Ok(request);
         }
+        Pin::new(&mut *stream).consume(new_bytes);
     }
 }
 
-/// This function reads the body for a request from the stream. The client only sends a body if the
-/// Content-Length header is present; this function reads that number of bytes from the stream. It
-/// returns Ok(()) if successful, or Err(Error) if Content-Length bytes couldn't be read.
-///
-/// You will need to modify this function in Milestone 2.
-async fn read_body(
-    stream: &mut TcpStream,
-    request: &mut http::Request<Vec<u8>>,
-    content_length: usize,
-) -> Result<(), Error> {
-    // Keep reading data until we read the full body length, or until we hit an error.
-    while request.body().len() < content_length {
-        // Read up to 512 bytes at a time. (If the client only sent a small body, then only allocate
-        // space to read that body.)
-        let mut buffer = vec![0_u8; min(512, content_length)];
-        let bytes_read = stream.read(&mut buffer).await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+/// This function reads an HTTP request's line and headers from a stream, returning an Error if the
+/// client closes the connection prematurely or sends an invalid request. The body is not read; it
+/// is left in the stream, to be streamed onwards with body::copy using the returned body length.
+pub async fn read_from_stream<S: AsyncBufRead + Unpin>(
+    stream: &mut S,
+) -> Result<(http::Request<Vec<u8>>, body::Length), Error> {
+    let request = read_headers(stream).await?;
+    let length = body_length(&request)?;
+    Ok((request, length))
+}
 
-        // Make sure the client is still sending us bytes
-        if bytes_read == 0 {
-            log::debug!(
-                "Client hung up after sending a body of length {}, even though it said the content \
-                length is {}",
-                request.body().len(),
-                content_length
-            );
-            return Err(Error::ContentLengthMismatch);
+/// Works out how the end of the request body will be found. A request only has a body if the
+/// client supplied the Transfer-Encoding: chunked or Content-Length header (which it does for POST
+/// requests). Requests that the upstream might frame differently than we do are refused (RFC 9112
+/// section 6.3): a Transfer-Encoding that doesn't end in chunked, or one alongside Content-Length,
+/// and Content-Length headers that disagree.
+fn body_length(request: &http::Request<Vec<u8>>) -> Result<body::Length, Error> {
+    if request.headers().contains_key("transfer-encoding") {
+        if request.headers().contains_key("content-length") {
+            return Err(Error::ConflictingBodyLength);
         }
-
-        // Make sure the client didn't send us *too many* bytes
-        if request.body().len() + bytes_read > content_length {
-            log::debug!(
-                "Client sent more bytes than we expected based on the given content length!"
-            );
-            return Err(Error::ContentLengthMismatch);
+        if !chunked::is_chunked(request.headers()) {
+            return Err(Error::InvalidTransferEncoding);
         }
-
-        // Store the received bytes in the request body
-        request.body_mut().extend_from_slice(&buffer[..bytes_read]);
+        Ok(body::Length::Chunked)
+    } else if let Some(content_length) = get_content_length(request)? {
+        Ok(body::Length::ContentLength(content_length))
+    } else {
+        Ok(body::Length::Empty)
     }
-    Ok(())
 }
 
-/// This function reads and returns an HTTP request from a stream, returning an Error if the client
-/// closes the connection prematurely or sends an invalid request.
-///
-/// You will need to modify this function in Milestone 2.
-pub async fn read_from_stream(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>, Error> {
-    // Read headers
-    let mut request = read_headers(stream).await?;
-    // Read body if the client supplied the Content-Length header (which it does for POST requests)
-    if let Some(content_length) = get_content_length(&request)? {
-        if content_length > MAX_BODY_SIZE {
-            return Err(Error::RequestBodyTooLarge);
-        } else {
-            read_body(stream, &mut request, content_length).await?;
-        }
+/// This function writes the request line and headers to the provided stream. The body has to be
+/// sent separately (e.g. with body::copy).
+pub async fn write_headers<S: AsyncWrite + Unpin>(
+    request: &http::Request<Vec<u8>>,
+    stream: &mut S,
+) -> Result<(), std::io::Error> {
+    stream.write_all(&format_request_line(request).into_bytes()).await?;
+    stream.write_all(b"\r\n").await?; // \r\n
+    for (header_name, header_value) in request.headers() {
+        stream.write_all(format!("{}: ", header_name).as_bytes()).await?;
+        stream.write_all(header_value.as_bytes()).await?;
+        stream.write_all(b"\r\n").await?; // \r\n
     }
-    Ok(request)
+    stream.write_all(b"\r\n").await?;
+    Ok(())
 }
 
-/// This function serializes a request to bytes and writes those bytes to the provided stream.
-///
-/// You will need to modify this function in Milestone 2.
-pub async fn write_to_stream(
+/// This function serializes a request that is held entirely in memory to bytes and writes those
+/// bytes to the provided stream.
+pub async fn write_to_stream<S: AsyncWrite + Unpin>(
     request: &http::Request<Vec<u8>>,
-    stream: &mut TcpStream,
+    stream: &mut S,
 ) -> Result<(), std::io::Error> {
-    stream.write(&format_request_line(request).into_bytes()).await?;
-    stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
-    for (header_name, header_value) in request.headers() {
-        stream.write(&format!("{}: ", header_name).as_bytes()).await?;
-        stream.write(header_value.as_bytes()).await?;
-        stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
-    }
-    stream.write(&['\r' as u8, '\n' as u8]).await?;
-    if request.body().len() > 0 {
-        stream.write(request.body()).await?;
+    write_headers(request, stream).await?;
+    if !request.body().is_empty() {
+        stream.write_all(request.body()).await?;
     }
+    stream.flush().await?;
     Ok(())
 }
 
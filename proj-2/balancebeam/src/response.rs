@@ -1,46 +1,44 @@
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use crate::body;
+use crate::chunked;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 const MAX_HEADERS_SIZE: usize = 8000;
-const MAX_BODY_SIZE: usize = 10000000;
 const MAX_NUM_HEADERS: usize = 32;
 
+// The payloads are only read through the Debug impl when errors are logged
+#[allow(dead_code, clippy::enum_variant_names)]
 #[derive(Debug)]
 pub enum Error {
     /// Client hung up before sending a complete request
     IncompleteResponse,
     /// Client sent an invalid HTTP request. httparse::Error contains more details
     MalformedResponse(httparse::Error),
-    /// The Content-Length header is present, but does not contain a valid numeric value
+    /// The Content-Length header is present, but does not contain a valid numeric value, or is
+    /// repeated with different values
     InvalidContentLength,
-    /// The Content-Length header does not match the size of the request body that was sent
-    ContentLengthMismatch,
-    /// The request body is bigger than MAX_BODY_SIZE
-    ResponseBodyTooLarge,
     /// Encountered an I/O error when reading/writing a TcpStream
     ConnectionError(std::io::Error),
 }
 
 /// Extracts the Content-Length header value from the provided response. Returns Ok(Some(usize)) if
 /// the Content-Length is present and valid, Ok(None) if Content-Length is not present, or
-/// Err(Error) if Content-Length is present but invalid.
-///
-/// You won't need to touch this function.
+/// Err(Error) if Content-Length is present but invalid. A response may repeat the header, but only
+/// with the same value each time; otherwise the client might pick a different one than we do.
 fn get_content_length(response: &http::Response<Vec<u8>>) -> Result<Option<usize>, Error> {
-    // Look for content-length header
-    if let Some(header_value) = response.headers().get("content-length") {
-        // If it exists, parse it as a usize (or return InvalidResponseFormat if it can't be parsed as such)
-        Ok(Some(
-            header_value
-                .to_str()
-                .or(Err(Error::InvalidContentLength))?
-                .parse::<usize>()
-                .or(Err(Error::InvalidContentLength))?,
-        ))
-    } else {
-        // If it doesn't exist, return None
-        Ok(None)
+    let mut content_length = None;
+    for header_value in response.headers().get_all("content-length") {
+        // Parse each value as a usize (or return InvalidContentLength if it can't be parsed as such)
+        let value = header_value
+            .to_str()
+            .or(Err(Error::InvalidContentLength))?
+            .parse::<usize>()
+            .or(Err(Error::InvalidContentLength))?;
+        if content_length.is_some_and(|content_length| content_length != value) {
+            return Err(Error::InvalidContentLength);
+        }
+        content_length = Some(value);
     }
+    Ok(content_length)
 }
 
 /// Attempts to parse the data in the supplied buffer as an HTTP response. Returns one of the
@@ -50,14 +48,13 @@ fn get_content_length(response: &http::Response<Vec<u8>>) -> Result<Option<usize
 /// * If there is an incomplete but valid-so-far response in the buffer, returns Ok(None)
 /// * If there is data in the buffer that is definitely not a valid HTTP response, returns
 ///   Err(Error)
-///
-/// You won't need to touch this function.
+#[allow(clippy::type_complexity)]
 fn parse_response(buffer: &[u8]) -> Result<Option<(http::Response<Vec<u8>>, usize)>, Error> {
     let mut headers = [httparse::EMPTY_HEADER; MAX_NUM_HEADERS];
     let mut resp = httparse::Response::new(&mut headers);
     let res = resp
         .parse(buffer)
-        .or_else(|err| Err(Error::MalformedResponse(err)))?;
+        .map_err(Error::MalformedResponse)?;
 
     if let httparse::Status::Complete(len) = res {
         let mut response = http::Response::builder()
@@ -74,29 +71,22 @@ fn parse_response(buffer: &[u8]) -> Result<Option<(http::Response<Vec<u8>>, usiz
 }
 
 /// Reads an HTTP response from the provided stream, waiting until a complete set of headers is
-/// sent. This function only reads the response line and headers; the read_body function can
-/// subsequently be called in order to read the response body.
+/// sent. `already_read` holds bytes of the response that were read off the stream earlier (e.g.
+/// along with a preceding 1xx response). This function only reads the response line and headers;
+/// any bytes of the body that were read along with them are stored as the response body.
 ///
 /// Returns Ok(http::Response) if a valid response is received, or Error if not.
-///
-/// You will need to modify this function in Milestone 2.
-async fn read_headers(stream: &mut TcpStream) -> Result<http::Response<Vec<u8>>, Error> {
+async fn read_headers<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    already_read: &[u8],
+) -> Result<http::Response<Vec<u8>>, Error> {
     // Try reading the headers from the response. We may not receive all the headers in one shot
     // (e.g. we might receive the first few bytes of a response, and then the rest follows later).
     // Try parsing repeatedly until we read a valid HTTP response
     let mut response_buffer = [0_u8; MAX_HEADERS_SIZE];
-    let mut bytes_read = 0;
+    let mut bytes_read = already_read.len();
+    response_buffer[..bytes_read].copy_from_slice(already_read);
     loop {
-        // Read bytes from the connection into the buffer, starting at position bytes_read
-        let new_bytes = stream
-            .read(&mut response_buffer[bytes_read..]).await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
-        if new_bytes == 0 {
-            // We didn't manage to read a complete response
-            return Err(Error::IncompleteResponse);
-        }
-        bytes_read += new_bytes;
-
         // See if we've read a valid response so far
         if let Some((mut response, headers_len)) = parse_response(&response_buffer[..bytes_read])? {
             // We've read a complete set of headers. We may have also read the first part of the
@@ -107,92 +97,104 @@ async fn read_headers(stream: &mut TcpStream) -> Result<http::Response<Vec<u8>>,
                 .extend_from_slice(&response_buffer[headers_len..bytes_read]);
             return Ok(response);
         }
+
+        // Read bytes from the connection into the buffer, starting at position bytes_read
+        let new_bytes = stream
+            .read(&mut response_buffer[bytes_read..]).await
+            .map_err(Error::ConnectionError)?;
+        if new_bytes == 0 {
+            // We didn't manage to read a complete response
+            return Err(Error::IncompleteResponse);
+        }
+        bytes_read += new_bytes;
     }
 }
 
-/// This function reads the body for a response from the stream. If the Content-Length header is
-/// present, it reads that many bytes; otherwise, it reads bytes until the connection is closed.
+/// This function reads an HTTP response's status line and headers from a stream, returning an
+/// Error if the server closes the connection prematurely or sends an invalid response. The body is
+/// not read; the returned response's body only holds whatever part of it arrived together with the
+/// headers, and the rest can be streamed onwards with body::copy, using the returned body length.
 ///
-/// You will need to modify this function in Milestone 2.
-async fn read_body(stream: &mut TcpStream, response: &mut http::Response<Vec<u8>>) -> Result<(), Error> {
-    // The response may or may not supply a Content-Length header. If it provides the header, then
-    // we want to read that number of bytes; if it does not, we want to keep reading bytes until
-    // the connection is closed.
-    let content_length = get_content_length(response)?;
-
-    while content_length.is_none() || response.body().len() < content_length.unwrap() {
-        let mut buffer = [0_u8; 512];
-        let bytes_read = stream
-            .read(&mut buffer).await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
-        if bytes_read == 0 {
-            // The server has hung up!
-            if content_length.is_none() {
-                // We've reached the end of the response
-                break;
-            } else {
-                // Content-Length was set, but the server hung up before we managed to read that
-                // number of bytes
-                return Err(Error::ContentLengthMismatch);
-            }
-        }
-
-        // Make sure the server doesn't send more bytes than it promised to send
-        if content_length.is_some() && response.body().len() + bytes_read > content_length.unwrap()
-        {
-            return Err(Error::ContentLengthMismatch);
-        }
-
-        // Make sure server doesn't send more bytes than we allow
-        if response.body().len() + bytes_read > MAX_BODY_SIZE {
-            return Err(Error::ResponseBodyTooLarge);
-        }
-
-        // Append received bytes to the response body
-        response.body_mut().extend_from_slice(&buffer[..bytes_read]);
+/// Interim 1xx responses (such as 100 Continue) are skipped: the request body has already been
+/// sent by the time we read the response, so they tell the client nothing. 101 Switching Protocols
+/// is returned, since it is the final response before the connection changes protocols.
+pub async fn read_from_stream<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    request_method: &http::Method,
+) -> Result<(http::Response<Vec<u8>>, body::Length), Error> {
+    let mut response = read_headers(stream, &[]).await?;
+    while response.status().is_informational()
+        && response.status() != http::StatusCode::SWITCHING_PROTOCOLS
+    {
+        log::debug!("Skipping interim response {}", format_response_line(&response));
+        // Whatever followed the interim response is the start of the next one
+        let already_read = std::mem::take(response.body_mut());
+        response = read_headers(stream, &already_read).await?;
     }
-    Ok(())
+    if response.headers().contains_key("transfer-encoding") {
+        // Transfer-Encoding overrides Content-Length; drop the latter so we don't forward a
+        // message with both
+        response.headers_mut().remove("content-length");
+    }
+    let length = body_length(&response, request_method)?;
+    Ok((response, length))
 }
 
-/// This function reads and returns an HTTP response from a stream, returning an Error if the server
-/// closes the connection prematurely or sends an invalid response.
-///
-/// You will need to modify this function in Milestone 2.
-pub async fn read_from_stream(
-    stream: &mut TcpStream,
+/// Works out how the end of the body of a response to a `request_method` request will be found.
+/// A Transfer-Encoding that doesn't end in chunked means the body ends when the server closes the
+/// connection, whatever Content-Length says (RFC 9112 section 6.3).
+fn body_length(
+    response: &http::Response<Vec<u8>>,
     request_method: &http::Method,
-) -> Result<http::Response<Vec<u8>>, Error> {
-    let mut response = read_headers(stream).await?;
+) -> Result<body::Length, Error> {
     // A response may have a body as long as it is not responding to a HEAD request and as long as
     // the response status code is not 1xx, 204 (no content), or 304 (not modified).
-    if !(request_method == http::Method::HEAD
+    if request_method == http::Method::HEAD
         || response.status().as_u16() < 200
         || response.status() == http::StatusCode::NO_CONTENT
-        || response.status() == http::StatusCode::NOT_MODIFIED)
+        || response.status() == http::StatusCode::NOT_MODIFIED
     {
-        read_body(stream, &mut response).await?;
+        Ok(body::Length::Empty)
+    } else if chunked::is_chunked(response.headers()) {
+        Ok(body::Length::Chunked)
+    } else if response.headers().contains_key("transfer-encoding") {
+        Ok(body::Length::UntilClose)
+    } else if let Some(content_length) = get_content_length(response)? {
+        Ok(body::Length::ContentLength(content_length))
+    } else {
+        // Without either header, the body ends when the server closes the connection
+        Ok(body::Length::UntilClose)
     }
-    Ok(response)
 }
 
-/// This function serializes a response to bytes and writes those bytes to the provided stream.
-///
-/// You will need to modify this function in Milestone 2.
-pub async fn write_to_stream(
+/// This function writes the status line and headers to the provided stream. The body has to be
+/// sent separately (e.g. with body::copy).
+pub async fn write_headers<S: AsyncWrite + Unpin>(
     response: &http::Response<Vec<u8>>,
-    stream: &mut TcpStream,
+    stream: &mut S,
 ) -> Result<(), std::io::Error> {
-    stream.write(&format_response_line(response).into_bytes()).await?;
-    stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+    stream.write_all(&format_response_line(response).into_bytes()).await?;
+    stream.write_all(b"\r\n").await?; // \r\n
     for (header_name, header_value) in response.headers() {
-        stream.write(&format!("{}: ", header_name).as_bytes()).await?;
-        stream.write(header_value.as_bytes()).await?;
-        stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+        stream.write_all(format!("{}: ", header_name).as_bytes()).await?;
+        stream.write_all(header_value.as_bytes()).await?;
+        stream.write_all(b"\r\n").await?; // \r\n
     }
-    stream.write(&['\r' as u8, '\n' as u8]).await?;
-    if response.body().len() > 0 {
-        stream.write(response.body()).await?;
+    stream.write_all(b"\r\n").await?;
+    Ok(())
+}
+
+/// This function serializes a response that is held entirely in memory (such as one made by
+/// make_http_error) to bytes and writes those bytes to the provided stream.
+pub async fn write_to_stream<S: AsyncWrite + Unpin>(
+    response: &http::Response<Vec<u8>>,
+    stream: &mut S,
+) -> Result<(), std::io::Error> {
+    write_headers(response, stream).await?;
+    if !response.body().is_empty() {
+        stream.write_all(response.body()).await?;
     }
+    stream.flush().await?;
     Ok(())
 }
 
@@ -0,0 +1,117 @@
+use crate::config;
+use parking_lot::Mutex;
+use rand::Rng;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Length of the window that the retry budget is measured over.
+const WINDOW: Duration = Duration::from_secs(10);
+
+/// Width of the buckets that requests and retries are counted in, so the window slides forward a
+/// bucket at a time instead of remembering every request.
+const BUCKET_WIDTH: Duration = Duration::from_secs(1);
+
+/// Largest request body that is held on to so the request can be retried. Requests with larger
+/// bodies are streamed to the upstream as usual, and aren't retried.
+pub const MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// Methods that can be retried without being marked as such in the settings, since sending them
+/// twice has the same effect as sending them once.
+const IDEMPOTENT_METHODS: [http::Method; 4] = [
+    http::Method::GET,
+    http::Method::HEAD,
+    http::Method::PUT,
+    http::Method::DELETE,
+];
+
+/// Returns true if a request with this method may be sent again after it failed.
+pub fn is_idempotent(method: &http::Method, settings: &config::Retry) -> bool {
+    IDEMPOTENT_METHODS.contains(method)
+        || settings
+            .methods
+            .iter()
+            .any(|m| m.eq_ignore_ascii_case(method.as_str()))
+}
+
+/// Returns how long to wait before retry number `retry` (counting from 1): a random time up to
+/// the base back-off, doubled for every retry before it, and capped at the maximum back-off.
+/// Spreading retries out this way keeps clients from hammering the upstreams in lockstep.
+pub fn backoff(retry: u32, settings: &config::Retry) -> Duration {
+    let ceiling = settings
+        .backoff_base
+        .saturating_mul(1_u64.checked_shl(retry - 1).unwrap_or(u64::MAX))
+        .min(settings.backoff_max);
+    if ceiling == 0 {
+        return Duration::from_millis(0);
+    }
+    Duration::from_millis(rand::thread_rng().gen_range(0, ceiling + 1))
+}
+
+/// Number of requests and retries that were sent within one bucket.
+struct Bucket {
+    start: Instant,
+    requests: usize,
+    retries: usize,
+}
+
+/// Caps retries at a fraction of the requests sent over the last few seconds (plus a small
+/// allowance for when there is little traffic), so that when the upstreams are struggling,
+/// retries can't pile even more load onto them.
+pub struct Budget {
+    /// Counts over the window, oldest first
+    buckets: Mutex<VecDeque<Bucket>>,
+}
+
+impl Budget {
+    pub fn new() -> Budget {
+        Budget {
+            buckets: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Records a request being sent for the first time.
+    pub fn record_request(&self) {
+        let mut buckets = self.buckets.lock();
+        current_bucket(&mut buckets, Instant::now()).requests += 1;
+    }
+
+    /// Returns true (and counts the retry) if the budget allows for another retry.
+    pub fn try_retry(&self, settings: &config::Retry) -> bool {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock();
+        prune(&mut buckets, now);
+        let requests: usize = buckets.iter().map(|bucket| bucket.requests).sum();
+        let retries: usize = buckets.iter().map(|bucket| bucket.retries).sum();
+        let allowance = settings.budget_min_per_second as f64 * WINDOW.as_secs_f64()
+            + settings.budget_ratio * requests as f64;
+        if retries as f64 >= allowance {
+            return false;
+        }
+        current_bucket(&mut buckets, now).retries += 1;
+        true
+    }
+}
+
+/// Drops the buckets that have slid out of the window.
+fn prune(buckets: &mut VecDeque<Bucket>, now: Instant) {
+    while buckets
+        .front()
+        .is_some_and(|bucket| now.duration_since(bucket.start) >= WINDOW)
+    {
+        buckets.pop_front();
+    }
+}
+
+/// Returns the bucket that `now` falls in, starting a new one if needed.
+fn current_bucket(buckets: &mut VecDeque<Bucket>, now: Instant) -> &mut Bucket {
+    prune(buckets, now);
+    match buckets.back() {
+        Some(bucket) if now.duration_since(bucket.start) < BUCKET_WIDTH => {}
+        _ => buckets.push_back(Bucket {
+            start: now,
+            requests: 0,
+            retries: 0,
+        }),
+    }
+    buckets.back_mut().unwrap()
+}
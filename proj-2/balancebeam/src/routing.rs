@@ -0,0 +1,101 @@
+use crate::config;
+use std::convert::TryFrom;
+
+/// Returns the first route that `request` matches, if any.
+pub fn find_route<'a>(
+    routes: &'a [config::Route],
+    request: &http::Request<Vec<u8>>,
+) -> Option<&'a config::Route> {
+    routes.iter().find(|route| matches(route, request))
+}
+
+fn matches(route: &config::Route, request: &http::Request<Vec<u8>>) -> bool {
+    let path = request.uri().path();
+    if let Some(host) = &route.host {
+        if !request_host(request).is_some_and(|request_host| host_matches(host, &request_host)) {
+            return false;
+        }
+    }
+    if let Some(prefix) = &route.path_prefix {
+        if !path.starts_with(prefix.as_str()) {
+            return false;
+        }
+    }
+    if let Some(regex) = &route.path_regex {
+        if !regex.is_match(path) {
+            return false;
+        }
+    }
+    if !route.methods.is_empty()
+        && !route
+            .methods
+            .iter()
+            .any(|method| method.eq_ignore_ascii_case(request.method().as_str()))
+    {
+        return false;
+    }
+    route.headers.iter().all(|(name, value)| {
+        request
+            .headers()
+            .get_all(name.as_str())
+            .iter()
+            .any(|actual| actual.as_bytes() == value.as_bytes())
+    })
+}
+
+/// Returns the host the request was sent to, lowercased and without the port. This comes from the
+/// request target if it is in absolute form, and from the Host header otherwise.
+fn request_host(request: &http::Request<Vec<u8>>) -> Option<String> {
+    let host = match request.uri().host() {
+        Some(host) => host,
+        None => {
+            let header = request.headers().get("host")?.to_str().ok()?;
+            match header.rsplit_once(':') {
+                // Leave the colons in bracketed IPv6 addresses alone
+                Some((host, port)) if !port.contains(']') => host,
+                _ => header,
+            }
+        }
+    };
+    Some(host.to_ascii_lowercase())
+}
+
+/// Returns true if `host` matches `pattern`, which may start with "*." to match any single label.
+pub fn host_matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host
+            .split_once('.')
+            .is_some_and(|(_, rest)| rest.eq_ignore_ascii_case(domain)),
+        None => pattern.eq_ignore_ascii_case(host),
+    }
+}
+
+/// Applies the route's path rewrite (if it has one) to `request`, keeping the query string.
+/// Returns an error if the rewritten path isn't a valid request target.
+pub fn rewrite_path(
+    route: &config::Route,
+    request: &mut http::Request<Vec<u8>>,
+) -> Result<(), http::Error> {
+    let replacement = match &route.rewrite {
+        Some(replacement) => replacement,
+        None => return Ok(()),
+    };
+    let path = request.uri().path();
+    let mut new_path = if let Some(prefix) = &route.path_prefix {
+        format!("{}{}", replacement, &path[prefix.len()..])
+    } else if let Some(regex) = &route.path_regex {
+        regex.replace(path, replacement.as_str()).into_owned()
+    } else {
+        replacement.clone()
+    };
+    if !new_path.starts_with('/') {
+        new_path.insert(0, '/');
+    }
+    if let Some(query) = request.uri().query() {
+        new_path = format!("{}?{}", new_path, query);
+    }
+    let mut parts = request.uri().clone().into_parts();
+    parts.path_and_query = Some(http::uri::PathAndQuery::try_from(new_path.as_str())?);
+    *request.uri_mut() = http::Uri::from_parts(parts)?;
+    Ok(())
+}
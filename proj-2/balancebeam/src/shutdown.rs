@@ -0,0 +1,88 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{watch, Notify};
+
+/// Coordinates a graceful shutdown: once it starts, listeners stop accepting, idle client
+/// connections are closed, and connections that are in the middle of a request are closed as soon
+/// as the request has been answered.
+pub struct Shutdown {
+    started: watch::Sender<bool>,
+    started_receiver: watch::Receiver<bool>,
+    /// Number of client connections that are still open
+    connections: AtomicUsize,
+    /// Notified whenever the last open connection closes
+    drained: Notify,
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        let (started, started_receiver) = watch::channel(false);
+        Shutdown {
+            started,
+            started_receiver,
+            connections: AtomicUsize::new(0),
+            drained: Notify::new(),
+        }
+    }
+
+    /// Starts shutting down.
+    pub fn start(&self) {
+        let _ = self.started.broadcast(true);
+    }
+
+    /// Returns true once shutdown has started.
+    pub fn is_started(&self) -> bool {
+        *self.started_receiver.borrow()
+    }
+
+    /// Waits until shutdown starts.
+    pub async fn started(&self) {
+        let mut receiver = self.started_receiver.clone();
+        while let Some(started) = receiver.recv().await {
+            if started {
+                return;
+            }
+        }
+    }
+
+    /// Counts a client connection as open until the returned guard is dropped.
+    pub fn track_connection(self: &Arc<Self>) -> ConnectionGuard {
+        self.connections.fetch_add(1, Ordering::SeqCst);
+        ConnectionGuard {
+            shutdown: self.clone(),
+        }
+    }
+
+    /// Returns the number of client connections that are still open.
+    pub fn open_connections(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+
+    /// Waits up to `timeout` for every client connection to close. Returns true if they all did.
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        let deadline = tokio::time::Instant::now() + timeout;
+        while self.open_connections() > 0 {
+            if tokio::time::timeout_at(deadline, self.drained.notified())
+                .await
+                .is_err()
+            {
+                return self.open_connections() == 0;
+            }
+        }
+        true
+    }
+}
+
+/// Keeps a client connection counted as open for as long as it is alive.
+pub struct ConnectionGuard {
+    shutdown: Arc<Shutdown>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        if self.shutdown.connections.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.shutdown.drained.notify();
+        }
+    }
+}
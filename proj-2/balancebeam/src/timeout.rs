@@ -0,0 +1,121 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncRead};
+use tokio::time::Delay;
+
+/// Turns a timeout setting (in seconds, 0 = no limit) into a time limit.
+pub fn from_secs(seconds: u64) -> Option<Duration> {
+    if seconds == 0 {
+        None
+    } else {
+        Some(Duration::from_secs(seconds))
+    }
+}
+
+/// The error that I/O which took too long fails with.
+pub fn timed_out() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "timed out")
+}
+
+/// Returns true if `error` came from I/O that took too long.
+pub fn is_timeout(error: &io::Error) -> bool {
+    error.kind() == io::ErrorKind::TimedOut
+}
+
+/// Runs `future` to completion, or returns None if it takes longer than `limit`.
+pub async fn limit<F: Future>(limit: Option<Duration>, future: F) -> Option<F::Output> {
+    match limit {
+        Some(limit) => tokio::time::timeout(limit, future).await.ok(),
+        None => Some(future.await),
+    }
+}
+
+/// Like `limit`, but for I/O: running out of time becomes a TimedOut error.
+pub async fn io<T, F>(limit: Option<Duration>, future: F) -> io::Result<T>
+where
+    F: Future<Output = io::Result<T>>,
+{
+    self::limit(limit, future)
+        .await
+        .unwrap_or_else(|| Err(timed_out()))
+}
+
+/// Waits until `limit` has passed, or forever if there is no limit.
+pub async fn expired(limit: Option<Duration>) {
+    match limit {
+        Some(limit) => tokio::time::delay_for(limit).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Wraps a stream so that reading from it fails with a TimedOut error if nothing arrives for
+/// `limit`. Unlike a limit on a whole body, this lets large bodies take as long as they need, as
+/// long as they keep moving.
+pub struct ReadTimeout<'a, S> {
+    stream: &'a mut S,
+    limit: Option<Duration>,
+    /// Started when a read finds nothing to read, and dropped once something arrives
+    delay: Option<Delay>,
+}
+
+impl<'a, S> ReadTimeout<'a, S> {
+    pub fn new(stream: &'a mut S, limit: Option<Duration>) -> ReadTimeout<'a, S> {
+        ReadTimeout {
+            stream,
+            limit,
+            delay: None,
+        }
+    }
+}
+
+/// Starts `delay` if it isn't running yet, and returns whether it has run out. Called when a
+/// `ReadTimeout` stream has nothing to read.
+fn poll_delay(delay: &mut Option<Delay>, limit: Option<Duration>, cx: &mut Context<'_>) -> bool {
+    let limit = match limit {
+        Some(limit) => limit,
+        None => return false,
+    };
+    let delay = delay.get_or_insert_with(|| tokio::time::delay_for(limit));
+    Pin::new(delay).poll(cx).is_ready()
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ReadTimeout<'_, S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if let Poll::Ready(result) = Pin::new(&mut *this.stream).poll_read(cx, buf) {
+            this.delay = None;
+            return Poll::Ready(result);
+        }
+        if poll_delay(&mut this.delay, this.limit, cx) {
+            return Poll::Ready(Err(timed_out()));
+        }
+        Poll::Pending
+    }
+}
+
+impl<S: AsyncBufRead + Unpin> AsyncBufRead for ReadTimeout<'_, S> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        match Pin::new(&mut *this.stream).poll_fill_buf(cx) {
+            Poll::Ready(result) => {
+                this.delay = None;
+                Poll::Ready(result)
+            }
+            Poll::Pending if poll_delay(&mut this.delay, this.limit, cx) => {
+                Poll::Ready(Err(timed_out()))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        Pin::new(&mut *self.get_mut().stream).consume(amt)
+    }
+}
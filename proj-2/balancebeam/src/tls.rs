@@ -0,0 +1,421 @@
+use crate::config;
+use crate::proxy_protocol;
+use parking_lot::RwLock;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::sign::{self, CertifiedKey};
+use tokio_rustls::rustls::{
+    Certificate, ClientConfig, ClientHello, NoClientAuth, PrivateKey, ResolvesServerCert,
+    RootCertStore, ServerCertVerified, ServerCertVerifier, ServerConfig, TLSError,
+    WebPKIVerifier,
+};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+#[derive(Debug)]
+pub enum Error {
+    /// A certificate or key file could not be read
+    Io(String, std::io::Error),
+    /// The certificate file doesn't contain any PEM-encoded certificates
+    NoCertificates(String),
+    /// The key file doesn't contain a PEM-encoded (PKCS#8 or RSA) private key
+    NoPrivateKey(String),
+    /// The private key is of a type rustls can't sign with
+    UnsupportedKey(String),
+    /// A hostname is not a valid DNS name
+    InvalidHostname(String),
+    /// The certificate is not valid for a hostname it is configured to be served for
+    HostnameMismatch(String, String),
+    /// An upstream was given a client certificate without a key, or the other way around
+    IncompleteClientCertificate(String),
+    /// An upstream is addressed by IP, so the name to verify its certificate against has to be
+    /// given explicitly
+    NoServerName(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(path, err) => write!(f, "could not read {}: {}", path, err),
+            Error::NoCertificates(path) => write!(f, "no certificates found in {}", path),
+            Error::NoPrivateKey(path) => write!(f, "no private key found in {}", path),
+            Error::UnsupportedKey(path) => write!(f, "unsupported private key type in {}", path),
+            Error::InvalidHostname(hostname) => write!(f, "invalid hostname {}", hostname),
+            Error::HostnameMismatch(path, hostname) => {
+                write!(f, "certificate {} is not valid for {}", path, hostname)
+            }
+            Error::IncompleteClientCertificate(upstream) => write!(
+                f,
+                "client certificate for upstream {} needs both a cert and a key",
+                upstream
+            ),
+            Error::NoServerName(upstream) => write!(
+                f,
+                "upstream {} is addressed by IP, so its TLS server_name must be set",
+                upstream
+            ),
+        }
+    }
+}
+
+/// The certificates currently being served.
+#[derive(Default)]
+struct Certificates {
+    /// Served to clients that don't send a server name, or send one that no certificate is
+    /// configured for
+    default: Option<CertifiedKey>,
+    /// Lowercase hostnames (possibly wildcards like "*.example.com") and their certificates
+    by_hostname: HashMap<String, CertifiedKey>,
+}
+
+/// Picks a certificate for each TLS handshake based on the server name the client asked for
+/// (SNI). The certificates can be swapped out while balancebeam is running; handshakes that
+/// start afterwards get the new ones, and connections that are already open are left alone.
+pub struct CertificateStore {
+    certificates: RwLock<Certificates>,
+}
+
+impl CertificateStore {
+    pub fn new() -> CertificateStore {
+        CertificateStore {
+            certificates: RwLock::new(Certificates::default()),
+        }
+    }
+
+    /// Loads `certificates` from disk and starts serving them. The first one is the default
+    /// certificate. If any of them can't be loaded, the current certificates are kept.
+    pub fn load(&self, certificates: &[config::Certificate]) -> Result<(), Error> {
+        let mut loaded = Certificates::default();
+        for certificate in certificates {
+            let certified_key = load_certified_key(certificate)?;
+            for hostname in &certificate.hostnames {
+                // webpki can't check wildcard names, so those are taken on trust
+                if !hostname.starts_with("*.") {
+                    let name = webpki::DNSNameRef::try_from_ascii_str(hostname)
+                        .map_err(|_| Error::InvalidHostname(hostname.clone()))?;
+                    certified_key
+                        .cross_check_end_entity_cert(Some(name))
+                        .map_err(|_| {
+                            Error::HostnameMismatch(certificate.cert.clone(), hostname.clone())
+                        })?;
+                }
+                loaded
+                    .by_hostname
+                    .insert(hostname.to_lowercase(), certified_key.clone());
+            }
+            if loaded.default.is_none() {
+                loaded.default = Some(certified_key);
+            }
+        }
+        *self.certificates.write() = loaded;
+        Ok(())
+    }
+
+    /// Returns an acceptor that performs TLS handshakes with the certificates in this store.
+    /// Clients can ask for HTTP/2 or HTTP/1.1 through ALPN.
+    pub fn acceptor(self: &Arc<Self>) -> TlsAcceptor {
+        let mut config = ServerConfig::new(NoClientAuth::new());
+        config.cert_resolver = self.clone();
+        config.set_protocols(&[b"h2".to_vec(), b"http/1.1".to_vec()]);
+        TlsAcceptor::from(Arc::new(config))
+    }
+}
+
+impl ResolvesServerCert for CertificateStore {
+    fn resolve(&self, client_hello: ClientHello) -> Option<CertifiedKey> {
+        let certificates = self.certificates.read();
+        let by_name = client_hello.server_name().and_then(|name| {
+            let name: &str = name.into();
+            let name = name.to_lowercase();
+            let wildcard = name
+                .split_once('.')
+                .map(|(_, domain)| format!("*.{}", domain));
+            certificates.by_hostname.get(&name).or_else(|| {
+                wildcard.and_then(|wildcard| certificates.by_hostname.get(&wildcard))
+            })
+        });
+        by_name.or(certificates.default.as_ref()).cloned()
+    }
+}
+
+/// Reads a certificate chain and its private key from the PEM files named in `certificate`.
+fn load_certified_key(certificate: &config::Certificate) -> Result<CertifiedKey, Error> {
+    let chain = load_chain(&certificate.cert)?;
+    let key = sign::any_supported_type(&load_key(&certificate.key)?)
+        .map_err(|_| Error::UnsupportedKey(certificate.key.clone()))?;
+    Ok(CertifiedKey::new(chain, Arc::new(key)))
+}
+
+fn open(path: &str) -> Result<BufReader<File>, Error> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|err| Error::Io(path.to_string(), err))
+}
+
+/// Reads the PEM-encoded certificates in `path`.
+fn load_chain(path: &str) -> Result<Vec<Certificate>, Error> {
+    pemfile::certs(&mut open(path)?)
+        .ok()
+        .filter(|chain| !chain.is_empty())
+        .ok_or_else(|| Error::NoCertificates(path.to_string()))
+}
+
+/// Reads the first PEM-encoded private key (PKCS#8 or RSA) in `path`.
+fn load_key(path: &str) -> Result<PrivateKey, Error> {
+    let mut keys = pemfile::pkcs8_private_keys(&mut open(path)?).unwrap_or_default();
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut open(path)?).unwrap_or_default();
+    }
+    keys.into_iter()
+        .next()
+        .ok_or_else(|| Error::NoPrivateKey(path.to_string()))
+}
+
+/// A connection to an upstream, over TLS if the upstream is configured for it.
+pub enum UpstreamStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl UpstreamStream {
+    /// Returns the TCP connection underneath.
+    pub fn tcp_mut(&mut self) -> &mut TcpStream {
+        match self {
+            UpstreamStream::Plain(stream) => stream,
+            UpstreamStream::Tls(stream) => stream.get_mut().0,
+        }
+    }
+}
+
+impl AsyncRead for UpstreamStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for UpstreamStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// How to set up TLS with one upstream.
+struct UpstreamTls {
+    connector: TlsConnector,
+    /// Name sent in the handshake (SNI) and checked against the upstream's certificate
+    server_name: webpki::DNSName,
+}
+
+/// Opens connections to upstreams, with TLS and PROXY protocol headers for the upstreams that are
+/// configured for them.
+pub struct UpstreamConnector {
+    /// TLS settings of each upstream that uses TLS
+    upstreams: RwLock<HashMap<String, Arc<UpstreamTls>>>,
+    /// PROXY protocol version of each upstream that is sent a PROXY protocol header
+    proxy_protocol: RwLock<HashMap<String, proxy_protocol::Version>>,
+}
+
+impl UpstreamConnector {
+    pub fn new() -> UpstreamConnector {
+        UpstreamConnector {
+            upstreams: RwLock::new(HashMap::new()),
+            proxy_protocol: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Loads the TLS and PROXY protocol settings of `upstreams`, replacing the current ones. If
+    /// the settings of any upstream can't be loaded, the current ones are kept.
+    pub fn load<'a>(
+        &self,
+        upstreams: impl IntoIterator<Item = &'a config::Upstream>,
+    ) -> Result<(), Error> {
+        let mut loaded = HashMap::new();
+        let mut proxy_protocol = HashMap::new();
+        for upstream in upstreams {
+            if let Some(settings) = &upstream.tls {
+                let tls = load_upstream_tls(&upstream.address, settings)?;
+                loaded.insert(upstream.address.clone(), Arc::new(tls));
+            }
+            if let Some(version) = upstream.proxy_protocol {
+                proxy_protocol.insert(upstream.address.clone(), version);
+            }
+        }
+        *self.upstreams.write() = loaded;
+        *self.proxy_protocol.write() = proxy_protocol;
+        Ok(())
+    }
+
+    /// Loads the TLS and PROXY protocol settings of a single upstream, replacing any it had
+    /// before.
+    pub fn add(&self, upstream: &config::Upstream) -> Result<(), Error> {
+        match upstream.proxy_protocol {
+            Some(version) => {
+                self.proxy_protocol
+                    .write()
+                    .insert(upstream.address.clone(), version);
+            }
+            None => {
+                self.proxy_protocol.write().remove(&upstream.address);
+            }
+        }
+        match &upstream.tls {
+            Some(settings) => {
+                let tls = load_upstream_tls(&upstream.address, settings)?;
+                self.upstreams
+                    .write()
+                    .insert(upstream.address.clone(), Arc::new(tls));
+            }
+            None => {
+                self.upstreams.write().remove(&upstream.address);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns true if connections to `upstream` start with a PROXY protocol header. Such a
+    /// connection belongs to the client it was opened for, and mustn't be reused for anyone else.
+    pub fn sends_proxy_protocol(&self, upstream: &str) -> bool {
+        self.proxy_protocol.read().contains_key(upstream)
+    }
+
+    /// Opens a new connection to `upstream`, doing the TLS handshake if it uses TLS. If the
+    /// upstream takes the PROXY protocol, the connection starts with a header naming `client`, or
+    /// saying the connection is balancebeam's own if None.
+    pub async fn connect(
+        &self,
+        upstream: &str,
+        client: Option<&proxy_protocol::Addresses>,
+    ) -> std::io::Result<UpstreamStream> {
+        let tls = self.upstreams.read().get(upstream).cloned();
+        let version = self.proxy_protocol.read().get(upstream).copied();
+        let mut stream = TcpStream::connect(upstream).await?;
+        if let Some(version) = version {
+            // The header goes before everything else, the TLS handshake included
+            stream
+                .write_all(&proxy_protocol::encode(version, client))
+                .await?;
+        }
+        match tls {
+            Some(tls) => {
+                let server_name = tls.server_name.as_ref();
+                let stream = tls.connector.connect(server_name, stream).await?;
+                Ok(UpstreamStream::Tls(Box::new(stream)))
+            }
+            None => Ok(UpstreamStream::Plain(stream)),
+        }
+    }
+}
+
+/// Builds the TLS client configuration for the upstream at `address`.
+fn load_upstream_tls(address: &str, settings: &config::UpstreamTls) -> Result<UpstreamTls, Error> {
+    let mut config = ClientConfig::new();
+    match &settings.ca {
+        Some(path) => {
+            let (added, _) = config
+                .root_store
+                .add_pem_file(&mut open(path)?)
+                .map_err(|_| Error::NoCertificates(path.clone()))?;
+            if added == 0 {
+                return Err(Error::NoCertificates(path.clone()));
+            }
+        }
+        None => config
+            .root_store
+            .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS),
+    }
+    match (&settings.cert, &settings.key) {
+        (Some(cert), Some(key)) => config
+            .set_single_client_cert(load_chain(cert)?, load_key(key)?)
+            .map_err(|_| Error::UnsupportedKey(key.clone()))?,
+        (None, None) => {}
+        _ => return Err(Error::IncompleteClientCertificate(address.to_string())),
+    }
+    if !settings.verify_hostname {
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(SkipHostnameVerification(WebPKIVerifier::new())));
+    }
+    // Requests are always forwarded as HTTP/1.1, so that is all the upstream may pick
+    config.set_protocols(&[b"http/1.1".to_vec()]);
+
+    let server_name = match &settings.server_name {
+        Some(server_name) => server_name.clone(),
+        None => {
+            let host = address.rsplit_once(':').map_or(address, |(host, _)| host);
+            let unbracketed = host.trim_start_matches('[').trim_end_matches(']');
+            if unbracketed.parse::<std::net::IpAddr>().is_ok() {
+                return Err(Error::NoServerName(address.to_string()));
+            }
+            host.to_string()
+        }
+    };
+    let server_name = webpki::DNSNameRef::try_from_ascii_str(&server_name)
+        .map_err(|_| Error::InvalidHostname(server_name.clone()))?
+        .to_owned();
+    Ok(UpstreamTls {
+        connector: TlsConnector::from(Arc::new(config)),
+        server_name,
+    })
+}
+
+/// Verifies an upstream's certificate chain like rustls normally does, but accepts certificates
+/// that aren't valid for the server name.
+struct SkipHostnameVerification(WebPKIVerifier);
+
+impl ServerCertVerifier for SkipHostnameVerification {
+    fn verify_server_cert(
+        &self,
+        roots: &RootCertStore,
+        presented_certs: &[Certificate],
+        dns_name: webpki::DNSNameRef,
+        ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        // The name is only checked once the chain has been verified, so this error means
+        // everything but the name checked out
+        match self
+            .0
+            .verify_server_cert(roots, presented_certs, dns_name, ocsp_response)
+        {
+            Err(TLSError::WebPKIError(webpki::Error::CertNotValidForName)) => {
+                Ok(ServerCertVerified::assertion())
+            }
+            result => result,
+        }
+    }
+}
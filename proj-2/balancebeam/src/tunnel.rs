@@ -0,0 +1,94 @@
+use crate::timeout;
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Size of the buffers used to move bytes between the client and the upstream.
+const BUFFER_SIZE: usize = 8192;
+
+/// Returns true if one of the Connection headers lists `token` (e.g. "upgrade").
+fn has_connection_token(headers: &http::HeaderMap, token: &str) -> bool {
+    headers.get_all("connection").iter().any(|value| {
+        value.to_str().is_ok_and(|value| {
+            value
+                .split(',')
+                .any(|item| item.trim().eq_ignore_ascii_case(token))
+        })
+    })
+}
+
+/// Returns true if `request` asks to switch the connection over to another protocol, as a
+/// WebSocket handshake or an h2c upgrade does.
+pub fn is_upgrade_request(request: &http::Request<Vec<u8>>) -> bool {
+    request.headers().contains_key("upgrade") && has_connection_token(request.headers(), "upgrade")
+}
+
+/// Returns true if the upstream agreed to switch protocols, so that from now on the connection
+/// carries whatever the new protocol is rather than HTTP requests.
+pub fn is_upgrade(request: &http::Request<Vec<u8>>, response: &http::Response<Vec<u8>>) -> bool {
+    response.status() == http::StatusCode::SWITCHING_PROTOCOLS && is_upgrade_request(request)
+}
+
+/// Copies bytes both ways between an upgraded client connection and its upstream until both sides
+/// have hung up. `already_read` holds anything the upstream sent right after its response
+/// headers, which already belongs to the new protocol. When one side finishes sending, the other
+/// is told so (by shutting down the writing half of its connection) and may keep sending. Fails
+/// with a TimedOut error if nothing moves in either direction for `idle`, or if a side stops
+/// accepting what is passed along to it for that long.
+pub async fn relay<C, U>(
+    client: &mut C,
+    upstream: &mut U,
+    already_read: &[u8],
+    idle: Option<Duration>,
+) -> io::Result<()>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    U: AsyncRead + AsyncWrite + Unpin,
+{
+    if !already_read.is_empty() {
+        send(client, already_read, idle).await?;
+    }
+    let mut client_buffer = [0_u8; BUFFER_SIZE];
+    let mut upstream_buffer = [0_u8; BUFFER_SIZE];
+    let mut client_open = true;
+    let mut upstream_open = true;
+    while client_open || upstream_open {
+        // The idle timer starts over every time something is passed along
+        tokio::select! {
+            result = client.read(&mut client_buffer), if client_open => {
+                let bytes_read = result?;
+                if bytes_read == 0 {
+                    client_open = false;
+                    timeout::io(idle, upstream.shutdown()).await?;
+                } else {
+                    send(upstream, &client_buffer[..bytes_read], idle).await?;
+                }
+            }
+            result = upstream.read(&mut upstream_buffer), if upstream_open => {
+                let bytes_read = result?;
+                if bytes_read == 0 {
+                    upstream_open = false;
+                    timeout::io(idle, client.shutdown()).await?;
+                } else {
+                    send(client, &upstream_buffer[..bytes_read], idle).await?;
+                }
+            }
+            _ = timeout::expired(idle) => return Err(timeout::timed_out()),
+        }
+    }
+    Ok(())
+}
+
+/// Writes and flushes `bytes`, failing with a TimedOut error if that takes longer than `idle`, so
+/// that a peer which stops reading can't hold the tunnel open forever.
+async fn send<W: AsyncWrite + Unpin>(
+    dst: &mut W,
+    bytes: &[u8],
+    idle: Option<Duration>,
+) -> io::Result<()> {
+    timeout::io(idle, async {
+        dst.write_all(bytes).await?;
+        dst.flush().await
+    })
+    .await
+}
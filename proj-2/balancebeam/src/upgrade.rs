@@ -0,0 +1,121 @@
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use std::collections::HashMap;
+use std::io;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use tokio::net::TcpListener;
+use tokio::process::Child;
+
+/// Lists the listening sockets handed over by the previous process, as `bind=fd` pairs separated
+/// by semicolons
+const LISTEN_FDS_VAR: &str = "BALANCEBEAM_LISTEN_FDS";
+/// Process ID of the previous process, which is told to shut down once we are accepting
+const PARENT_PID_VAR: &str = "BALANCEBEAM_PARENT_PID";
+
+/// Listening sockets inherited from the process that started us for a zero-downtime upgrade. The
+/// sockets stay open across the exec, so connections queued on them are never refused.
+pub struct Inherited {
+    fds: HashMap<String, RawFd>,
+    parent: Option<Pid>,
+}
+
+impl Inherited {
+    /// Picks up the sockets listed in the environment (if any), and clears the environment so the
+    /// list isn't passed on to anything we start.
+    pub fn from_env() -> Inherited {
+        let fds = std::env::var(LISTEN_FDS_VAR)
+            .map(|list| {
+                list.split(';')
+                    .filter_map(|entry| {
+                        let (bind, fd) = entry.rsplit_once('=')?;
+                        Some((bind.to_string(), fd.parse().ok()?))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        let parent = std::env::var(PARENT_PID_VAR)
+            .ok()
+            .and_then(|pid| pid.parse().ok())
+            .map(Pid::from_raw);
+        std::env::remove_var(LISTEN_FDS_VAR);
+        std::env::remove_var(PARENT_PID_VAR);
+        Inherited { fds, parent }
+    }
+
+    /// Returns the socket inherited for `bind`, if there is one.
+    fn take(&mut self, bind: &str) -> io::Result<Option<std::net::TcpListener>> {
+        let fd = match self.fds.remove(bind) {
+            Some(fd) => fd,
+            None => return Ok(None),
+        };
+        // Safe as long as the previous process told the truth about what the fd is. It was left
+        // open across the exec on purpose; close it on the next one unless it is handed over again
+        let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+        fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)).map_err(io::Error::other)?;
+        listener.set_nonblocking(true)?;
+        Ok(Some(listener))
+    }
+
+    /// Closes the inherited sockets that the current configuration has no use for.
+    pub fn close_unused(&mut self) {
+        for (bind, fd) in self.fds.drain() {
+            log::info!("No longer listening on {}", bind);
+            drop(unsafe { std::net::TcpListener::from_raw_fd(fd) });
+        }
+    }
+
+    /// Tells the previous process (if any) that we have taken over, so it can stop accepting
+    /// connections and shut down once the ones it has are finished.
+    pub fn notify_parent(&self) {
+        if let Some(parent) = self.parent {
+            log::info!("Taking over from process {}", parent);
+            if let Err(err) = kill(parent, Signal::SIGTERM) {
+                log::error!("Failed to tell process {} to shut down: {}", parent, err);
+            }
+        }
+    }
+}
+
+/// Listens on `bind`, using the socket inherited from the previous process if there is one.
+pub async fn bind(bind: &str, inherited: &mut Inherited) -> io::Result<TcpListener> {
+    match inherited.take(bind)? {
+        Some(listener) => {
+            log::debug!("Using the listening socket for {} from the previous process", bind);
+            TcpListener::from_std(listener)
+        }
+        None => TcpListener::bind(bind).await,
+    }
+}
+
+/// Starts a fresh copy of the balancebeam executable with the same arguments, handing it the given
+/// listening sockets. The new process tells this one to shut down once it is accepting
+/// connections on them.
+pub fn spawn_successor(listeners: &[(String, RawFd)]) -> io::Result<Child> {
+    let mut args = std::env::args_os();
+    let program = args
+        .next()
+        .ok_or_else(|| io::Error::other("can't tell how balancebeam was started"))?;
+    let list: Vec<String> = listeners
+        .iter()
+        .map(|(bind, fd)| format!("{}={}", bind, fd))
+        .collect();
+    let fds: Vec<RawFd> = listeners.iter().map(|(_, fd)| *fd).collect();
+    let mut cmd = std::process::Command::new(program);
+    cmd.args(args)
+        .env(LISTEN_FDS_VAR, list.join(";"))
+        .env(PARENT_PID_VAR, std::process::id().to_string());
+    // Runs in the forked child, so it must not allocate or take locks
+    unsafe {
+        cmd.pre_exec(move || {
+            for fd in &fds {
+                if fcntl(*fd, FcntlArg::F_SETFD(FdFlag::empty())).is_err() {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+    tokio::process::Command::from(cmd).spawn()
+}
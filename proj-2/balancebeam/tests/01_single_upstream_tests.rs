@@ -1,7 +1,12 @@
 mod common;
 
 use common::{init_logging, BalanceBeam, EchoServer, Server};
+use rand::Rng;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{delay_for, timeout};
 
 async fn setup() -> (BalanceBeam, EchoServer) {
     init_logging();
@@ -99,3 +104,281 @@ async fn test_multiple_requests_per_connection() {
 
     log::info!("All done :)");
 }
+
+/// Send several requests, each on its own client connection, and make sure balancebeam reuses a
+/// single pooled connection to the upstream for all of them.
+#[tokio::test]
+async fn test_upstream_connection_pooling() {
+    let n_requests = 5;
+    let (balancebeam, upstream) = setup().await;
+
+    for i in 0..n_requests {
+        let path = format!("/pooled-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    log::info!("Checking that the upstream only saw one connection");
+    assert_eq!(upstream.connections_received(), 1);
+    assert_eq!(Box::new(upstream).stop().await, n_requests);
+
+    log::info!("All done :)");
+}
+
+/// Make sure pooled connections are closed once they have been idle for longer than the idle
+/// timeout, so the next request opens a new one.
+#[tokio::test]
+async fn test_upstream_pool_idle_timeout() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--pool-idle-timeout", "1"]).await;
+
+    balancebeam
+        .get("/first")
+        .await
+        .expect("Error sending request to balancebeam");
+    log::info!("Waiting for the pooled connection to time out");
+    delay_for(Duration::from_millis(2500)).await;
+    balancebeam
+        .get("/second")
+        .await
+        .expect("Error sending request to balancebeam");
+
+    assert_eq!(upstream.connections_received(), 2);
+    assert_eq!(Box::new(upstream).stop().await, 2);
+
+    log::info!("All done :)");
+}
+
+/// Reads from the connection until the end of a set of headers (the requests and interim responses
+/// read with this have no body), and returns everything read
+async fn read_request(conn: &mut TcpStream) -> String {
+    let mut data = Vec::new();
+    while !String::from_utf8_lossy(&data).contains("\r\n\r\n") {
+        let mut buffer = [0_u8; 512];
+        let bytes_read = conn.read(&mut buffer).await.expect("Error reading request");
+        assert!(bytes_read > 0, "Connection closed before a full request arrived");
+        data.extend_from_slice(&buffer[..bytes_read]);
+    }
+    String::from_utf8_lossy(&data).to_string()
+}
+
+/// An upstream that sends 100 Continue ahead of its real response must not have the 100 taken as
+/// the final response: the client should get the real one, and the pooled connection must not hand
+/// it to the next request instead.
+#[tokio::test]
+async fn test_interim_response_skipped() {
+    init_logging();
+    let mut rng = rand::thread_rng();
+    let upstream_address = format!("127.0.0.1:{}", rng.gen_range(1024, 65535));
+    let mut listener = TcpListener::bind(&upstream_address)
+        .await
+        .expect("Failed to bind upstream");
+    let upstream_task = tokio::spawn(async move {
+        let (mut conn, _) = listener.accept().await.expect("Failed to accept connection");
+        read_request(&mut conn).await;
+        conn.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+            .await
+            .expect("Failed to send interim response");
+        delay_for(Duration::from_millis(200)).await;
+        conn.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nfirst")
+            .await
+            .expect("Failed to send response");
+        read_request(&mut conn).await;
+        conn.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 6\r\n\r\nsecond")
+            .await
+            .expect("Failed to send response");
+    });
+    let balancebeam = BalanceBeam::new(&[&upstream_address], None, None).await;
+
+    let response_text = balancebeam
+        .get("/first")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response_text, "first");
+    let response_text = balancebeam
+        .get("/second")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response_text, "second");
+
+    upstream_task.await.expect("Upstream task panicked");
+    log::info!("All done :)");
+}
+
+/// A request that isn't idempotent must not be sent again when the pooled connection it went out
+/// on is closed before the response arrives, even if it has no body: the upstream may already have
+/// acted on it.
+#[tokio::test]
+async fn test_non_idempotent_request_not_replayed() {
+    init_logging();
+    let mut rng = rand::thread_rng();
+    let upstream_address = format!("127.0.0.1:{}", rng.gen_range(1024, 65535));
+    let mut listener = TcpListener::bind(&upstream_address)
+        .await
+        .expect("Failed to bind upstream");
+    let upstream_task = tokio::spawn(async move {
+        let (mut conn, _) = listener.accept().await.expect("Failed to accept connection");
+        read_request(&mut conn).await;
+        conn.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nfirst")
+            .await
+            .expect("Failed to send response");
+        let request_text = read_request(&mut conn).await;
+        assert!(request_text.starts_with("POST /charge HTTP/1.1"));
+        // Hang up without answering, as if the upstream had acted on the request and then died
+        drop(conn);
+        tokio::time::timeout(Duration::from_secs(1), listener.accept())
+            .await
+            .is_ok()
+    });
+    let balancebeam = BalanceBeam::new(&[&upstream_address], None, None).await;
+
+    let response_text = balancebeam
+        .get("/first")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response_text, "first");
+    let response_text = balancebeam
+        .post("/charge", "")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("502"));
+
+    let replayed = upstream_task.await.expect("Upstream task panicked");
+    assert!(!replayed, "The POST was sent to the upstream again");
+    log::info!("All done :)");
+}
+
+/// A client that asks for 100-continue should be told to go ahead before it sends the body, and
+/// the request should make it to the upstream (without the Expect header) once it does.
+#[tokio::test]
+async fn test_expect_continue() {
+    let (balancebeam, upstream) = setup().await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Failed to connect to balancebeam");
+    conn.write_all(
+        b"POST /expect HTTP/1.1\r\n\
+        Host: balancebeam\r\n\
+        Expect: 100-continue\r\n\
+        Content-Length: 5\r\n\
+        \r\n",
+    )
+    .await
+    .expect("Failed to send request to balancebeam");
+    let interim = tokio::time::timeout(Duration::from_secs(3), read_request(&mut conn))
+        .await
+        .expect("Timed out waiting for 100 Continue");
+    assert!(interim.starts_with("HTTP/1.1 100"), "Got {:?} instead of 100 Continue", interim);
+
+    conn.write_all(b"hello")
+        .await
+        .expect("Failed to send body to balancebeam");
+    let mut response = Vec::new();
+    while !String::from_utf8_lossy(&response).ends_with("hello") {
+        let mut buffer = [0_u8; 512];
+        let bytes_read = tokio::time::timeout(Duration::from_secs(3), conn.read(&mut buffer))
+            .await
+            .expect("Timed out waiting for the response")
+            .expect("Error reading response");
+        assert!(bytes_read > 0, "Connection closed before the response arrived");
+        response.extend_from_slice(&buffer[..bytes_read]);
+    }
+    let response = String::from_utf8_lossy(&response).to_lowercase();
+    assert!(response.starts_with("http/1.1 200"));
+    assert!(response.contains("post /expect http/1.1"));
+    assert!(!response.contains("expect: 100-continue"));
+
+    drop(conn);
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// Bodies are streamed through balancebeam rather than buffered, so there is no limit on their
+/// size. Upload a body that is bigger than the old 10 MB cap and make sure it makes it to the
+/// upstream and back intact.
+#[tokio::test]
+async fn test_large_body() {
+    let (balancebeam, upstream) = setup().await;
+
+    let body: String = (0..20_000_000).map(|i| (b'a' + (i % 26) as u8) as char).collect();
+    let response_text = balancebeam
+        .post("/upload", &body)
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("POST /upload HTTP/1.1"));
+    assert!(response_text.contains("x-forwarded-for: 127.0.0.1"));
+    assert!(response_text.ends_with(&format!("\n\n{}", body)));
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// A keep-alive client may send its next requests without waiting for the answers. Whatever
+/// follows a request's body on the connection is the next request, and must be answered in turn
+/// rather than taken for a body that is too long.
+#[tokio::test]
+async fn test_pipelined_requests() {
+    let (balancebeam, upstream) = setup().await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Failed to connect to balancebeam");
+    conn.write_all(
+        b"POST /first HTTP/1.1\r\n\
+        Host: balancebeam\r\n\
+        Content-Length: 5\r\n\
+        \r\n\
+        Hello\
+        GET /second HTTP/1.1\r\n\
+        Host: balancebeam\r\n\
+        \r\n\
+        POST /third HTTP/1.1\r\n\
+        Host: balancebeam\r\n\
+        Transfer-Encoding: chunked\r\n\
+        \r\n\
+        5\r\n\
+        World\r\n\
+        0\r\n\
+        \r\n\
+        GET /fourth HTTP/1.1\r\n\
+        Host: balancebeam\r\n\
+        \r\n",
+    )
+    .await
+    .expect("Failed to send requests to balancebeam");
+
+    let mut data = Vec::new();
+    loop {
+        let text = String::from_utf8_lossy(&data);
+        if text.contains("GET /fourth HTTP/1.1") && text.ends_with("\n\n") {
+            break;
+        }
+        let mut buffer = [0_u8; 4096];
+        let bytes_read = timeout(Duration::from_secs(5), conn.read(&mut buffer))
+            .await
+            .expect("Timed out waiting for responses")
+            .expect("Error reading from balancebeam");
+        assert!(bytes_read > 0, "Connection closed after {:?}", text);
+        data.extend_from_slice(&buffer[..bytes_read]);
+    }
+    let text = String::from_utf8_lossy(&data);
+    assert_eq!(text.matches("HTTP/1.1 200 OK").count(), 4, "Unexpected responses: {}", text);
+    let first = text.find("POST /first HTTP/1.1").expect("No response to the first request");
+    let second = text.find("GET /second HTTP/1.1").expect("No response to the second request");
+    let third = text.find("POST /third HTTP/1.1").expect("No response to the third request");
+    let fourth = text.find("GET /fourth HTTP/1.1").expect("No response to the fourth request");
+    assert!(first < second && second < third && third < fourth);
+    assert!(text[first..second].contains("\n\nHello"));
+    assert!(text[third..fourth].contains("\n\nWorld"));
+
+    drop(conn);
+    drop(balancebeam);
+    assert_eq!(Box::new(upstream).stop().await, 4);
+    log::info!("All done :)");
+}
@@ -3,6 +3,8 @@ mod common;
 use common::{init_logging, BalanceBeam, EchoServer, ErrorServer, Server};
 
 use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
 use tokio::time::delay_for;
 
 async fn setup_with_params(
@@ -36,6 +38,70 @@ async fn setup(n_upstreams: usize) -> (BalanceBeam, Vec<Box<dyn Server>>) {
     setup_with_params(n_upstreams, None, None).await
 }
 
+/// Starts one upstream per entry in `weights` and a balancebeam instance using the given balancing
+/// strategy, passing each upstream as `host:port=weight`.
+async fn setup_with_strategy(
+    weights: &[usize],
+    strategy: &str,
+) -> (BalanceBeam, Vec<Box<dyn Server>>) {
+    setup_with_strategy_and_args(weights, strategy, &[]).await
+}
+
+/// Like setup_with_strategy, but passes extra command-line arguments to balancebeam
+async fn setup_with_strategy_and_args(
+    weights: &[usize],
+    strategy: &str,
+    extra_args: &[&str],
+) -> (BalanceBeam, Vec<Box<dyn Server>>) {
+    init_logging();
+    let mut upstreams: Vec<Box<dyn Server>> = Vec::new();
+    for _ in weights {
+        upstreams.push(Box::new(EchoServer::new().await));
+    }
+    let upstream_args: Vec<String> = upstreams
+        .iter()
+        .zip(weights)
+        .map(|(upstream, weight)| format!("{}={}", upstream.address(), weight))
+        .collect();
+    let upstream_args: Vec<&str> = upstream_args.iter().map(|arg| arg.as_str()).collect();
+    // Keep active health checks out of the way, since they would show up in the request counts
+    let mut args = vec![
+        "--balancing-strategy",
+        strategy,
+        "--active-health-check-interval",
+        "3600",
+    ];
+    args.extend_from_slice(extra_args);
+    let balancebeam = BalanceBeam::new_with_args(&upstream_args, &args).await;
+    (balancebeam, upstreams)
+}
+
+/// Sends `n_requests` sequential requests to balancebeam, making sure each one is echoed back
+async fn send_requests(balancebeam: &BalanceBeam, n_requests: usize) {
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+}
+
+/// Stops all the upstreams, returning the number of requests each one received (in the order the
+/// upstreams were started)
+async fn stop_upstreams(mut upstreams: Vec<Box<dyn Server>>) -> Vec<usize> {
+    let mut request_counters = Vec::new();
+    while let Some(upstream) = upstreams.pop() {
+        request_counters.insert(0, upstream.stop().await);
+    }
+    log::info!(
+        "Number of requests received by each upstream: {:?}",
+        request_counters
+    );
+    request_counters
+}
+
 /// Send a bunch of requests to the load balancer, and ensure they are evenly distributed across the
 /// upstream servers
 #[tokio::test]
@@ -240,6 +306,134 @@ async fn test_active_health_checks_restore_failed_upstream() {
     log::info!("All done :)");
 }
 
+/// Make sure an upstream that accepts connections but never answers doesn't hold up active health
+/// checks (or clients) forever:
+///
+/// * Start one working upstream and one that never responds
+/// * Wait for the health checks to time out often enough to mark the silent upstream dead
+/// * Ensure requests keep being answered promptly, by the working upstream
+#[tokio::test]
+async fn test_active_health_checks_time_out() {
+    init_logging();
+    let working_upstream: Box<dyn Server> = Box::new(EchoServer::new().await);
+    let mut silent_upstream = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Could not bind silent upstream");
+    let silent_address = silent_upstream.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        // Accept connections and hold on to them without ever answering
+        let mut connections = Vec::new();
+        while let Ok((stream, _)) = silent_upstream.accept().await {
+            connections.push(stream);
+        }
+    });
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&working_upstream.address(), &silent_address],
+        &[
+            "--active-health-check-interval",
+            "1",
+            "--active-health-check-timeout",
+            "1",
+            "--active-health-check-unhealthy-threshold",
+            "2",
+        ],
+    )
+    .await;
+
+    log::info!("Waiting for health checks to give up on the silent upstream...");
+    delay_for(Duration::from_secs(5)).await;
+
+    for i in 0..10 {
+        let path = format!("/request-{}", i);
+        let response_text = tokio::time::timeout(Duration::from_secs(3), balancebeam.get(&path))
+            .await
+            .expect("balancebeam took too long to answer. Health checks may be stuck")
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    drop(balancebeam);
+    let working_req_count = working_upstream.stop().await;
+    assert!(working_req_count >= 10);
+    log::info!("All done :)");
+}
+
+/// Make sure passive health checks eject an upstream that accepts connections but fails requests,
+/// and let it back in through a trial request once the ejection is over:
+///
+/// * Start one working upstream and one that only returns HTTP error 500s
+/// * Send requests until the failing upstream gets ejected
+/// * Wait out the ejection. The failing upstream gets a single trial request, fails it, and is
+///   ejected again
+/// * Replace the failing upstream with a working one and wait out the (doubled) ejection
+/// * Ensure the restored upstream passes its trial and gets requests again
+#[tokio::test]
+async fn test_passive_health_checks_eject_failing_upstream() {
+    init_logging();
+    let mut upstreams: Vec<Box<dyn Server>> = vec![
+        Box::new(EchoServer::new().await),
+        Box::new(ErrorServer::new().await),
+    ];
+    let failing_ip = upstreams[1].address();
+    let upstream_addresses: Vec<String> =
+        upstreams.iter().map(|upstream| upstream.address()).collect();
+    let upstream_addresses: Vec<&str> =
+        upstream_addresses.iter().map(|addr| addr.as_str()).collect();
+    let balancebeam = BalanceBeam::new_with_args(
+        &upstream_addresses,
+        &[
+            "--balancing-strategy",
+            "round-robin",
+            "--active-health-check-interval",
+            "3600",
+            "--passive-health-check-consecutive-failures",
+            "2",
+            "--passive-health-check-base-ejection-time",
+            "2",
+        ],
+    )
+    .await;
+
+    log::info!("Sending requests until the failing upstream is ejected");
+    for i in 0..10 {
+        let _ = balancebeam.get(&format!("/request-{}", i)).await;
+    }
+
+    log::info!("Waiting for the ejection to run out...");
+    delay_for(Duration::from_secs(3)).await;
+    for i in 0..10 {
+        let _ = balancebeam.get(&format!("/after-ejection-{}", i)).await;
+    }
+    let failing_req_count = upstreams.pop().unwrap().stop().await;
+    assert_eq!(
+        failing_req_count, 3,
+        "The failing upstream should have gotten two requests before being ejected, and one \
+        trial request afterwards"
+    );
+
+    log::info!("Replacing the failing upstream with a working one...");
+    upstreams.push(Box::new(EchoServer::new_at_address(failing_ip).await));
+    delay_for(Duration::from_secs(5)).await;
+    for i in 0..10 {
+        let path = format!("/after-restore-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+    let restored_req_count = upstreams.pop().unwrap().stop().await;
+    assert!(
+        restored_req_count > 1,
+        "The restored upstream should have passed its trial request and gotten more requests"
+    );
+
+    while let Some(upstream) = upstreams.pop() {
+        upstream.stop().await;
+    }
+    log::info!("All done :)");
+}
+
 /// Enable rate limiting and ensure that requests fail after sending more than the threshold
 #[tokio::test]
 async fn test_rate_limiting() {
@@ -294,3 +488,205 @@ async fn test_rate_limiting() {
 
     log::info!("All done :)");
 }
+
+/// Round-robin should spread sequential requests exactly evenly across the upstreams
+#[tokio::test]
+async fn test_round_robin_distribution() {
+    let (balancebeam, upstreams) = setup_with_strategy(&[1, 1, 1], "round-robin").await;
+    send_requests(&balancebeam, 30).await;
+    assert_eq!(stop_upstreams(upstreams).await, vec![10, 10, 10]);
+
+    log::info!("All done :)");
+}
+
+/// With per-request balancing, requests sent over a single keep-alive connection should still be
+/// spread across all the upstreams
+#[tokio::test]
+async fn test_per_request_balancing() {
+    let n_requests = 30;
+    let (balancebeam, upstreams) =
+        setup_with_strategy_and_args(&[1, 1, 1], "round-robin", &["--per-request-balancing"])
+            .await;
+
+    let client = reqwest::Client::new();
+    for i in 0..n_requests {
+        let path = format!("/keep-alive-{}", i);
+        let response_text = client
+            .get(&format!("http://{}{}", balancebeam.address, path))
+            .send()
+            .await
+            .expect("Failed to connect to balancebeam")
+            .text()
+            .await
+            .expect("Balancebeam replied with a malformed response");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+    assert_eq!(stop_upstreams(upstreams).await, vec![10, 10, 10]);
+
+    log::info!("All done :)");
+}
+
+/// Weighted round-robin should send each upstream a share of the requests proportional to its
+/// weight
+#[tokio::test]
+async fn test_weighted_round_robin_distribution() {
+    let (balancebeam, upstreams) =
+        setup_with_strategy(&[1, 2, 3], "weighted-round-robin").await;
+    send_requests(&balancebeam, 60).await;
+    assert_eq!(stop_upstreams(upstreams).await, vec![10, 20, 30]);
+
+    log::info!("All done :)");
+}
+
+/// Least-connections should avoid an upstream that is still serving an open connection:
+///
+/// * Open a keep-alive connection and send one request on it, pinning it to one upstream
+/// * Open a second keep-alive connection while the first one is still open, and send more requests
+/// * Make sure all of those went to the other upstream
+#[tokio::test]
+async fn test_least_connections_distribution() {
+    let n_requests = 10;
+    let (balancebeam, upstreams) = setup_with_strategy(&[1, 1], "least-connections").await;
+
+    log::info!("Opening a long-lived connection to balancebeam");
+    let mut held_conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Failed to connect to balancebeam");
+    held_conn
+        .write_all(b"GET /held HTTP/1.1\r\nHost: balancebeam\r\n\r\n")
+        .await
+        .expect("Failed to send request to balancebeam");
+    let mut response = Vec::new();
+    while !String::from_utf8_lossy(&response).contains("GET /held HTTP/1.1") {
+        let mut buffer = [0_u8; 512];
+        let bytes_read = held_conn
+            .read(&mut buffer)
+            .await
+            .expect("Failed to read response from balancebeam");
+        assert!(bytes_read > 0, "balancebeam closed the connection unexpectedly");
+        response.extend_from_slice(&buffer[..bytes_read]);
+    }
+
+    log::info!("Sending requests on a second connection while the first one is still open");
+    let client = reqwest::Client::new();
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        let response_text = client
+            .get(&format!("http://{}{}", balancebeam.address, path))
+            .send()
+            .await
+            .expect("Failed to connect to balancebeam")
+            .text()
+            .await
+            .expect("Balancebeam replied with a malformed response");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+    drop(held_conn);
+
+    let mut request_counters = stop_upstreams(upstreams).await;
+    request_counters.sort_unstable();
+    assert_eq!(request_counters, vec![1, n_requests]);
+
+    log::info!("All done :)");
+}
+
+/// Enable token-bucket rate limiting and make sure bursts are capped and tokens refill over time.
+/// All requests go over a single keep-alive connection, so this also checks that the limit is
+/// applied per request rather than per connection.
+#[tokio::test]
+async fn test_token_bucket_rate_limiting() {
+    init_logging();
+    let burst = 3;
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--max-requests-per-minute",
+            "60",
+            "--rate-limit-algorithm",
+            "token-bucket",
+            "--rate-limit-burst",
+            &burst.to_string(),
+        ],
+    )
+    .await;
+    let client = reqwest::Client::new();
+    let send = |path: &'static str| {
+        client
+            .get(&format!("http://{}{}", balancebeam.address, path))
+            .header("x-sent-by", "balancebeam-tests")
+            .send()
+    };
+
+    log::info!("Sending a full burst of requests. These should succeed.");
+    for _ in 0..burst {
+        let response = send("/burst").await.expect("Error sending request to balancebeam");
+        assert_eq!(response.status().as_u16(), 200);
+    }
+
+    log::info!("Sending one more request. The bucket is empty, so this should get a 429.");
+    let response = send("/overboard").await.expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 429);
+
+    log::info!("Waiting for a token to refill (the rate is one per second)");
+    delay_for(Duration::from_millis(1200)).await;
+    let response = send("/refilled").await.expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+
+    assert_eq!(Box::new(upstream).stop().await, burst + 1);
+    log::info!("All done :)");
+}
+
+/// Make sure a health check timeout of 0 doesn't cut health checks short right away, and that an
+/// upstream that never answers still can't hold up health checks forever:
+///
+/// * Start one working upstream and one that never responds, with health checks every second and
+///   no timeout of their own
+/// * Wait for a few health checks. The silent upstream's probes give up once the next check is
+///   due, so it gets marked dead
+/// * Ensure requests keep being answered promptly, by the working upstream
+#[tokio::test]
+async fn test_active_health_checks_without_timeout() {
+    init_logging();
+    let upstream: Box<dyn Server> = Box::new(EchoServer::new().await);
+    let mut silent_upstream = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Could not bind silent upstream");
+    let silent_address = silent_upstream.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        // Accept connections and hold on to them without ever answering
+        let mut connections = Vec::new();
+        while let Ok((stream, _)) = silent_upstream.accept().await {
+            connections.push(stream);
+        }
+    });
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address(), &silent_address],
+        &[
+            "--active-health-check-interval",
+            "1",
+            "--active-health-check-timeout",
+            "0",
+            "--active-health-check-unhealthy-threshold",
+            "2",
+        ],
+    )
+    .await;
+
+    log::info!("Waiting for a few health checks...");
+    delay_for(Duration::from_secs(5)).await;
+
+    for i in 0..10 {
+        let path = format!("/after-health-checks-{}", i);
+        let response_text = tokio::time::timeout(Duration::from_secs(3), balancebeam.get(&path))
+            .await
+            .expect("balancebeam took too long to answer. Health checks may be stuck")
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    drop(balancebeam);
+    // The requests plus at least a few health checks
+    assert!(upstream.stop().await >= 12);
+    log::info!("All done :)");
+}
@@ -0,0 +1,156 @@
+mod common;
+
+use common::{init_logging, temp_config_path, BalanceBeam, EchoServer, Server};
+use nix::sys::signal::Signal;
+use rand::Rng;
+use std::time::Duration;
+use tokio::time::delay_for;
+
+fn write_toml_config(path: &std::path::Path, upstream: &str) {
+    std::fs::write(
+        path,
+        format!(
+            "[[upstreams]]\naddress = \"{}\"\n\n[health_check]\ninterval = 60\n",
+            upstream
+        ),
+    )
+    .expect("Failed to write config file");
+}
+
+/// Send a request on the given client and make sure it was echoed back
+async fn send_request(client: &reqwest::Client, balancebeam: &BalanceBeam, path: &str) {
+    let response_text = client
+        .get(&format!("http://{}{}", balancebeam.address, path))
+        .header("x-sent-by", "balancebeam-tests")
+        .send()
+        .await
+        .expect("Failed to connect to balancebeam")
+        .text()
+        .await
+        .expect("Balancebeam replied with a malformed response");
+    assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+}
+
+/// Make sure upstreams can be read from a YAML file instead of the command line
+#[tokio::test]
+async fn test_yaml_config() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let config_path = temp_config_path("yaml");
+    std::fs::write(
+        &config_path,
+        format!("upstreams:\n  - address: \"{}\"\n", upstream.address),
+    )
+    .expect("Failed to write config file");
+    let balancebeam =
+        BalanceBeam::new_with_args(&[], &["--config", config_path.to_str().unwrap()]).await;
+
+    let response_text = balancebeam
+        .get("/yaml")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /yaml HTTP/1.1"));
+    assert_eq!(Box::new(upstream).stop().await, 1);
+
+    let _ = std::fs::remove_file(&config_path);
+    log::info!("All done :)");
+}
+
+/// Make sure SIGHUP swaps in a new set of upstreams without disturbing open connections:
+///
+/// * Start balancebeam with a config file pointing at one upstream
+/// * Open a keep-alive connection and send a request
+/// * Rewrite the config file to point at a second upstream and send SIGHUP
+/// * Make sure the open connection still works, and new connections go to the second upstream
+#[tokio::test]
+async fn test_reload_on_sighup() {
+    init_logging();
+    let first_upstream = EchoServer::new().await;
+    let second_upstream = EchoServer::new().await;
+    let config_path = temp_config_path("toml");
+    write_toml_config(&config_path, &first_upstream.address);
+    let balancebeam =
+        BalanceBeam::new_with_args(&[], &["--config", config_path.to_str().unwrap()]).await;
+
+    log::info!("Sending a request on a keep-alive connection");
+    let kept_alive_client = reqwest::Client::new();
+    send_request(&kept_alive_client, &balancebeam, "/before-reload").await;
+
+    log::info!("Pointing the config file at the second upstream and sending SIGHUP");
+    write_toml_config(&config_path, &second_upstream.address);
+    balancebeam.send_signal(Signal::SIGHUP);
+    delay_for(Duration::from_millis(500)).await;
+
+    log::info!("Sending another request on the existing connection");
+    send_request(&kept_alive_client, &balancebeam, "/existing-connection").await;
+
+    log::info!("Sending requests on new connections");
+    for i in 0..3 {
+        send_request(
+            &reqwest::Client::new(),
+            &balancebeam,
+            &format!("/after-reload-{}", i),
+        )
+        .await;
+    }
+
+    assert_eq!(
+        Box::new(first_upstream).stop().await,
+        2,
+        "The connection opened before the reload should have stayed on the first upstream"
+    );
+    assert_eq!(
+        Box::new(second_upstream).stop().await,
+        3,
+        "New connections should have gone to the upstream from the reloaded config"
+    );
+
+    let _ = std::fs::remove_file(&config_path);
+    log::info!("All done :)");
+}
+
+/// Make sure SIGHUP moves balancebeam over to the listeners in the new config:
+///
+/// * Start balancebeam listening on the address from the command line
+/// * Rewrite the config file to listen on another address instead, and send SIGHUP
+/// * Make sure requests are answered on the new address, and the old one is closed
+#[tokio::test]
+async fn test_reload_listeners() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let config_path = temp_config_path("toml");
+    write_toml_config(&config_path, &upstream.address);
+    let balancebeam =
+        BalanceBeam::new_with_args(&[], &["--config", config_path.to_str().unwrap()]).await;
+    send_request(&reqwest::Client::new(), &balancebeam, "/before-reload").await;
+
+    log::info!("Moving the listener to another address and sending SIGHUP");
+    let mut rng = rand::thread_rng();
+    let new_address = format!("127.0.0.1:{}", rng.gen_range(1024, 65535));
+    let mut config = std::fs::read_to_string(&config_path).expect("Failed to read config file");
+    config.insert_str(0, &format!("listeners = [\"{}\"]\n\n", new_address));
+    std::fs::write(&config_path, config).expect("Failed to write config file");
+    balancebeam.send_signal(Signal::SIGHUP);
+    delay_for(Duration::from_millis(500)).await;
+
+    log::info!("Sending a request to the new address");
+    let response_text = reqwest::get(&format!("http://{}/after-reload", new_address))
+        .await
+        .expect("The new listener should be accepting connections")
+        .text()
+        .await
+        .expect("Balancebeam replied with a malformed response");
+    assert!(response_text.contains("GET /after-reload HTTP/1.1"));
+
+    log::info!("Making sure the old address is closed");
+    assert!(
+        tokio::net::TcpStream::connect(&balancebeam.address)
+            .await
+            .is_err(),
+        "The listener dropped from the config should have been closed"
+    );
+    assert_eq!(Box::new(upstream).stop().await, 2);
+
+    let _ = std::fs::remove_file(&config_path);
+    log::info!("All done :)");
+}
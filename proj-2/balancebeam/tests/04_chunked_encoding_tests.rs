@@ -0,0 +1,352 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use rand::Rng;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+
+async fn setup() -> (BalanceBeam, EchoServer) {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+    (balancebeam, upstream)
+}
+
+/// Reads from the stream until the data read so far contains `terminator`, and returns everything
+/// that was read
+async fn read_until(stream: &mut TcpStream, terminator: &str) -> String {
+    let mut data = Vec::new();
+    while !String::from_utf8_lossy(&data).contains(terminator) {
+        let mut buffer = [0_u8; 512];
+        let bytes_read = timeout(Duration::from_secs(5), stream.read(&mut buffer))
+            .await
+            .expect("Timed out waiting for data")
+            .expect("Error reading from stream");
+        assert!(
+            bytes_read > 0,
+            "Connection closed before receiving {:?}; got {:?}",
+            terminator,
+            String::from_utf8_lossy(&data)
+        );
+        data.extend_from_slice(&buffer[..bytes_read]);
+    }
+    String::from_utf8_lossy(&data).to_string()
+}
+
+/// Starts a bare-bones upstream that answers a single request with `response`, word for word, and
+/// then hangs up. Returns its address.
+async fn one_shot_upstream(response: &'static [u8]) -> String {
+    let mut rng = rand::thread_rng();
+    let address = format!("127.0.0.1:{}", rng.gen_range(1024, 65535));
+    let mut listener = TcpListener::bind(&address)
+        .await
+        .expect("Failed to bind upstream");
+    tokio::spawn(async move {
+        let (mut conn, _) = listener.accept().await.expect("Failed to accept connection");
+        read_until(&mut conn, "\r\n\r\n").await;
+        conn.write_all(response)
+            .await
+            .expect("Failed to send response");
+    });
+    address
+}
+
+/// Send a request with a chunked body and make sure the upstream receives the decoded body
+#[tokio::test]
+async fn test_chunked_request() {
+    let (balancebeam, upstream) = setup().await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Failed to connect to balancebeam");
+    conn.write_all(
+        b"POST /chunked HTTP/1.1\r\n\
+        Host: balancebeam\r\n\
+        Transfer-Encoding: chunked\r\n\
+        \r\n\
+        5\r\nHello\r\n\
+        7;ext=1\r\n, world\r\n\
+        0\r\n\
+        \r\n",
+    )
+    .await
+    .expect("Failed to send request to balancebeam");
+    let response_text = read_until(&mut conn, "Hello, world").await;
+    assert!(response_text.starts_with("HTTP/1.1 200"));
+    assert!(response_text.contains("POST /chunked HTTP/1.1"));
+    assert!(response_text.contains("transfer-encoding: chunked"));
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// Requests with both Transfer-Encoding and Content-Length are ambiguous and should be rejected
+#[tokio::test]
+async fn test_chunked_request_with_content_length() {
+    let (balancebeam, upstream) = setup().await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Failed to connect to balancebeam");
+    conn.write_all(
+        b"POST /ambiguous HTTP/1.1\r\n\
+        Host: balancebeam\r\n\
+        Transfer-Encoding: chunked\r\n\
+        Content-Length: 5\r\n\
+        \r\n\
+        0\r\n\
+        \r\n",
+    )
+    .await
+    .expect("Failed to send request to balancebeam");
+    let response_text = read_until(&mut conn, "\r\n\r\n").await;
+    assert!(response_text.starts_with("HTTP/1.1 400"));
+
+    // balancebeam opened a connection to the upstream but never sent a request over it, and hyper
+    // won't finish shutting down until that connection is closed
+    drop(conn);
+    drop(balancebeam);
+    assert_eq!(Box::new(upstream).stop().await, 0);
+    log::info!("All done :)");
+}
+
+/// Requests with Content-Length headers that disagree are just as ambiguous and should be rejected,
+/// while repeating the same value is harmless
+#[tokio::test]
+async fn test_request_with_conflicting_content_lengths() {
+    let (balancebeam, upstream) = setup().await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Failed to connect to balancebeam");
+    conn.write_all(
+        b"POST /ambiguous HTTP/1.1\r\n\
+        Host: balancebeam\r\n\
+        Content-Length: 5\r\n\
+        Content-Length: 0\r\n\
+        \r\n\
+        Hello",
+    )
+    .await
+    .expect("Failed to send request to balancebeam");
+    let response_text = read_until(&mut conn, "\r\n\r\n").await;
+    assert!(response_text.starts_with("HTTP/1.1 400"));
+
+    let mut conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Failed to connect to balancebeam");
+    conn.write_all(
+        b"POST /repeated HTTP/1.1\r\n\
+        Host: balancebeam\r\n\
+        Content-Length: 5\r\n\
+        Content-Length: 5\r\n\
+        \r\n\
+        Hello",
+    )
+    .await
+    .expect("Failed to send request to balancebeam");
+    let response_text = read_until(&mut conn, "\r\n\r\n").await;
+    assert!(response_text.starts_with("HTTP/1.1 200"));
+
+    drop(conn);
+    drop(balancebeam);
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// Requests whose Transfer-Encoding doesn't end in chunked have no knowable length, whether or not
+/// they also carry a Content-Length, and should be rejected
+#[tokio::test]
+async fn test_request_with_unsupported_transfer_encoding() {
+    let (balancebeam, upstream) = setup().await;
+
+    let mut conns = Vec::new();
+    for headers in &[
+        "Transfer-Encoding: gzip\r\n",
+        "Transfer-Encoding: chunked, identity\r\n",
+        "Transfer-Encoding: gzip\r\nContent-Length: 5\r\n",
+    ] {
+        let mut conn = TcpStream::connect(&balancebeam.address)
+            .await
+            .expect("Failed to connect to balancebeam");
+        let request = format!(
+            "POST /unsupported HTTP/1.1\r\nHost: balancebeam\r\n{}\r\nHello",
+            headers
+        );
+        conn.write_all(request.as_bytes())
+            .await
+            .expect("Failed to send request to balancebeam");
+        let response_text = read_until(&mut conn, "\r\n\r\n").await;
+        assert!(
+            response_text.starts_with("HTTP/1.1 400"),
+            "Unexpected response to {:?}: {}",
+            headers,
+            response_text
+        );
+        conns.push(conn);
+    }
+
+    drop(conns);
+    drop(balancebeam);
+    assert_eq!(Box::new(upstream).stop().await, 0);
+    log::info!("All done :)");
+}
+
+/// Responses with Content-Length headers that disagree can't be passed on safely, since the client
+/// might pick a different one than we do
+#[tokio::test]
+async fn test_response_with_conflicting_content_lengths() {
+    init_logging();
+    let upstream_address = one_shot_upstream(
+        b"HTTP/1.1 200 OK\r\n\
+        Content-Length: 5\r\n\
+        Content-Length: 6\r\n\
+        \r\n\
+        Hello!",
+    )
+    .await;
+    let balancebeam = BalanceBeam::new(&[&upstream_address], None, None).await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Failed to connect to balancebeam");
+    conn.write_all(b"GET /ambiguous HTTP/1.1\r\nHost: balancebeam\r\n\r\n")
+        .await
+        .expect("Failed to send request to balancebeam");
+    let response_text = read_until(&mut conn, "\r\n\r\n").await;
+    assert!(
+        response_text.starts_with("HTTP/1.1 502"),
+        "Unexpected response: {}",
+        response_text
+    );
+    log::info!("All done :)");
+}
+
+/// A response whose Transfer-Encoding doesn't end in chunked runs until the upstream hangs up,
+/// whatever its Content-Length says. The client should get the whole body, without the
+/// Content-Length, and then have its connection closed so it can tell where the body ended
+#[tokio::test]
+async fn test_response_with_unsupported_transfer_encoding() {
+    init_logging();
+    let upstream_address = one_shot_upstream(
+        b"HTTP/1.1 200 OK\r\n\
+        Transfer-Encoding: gzip\r\n\
+        Content-Length: 5\r\n\
+        \r\n\
+        Hello, world",
+    )
+    .await;
+    let balancebeam = BalanceBeam::new(&[&upstream_address], None, None).await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Failed to connect to balancebeam");
+    conn.write_all(b"GET /gzip HTTP/1.1\r\nHost: balancebeam\r\n\r\n")
+        .await
+        .expect("Failed to send request to balancebeam");
+    let mut response = Vec::new();
+    timeout(Duration::from_secs(5), conn.read_to_end(&mut response))
+        .await
+        .expect("Balancebeam didn't close the connection after the response")
+        .expect("Error reading from balancebeam");
+    let response_text = String::from_utf8_lossy(&response).to_ascii_lowercase();
+    assert!(
+        response_text.starts_with("http/1.1 200"),
+        "Unexpected response: {}",
+        response_text
+    );
+    assert!(response_text.contains("\r\ntransfer-encoding: gzip\r\n"));
+    assert!(!response_text.contains("content-length"));
+    assert!(response_text.ends_with("\r\n\r\nhello, world"));
+    log::info!("All done :)");
+}
+
+/// Have the upstream send a chunked response, and make sure the client gets the whole body
+#[tokio::test]
+async fn test_chunked_response() {
+    let (balancebeam, upstream) = setup().await;
+
+    let client = reqwest::Client::new();
+    for i in 0..3 {
+        let path = format!("/chunked-response-{}", i);
+        let response = client
+            .get(&format!("http://{}{}", balancebeam.address, path))
+            .header("x-echo-chunked", "true")
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(
+            response
+                .headers()
+                .get("transfer-encoding")
+                .map(|value| value.to_str().unwrap()),
+            Some("chunked")
+        );
+        let response_text = response
+            .text()
+            .await
+            .expect("Balancebeam replied with a malformed response");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+        assert!(response_text.contains("x-echo-chunked: true"));
+    }
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+    log::info!("All done :)");
+}
+
+/// Make sure trailers are passed along in both directions. The echo server can't see or send
+/// trailers, so this uses a bare-bones upstream that checks the request and replies by hand.
+#[tokio::test]
+async fn test_chunked_trailers() {
+    init_logging();
+    let mut rng = rand::thread_rng();
+    let upstream_address = format!("127.0.0.1:{}", rng.gen_range(1024, 65535));
+    let mut listener = TcpListener::bind(&upstream_address)
+        .await
+        .expect("Failed to bind upstream");
+    let upstream_task = tokio::spawn(async move {
+        let (mut conn, _) = listener.accept().await.expect("Failed to accept connection");
+        let request_text = read_until(&mut conn, "x-request-trailer: yes\r\n\r\n").await;
+        conn.write_all(
+            b"HTTP/1.1 200 OK\r\n\
+            Transfer-Encoding: chunked\r\n\
+            Trailer: x-response-trailer\r\n\
+            \r\n\
+            5\r\nhello\r\n\
+            0\r\n\
+            x-response-trailer: done\r\n\
+            \r\n",
+        )
+        .await
+        .expect("Failed to send response");
+        request_text
+    });
+    let balancebeam = BalanceBeam::new(&[&upstream_address], None, None).await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Failed to connect to balancebeam");
+    conn.write_all(
+        b"POST /trailers HTTP/1.1\r\n\
+        Host: balancebeam\r\n\
+        Transfer-Encoding: chunked\r\n\
+        Trailer: x-request-trailer\r\n\
+        \r\n\
+        5\r\nHello\r\n\
+        7\r\n, world\r\n\
+        0\r\n\
+        x-request-trailer: yes\r\n\
+        \r\n",
+    )
+    .await
+    .expect("Failed to send request to balancebeam");
+    let response_text = read_until(&mut conn, "x-response-trailer: done\r\n\r\n").await;
+    assert!(response_text.starts_with("HTTP/1.1 200"));
+    assert!(response_text.contains("\r\n5\r\nhello\r\n0\r\n"));
+
+    let request_text = upstream_task.await.expect("Upstream task panicked");
+    assert!(request_text.contains("\r\n5\r\nHello\r\n7\r\n, world\r\n0\r\n"));
+    log::info!("All done :)");
+}
@@ -0,0 +1,396 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use rand::Rng;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+use tokio::time::{delay_for, timeout};
+
+/// Returns the value of the sample with exactly the given name and labels, e.g.
+/// `balancebeam_responses_total{class="2xx"}`
+fn metric_value(metrics: &str, sample: &str) -> Option<f64> {
+    metrics.lines().find_map(|line| {
+        let value = line.strip_prefix(sample)?.strip_prefix(' ')?;
+        value.parse().ok()
+    })
+}
+
+/// Send some requests (one of which is rate limited) with one live and one dead upstream, and make
+/// sure the metrics endpoint reports them
+#[tokio::test]
+async fn test_metrics() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    // Nothing listens on port 1, so connecting to this upstream fails and it is marked dead
+    let dead_upstream = "127.0.0.1:1";
+    let balancebeam = BalanceBeam::new_with_admin(
+        &[&upstream.address, dead_upstream],
+        &[
+            "--balancing-strategy",
+            "round-robin",
+            "--max-requests-per-minute",
+            "3",
+            "--active-health-check-interval",
+            "3600",
+        ],
+    )
+    .await;
+
+    for i in 0..3 {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+    let response_text = balancebeam
+        .get("/rate-limited")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("429"));
+
+    let (status, metrics) = balancebeam
+        .admin_get("/metrics")
+        .await
+        .expect("Error fetching metrics");
+    log::info!("Metrics:\n{}", metrics);
+    assert_eq!(status, 200);
+    let upstream_label = format!("{{upstream=\"{}\"}}", upstream.address);
+    let sample = |name: &str| metric_value(&metrics, &format!("{}{}", name, upstream_label));
+    assert_eq!(sample("balancebeam_upstream_requests_total"), Some(3.0));
+    assert_eq!(sample("balancebeam_request_duration_seconds_count"), Some(3.0));
+    assert_eq!(sample("balancebeam_upstream_up"), Some(1.0));
+    assert_eq!(
+        metric_value(
+            &metrics,
+            &format!("balancebeam_upstream_up{{upstream=\"{}\"}}", dead_upstream)
+        ),
+        Some(0.0)
+    );
+    assert_eq!(
+        metric_value(
+            &metrics,
+            &format!(
+                "balancebeam_request_duration_seconds_bucket{{upstream=\"{}\",le=\"+Inf\"}}",
+                upstream.address
+            )
+        ),
+        Some(3.0)
+    );
+    assert_eq!(
+        metric_value(&metrics, "balancebeam_responses_total{class=\"2xx\"}"),
+        Some(3.0)
+    );
+    assert_eq!(
+        metric_value(&metrics, "balancebeam_responses_total{class=\"4xx\"}"),
+        Some(1.0)
+    );
+    assert_eq!(metric_value(&metrics, "balancebeam_rate_limited_total"), Some(1.0));
+    assert_eq!(metric_value(&metrics, "balancebeam_access_log_dropped_total"), Some(0.0));
+    assert!(metric_value(&metrics, "balancebeam_client_connections").is_some());
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+    log::info!("All done :)");
+}
+
+/// Unknown admin paths should get a 404, and the admin endpoints shouldn't be reachable through
+/// the proxy listener
+#[tokio::test]
+async fn test_admin_not_found() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_admin(&[&upstream.address], &[]).await;
+
+    let (status, _) = balancebeam
+        .admin_get("/nonexistent")
+        .await
+        .expect("Error sending request to admin listener");
+    assert_eq!(status, 404);
+
+    let response_text = balancebeam
+        .get("/metrics")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /metrics HTTP/1.1"));
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// Admin clients that are too slow to send their request (headers or body) get a 408 and have their
+/// connection closed, just like clients of the proxy, whether or not they ever send a token
+#[tokio::test]
+async fn test_slow_admin_client() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_admin(
+        &[&upstream.address],
+        &["--header-read-timeout", "1", "--body-read-timeout", "1"],
+    )
+    .await;
+    let admin_address = balancebeam.admin_address.clone().unwrap();
+
+    for request in &[
+        "GET /upstreams HTTP/1.1\r\nHost: admin\r\n",
+        "POST /upstreams HTTP/1.1\r\nHost: admin\r\nContent-Length: 10\r\n\r\nhalf",
+    ] {
+        let mut conn = TcpStream::connect(&admin_address)
+            .await
+            .expect("Failed to connect to admin listener");
+        conn.write_all(request.as_bytes())
+            .await
+            .expect("Failed to send admin request");
+        let mut response = Vec::new();
+        timeout(Duration::from_secs(5), conn.read_to_end(&mut response))
+            .await
+            .expect("balancebeam should have closed the admin connection")
+            .expect("Error reading from admin listener");
+        let response = String::from_utf8_lossy(&response);
+        assert!(response.starts_with("HTTP/1.1 408"), "Unexpected response: {}", response);
+    }
+
+    drop(balancebeam);
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+const ADMIN_TOKEN: &str = "hunter2";
+
+/// Returns the state of each upstream listed by GET /upstreams
+async fn upstream_states(balancebeam: &BalanceBeam) -> Vec<(String, String)> {
+    let (status, body) = balancebeam
+        .admin_request(reqwest::Method::GET, "/upstreams", Some(ADMIN_TOKEN), None)
+        .await
+        .expect("Error listing upstreams");
+    assert_eq!(status, 200);
+    let upstreams: Vec<serde_json::Value> =
+        serde_json::from_str(&body).expect("GET /upstreams returned invalid JSON");
+    upstreams
+        .iter()
+        .map(|upstream| {
+            (
+                upstream["address"].as_str().unwrap().to_string(),
+                upstream["state"].as_str().unwrap().to_string(),
+            )
+        })
+        .collect()
+}
+
+/// The upstream API should be disabled without a token, and should reject requests without the
+/// right token
+#[tokio::test]
+async fn test_upstream_api_authentication() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_admin(&[&upstream.address], &[]).await;
+    let (status, _) = balancebeam
+        .admin_request(reqwest::Method::GET, "/upstreams", Some(ADMIN_TOKEN), None)
+        .await
+        .expect("Error sending request to admin listener");
+    assert_eq!(status, 403);
+
+    let balancebeam =
+        BalanceBeam::new_with_admin(&[&upstream.address], &["--admin-token", ADMIN_TOKEN]).await;
+    for token in [None, Some("wrong")].iter() {
+        let (status, _) = balancebeam
+            .admin_request(reqwest::Method::GET, "/upstreams", *token, None)
+            .await
+            .expect("Error sending request to admin listener");
+        assert_eq!(status, 401);
+    }
+    let (status, _) = balancebeam
+        .admin_request(
+            reqwest::Method::DELETE,
+            &format!("/upstreams/{}", upstream.address),
+            Some("wrong"),
+            None,
+        )
+        .await
+        .expect("Error sending request to admin listener");
+    assert_eq!(status, 401);
+    assert_eq!(
+        upstream_states(&balancebeam).await,
+        vec![(upstream.address.clone(), "live".to_string())]
+    );
+
+    log::info!("All done :)");
+}
+
+/// Add an upstream at runtime and make sure it gets traffic, then remove the original one and make
+/// sure it stops getting traffic
+#[tokio::test]
+async fn test_add_and_remove_upstreams() {
+    init_logging();
+    let first_upstream = EchoServer::new().await;
+    let second_upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_admin(
+        &[&first_upstream.address],
+        &[
+            "--admin-token",
+            ADMIN_TOKEN,
+            "--balancing-strategy",
+            "round-robin",
+            "--active-health-check-interval",
+            "3600",
+        ],
+    )
+    .await;
+
+    let new_upstream = format!("{{\"address\": \"{}\"}}", second_upstream.address);
+    let (status, _) = balancebeam
+        .admin_request(
+            reqwest::Method::POST,
+            "/upstreams",
+            Some(ADMIN_TOKEN),
+            Some(&new_upstream),
+        )
+        .await
+        .expect("Error adding upstream");
+    assert_eq!(status, 201);
+    let (status, _) = balancebeam
+        .admin_request(
+            reqwest::Method::POST,
+            "/upstreams",
+            Some(ADMIN_TOKEN),
+            Some(&new_upstream),
+        )
+        .await
+        .expect("Error adding upstream");
+    assert_eq!(status, 409, "Adding the same upstream twice should be rejected");
+    let heavy_upstream = "{\"address\": \"127.0.0.1:1\", \"weight\": 1000000000}";
+    let (status, _) = balancebeam
+        .admin_request(
+            reqwest::Method::POST,
+            "/upstreams",
+            Some(ADMIN_TOKEN),
+            Some(heavy_upstream),
+        )
+        .await
+        .expect("Error adding upstream");
+    assert_eq!(status, 400, "Weights over the maximum should be rejected");
+    for i in 0..4 {
+        balancebeam
+            .get(&format!("/both-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    let (status, _) = balancebeam
+        .admin_request(
+            reqwest::Method::DELETE,
+            &format!("/upstreams/{}", first_upstream.address),
+            Some(ADMIN_TOKEN),
+            None,
+        )
+        .await
+        .expect("Error removing upstream");
+    assert_eq!(status, 204);
+    assert_eq!(
+        upstream_states(&balancebeam).await,
+        vec![(second_upstream.address.clone(), "live".to_string())]
+    );
+    for i in 0..4 {
+        balancebeam
+            .get(&format!("/second-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    drop(balancebeam);
+    assert_eq!(Box::new(first_upstream).stop().await, 2);
+    assert_eq!(Box::new(second_upstream).stop().await, 6);
+    log::info!("All done :)");
+}
+
+/// Drain an upstream while it is serving a request. It should get no new requests, and should be
+/// removed once the outstanding request has been answered.
+#[tokio::test]
+async fn test_drain_upstream() {
+    init_logging();
+    // A bare-bones upstream that holds its first response until told to send it
+    let mut rng = rand::thread_rng();
+    let slow_upstream = format!("127.0.0.1:{}", rng.gen_range(1024, 65535));
+    let mut listener = TcpListener::bind(&slow_upstream)
+        .await
+        .expect("Failed to bind upstream");
+    let (respond_tx, respond_rx) = oneshot::channel::<()>();
+    tokio::spawn(async move {
+        let (mut conn, _) = listener.accept().await.expect("Failed to accept connection");
+        let mut buffer = [0_u8; 1024];
+        let _ = conn.read(&mut buffer).await;
+        let _ = respond_rx.await;
+        let _ = conn
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nslow")
+            .await;
+    });
+    let other_upstream = EchoServer::new().await;
+    let balancebeam = Arc::new(
+        BalanceBeam::new_with_admin(
+            &[&slow_upstream, &other_upstream.address],
+            &[
+                "--admin-token",
+                ADMIN_TOKEN,
+                "--balancing-strategy",
+                "round-robin",
+                "--active-health-check-interval",
+                "3600",
+            ],
+        )
+        .await,
+    );
+
+    // The first connection goes to the slow upstream, which doesn't answer yet
+    let balancebeam_copy = balancebeam.clone();
+    let slow_request = tokio::spawn(async move { balancebeam_copy.get("/slow").await });
+    delay_for(Duration::from_millis(500)).await;
+
+    let (status, _) = balancebeam
+        .admin_request(
+            reqwest::Method::POST,
+            &format!("/upstreams/{}/drain", slow_upstream),
+            Some(ADMIN_TOKEN),
+            None,
+        )
+        .await
+        .expect("Error draining upstream");
+    assert_eq!(status, 202);
+    assert!(upstream_states(&balancebeam)
+        .await
+        .contains(&(slow_upstream.clone(), "draining".to_string())));
+
+    log::info!("New requests should all go to the other upstream while draining");
+    for i in 0..3 {
+        balancebeam
+            .get(&format!("/other-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+    delay_for(Duration::from_millis(1500)).await;
+    assert!(
+        upstream_states(&balancebeam)
+            .await
+            .contains(&(slow_upstream.clone(), "draining".to_string())),
+        "Upstream was removed while it still had a request in flight"
+    );
+
+    log::info!("Letting the in-flight request finish");
+    respond_tx.send(()).unwrap();
+    let response_text = slow_request
+        .await
+        .unwrap()
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response_text, "slow");
+    delay_for(Duration::from_millis(1500)).await;
+    assert_eq!(
+        upstream_states(&balancebeam).await,
+        vec![(other_upstream.address.clone(), "live".to_string())]
+    );
+
+    drop(balancebeam);
+    assert_eq!(Box::new(other_upstream).stop().await, 3);
+    log::info!("All done :)");
+}
@@ -0,0 +1,261 @@
+mod common;
+
+use common::{fixture, init_logging, temp_config_path, BalanceBeam, EchoServer, Server};
+use nix::sys::signal::Signal;
+use rand::Rng;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::delay_for;
+use tokio_rustls::rustls::ClientConfig;
+use tokio_rustls::TlsConnector;
+
+fn random_address() -> String {
+    let mut rng = rand::thread_rng();
+    format!("127.0.0.1:{}", rng.gen_range(1024, 65535))
+}
+
+/// Writes a config file with an HTTPS listener on `tls_address` serving the given certificates.
+/// Each certificate is given as (fixture name, hostnames).
+fn write_tls_config(
+    path: &std::path::Path,
+    upstream: &str,
+    tls_address: &str,
+    certificates: &[(&str, &[&str])],
+) {
+    let mut config = format!(
+        "[[upstreams]]\naddress = \"{}\"\n\n[tls]\nlisteners = [\"{}\"]\n",
+        upstream, tls_address
+    );
+    for (name, hostnames) in certificates {
+        let hostnames: Vec<String> =
+            hostnames.iter().map(|host| format!("\"{}\"", host)).collect();
+        config.push_str(&format!(
+            "\n[[tls.certificates]]\ncert = \"{}\"\nkey = \"{}\"\nhostnames = [{}]\n",
+            fixture(&format!("{}.pem", name)),
+            fixture(&format!("{}.key", name)),
+            hostnames.join(", ")
+        ));
+    }
+    std::fs::write(path, config).expect("Failed to write config file");
+}
+
+/// Sends a GET request over HTTPS, asking for `hostname` in the TLS handshake and trusting only the
+/// certificates named in `trusted`. Returns the raw response, or an error if the handshake fails
+/// (e.g. because balancebeam served a certificate that isn't valid for `hostname`).
+async fn https_get(
+    address: &str,
+    hostname: &str,
+    trusted: &[&str],
+    path: &str,
+) -> Result<String, std::io::Error> {
+    let mut config = ClientConfig::new();
+    for name in trusted {
+        let pem = std::fs::read(fixture(&format!("{}.pem", name))).unwrap();
+        config.root_store.add_pem_file(&mut &pem[..]).unwrap();
+    }
+    let connector = TlsConnector::from(Arc::new(config));
+    let stream = TcpStream::connect(address).await?;
+    let dns_name = webpki::DNSNameRef::try_from_ascii_str(hostname).unwrap();
+    let mut stream = connector.connect(dns_name, stream).await?;
+    stream
+        .write_all(
+            format!(
+                "GET {} HTTP/1.1\r\nHost: {}\r\nx-sent-by: balancebeam-tests\r\n\r\n",
+                path, hostname
+            )
+            .as_bytes(),
+        )
+        .await?;
+    // Hang up once the request is out, so balancebeam closes the connection after responding
+    stream.shutdown().await?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+    Ok(response)
+}
+
+/// Make sure balancebeam terminates HTTPS, and tells the upstream which protocol the client used:
+///
+/// * Send a request over HTTPS and make sure it is answered, with X-Forwarded-Proto set to https
+/// * Send a request over plain HTTP, claiming to have used HTTPS, and make sure X-Forwarded-Proto
+///   is corrected to http
+#[tokio::test]
+async fn test_https_termination() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let tls_address = random_address();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--tls-bind",
+            &tls_address,
+            "--tls-cert",
+            &fixture("a.test.pem"),
+            "--tls-key",
+            &fixture("a.test.key"),
+        ],
+    )
+    .await;
+
+    let response_text = https_get(&tls_address, "a.test", &["a.test"], "/secure")
+        .await
+        .expect("Error sending request to balancebeam over HTTPS");
+    assert!(response_text.starts_with("HTTP/1.1 200"));
+    assert!(response_text.contains("GET /secure HTTP/1.1"));
+    assert!(response_text.contains("x-forwarded-proto: https"));
+
+    let response_text = reqwest::Client::new()
+        .get(&format!("http://{}/plain", balancebeam.address))
+        .header("x-forwarded-proto", "https")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .text()
+        .await
+        .expect("Balancebeam replied with a malformed response");
+    assert!(response_text.contains("GET /plain HTTP/1.1"));
+    assert!(response_text.contains("x-forwarded-proto: http\n"));
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// Make sure the certificate is picked based on the server name the client asks for:
+///
+/// * b.test gets the certificate listed for it
+/// * x.c.test gets the certificate listed for *.c.test
+/// * a.test isn't listed for any certificate, so it gets the first (default) one, which happens to
+///   be a.test's
+/// * a.test doesn't get the certificate listed for b.test
+#[tokio::test]
+async fn test_sni_certificate_selection() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let tls_address = random_address();
+    let config_path = temp_config_path("toml");
+    write_tls_config(
+        &config_path,
+        &upstream.address,
+        &tls_address,
+        &[
+            ("a.test", &[]),
+            ("b.test", &["b.test"]),
+            ("star.c.test", &["*.c.test"]),
+        ],
+    );
+    let _balancebeam =
+        BalanceBeam::new_with_args(&[], &["--config", config_path.to_str().unwrap()]).await;
+
+    for (hostname, certificate) in [
+        ("b.test", "b.test"),
+        ("x.c.test", "star.c.test"),
+        ("a.test", "a.test"),
+    ]
+    .iter()
+    {
+        let response_text = https_get(&tls_address, hostname, &[certificate], "/sni")
+            .await
+            .unwrap_or_else(|err| panic!("TLS handshake for {} failed: {}", hostname, err));
+        assert!(response_text.contains("GET /sni HTTP/1.1"));
+    }
+    https_get(&tls_address, "a.test", &["b.test"], "/wrong-cert")
+        .await
+        .expect_err("a.test should not have been served the certificate for b.test");
+
+    let _ = std::fs::remove_file(&config_path);
+    log::info!("All done :)");
+}
+
+/// Make sure certificates are reloaded on SIGHUP, and that a broken certificate doesn't replace a
+/// working one:
+///
+/// * Start balancebeam serving the certificate for a.test
+/// * Switch the config to the certificate for b.test and send SIGHUP
+/// * Make sure b.test's certificate is served
+/// * Switch the config to a certificate that doesn't exist and send SIGHUP
+/// * Make sure b.test's certificate is still served
+#[tokio::test]
+async fn test_certificate_reload() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let tls_address = random_address();
+    let config_path = temp_config_path("toml");
+    write_tls_config(&config_path, &upstream.address, &tls_address, &[("a.test", &[])]);
+    let balancebeam =
+        BalanceBeam::new_with_args(&[], &["--config", config_path.to_str().unwrap()]).await;
+
+    https_get(&tls_address, "b.test", &["b.test"], "/before-reload")
+        .await
+        .expect_err("b.test's certificate should not be served yet");
+
+    log::info!("Switching to b.test's certificate and sending SIGHUP");
+    write_tls_config(&config_path, &upstream.address, &tls_address, &[("b.test", &[])]);
+    balancebeam.send_signal(Signal::SIGHUP);
+    delay_for(Duration::from_millis(500)).await;
+    let response_text = https_get(&tls_address, "b.test", &["b.test"], "/after-reload")
+        .await
+        .expect("b.test's certificate should be served after the reload");
+    assert!(response_text.contains("GET /after-reload HTTP/1.1"));
+
+    log::info!("Switching to a missing certificate and sending SIGHUP");
+    write_tls_config(&config_path, &upstream.address, &tls_address, &[("missing", &[])]);
+    balancebeam.send_signal(Signal::SIGHUP);
+    delay_for(Duration::from_millis(500)).await;
+    https_get(&tls_address, "b.test", &["b.test"], "/after-failed-reload")
+        .await
+        .expect("The old certificate should have been kept");
+
+    let _ = std::fs::remove_file(&config_path);
+    log::info!("All done :)");
+}
+
+/// Make sure SIGHUP reloads the certificate given on the command line when there is no config
+/// file, rather than killing balancebeam:
+///
+/// * Start balancebeam with a copy of a.test's certificate, and make sure it is served
+/// * Overwrite the copy with b.test's certificate and send SIGHUP
+/// * Make sure b.test's certificate is served
+#[tokio::test]
+async fn test_certificate_reload_without_config() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let tls_address = random_address();
+    let cert_path = temp_config_path("pem");
+    let key_path = temp_config_path("key");
+    let install = |name: &str| {
+        std::fs::copy(fixture(&format!("{}.pem", name)), &cert_path).unwrap();
+        std::fs::copy(fixture(&format!("{}.key", name)), &key_path).unwrap();
+    };
+    install("a.test");
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--tls-bind",
+            &tls_address,
+            "--tls-cert",
+            cert_path.to_str().unwrap(),
+            "--tls-key",
+            key_path.to_str().unwrap(),
+        ],
+    )
+    .await;
+
+    https_get(&tls_address, "a.test", &["a.test"], "/before-reload")
+        .await
+        .expect("a.test's certificate should be served");
+
+    log::info!("Replacing the certificate with b.test's and sending SIGHUP");
+    install("b.test");
+    balancebeam.send_signal(Signal::SIGHUP);
+    delay_for(Duration::from_millis(500)).await;
+    let response_text = https_get(&tls_address, "b.test", &["b.test"], "/after-reload")
+        .await
+        .expect("b.test's certificate should be served after the reload");
+    assert!(response_text.contains("GET /after-reload HTTP/1.1"));
+
+    let _ = std::fs::remove_file(&cert_path);
+    let _ = std::fs::remove_file(&key_path);
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
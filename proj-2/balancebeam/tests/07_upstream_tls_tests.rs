@@ -0,0 +1,203 @@
+mod common;
+
+use common::{fixture, init_logging, temp_config_path, BalanceBeam, Server, TlsEchoServer};
+use std::time::Duration;
+use tokio::time::delay_for;
+
+/// Starts balancebeam with a single upstream that is reached over TLS. `tls` holds the lines of
+/// the upstream's [upstreams.tls] table.
+async fn start_balancebeam(
+    config_path: &std::path::Path,
+    upstream: &str,
+    tls: &[String],
+    health_check_interval: usize,
+) -> BalanceBeam {
+    let config = format!(
+        "[health_check]\ninterval = {}\n\n[[upstreams]]\naddress = \"{}\"\n\n[upstreams.tls]\n{}\n",
+        health_check_interval,
+        upstream,
+        tls.join("\n")
+    );
+    std::fs::write(config_path, config).expect("Failed to write config file");
+    BalanceBeam::new_with_args(&[], &["--config", config_path.to_str().unwrap()]).await
+}
+
+/// Sends a GET request to balancebeam and returns the status code and body
+async fn get(balancebeam: &BalanceBeam, path: &str) -> (u16, String) {
+    let response = reqwest::Client::new()
+        .get(&format!("http://{}{}", balancebeam.address, path))
+        .header("x-sent-by", "balancebeam-tests")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    let status = response.status().as_u16();
+    let body = response
+        .text()
+        .await
+        .expect("Balancebeam replied with a malformed response");
+    (status, body)
+}
+
+/// Make sure requests and health checks reach an HTTPS upstream:
+///
+/// * Start an HTTPS upstream serving a.test's certificate, and point balancebeam at it by IP, with
+///   a.test as the server name and a.test's certificate as the CA
+/// * Send a request and make sure it is echoed back
+/// * Let a few health checks run, and make sure they kept the upstream alive
+#[tokio::test]
+async fn test_upstream_tls() {
+    init_logging();
+    let upstream = TlsEchoServer::new(&fixture("a.test.pem"), &fixture("a.test.key"), None).await;
+    let config_path = temp_config_path("toml");
+    let balancebeam = start_balancebeam(
+        &config_path,
+        &upstream.address,
+        &[
+            format!("ca = \"{}\"", fixture("a.test.pem")),
+            "server_name = \"a.test\"".to_string(),
+        ],
+        1,
+    )
+    .await;
+
+    let (status, body) = get(&balancebeam, "/over-tls").await;
+    assert_eq!(status, 200);
+    assert!(body.contains("GET /over-tls HTTP/1.1"));
+
+    log::info!("Waiting for a few health checks");
+    delay_for(Duration::from_secs(3)).await;
+    let (status, body) = get(&balancebeam, "/after-health-checks").await;
+    assert_eq!(status, 200);
+    assert!(body.contains("GET /after-health-checks HTTP/1.1"));
+
+    // Two requests plus at least two health checks
+    assert!(Box::new(upstream).stop().await >= 4);
+    let _ = std::fs::remove_file(&config_path);
+    log::info!("All done :)");
+}
+
+/// Make sure upstreams that also speak HTTP/2 are still spoken to in HTTP/1.1, which is all
+/// balancebeam forwards:
+///
+/// * Start an HTTPS upstream that offers h2 and http/1.1 through ALPN, and serves only HTTP/2 to
+///   clients that pick h2
+/// * Send a request, let a few health checks run, and make sure both requests are answered
+#[tokio::test]
+async fn test_upstream_tls_offering_h2() {
+    init_logging();
+    let upstream = TlsEchoServer::new_with_alpn(
+        &fixture("a.test.pem"),
+        &fixture("a.test.key"),
+        None,
+        &["h2", "http/1.1"],
+    )
+    .await;
+    let config_path = temp_config_path("toml");
+    let balancebeam = start_balancebeam(
+        &config_path,
+        &upstream.address,
+        &[
+            format!("ca = \"{}\"", fixture("a.test.pem")),
+            "server_name = \"a.test\"".to_string(),
+        ],
+        1,
+    )
+    .await;
+
+    let (status, body) = get(&balancebeam, "/alpn").await;
+    assert_eq!(status, 200);
+    assert!(body.contains("GET /alpn HTTP/1.1"), "Unexpected echo: {}", body);
+
+    log::info!("Waiting for a few health checks");
+    delay_for(Duration::from_secs(3)).await;
+    let (status, body) = get(&balancebeam, "/alpn-after-health-checks").await;
+    assert_eq!(status, 200);
+    assert!(body.contains("GET /alpn-after-health-checks HTTP/1.1"));
+
+    // Two requests plus at least two health checks
+    assert!(Box::new(upstream).stop().await >= 4);
+    let _ = std::fs::remove_file(&config_path);
+    log::info!("All done :)");
+}
+
+/// Make sure balancebeam presents its client certificate to upstreams that require one:
+///
+/// * Start an HTTPS upstream that only accepts clients with the client certificate
+/// * Point balancebeam at it without a client certificate, and make sure the request fails
+/// * Point another balancebeam at it with the client certificate, and make sure the request
+///   succeeds
+#[tokio::test]
+async fn test_upstream_mutual_tls() {
+    init_logging();
+    let upstream = TlsEchoServer::new(
+        &fixture("a.test.pem"),
+        &fixture("a.test.key"),
+        Some(&fixture("client.pem")),
+    )
+    .await;
+    let mut tls = vec![
+        format!("ca = \"{}\"", fixture("a.test.pem")),
+        "server_name = \"a.test\"".to_string(),
+    ];
+
+    let config_path = temp_config_path("toml");
+    let balancebeam = start_balancebeam(&config_path, &upstream.address, &tls, 60).await;
+    let (status, _) = get(&balancebeam, "/no-client-cert").await;
+    assert_eq!(status, 502);
+    drop(balancebeam);
+
+    tls.push(format!("cert = \"{}\"", fixture("client.pem")));
+    tls.push(format!("key = \"{}\"", fixture("client.key")));
+    let balancebeam = start_balancebeam(&config_path, &upstream.address, &tls, 60).await;
+    let (status, body) = get(&balancebeam, "/client-cert").await;
+    assert_eq!(status, 200);
+    assert!(body.contains("GET /client-cert HTTP/1.1"));
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    let _ = std::fs::remove_file(&config_path);
+    log::info!("All done :)");
+}
+
+/// Make sure the upstream's certificate is checked against the server name unless hostname
+/// verification is turned off:
+///
+/// * Start an HTTPS upstream serving a.test's certificate, and point balancebeam at it with b.test
+///   as the server name. Make sure the request fails
+/// * Do the same with verify_hostname = false, and make sure the request succeeds
+/// * Do the same with a CA that didn't sign the upstream's certificate, and make sure the request
+///   fails even though the hostname isn't verified
+#[tokio::test]
+async fn test_upstream_hostname_verification() {
+    init_logging();
+    let upstream = TlsEchoServer::new(&fixture("a.test.pem"), &fixture("a.test.key"), None).await;
+    let config_path = temp_config_path("toml");
+    let tls = |ca: &str, verify_hostname: bool| {
+        vec![
+            format!("ca = \"{}\"", fixture(ca)),
+            "server_name = \"b.test\"".to_string(),
+            format!("verify_hostname = {}", verify_hostname),
+        ]
+    };
+
+    let balancebeam =
+        start_balancebeam(&config_path, &upstream.address, &tls("a.test.pem", true), 60).await;
+    let (status, _) = get(&balancebeam, "/hostname-verified").await;
+    assert_eq!(status, 502);
+    drop(balancebeam);
+
+    let balancebeam =
+        start_balancebeam(&config_path, &upstream.address, &tls("a.test.pem", false), 60).await;
+    let (status, body) = get(&balancebeam, "/hostname-not-verified").await;
+    assert_eq!(status, 200);
+    assert!(body.contains("GET /hostname-not-verified HTTP/1.1"));
+    drop(balancebeam);
+
+    let balancebeam =
+        start_balancebeam(&config_path, &upstream.address, &tls("b.test.pem", false), 60).await;
+    let (status, _) = get(&balancebeam, "/untrusted").await;
+    assert_eq!(status, 502);
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    let _ = std::fs::remove_file(&config_path);
+    log::info!("All done :)");
+}
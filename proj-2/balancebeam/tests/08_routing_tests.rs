@@ -0,0 +1,184 @@
+mod common;
+
+use common::{init_logging, temp_config_path, BalanceBeam, EchoServer, Server};
+
+/// Starts balancebeam with `default` as the top-level upstream, a pool for each of `pools` (given
+/// as (name, upstream address)), and the routes in `routes` (TOML tables, without the header).
+async fn start_balancebeam(
+    config_path: &std::path::Path,
+    default: &str,
+    pools: &[(&str, &str)],
+    routes: &[&str],
+) -> BalanceBeam {
+    let mut config = format!("[[upstreams]]\naddress = \"{}\"\n", default);
+    for (name, address) in pools {
+        config.push_str(&format!(
+            "\n[[pools]]\nname = \"{}\"\nupstreams = [{{ address = \"{}\" }}]\n",
+            name, address
+        ));
+    }
+    for route in routes {
+        config.push_str(&format!("\n[[routes]]\n{}\n", route));
+    }
+    std::fs::write(config_path, config).expect("Failed to write config file");
+    BalanceBeam::new_with_args(&[], &["--config", config_path.to_str().unwrap()]).await
+}
+
+/// Sends a request through `client` and returns the echoed request
+async fn send_request(
+    client: &reqwest::Client,
+    balancebeam: &BalanceBeam,
+    method: reqwest::Method,
+    path: &str,
+    headers: &[(&str, &str)],
+) -> String {
+    let mut request = client.request(method, &format!("http://{}{}", balancebeam.address, path));
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    request
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .text()
+        .await
+        .expect("Balancebeam replied with a malformed response")
+}
+
+/// Make sure requests are routed on their Host header and path, with paths rewritten as
+/// configured. All requests go over the same keep-alive connection, so the connection has to move
+/// between pools along the way:
+///
+/// * Requests for api.test go to the api pool
+/// * Requests under /static/ go to the static pool, with the prefix replaced by /assets/
+/// * Requests for /vN/users go to the api pool as /users/vN
+/// * Everything else goes to the top-level upstream
+#[tokio::test]
+async fn test_host_and_path_routing() {
+    init_logging();
+    let default_upstream = EchoServer::new().await;
+    let api_upstream = EchoServer::new().await;
+    let static_upstream = EchoServer::new().await;
+    let config_path = temp_config_path("toml");
+    let balancebeam = start_balancebeam(
+        &config_path,
+        &default_upstream.address,
+        &[
+            ("api", &api_upstream.address),
+            ("static", &static_upstream.address),
+        ],
+        &[
+            "pool = \"api\"\nhost = \"api.test\"",
+            "pool = \"static\"\npath_prefix = \"/static/\"\nrewrite = \"/assets/\"",
+            "pool = \"api\"\npath_regex = \"^/v([0-9]+)/users$\"\nrewrite = \"/users/v$1\"",
+        ],
+    )
+    .await;
+    let client = reqwest::Client::new();
+    let get = reqwest::Method::GET;
+
+    let response = send_request(&client, &balancebeam, get.clone(), "/home", &[]).await;
+    assert!(response.contains("GET /home HTTP/1.1"));
+    let response =
+        send_request(&client, &balancebeam, get.clone(), "/home", &[("host", "API.test:80")])
+            .await;
+    assert!(response.contains("GET /home HTTP/1.1"));
+    let response =
+        send_request(&client, &balancebeam, get.clone(), "/static/logo.png?v=2", &[]).await;
+    assert!(response.contains("GET /assets/logo.png?v=2 HTTP/1.1"));
+    let response = send_request(&client, &balancebeam, get.clone(), "/v2/users", &[]).await;
+    assert!(response.contains("GET /users/v2 HTTP/1.1"));
+    let response = send_request(&client, &balancebeam, get.clone(), "/v2/users/7", &[]).await;
+    assert!(response.contains("GET /v2/users/7 HTTP/1.1"));
+
+    assert_eq!(Box::new(default_upstream).stop().await, 2);
+    assert_eq!(Box::new(api_upstream).stop().await, 2);
+    assert_eq!(Box::new(static_upstream).stop().await, 1);
+    let _ = std::fs::remove_file(&config_path);
+    log::info!("All done :)");
+}
+
+/// Make sure requests are routed on their method and headers, and that routes are tried in order:
+///
+/// * Requests carrying x-canary: 1 go to the canary pool, whatever their method
+/// * Other POST requests go to the writes pool
+/// * Other GET requests go to the top-level upstream
+#[tokio::test]
+async fn test_method_and_header_routing() {
+    init_logging();
+    let default_upstream = EchoServer::new().await;
+    let writes_upstream = EchoServer::new().await;
+    let canary_upstream = EchoServer::new().await;
+    let config_path = temp_config_path("toml");
+    let balancebeam = start_balancebeam(
+        &config_path,
+        &default_upstream.address,
+        &[
+            ("writes", &writes_upstream.address),
+            ("canary", &canary_upstream.address),
+        ],
+        &[
+            "pool = \"canary\"\nheaders = { \"X-Canary\" = \"1\" }",
+            "pool = \"writes\"\nmethods = [\"POST\", \"PUT\"]",
+        ],
+    )
+    .await;
+    let client = reqwest::Client::new();
+
+    for (method, headers, expected) in [
+        (reqwest::Method::GET, &[][..], "GET /"),
+        (reqwest::Method::POST, &[][..], "POST /"),
+        (reqwest::Method::PUT, &[][..], "PUT /"),
+        (reqwest::Method::POST, &[("x-canary", "1")][..], "POST /"),
+        (reqwest::Method::GET, &[("x-canary", "2")][..], "GET /"),
+    ]
+    .iter()
+    {
+        let response = send_request(&client, &balancebeam, method.clone(), "/", headers).await;
+        assert!(response.starts_with(expected));
+    }
+
+    assert_eq!(Box::new(default_upstream).stop().await, 2);
+    assert_eq!(Box::new(writes_upstream).stop().await, 2);
+    assert_eq!(Box::new(canary_upstream).stop().await, 1);
+    let _ = std::fs::remove_file(&config_path);
+    log::info!("All done :)");
+}
+
+/// Make sure a configuration with a route to a pool that doesn't exist is rejected on reload, and
+/// the old routes are kept
+#[tokio::test]
+async fn test_unknown_pool_rejected() {
+    init_logging();
+    let default_upstream = EchoServer::new().await;
+    let api_upstream = EchoServer::new().await;
+    let config_path = temp_config_path("toml");
+    let balancebeam = start_balancebeam(
+        &config_path,
+        &default_upstream.address,
+        &[("api", &api_upstream.address)],
+        &["pool = \"api\"\npath_prefix = \"/api\""],
+    )
+    .await;
+
+    std::fs::write(
+        &config_path,
+        format!(
+            "[[upstreams]]\naddress = \"{}\"\n\n[[routes]]\npool = \"missing\"\n",
+            default_upstream.address
+        ),
+    )
+    .expect("Failed to write config file");
+    balancebeam.send_signal(nix::sys::signal::Signal::SIGHUP);
+    tokio::time::delay_for(std::time::Duration::from_millis(500)).await;
+    let response = balancebeam
+        .get("/api/items")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response.contains("GET /api/items HTTP/1.1"));
+
+    assert_eq!(Box::new(default_upstream).stop().await, 0);
+    assert_eq!(Box::new(api_upstream).stop().await, 1);
+    let _ = std::fs::remove_file(&config_path);
+    log::info!("All done :)");
+}
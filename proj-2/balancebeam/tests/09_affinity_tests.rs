@@ -0,0 +1,171 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use std::collections::HashMap;
+
+const ADMIN_TOKEN: &str = "affinity-test-token";
+
+/// Sends a GET request on a new connection, with the given headers, and returns the response
+async fn send_request(
+    balancebeam: &BalanceBeam,
+    path: &str,
+    headers: &[(&str, &str)],
+) -> reqwest::Response {
+    let mut request =
+        reqwest::Client::new().get(&format!("http://{}{}", balancebeam.address, path));
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    request
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+}
+
+/// Returns the index of the only upstream whose request count differs between `before` and
+/// `upstreams`
+fn upstream_hit(upstreams: &[EchoServer], before: &[usize]) -> usize {
+    let hit: Vec<usize> = (0..upstreams.len())
+        .filter(|idx| upstreams[*idx].requests_received() != before[*idx])
+        .collect();
+    assert_eq!(hit.len(), 1, "Expected exactly one upstream to get the request");
+    hit[0]
+}
+
+/// Make sure cookie affinity keeps a client on the upstream that first answered it:
+///
+/// * Send a request without a cookie and make sure a cookie comes back
+/// * Send several requests with the cookie, each on a new connection, and make sure they all go to
+///   the same upstream and don't get a new cookie
+/// * Send a request with a cookie that doesn't name any upstream, and make sure it is balanced as
+///   usual and given a new cookie
+#[tokio::test]
+async fn test_cookie_affinity() {
+    init_logging();
+    let upstreams = vec![
+        EchoServer::new().await,
+        EchoServer::new().await,
+        EchoServer::new().await,
+    ];
+    let upstream_addresses: Vec<&str> = upstreams.iter().map(|u| u.address.as_str()).collect();
+    let balancebeam = BalanceBeam::new_with_args(
+        &upstream_addresses,
+        &[
+            "--balancing-strategy",
+            "round-robin",
+            "--affinity",
+            "cookie",
+            "--affinity-cookie-name",
+            "sticky",
+            "--active-health-check-interval",
+            "3600",
+        ],
+    )
+    .await;
+
+    let response = send_request(&balancebeam, "/first", &[]).await;
+    let cookie = response
+        .headers()
+        .get("set-cookie")
+        .expect("Balancebeam should have set an affinity cookie")
+        .to_str()
+        .unwrap()
+        .to_string();
+    assert!(cookie.starts_with("sticky="));
+    let cookie = cookie.split(';').next().unwrap().to_string();
+
+    for _ in 0..6 {
+        let response = send_request(&balancebeam, "/sticky", &[("cookie", &cookie)]).await;
+        assert!(response.headers().get("set-cookie").is_none());
+    }
+
+    let stale_cookie = ("cookie", "sticky=0123456789abcdef");
+    let response = send_request(&balancebeam, "/stale", &[stale_cookie]).await;
+    assert!(response.headers().get("set-cookie").is_some());
+
+    let mut counts = Vec::new();
+    for upstream in upstreams {
+        counts.push(Box::new(upstream).stop().await);
+    }
+    counts.sort_unstable();
+    // The first request and the six that carried its cookie all went to one upstream, and the
+    // stale cookie to another one (picked round-robin)
+    assert_eq!(counts, vec![0, 1, 7]);
+    log::info!("All done :)");
+}
+
+/// Make sure consistent-hash affinity on a header sends each key to the same upstream, and that
+/// removing an upstream only moves the keys that were on it:
+///
+/// * Send a request for each of 30 users, noting which upstream each one lands on
+/// * Send them all again and make sure every user lands on the same upstream
+/// * Remove one upstream through the admin API, send them all again, and make sure only the users
+///   that were on the removed upstream moved
+#[tokio::test]
+async fn test_consistent_hash_affinity() {
+    init_logging();
+    let upstreams = vec![
+        EchoServer::new().await,
+        EchoServer::new().await,
+        EchoServer::new().await,
+    ];
+    let upstream_addresses: Vec<&str> = upstreams.iter().map(|u| u.address.as_str()).collect();
+    let balancebeam = BalanceBeam::new_with_admin(
+        &upstream_addresses,
+        &[
+            "--affinity",
+            "consistent-hash",
+            "--affinity-hash-header",
+            "x-user",
+            // Health checks would throw off the request counts
+            "--active-health-check-interval",
+            "3600",
+            "--admin-token",
+            ADMIN_TOKEN,
+        ],
+    )
+    .await;
+
+    let users: Vec<String> = (0..30).map(|idx| format!("user-{}", idx)).collect();
+    let mut placement = HashMap::new();
+    for round in 0..2 {
+        for user in &users {
+            let before: Vec<usize> = upstreams.iter().map(|u| u.requests_received()).collect();
+            send_request(&balancebeam, "/", &[("x-user", user)]).await;
+            let hit = upstream_hit(&upstreams, &before);
+            if round == 0 {
+                placement.insert(user.clone(), hit);
+            } else {
+                assert_eq!(placement[user], hit, "{} moved between upstreams", user);
+            }
+        }
+    }
+    let users_on_removed = placement.values().filter(|idx| **idx == 2).count();
+    assert!(users_on_removed > 0 && users_on_removed < users.len());
+
+    log::info!("Removing {}", upstreams[2].address);
+    let (status, _) = balancebeam
+        .admin_request(
+            reqwest::Method::DELETE,
+            &format!("/upstreams/{}", upstreams[2].address),
+            Some(ADMIN_TOKEN),
+            None,
+        )
+        .await
+        .expect("Error sending request to admin listener");
+    assert_eq!(status, 204);
+    for user in &users {
+        let before: Vec<usize> = upstreams.iter().map(|u| u.requests_received()).collect();
+        send_request(&balancebeam, "/", &[("x-user", user)]).await;
+        let hit = upstream_hit(&upstreams, &before);
+        assert_ne!(hit, 2);
+        if placement[user] != 2 {
+            assert_eq!(placement[user], hit, "{} moved although its upstream stayed", user);
+        }
+    }
+
+    for upstream in upstreams {
+        Box::new(upstream).stop().await;
+    }
+    log::info!("All done :)");
+}
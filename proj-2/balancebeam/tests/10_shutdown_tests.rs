@@ -0,0 +1,140 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use nix::sys::signal::Signal;
+use rand::Rng;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::delay_for;
+
+/// Starts an upstream that waits `delay` after reading each request's headers before answering
+/// it. Returns the upstream's address.
+async fn start_slow_upstream(delay: Duration) -> String {
+    let mut rng = rand::thread_rng();
+    let address = format!("127.0.0.1:{}", rng.gen_range(1024, 65535));
+    let mut listener = TcpListener::bind(&address).await.unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(_) => return,
+            };
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                let mut buffer = [0_u8; 1024];
+                while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                    match stream.read(&mut buffer).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => request.extend_from_slice(&buffer[..n]),
+                    }
+                }
+                delay_for(delay).await;
+                let _ = stream
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nslow")
+                    .await;
+            });
+        }
+    });
+    address
+}
+
+/// Make sure shutdown waits for in-flight requests, but stops accepting new connections:
+///
+/// * Send a request to an upstream that takes 2 seconds to answer
+/// * Send SIGTERM while the request is in flight
+/// * Make sure new connections are refused
+/// * Make sure the in-flight request is answered, and balancebeam then exits cleanly
+#[tokio::test]
+async fn test_shutdown_waits_for_in_flight_requests() {
+    init_logging();
+    let upstream = start_slow_upstream(Duration::from_secs(2)).await;
+    let mut balancebeam = BalanceBeam::new_with_args(&[&upstream], &[]).await;
+
+    let address = balancebeam.address.clone();
+    let in_flight = tokio::spawn(async move {
+        reqwest::get(&format!("http://{}/slow", address))
+            .await?
+            .text()
+            .await
+    });
+    delay_for(Duration::from_millis(500)).await;
+    log::info!("Sending SIGTERM");
+    balancebeam.send_signal(Signal::SIGTERM);
+    delay_for(Duration::from_millis(500)).await;
+    TcpStream::connect(&balancebeam.address)
+        .await
+        .expect_err("balancebeam should have stopped accepting connections");
+
+    let response_text = in_flight
+        .await
+        .unwrap()
+        .expect("The in-flight request should have been answered");
+    assert_eq!(response_text, "slow");
+    let status = balancebeam
+        .wait_for_exit(Duration::from_secs(5))
+        .await
+        .expect("balancebeam should have exited once the request was answered");
+    assert!(status.success());
+    log::info!("All done :)");
+}
+
+/// Make sure idle keep-alive connections don't hold up shutdown:
+///
+/// * Send a request over a keep-alive connection and leave the connection open
+/// * Send SIGINT and make sure balancebeam closes the connection and exits cleanly right away
+#[tokio::test]
+async fn test_shutdown_closes_idle_connections() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let mut balancebeam = BalanceBeam::new_with_args(&[&upstream.address], &[]).await;
+
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    client
+        .write_all(b"GET /idle HTTP/1.1\r\nHost: test\r\nContent-Length: 0\r\n\r\n")
+        .await
+        .unwrap();
+    let mut buffer = [0_u8; 4096];
+    let n = client.read(&mut buffer).await.unwrap();
+    assert!(String::from_utf8_lossy(&buffer[..n]).starts_with("HTTP/1.1 200"));
+
+    log::info!("Sending SIGINT");
+    balancebeam.send_signal(Signal::SIGINT);
+    let status = balancebeam
+        .wait_for_exit(Duration::from_secs(3))
+        .await
+        .expect("balancebeam should have exited without waiting for the idle connection");
+    assert!(status.success());
+    // Anything left over from the response, then the end of the connection
+    while client.read(&mut buffer).await.unwrap() > 0 {}
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Make sure shutdown gives up on requests that take longer than the shutdown timeout:
+///
+/// * Send a request to an upstream that takes 30 seconds to answer
+/// * Send SIGTERM, with a 1-second shutdown timeout
+/// * Make sure balancebeam exits within a few seconds, with a failure status
+#[tokio::test]
+async fn test_shutdown_timeout() {
+    init_logging();
+    let upstream = start_slow_upstream(Duration::from_secs(30)).await;
+    let mut balancebeam =
+        BalanceBeam::new_with_args(&[&upstream], &["--shutdown-timeout", "1"]).await;
+
+    let address = balancebeam.address.clone();
+    tokio::spawn(async move {
+        let _ = reqwest::get(&format!("http://{}/very-slow", address)).await;
+    });
+    delay_for(Duration::from_millis(500)).await;
+    log::info!("Sending SIGTERM");
+    balancebeam.send_signal(Signal::SIGTERM);
+    let status = balancebeam
+        .wait_for_exit(Duration::from_secs(5))
+        .await
+        .expect("balancebeam should have given up on the request after the shutdown timeout");
+    assert!(!status.success());
+    log::info!("All done :)");
+}
@@ -0,0 +1,140 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use nix::sys::signal::Signal;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::delay_for;
+
+/// Make sure an upgrade doesn't turn any clients away:
+///
+/// * Send requests over new connections in a tight loop
+/// * Send SIGUSR2 so balancebeam starts a new copy of itself on the same sockets
+/// * Make sure the old process exits cleanly, and the new one keeps answering requests (on the
+///   proxy and admin listeners alike)
+/// * Make sure no request failed along the way
+#[tokio::test]
+async fn test_upgrade_keeps_accepting_connections() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let mut balancebeam = BalanceBeam::new_with_admin(
+        &[&upstream.address],
+        &["--active-health-check-interval", "3600"],
+    )
+    .await;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let failures = Arc::new(AtomicUsize::new(0));
+    let mut clients = Vec::new();
+    for _ in 0..4 {
+        let address = balancebeam.address.clone();
+        let stop = stop.clone();
+        let failures = failures.clone();
+        clients.push(tokio::spawn(async move {
+            let mut sent = 0;
+            while !stop.load(Ordering::SeqCst) {
+                // A new client every time, so every request is a new connection
+                let result = async {
+                    reqwest::get(&format!("http://{}/request-{}", address, sent))
+                        .await?
+                        .text()
+                        .await
+                }
+                .await;
+                if let Err(err) = result {
+                    log::error!("Request failed: {}", err);
+                    failures.fetch_add(1, Ordering::SeqCst);
+                }
+                sent += 1;
+            }
+            sent
+        }));
+    }
+    delay_for(Duration::from_millis(500)).await;
+
+    log::info!("Sending SIGUSR2");
+    balancebeam.send_signal(Signal::SIGUSR2);
+    let status = balancebeam
+        .wait_for_exit(Duration::from_secs(10))
+        .await
+        .expect("The old process should have exited once the new one took over");
+    assert!(status.success());
+    assert_eq!(balancebeam.successors().len(), 1);
+
+    log::info!("Old process is gone; the new one should still be answering");
+    delay_for(Duration::from_millis(500)).await;
+    let response_text = balancebeam
+        .get("/after-upgrade")
+        .await
+        .expect("Error sending request to the upgraded balancebeam");
+    assert!(response_text.contains("GET /after-upgrade HTTP/1.1"));
+    let (status, _) = balancebeam
+        .admin_get("/metrics")
+        .await
+        .expect("Error fetching metrics from the upgraded balancebeam");
+    assert_eq!(status, 200);
+
+    stop.store(true, Ordering::SeqCst);
+    let mut sent = 0;
+    for client in clients {
+        sent += client.await.unwrap();
+    }
+    log::info!("Sent {} requests during the upgrade", sent);
+    assert_eq!(failures.load(Ordering::SeqCst), 0);
+    assert_eq!(Box::new(upstream).stop().await, sent + 1);
+    log::info!("All done :)");
+}
+
+/// Make sure a keep-alive connection to the old process is still answered after the upgrade, and
+/// is then closed:
+///
+/// * Send a request over a keep-alive connection, and start sending another one
+/// * Upgrade, and finish sending the second request
+/// * Make sure it is answered, and the connection is closed afterwards
+#[tokio::test]
+async fn test_upgrade_finishes_open_requests() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let mut balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--active-health-check-interval", "3600"],
+    )
+    .await;
+
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    client
+        .write_all(b"GET /first HTTP/1.1\r\nHost: test\r\nContent-Length: 0\r\n\r\n")
+        .await
+        .unwrap();
+    let mut buffer = [0_u8; 4096];
+    let n = client.read(&mut buffer).await.unwrap();
+    assert!(String::from_utf8_lossy(&buffer[..n]).starts_with("HTTP/1.1 200"));
+    client.write_all(b"GET /second HTTP/1.1\r\n").await.unwrap();
+
+    log::info!("Sending SIGUSR2");
+    balancebeam.send_signal(Signal::SIGUSR2);
+    delay_for(Duration::from_secs(2)).await;
+    client
+        .write_all(b"Host: test\r\nContent-Length: 0\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = Vec::new();
+    client
+        .read_to_end(&mut response)
+        .await
+        .expect("The connection should have been closed after the second response");
+    let response = String::from_utf8_lossy(&response);
+    assert!(response.starts_with("HTTP/1.1 200"));
+    assert!(response.contains("GET /second HTTP/1.1"));
+
+    let status = balancebeam
+        .wait_for_exit(Duration::from_secs(5))
+        .await
+        .expect("The old process should have exited once its connection closed");
+    assert!(status.success());
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
@@ -0,0 +1,183 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use rand::Rng;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpListener;
+use tokio::time::delay_for;
+
+/// Starts an upstream that reads each request, then waits `delay` and hangs up without answering
+/// it. Returns the upstream's address.
+async fn start_broken_upstream(delay: Duration) -> String {
+    let mut rng = rand::thread_rng();
+    let address = format!("127.0.0.1:{}", rng.gen_range(1024, 65535));
+    let mut listener = TcpListener::bind(&address).await.unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(_) => return,
+            };
+            tokio::spawn(async move {
+                let mut buffer = [0_u8; 1024];
+                let _ = stream.read(&mut buffer).await;
+                delay_for(delay).await;
+            });
+        }
+    });
+    address
+}
+
+/// Sends a request over a new connection and returns the status code and body
+async fn send(balancebeam: &BalanceBeam, method: reqwest::Method, path: &str) -> (u16, String) {
+    let response = reqwest::Client::new()
+        .request(method, &format!("http://{}{}", balancebeam.address, path))
+        .body("request body")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    let status = response.status().as_u16();
+    (status, response.text().await.unwrap())
+}
+
+/// Make sure idempotent requests are retried on another upstream, and others aren't:
+///
+/// * Start one upstream that hangs up on every request and one that works
+/// * Send GET and PUT requests to both (round-robin), and make sure they all succeed
+/// * Send POST requests to both, and make sure the one sent to the broken upstream fails
+#[tokio::test]
+async fn test_idempotent_requests_are_retried() {
+    init_logging();
+    let broken_upstream = start_broken_upstream(Duration::from_millis(0)).await;
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_admin(
+        &[&broken_upstream, &upstream.address],
+        &[
+            "--balancing-strategy",
+            "round-robin",
+            "--active-health-check-interval",
+            "3600",
+        ],
+    )
+    .await;
+
+    for i in 0..2 {
+        let path = format!("/get-{}", i);
+        let (status, body) = send(&balancebeam, reqwest::Method::GET, &path).await;
+        assert_eq!(status, 200);
+        assert!(body.contains(&format!("GET {} HTTP/1.1", path)));
+        let path = format!("/put-{}", i);
+        let (status, body) = send(&balancebeam, reqwest::Method::PUT, &path).await;
+        assert_eq!(status, 200);
+        assert!(body.contains("request body"), "The retried request lost its body");
+    }
+
+    let mut statuses = Vec::new();
+    for i in 0..2 {
+        let path = format!("/post-{}", i);
+        let (status, _) = send(&balancebeam, reqwest::Method::POST, &path).await;
+        statuses.push(status);
+    }
+    statuses.sort_unstable();
+    assert_eq!(statuses, vec![200, 502], "POST requests should not be retried");
+
+    let (_, metrics) = balancebeam
+        .admin_get("/metrics")
+        .await
+        .expect("Error fetching metrics");
+    assert!(metrics.contains("balancebeam_retries_total 4\n"));
+
+    drop(balancebeam);
+    assert_eq!(Box::new(upstream).stop().await, 5);
+    log::info!("All done :)");
+}
+
+/// Make sure methods marked as retryable in the settings are retried, and that nothing is retried
+/// once the retry budget is used up
+#[tokio::test]
+async fn test_retry_methods_and_budget() {
+    init_logging();
+    let broken_upstream = start_broken_upstream(Duration::from_millis(0)).await;
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&broken_upstream, &upstream.address],
+        &[
+            "--balancing-strategy",
+            "round-robin",
+            "--active-health-check-interval",
+            "3600",
+            "--retry-method",
+            "post",
+            "--retry-budget-ratio",
+            "0",
+            "--retry-budget-min-per-second",
+            "0",
+        ],
+    )
+    .await;
+    let mut statuses = Vec::new();
+    for i in 0..2 {
+        let path = format!("/get-{}", i);
+        let (status, _) = send(&balancebeam, reqwest::Method::GET, &path).await;
+        statuses.push(status);
+    }
+    statuses.sort_unstable();
+    assert_eq!(statuses, vec![200, 502], "There is no budget for retries");
+
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&broken_upstream, &upstream.address],
+        &[
+            "--balancing-strategy",
+            "round-robin",
+            "--active-health-check-interval",
+            "3600",
+            "--retry-method",
+            "post",
+        ],
+    )
+    .await;
+    for i in 0..2 {
+        let path = format!("/post-{}", i);
+        let (status, _) = send(&balancebeam, reqwest::Method::POST, &path).await;
+        assert_eq!(status, 200);
+    }
+
+    drop(balancebeam);
+    assert_eq!(Box::new(upstream).stop().await, 3);
+    log::info!("All done :)");
+}
+
+/// Make sure an attempt that takes longer than the per-try timeout is retried on another upstream
+#[tokio::test]
+async fn test_per_try_timeout() {
+    init_logging();
+    let hanging_upstream = start_broken_upstream(Duration::from_secs(30)).await;
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&hanging_upstream, &upstream.address],
+        &[
+            "--balancing-strategy",
+            "round-robin",
+            "--active-health-check-interval",
+            "3600",
+            "--retry-per-try-timeout",
+            "1",
+        ],
+    )
+    .await;
+
+    for i in 0..2 {
+        let (status, _) = tokio::time::timeout(
+            Duration::from_secs(5),
+            send(&balancebeam, reqwest::Method::GET, &format!("/get-{}", i)),
+        )
+        .await
+        .expect("The request should have been retried after the per-try timeout");
+        assert_eq!(status, 200);
+    }
+
+    drop(balancebeam);
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
@@ -0,0 +1,166 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use rand::Rng;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+
+/// Starts an upstream that accepts connections and reads whatever is sent to it, but never says
+/// anything back. Returns the upstream's address.
+async fn start_silent_upstream() -> String {
+    let mut rng = rand::thread_rng();
+    let address = format!("127.0.0.1:{}", rng.gen_range(1024, 65535));
+    let mut listener = TcpListener::bind(&address).await.unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(_) => return,
+            };
+            tokio::spawn(async move {
+                let mut buffer = [0_u8; 1024];
+                while let Ok(n) = stream.read(&mut buffer).await {
+                    if n == 0 {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+    address
+}
+
+/// Reads from `stream` until balancebeam closes the connection, and returns everything it sent.
+/// Panics if the connection is still open after a few seconds.
+async fn read_until_closed(stream: &mut TcpStream) -> String {
+    let mut response = Vec::new();
+    timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+        .await
+        .expect("balancebeam should have closed the connection")
+        .expect("Error reading from balancebeam");
+    String::from_utf8_lossy(&response).to_string()
+}
+
+/// Sends a GET request to balancebeam, and returns the status code once it is answered. Panics if
+/// it isn't answered within a few seconds.
+async fn get_status(balancebeam: &BalanceBeam, path: &str) -> u16 {
+    let url = format!("http://{}{}", balancebeam.address, path);
+    timeout(Duration::from_secs(5), reqwest::get(&url))
+        .await
+        .expect("balancebeam should have given up on the upstream")
+        .expect("Error sending request to balancebeam")
+        .status()
+        .as_u16()
+}
+
+/// Make sure clients that are too slow to send their request get a 408:
+///
+/// * Send half of a request's headers, then stop
+/// * Send the headers of a request with a body, and half of the body, then stop
+/// * Make sure both get a 408, and their connections are closed
+#[tokio::test]
+async fn test_slow_client() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--header-read-timeout", "1", "--body-read-timeout", "1"],
+    )
+    .await;
+
+    log::info!("Sending half of the headers");
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    client
+        .write_all(b"GET /slow-headers HTTP/1.1\r\nHost: test\r\n")
+        .await
+        .unwrap();
+    let response = read_until_closed(&mut client).await;
+    assert!(response.starts_with("HTTP/1.1 408"), "Unexpected response: {}", response);
+
+    log::info!("Sending half of the body");
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    client
+        .write_all(b"POST /slow-body HTTP/1.1\r\nHost: test\r\nContent-Length: 10\r\n\r\nhalf")
+        .await
+        .unwrap();
+    let response = read_until_closed(&mut client).await;
+    assert!(response.starts_with("HTTP/1.1 408"), "Unexpected response: {}", response);
+
+    drop(balancebeam);
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Make sure keep-alive connections that are left idle are closed
+#[tokio::test]
+async fn test_idle_timeout() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--idle-timeout", "1"]).await;
+
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    client
+        .write_all(b"GET /first HTTP/1.1\r\nHost: test\r\nContent-Length: 0\r\n\r\n")
+        .await
+        .unwrap();
+    let response = read_until_closed(&mut client).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "Unexpected response: {}", response);
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// Make sure an upstream that doesn't answer in time gets the client a 504, and that the time
+/// limits for connecting to it and for getting a response both apply:
+///
+/// * Point balancebeam at an upstream that accepts requests but never answers them
+/// * Make sure a request gets a 504 soon after the response timeout
+/// * Point balancebeam at the same upstream over TLS (so the handshake never finishes)
+/// * Make sure a request gets a 504 soon after the connect timeout
+#[tokio::test]
+async fn test_slow_upstream() {
+    init_logging();
+    let upstream = start_silent_upstream().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream],
+        &[
+            "--upstream-response-timeout",
+            "1",
+            "--active-health-check-interval",
+            "3600",
+        ],
+    )
+    .await;
+    assert_eq!(get_status(&balancebeam, "/no-response").await, 504);
+    // Idempotent requests are retried, but there's no other upstream to retry on
+    assert_eq!(get_status(&balancebeam, "/no-response-again").await, 504);
+
+    let mut rng = rand::thread_rng();
+    let config_path = std::env::temp_dir().join(format!(
+        "balancebeam-test-{}.toml",
+        rng.gen_range(0, u32::MAX)
+    ));
+    let config = format!(
+        "[health_check]\ninterval = 3600\n\n[[upstreams]]\naddress = \"{}\"\n\n\
+        [upstreams.tls]\nserver_name = \"a.test\"\n",
+        upstream
+    );
+    std::fs::write(&config_path, config).expect("Failed to write config file");
+    let balancebeam = BalanceBeam::new_with_args(
+        &[],
+        &[
+            "--config",
+            config_path.to_str().unwrap(),
+            "--upstream-connect-timeout",
+            "1",
+        ],
+    )
+    .await;
+    assert_eq!(get_status(&balancebeam, "/no-handshake").await, 504);
+
+    let _ = std::fs::remove_file(&config_path);
+    log::info!("All done :)");
+}
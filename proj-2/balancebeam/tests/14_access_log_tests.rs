@@ -0,0 +1,203 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use rand::Rng;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::time::delay_for;
+
+/// Returns a path in the temp directory for an access log that doesn't exist yet.
+fn access_log_path() -> PathBuf {
+    let mut rng = rand::thread_rng();
+    std::env::temp_dir().join(format!(
+        "balancebeam-test-{}.log",
+        rng.gen_range(0, u32::MAX)
+    ))
+}
+
+/// Waits up to a few seconds for the access log at `path` to have `count` lines, and returns them.
+/// (The line for a request is written once the response is out, so it may land a moment after the
+/// client has the response.)
+async fn read_lines(path: &Path, count: usize) -> Vec<String> {
+    for _ in 0..50 {
+        let contents = std::fs::read_to_string(path).unwrap_or_default();
+        let lines: Vec<String> = contents.lines().map(|line| line.to_string()).collect();
+        if lines.len() >= count {
+            assert_eq!(lines.len(), count, "Unexpected access log: {:?}", lines);
+            return lines;
+        }
+        delay_for(Duration::from_millis(100)).await;
+    }
+    panic!("Access log {} never got {} lines", path.display(), count);
+}
+
+/// Sends a request with a body over a new connection and returns the status code
+async fn send(balancebeam: &BalanceBeam, method: reqwest::Method, path: &str, body: &str) -> u16 {
+    reqwest::Client::new()
+        .request(method, &format!("http://{}{}", balancebeam.address, path))
+        .header("user-agent", "access-log-test")
+        .header("referer", "http://example.com/")
+        .body(body.to_string())
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .status()
+        .as_u16()
+}
+
+/// Make sure JSON access log lines carry the details of each request:
+///
+/// * Send a POST request with a body, and make sure its line has the client, upstream, status,
+///   byte counts and latencies
+/// * Send a request that gets rate limited, and make sure its line has no upstream
+#[tokio::test]
+async fn test_json_access_log() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let path = access_log_path();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--access-log",
+            path.to_str().unwrap(),
+            "--access-log-format",
+            "json",
+            "--max-requests-per-minute",
+            "1",
+        ],
+    )
+    .await;
+
+    assert_eq!(send(&balancebeam, reqwest::Method::POST, "/post?q=1", "hello").await, 200);
+    assert_eq!(send(&balancebeam, reqwest::Method::GET, "/limited", "").await, 429);
+
+    let lines = read_lines(&path, 2).await;
+    let first: serde_json::Value = serde_json::from_str(&lines[0]).expect("Invalid JSON line");
+    assert_eq!(first["client_ip"], "127.0.0.1");
+    assert_eq!(first["method"], "POST");
+    assert_eq!(first["path"], "/post?q=1");
+    assert_eq!(first["protocol"], "HTTP/1.1");
+    assert_eq!(first["status"], 200);
+    assert_eq!(first["upstream"], upstream.address.as_str());
+    assert_eq!(first["user_agent"], "access-log-test");
+    assert_eq!(first["referer"], "http://example.com/");
+    assert!(first["bytes_in"].as_u64().unwrap() > 5);
+    // The echo server sends the request back, so the response is bigger than the request
+    assert!(first["bytes_out"].as_u64().unwrap() > first["bytes_in"].as_u64().unwrap());
+    let upstream_latency = first["upstream_latency"].as_f64().unwrap();
+    assert!(upstream_latency <= first["total_latency"].as_f64().unwrap());
+    assert!(first["time"].as_str().unwrap().ends_with('Z'));
+
+    let second: serde_json::Value = serde_json::from_str(&lines[1]).expect("Invalid JSON line");
+    assert_eq!(second["status"], 429);
+    assert_eq!(second["path"], "/limited");
+    assert!(second["upstream"].is_null());
+    assert!(second["upstream_latency"].is_null());
+
+    drop(balancebeam);
+    let _ = std::fs::remove_file(&path);
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Make sure the combined format is used by default, and that the log can be rotated:
+///
+/// * Send a request and check its line
+/// * Move the log away and send SIGUSR1, then send another request
+/// * Make sure the second line went to a new file at the original path
+#[tokio::test]
+async fn test_combined_access_log_and_reopen() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let path = access_log_path();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--access-log", path.to_str().unwrap()],
+    )
+    .await;
+
+    assert_eq!(send(&balancebeam, reqwest::Method::GET, "/first", "").await, 200);
+    let lines = read_lines(&path, 1).await;
+    assert!(
+        lines[0].starts_with("127.0.0.1 - - ["),
+        "Unexpected line: {}",
+        lines[0]
+    );
+    assert!(
+        lines[0].contains("] \"GET /first HTTP/1.1\" 200 "),
+        "Unexpected line: {}",
+        lines[0]
+    );
+    assert!(
+        lines[0].contains("\"http://example.com/\" \"access-log-test\" "),
+        "Unexpected line: {}",
+        lines[0]
+    );
+    assert!(
+        lines[0].contains(&format!("\"{}\"", upstream.address)),
+        "Unexpected line: {}",
+        lines[0]
+    );
+
+    let rotated_path = path.with_extension("log.1");
+    std::fs::rename(&path, &rotated_path).expect("Failed to move access log");
+    balancebeam.send_signal(nix::sys::signal::Signal::SIGUSR1);
+    delay_for(Duration::from_millis(500)).await;
+    assert_eq!(send(&balancebeam, reqwest::Method::GET, "/second", "").await, 200);
+    let lines = read_lines(&path, 1).await;
+    assert!(lines[0].contains("\"GET /second HTTP/1.1\""), "Unexpected line: {}", lines[0]);
+    assert_eq!(read_lines(&rotated_path, 1).await.len(), 1);
+
+    drop(balancebeam);
+    let _ = std::fs::remove_file(&path);
+    let _ = std::fs::remove_file(&rotated_path);
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Make sure custom templates are filled in, and that templates with unknown fields are rejected
+#[tokio::test]
+async fn test_template_access_log() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let path = access_log_path();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--access-log",
+            path.to_str().unwrap(),
+            "--access-log-format",
+            "template",
+            "--access-log-template",
+            "$method $path -> $status via $upstream ($bytes_in in, $100)",
+        ],
+    )
+    .await;
+    assert_eq!(send(&balancebeam, reqwest::Method::PUT, "/put", "12345").await, 200);
+    let lines = read_lines(&path, 1).await;
+    let prefix = format!("PUT /put -> 200 via {} (", upstream.address);
+    assert!(lines[0].starts_with(&prefix), "Unexpected line: {}", lines[0]);
+    assert!(lines[0].ends_with(" in, $100)"), "Unexpected line: {}", lines[0]);
+
+    let mut balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--access-log",
+            path.to_str().unwrap(),
+            "--access-log-format",
+            "template",
+            "--access-log-template",
+            "$client_ip $nonsense",
+        ],
+    )
+    .await;
+    let status = balancebeam
+        .wait_for_exit(Duration::from_secs(5))
+        .await
+        .expect("balancebeam should refuse to start with an unknown template field");
+    assert!(!status.success());
+
+    let _ = std::fs::remove_file(&path);
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
@@ -0,0 +1,187 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use rand::Rng;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+
+/// Starts an upstream that answers every request with a 101 (followed right away by "hello" in
+/// the new protocol), then sends back everything it receives in upper case, and says "bye" once
+/// the client is done sending. Returns the upstream's address.
+async fn start_upgrading_upstream() -> String {
+    let mut rng = rand::thread_rng();
+    let address = format!("127.0.0.1:{}", rng.gen_range(1024, 65535));
+    let mut listener = TcpListener::bind(&address).await.unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(_) => return,
+            };
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                let mut buffer = [0_u8; 1024];
+                while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                    match stream.read(&mut buffer).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => request.extend_from_slice(&buffer[..n]),
+                    }
+                }
+                let response = "HTTP/1.1 101 Switching Protocols\r\nUpgrade: shouting\r\n\
+                    Connection: Upgrade\r\n\r\nhello";
+                if stream.write_all(response.as_bytes()).await.is_err() {
+                    return;
+                }
+                loop {
+                    match stream.read(&mut buffer).await {
+                        Ok(0) => break,
+                        Ok(n) => {
+                            let shouted = buffer[..n].to_ascii_uppercase();
+                            if stream.write_all(&shouted).await.is_err() {
+                                return;
+                            }
+                        }
+                        Err(_) => return,
+                    }
+                }
+                let _ = stream.write_all(b"bye").await;
+            });
+        }
+    });
+    address
+}
+
+/// Sends an upgrade request over a new connection, and returns the connection once the response
+/// headers (and the "hello" that follows them) have arrived.
+async fn upgrade(balancebeam: &BalanceBeam) -> TcpStream {
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let request =
+        "GET /chat HTTP/1.1\r\nHost: test\r\nUpgrade: shouting\r\nConnection: Upgrade\r\n\r\n";
+    client.write_all(request.as_bytes()).await.unwrap();
+    let expected = "HTTP/1.1 101 Switching Protocols\r\n";
+    let mut received = Vec::new();
+    let mut buffer = [0_u8; 1024];
+    while !received.ends_with(b"hello") {
+        let n = timeout(Duration::from_secs(5), client.read(&mut buffer))
+            .await
+            .expect("Timed out waiting for the upgrade response")
+            .unwrap();
+        assert!(n > 0, "Connection closed during the upgrade");
+        received.extend_from_slice(&buffer[..n]);
+    }
+    let received = String::from_utf8_lossy(&received);
+    assert!(received.starts_with(expected), "Unexpected response: {}", received);
+    assert!(received.contains("\r\n\r\nhello"), "Unexpected response: {}", received);
+    client
+}
+
+/// Make sure an upgraded connection carries bytes both ways until the client is done:
+///
+/// * Send an upgrade request, and make sure the 101 and the upstream's first message arrive
+/// * Send a few messages and make sure each comes back in upper case
+/// * Close the client's sending half, and make sure the upstream's last word arrives before the
+///   connection closes
+#[tokio::test]
+async fn test_upgraded_connection_is_tunneled() {
+    init_logging();
+    let upstream = start_upgrading_upstream().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream], &["--active-health-check-interval", "3600"])
+            .await;
+
+    let mut client = upgrade(&balancebeam).await;
+    for message in &["ping", "GET / HTTP/1.1\r\n\r\n"] {
+        client.write_all(message.as_bytes()).await.unwrap();
+        let mut echoed = vec![0_u8; message.len()];
+        timeout(Duration::from_secs(5), client.read_exact(&mut echoed))
+            .await
+            .expect("Timed out waiting for the tunneled reply")
+            .unwrap();
+        assert_eq!(echoed, message.to_ascii_uppercase().into_bytes());
+    }
+    client.shutdown(std::net::Shutdown::Write).unwrap();
+    let mut rest = Vec::new();
+    timeout(Duration::from_secs(5), client.read_to_end(&mut rest))
+        .await
+        .expect("The tunnel should have closed once both sides were done")
+        .unwrap();
+    assert_eq!(rest, b"bye");
+    log::info!("All done :)");
+}
+
+/// Make sure an upgraded connection that sits idle is closed
+#[tokio::test]
+async fn test_idle_upgraded_connection_is_closed() {
+    init_logging();
+    let upstream = start_upgrading_upstream().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream],
+        &["--idle-timeout", "1", "--active-health-check-interval", "3600"],
+    )
+    .await;
+
+    let mut client = upgrade(&balancebeam).await;
+    let mut rest = Vec::new();
+    timeout(Duration::from_secs(5), client.read_to_end(&mut rest))
+        .await
+        .expect("The idle tunnel should have been closed")
+        .unwrap();
+    assert!(rest.is_empty());
+    log::info!("All done :)");
+}
+
+/// Reads the response to a request without a body from the echo server, which ends with a blank
+/// line (the end of the echoed headers).
+async fn read_echo(client: &mut TcpStream) -> String {
+    let mut response = Vec::new();
+    let mut buffer = [0_u8; 1024];
+    while !response.ends_with(b"\n\n") {
+        let n = timeout(Duration::from_secs(5), client.read(&mut buffer))
+            .await
+            .expect("Timed out waiting for the response")
+            .unwrap();
+        assert!(n > 0, "Connection closed before the response arrived");
+        response.extend_from_slice(&buffer[..n]);
+    }
+    String::from_utf8_lossy(&response).to_string()
+}
+
+/// Make sure a connection whose upgrade request is turned down keeps carrying HTTP requests:
+///
+/// * Send an upgrade request to an upstream that doesn't know the protocol, and make sure it gets
+///   an ordinary response
+/// * Send another request on the same connection, and make sure it is answered too
+#[tokio::test]
+async fn test_declined_upgrade_stays_http() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--active-health-check-interval", "3600"],
+    )
+    .await;
+
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    client
+        .write_all(b"GET /chat HTTP/1.1\r\nHost: test\r\nUpgrade: shouting\r\n")
+        .await
+        .unwrap();
+    client.write_all(b"Connection: Upgrade\r\n\r\n").await.unwrap();
+    let response = read_echo(&mut client).await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "Unexpected response: {}", response);
+    assert!(response.contains("GET /chat HTTP/1.1"));
+
+    client
+        .write_all(b"GET /after HTTP/1.1\r\nHost: test\r\n\r\n")
+        .await
+        .unwrap();
+    let response = read_echo(&mut client).await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "Unexpected response: {}", response);
+    assert!(response.contains("GET /after HTTP/1.1"));
+
+    drop(balancebeam);
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
@@ -0,0 +1,262 @@
+mod common;
+
+use common::{init_logging, temp_config_path, BalanceBeam, EchoServer, ErrorServer, Server};
+use rand::Rng;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{delay_for, timeout};
+
+/// Starts a raw TCP server (which doesn't speak HTTP) that greets every connection with
+/// "<name>\n" and then sends back everything it receives. Returns the server's address.
+async fn start_tcp_server(name: &'static str) -> String {
+    let mut rng = rand::thread_rng();
+    let address = format!("127.0.0.1:{}", rng.gen_range(1024, 65535));
+    let mut listener = TcpListener::bind(&address).await.unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(_) => return,
+            };
+            tokio::spawn(async move {
+                if stream.write_all(format!("{}\n", name).as_bytes()).await.is_err() {
+                    return;
+                }
+                let mut buffer = [0_u8; 1024];
+                loop {
+                    match stream.read(&mut buffer).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => {
+                            if stream.write_all(&buffer[..n]).await.is_err() {
+                                return;
+                            }
+                        }
+                    }
+                }
+            });
+        }
+    });
+    address
+}
+
+/// Reads from `stream` until `expected` bytes have arrived, and returns them as a string.
+async fn read_exactly(stream: &mut TcpStream, expected: usize) -> String {
+    let mut buffer = vec![0_u8; expected];
+    timeout(Duration::from_secs(5), stream.read_exact(&mut buffer))
+        .await
+        .expect("Timed out waiting for data from balancebeam")
+        .expect("Error reading from balancebeam");
+    String::from_utf8_lossy(&buffer).to_string()
+}
+
+/// Reads response headers from `stream`, returning them along with anything that came after them.
+async fn read_response_headers(stream: &mut TcpStream) -> (String, Vec<u8>) {
+    let mut received = Vec::new();
+    let mut buffer = [0_u8; 1024];
+    loop {
+        if let Some(end) = received.windows(4).position(|window| window == b"\r\n\r\n") {
+            let rest = received.split_off(end + 4);
+            return (String::from_utf8_lossy(&received).to_string(), rest);
+        }
+        let n = timeout(Duration::from_secs(5), stream.read(&mut buffer))
+            .await
+            .expect("Timed out waiting for the response")
+            .unwrap();
+        assert!(n > 0, "Connection closed before the response arrived");
+        received.extend_from_slice(&buffer[..n]);
+    }
+}
+
+/// Make sure TCP listeners balance raw connections across the upstreams in their pool:
+///
+/// * Start two servers that don't speak HTTP, in a pool only a TCP listener uses, and let a few
+///   rounds of health checks run (which must only connect to them, or they'd be marked dead)
+/// * Open several connections, and make sure they alternate between the servers and that data
+///   goes both ways
+#[tokio::test]
+async fn test_tcp_listener() {
+    init_logging();
+    let http_upstream = EchoServer::new().await;
+    let upstreams = [start_tcp_server("one").await, start_tcp_server("two").await];
+    let mut rng = rand::thread_rng();
+    let tcp_address = format!("127.0.0.1:{}", rng.gen_range(1024, 65535));
+    let config_path = temp_config_path("toml");
+    let config = format!(
+        "[[upstreams]]\naddress = \"{}\"\n\n\
+        [[pools]]\nname = \"raw\"\nupstreams = [{{ address = \"{}\" }}, {{ address = \"{}\" }}]\n\n\
+        [[tcp.listeners]]\nbind = \"{}\"\npool = \"raw\"\n",
+        http_upstream.address, upstreams[0], upstreams[1], tcp_address
+    );
+    std::fs::write(&config_path, config).expect("Failed to write config file");
+    let _balancebeam = BalanceBeam::new_with_args(
+        &[],
+        &[
+            "--config",
+            config_path.to_str().unwrap(),
+            "--balancing-strategy",
+            "round-robin",
+            "--active-health-check-interval",
+            "1",
+        ],
+    )
+    .await;
+    delay_for(Duration::from_secs(3)).await;
+
+    let mut names = Vec::new();
+    for i in 0..4 {
+        let mut client = TcpStream::connect(&tcp_address).await.unwrap();
+        names.push(read_exactly(&mut client, 4).await);
+        let message = format!("message {}", i);
+        client.write_all(message.as_bytes()).await.unwrap();
+        assert_eq!(read_exactly(&mut client, message.len()).await, message);
+    }
+    names.sort();
+    assert_eq!(names, vec!["one\n", "one\n", "two\n", "two\n"]);
+
+    let _ = std::fs::remove_file(&config_path);
+    log::info!("All done :)");
+}
+
+/// Make sure CONNECT requests open tunnels to allowed destinations only:
+///
+/// * Send a CONNECT request for an allowed destination, and make sure data goes both ways
+/// * Send a CONNECT request for another destination, and make sure it gets a 403
+/// * Make sure ordinary requests still go to the upstream
+/// * Make sure CONNECT requests get a 405 when no destinations are allowed
+#[tokio::test]
+async fn test_connect() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let destination = start_tcp_server("destination").await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--connect-allow",
+            &destination,
+            "--active-health-check-interval",
+            "3600",
+        ],
+    )
+    .await;
+
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", destination, destination);
+    client.write_all(request.as_bytes()).await.unwrap();
+    let (headers, mut data) = read_response_headers(&mut client).await;
+    assert!(headers.starts_with("HTTP/1.1 200"), "Unexpected response: {}", headers);
+    while data.len() < "destination\n".len() {
+        let mut buffer = [0_u8; 64];
+        let n = client.read(&mut buffer).await.unwrap();
+        assert!(n > 0, "Tunnel closed early");
+        data.extend_from_slice(&buffer[..n]);
+    }
+    assert_eq!(data, b"destination\n");
+    client.write_all(b"through the tunnel").await.unwrap();
+    assert_eq!(read_exactly(&mut client, 18).await, "through the tunnel");
+    drop(client);
+
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    client
+        .write_all(b"CONNECT 127.0.0.1:1 HTTP/1.1\r\nHost: 127.0.0.1:1\r\n\r\n")
+        .await
+        .unwrap();
+    let (headers, _) = read_response_headers(&mut client).await;
+    assert!(headers.starts_with("HTTP/1.1 403"), "Unexpected response: {}", headers);
+
+    let response_text = balancebeam
+        .get("/plain")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /plain HTTP/1.1"));
+
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--active-health-check-interval", "3600"],
+    )
+    .await;
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    client.write_all(request.as_bytes()).await.unwrap();
+    let (headers, _) = read_response_headers(&mut client).await;
+    assert!(headers.starts_with("HTTP/1.1 405"), "Unexpected response: {}", headers);
+
+    drop(balancebeam);
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// Make sure CONNECT requests don't depend on the upstreams: the tunnel opens even when no
+/// upstream can be reached, since the connection is never pinned to one
+#[tokio::test]
+async fn test_connect_without_upstreams() {
+    init_logging();
+    let mut rng = rand::thread_rng();
+    let dead_upstream = format!("127.0.0.1:{}", rng.gen_range(1024, 65535));
+    let destination = start_tcp_server("destination").await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&dead_upstream],
+        &[
+            "--connect-allow",
+            &destination,
+            "--active-health-check-interval",
+            "3600",
+        ],
+    )
+    .await;
+
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let request = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n\r\n", destination, destination);
+    client.write_all(request.as_bytes()).await.unwrap();
+    let (headers, mut data) = read_response_headers(&mut client).await;
+    assert!(headers.starts_with("HTTP/1.1 200"), "Unexpected response: {}", headers);
+    while data.len() < "destination\n".len() {
+        let mut buffer = [0_u8; 64];
+        let n = client.read(&mut buffer).await.unwrap();
+        assert!(n > 0, "Tunnel closed early");
+        data.extend_from_slice(&buffer[..n]);
+    }
+    assert_eq!(data, b"destination\n");
+    log::info!("All done :)");
+}
+
+/// Make sure upstreams that HTTP requests go to are health checked over HTTP, even when a TCP
+/// listener relays connections to them too:
+///
+/// * Start a TCP listener in front of the top-level upstreams, one of which fails every request
+/// * Let a few rounds of health checks run, and make sure the failing upstream was taken out of
+///   rotation (being reachable isn't enough)
+#[tokio::test]
+async fn test_tcp_listener_sharing_http_upstreams() {
+    init_logging();
+    let echo_upstream = EchoServer::new().await;
+    let error_upstream = ErrorServer::new().await;
+    let mut rng = rand::thread_rng();
+    let tcp_address = format!("127.0.0.1:{}", rng.gen_range(1024, 65535));
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&echo_upstream.address, &error_upstream.address],
+        &[
+            "--tcp-bind",
+            &tcp_address,
+            "--balancing-strategy",
+            "round-robin",
+            "--active-health-check-interval",
+            "1",
+        ],
+    )
+    .await;
+    delay_for(Duration::from_secs(3)).await;
+
+    for i in 0..4 {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(
+            response_text.contains(&format!("GET {} HTTP/1.1", path)),
+            "Request went to the failing upstream: {}",
+            response_text
+        );
+    }
+    log::info!("All done :)");
+}
@@ -0,0 +1,264 @@
+mod common;
+
+use bytes::Bytes;
+use common::{fixture, init_logging, BalanceBeam, EchoServer, Server};
+use h2::client::SendRequest;
+use rand::Rng;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+use tokio_rustls::rustls::{ClientConfig, Session};
+use tokio_rustls::TlsConnector;
+
+/// Performs the client side of the HTTP/2 handshake over `stream`, with a receive window of
+/// `window` bytes per stream, and returns a handle for sending requests on the connection.
+async fn h2_connect<S>(stream: S, window: u32) -> SendRequest<Bytes>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (client, connection) = h2::client::Builder::new()
+        .initial_window_size(window)
+        .handshake(stream)
+        .await
+        .expect("HTTP/2 handshake with balancebeam failed");
+    tokio::spawn(async move {
+        let _ = connection.await;
+    });
+    client
+}
+
+/// Sends a request over an HTTP/2 connection, with `body` (if any) sent in pieces and without a
+/// Content-Length, and returns the response headers along with the whole response body.
+async fn send(
+    client: &SendRequest<Bytes>,
+    method: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> (http::response::Parts, Vec<u8>) {
+    let mut request = http::Request::builder()
+        .method(method)
+        .uri(format!("http://test{}", path));
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    let mut client = client.clone().ready().await.expect("HTTP/2 connection closed");
+    let (response, mut stream) = client
+        .send_request(request.body(()).unwrap(), body.is_empty())
+        .unwrap();
+    if !body.is_empty() {
+        for piece in body.chunks(16 * 1024) {
+            stream.send_data(Bytes::copy_from_slice(piece), false).unwrap();
+        }
+        stream.send_data(Bytes::new(), true).unwrap();
+    }
+    let response = timeout(Duration::from_secs(10), response)
+        .await
+        .expect("Timed out waiting for the response")
+        .expect("Error receiving the response");
+    let (parts, mut body) = response.into_parts();
+    let mut received = Vec::new();
+    while let Some(data) = timeout(Duration::from_secs(10), body.data())
+        .await
+        .expect("Timed out waiting for the response body")
+    {
+        let data = data.expect("Error receiving the response body");
+        body.flow_control().release_capacity(data.len()).unwrap();
+        received.extend_from_slice(&data);
+    }
+    (parts, received)
+}
+
+/// Make sure HTTP/2 clients can talk to a plain listener without negotiating first (h2c with
+/// prior knowledge):
+///
+/// * Send two requests side by side on one connection, one of them with a body of unknown length
+///   that the upstream answers in chunks, and make sure both reach the upstream as HTTP/1.1
+/// * Make sure HTTP/1.1 clients are still served on the same listener
+#[tokio::test]
+async fn test_h2c_prior_knowledge() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--active-health-check-interval", "3600"],
+    )
+    .await;
+
+    let stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let client = h2_connect(stream, 65535).await;
+    let ((get, get_body), (post, post_body)) = tokio::join!(
+        send(&client, "GET", "/one", &[("cookie", "a=1"), ("cookie", "b=2")], b""),
+        send(&client, "POST", "/two", &[("x-echo-chunked", "yes")], b"hello"),
+    );
+    assert_eq!(get.status, 200);
+    assert_eq!(get.version, http::Version::HTTP_2);
+    let get_body = String::from_utf8_lossy(&get_body);
+    assert!(get_body.starts_with("GET /one HTTP/1.1\n"), "Unexpected echo: {}", get_body);
+    assert!(get_body.contains("\nhost: test\n"));
+    assert!(get_body.contains("\ncookie: a=1; b=2\n"));
+    assert!(get_body.contains("\nx-forwarded-proto: http\n"));
+    assert!(get_body.contains("\nx-forwarded-for: 127.0.0.1\n"));
+
+    assert_eq!(post.status, 200);
+    assert!(!post.headers.contains_key("transfer-encoding"));
+    let post_body = String::from_utf8_lossy(&post_body);
+    assert!(post_body.starts_with("POST /two HTTP/1.1\n"), "Unexpected echo: {}", post_body);
+    assert!(post_body.contains("\ntransfer-encoding: chunked\n"));
+    assert!(post_body.ends_with("\n\nhello"), "Unexpected echo: {}", post_body);
+
+    let response_text = balancebeam
+        .get("/plain")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /plain HTTP/1.1"));
+
+    drop(balancebeam);
+    assert_eq!(Box::new(upstream).stop().await, 3);
+    log::info!("All done :)");
+}
+
+/// Make sure clients that pick HTTP/2 through ALPN on a TLS listener are served over it
+#[tokio::test]
+async fn test_h2_over_tls() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let mut rng = rand::thread_rng();
+    let tls_address = format!("127.0.0.1:{}", rng.gen_range(1024, 65535));
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--tls-bind",
+            &tls_address,
+            "--tls-cert",
+            &fixture("a.test.pem"),
+            "--tls-key",
+            &fixture("a.test.key"),
+            "--active-health-check-interval",
+            "3600",
+        ],
+    )
+    .await;
+
+    let mut config = ClientConfig::new();
+    let pem = std::fs::read(fixture("a.test.pem")).unwrap();
+    config.root_store.add_pem_file(&mut &pem[..]).unwrap();
+    config.set_protocols(&[b"h2".to_vec(), b"http/1.1".to_vec()]);
+    let connector = TlsConnector::from(Arc::new(config));
+    let stream = TcpStream::connect(&tls_address).await.unwrap();
+    let dns_name = webpki::DNSNameRef::try_from_ascii_str("a.test").unwrap();
+    let stream = connector
+        .connect(dns_name, stream)
+        .await
+        .expect("TLS handshake with balancebeam failed");
+    assert_eq!(stream.get_ref().1.get_alpn_protocol(), Some(&b"h2"[..]));
+
+    let client = h2_connect(stream, 65535).await;
+    let (response, body) = send(&client, "GET", "/secure", &[], b"").await;
+    assert_eq!(response.status, 200);
+    let body = String::from_utf8_lossy(&body);
+    assert!(body.starts_with("GET /secure HTTP/1.1\n"), "Unexpected echo: {}", body);
+    assert!(body.contains("\nx-forwarded-proto: https\n"));
+
+    drop(balancebeam);
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// Make sure bodies much larger than the HTTP/2 flow control windows make it through both ways,
+/// which only works if balancebeam gives the client more room as it forwards the request body and
+/// holds back the response body until the client has room for it:
+///
+/// * Connect with a tiny receive window
+/// * Upload a large body, and make sure the upstream's echo of it arrives intact
+#[tokio::test]
+async fn test_http2_flow_control() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--active-health-check-interval", "3600"],
+    )
+    .await;
+
+    let stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let client = h2_connect(stream, 1024).await;
+    let upload: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+    let (response, body) = send(&client, "PUT", "/upload", &[], &upload).await;
+    assert_eq!(response.status, 200);
+    assert!(body.len() > upload.len());
+    assert!(body.ends_with(&upload), "The echoed body doesn't match what was sent");
+
+    drop(balancebeam);
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// Make sure trailers on an HTTP/2 request reach the upstream in the last chunk of the body. The
+/// echo server can't see trailers, so this uses a bare-bones upstream that replies by hand.
+#[tokio::test]
+async fn test_http2_request_trailers() {
+    init_logging();
+    let mut rng = rand::thread_rng();
+    let upstream_address = format!("127.0.0.1:{}", rng.gen_range(1024, 65535));
+    let mut listener = TcpListener::bind(&upstream_address)
+        .await
+        .expect("Failed to bind upstream");
+    let upstream_task = tokio::spawn(async move {
+        let (mut conn, _) = listener.accept().await.expect("Failed to accept connection");
+        let mut data = Vec::new();
+        while !String::from_utf8_lossy(&data).ends_with("x-request-trailer: yes\r\n\r\n") {
+            let mut buffer = [0_u8; 512];
+            let bytes_read = timeout(Duration::from_secs(5), conn.read(&mut buffer))
+                .await
+                .expect("Timed out waiting for the request")
+                .expect("Error reading from balancebeam");
+            assert!(
+                bytes_read > 0,
+                "Request ended early: {:?}",
+                String::from_utf8_lossy(&data)
+            );
+            data.extend_from_slice(&buffer[..bytes_read]);
+        }
+        conn.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+            .await
+            .expect("Failed to send response");
+        String::from_utf8_lossy(&data).to_string()
+    });
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream_address],
+        &["--active-health-check-interval", "3600"],
+    )
+    .await;
+
+    let stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let mut client = h2_connect(stream, 65535).await.ready().await.unwrap();
+    let request = http::Request::builder()
+        .method("POST")
+        .uri("http://test/trailers")
+        .body(())
+        .unwrap();
+    let (response, mut stream) = client.send_request(request, false).unwrap();
+    stream
+        .send_data(Bytes::from_static(b"hello"), false)
+        .unwrap();
+    let mut trailers = http::HeaderMap::new();
+    trailers.insert("x-request-trailer", http::HeaderValue::from_static("yes"));
+    stream.send_trailers(trailers).unwrap();
+    let response = timeout(Duration::from_secs(10), response)
+        .await
+        .expect("Timed out waiting for the response")
+        .expect("Error receiving the response");
+    assert_eq!(response.status(), 200);
+
+    let request_text = upstream_task.await.expect("Upstream task panicked");
+    assert!(request_text.contains("\r\ntransfer-encoding: chunked\r\n"));
+    assert!(
+        request_text.ends_with("\r\n5\r\nhello\r\n0\r\nx-request-trailer: yes\r\n\r\n"),
+        "Unexpected request: {:?}",
+        request_text
+    );
+    log::info!("All done :)");
+}
@@ -0,0 +1,257 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use rand::Rng;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+
+/// What a PROXY protocol v2 header starts with.
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Builds a PROXY protocol v2 header for a TCP over IPv4 connection from `source` to
+/// `destination`.
+fn v2_header(source: ([u8; 4], u16), destination: ([u8; 4], u16)) -> Vec<u8> {
+    let mut header = V2_SIGNATURE.to_vec();
+    header.extend_from_slice(&[0x21, 0x11, 0, 12]);
+    header.extend_from_slice(&source.0);
+    header.extend_from_slice(&destination.0);
+    header.extend_from_slice(&source.1.to_be_bytes());
+    header.extend_from_slice(&destination.1.to_be_bytes());
+    header
+}
+
+/// Sends a GET request for `path` over `stream` and returns the status line along with the
+/// response body.
+async fn get(stream: &mut TcpStream, path: &str) -> (String, String) {
+    let request = format!("GET {} HTTP/1.1\r\nHost: test\r\n\r\n", path);
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut received = Vec::new();
+    let mut buffer = [0_u8; 1024];
+    loop {
+        if let Some(end) = received.windows(4).position(|window| window == b"\r\n\r\n") {
+            let headers = String::from_utf8_lossy(&received[..end]).to_lowercase();
+            let length: usize = headers
+                .lines()
+                .find_map(|line| line.strip_prefix("content-length: "))
+                .expect("Response has no Content-Length")
+                .parse()
+                .unwrap();
+            if received.len() >= end + 4 + length {
+                let status = headers.lines().next().unwrap().to_string();
+                let body = String::from_utf8_lossy(&received[end + 4..end + 4 + length]);
+                return (status, body.to_string());
+            }
+        }
+        let n = timeout(Duration::from_secs(5), stream.read(&mut buffer))
+            .await
+            .expect("Timed out waiting for the response")
+            .unwrap();
+        assert!(n > 0, "Connection closed before the response arrived");
+        received.extend_from_slice(&buffer[..n]);
+    }
+}
+
+/// Starts an HTTP server that answers every request with the first line it received on the
+/// connection since its last answer, which is the PROXY protocol header if the request was the
+/// first on the connection. Returns the server's address and the number of connections it has
+/// accepted.
+async fn start_header_echo_server() -> (String, Arc<Mutex<usize>>) {
+    let mut rng = rand::thread_rng();
+    let address = format!("127.0.0.1:{}", rng.gen_range(1024, 65535));
+    let mut listener = TcpListener::bind(&address).await.unwrap();
+    let connections = Arc::new(Mutex::new(0));
+    let connections_copy = connections.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = match listener.accept().await {
+                Ok(connection) => connection,
+                Err(_) => return,
+            };
+            *connections_copy.lock().unwrap() += 1;
+            tokio::spawn(async move {
+                let mut received = Vec::new();
+                let mut buffer = [0_u8; 1024];
+                loop {
+                    if let Some(end) = received.windows(4).position(|window| window == b"\r\n\r\n")
+                    {
+                        let request: Vec<u8> = received.drain(..end + 4).collect();
+                        let line_end = request.windows(2).position(|w| w == b"\r\n").unwrap();
+                        let response = format!(
+                            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n",
+                            line_end + 2
+                        );
+                        let mut response = response.into_bytes();
+                        response.extend_from_slice(&request[..line_end + 2]);
+                        if stream.write_all(&response).await.is_err() {
+                            return;
+                        }
+                        continue;
+                    }
+                    match stream.read(&mut buffer).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => received.extend_from_slice(&buffer[..n]),
+                    }
+                }
+            });
+        }
+    });
+    (address, connections)
+}
+
+/// Make sure the client address in a PROXY protocol header from a trusted proxy is used in place
+/// of the proxy's:
+///
+/// * Send a request with a v1 header, and make sure X-Forwarded-For names the client in it
+/// * Send a request with a v2 header for an IPv6 client, and make sure it is let through even
+///   though the rate limit only allows one request per client
+/// * Send another request for the first client, and make sure it is rate limited
+/// * Make sure a connection from the trusted proxy without a header is closed
+#[tokio::test]
+async fn test_proxy_protocol_ingress() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--proxy-protocol-trusted",
+            "127.0.0.0/8",
+            "--max-requests-per-minute",
+            "1",
+            "--active-health-check-interval",
+            "3600",
+        ],
+    )
+    .await;
+
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    client
+        .write_all(b"PROXY TCP4 203.0.113.7 192.0.2.1 40000 80\r\n")
+        .await
+        .unwrap();
+    let (status, body) = get(&mut client, "/v1").await;
+    assert!(status.starts_with("http/1.1 200"), "Unexpected status: {}", status);
+    assert!(body.contains("\nx-forwarded-for: 203.0.113.7\n"), "Unexpected echo: {}", body);
+
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let mut header = V2_SIGNATURE.to_vec();
+    header.extend_from_slice(&[0x21, 0x21, 0, 36]);
+    header.extend_from_slice(&"2001:db8::1".parse::<std::net::Ipv6Addr>().unwrap().octets());
+    header.extend_from_slice(&"2001:db8::2".parse::<std::net::Ipv6Addr>().unwrap().octets());
+    header.extend_from_slice(&[0x9c, 0x40, 0, 80]);
+    client.write_all(&header).await.unwrap();
+    let (status, body) = get(&mut client, "/v2").await;
+    assert!(status.starts_with("http/1.1 200"), "Unexpected status: {}", status);
+    assert!(body.contains("\nx-forwarded-for: 2001:db8::1\n"), "Unexpected echo: {}", body);
+
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let header = v2_header(([203, 0, 113, 7], 40001), ([192, 0, 2, 1], 80));
+    client.write_all(&header).await.unwrap();
+    let (status, _) = get(&mut client, "/again").await;
+    assert!(status.starts_with("http/1.1 429"), "Unexpected status: {}", status);
+
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    client
+        .write_all(b"GET /sneaky HTTP/1.1\r\nHost: test\r\n\r\n")
+        .await
+        .unwrap();
+    let mut buffer = [0_u8; 64];
+    let n = timeout(Duration::from_secs(5), client.read(&mut buffer))
+        .await
+        .expect("Timed out waiting for balancebeam to hang up")
+        .unwrap_or(0);
+    assert_eq!(n, 0, "Connection without a PROXY protocol header was answered");
+
+    drop(balancebeam);
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// Make sure upstreams can be told about clients with the PROXY protocol:
+///
+/// * Relay a connection with a v2 header to an upstream that takes v1 headers, and make sure the
+///   upstream is told about the client in the v2 header
+/// * Send a second request on the same client connection, and make sure it goes over a new
+///   upstream connection with a header of its own instead of a pooled one
+#[tokio::test]
+async fn test_proxy_protocol_egress() {
+    init_logging();
+    let (upstream, connections) = start_header_echo_server().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream],
+        &[
+            "--proxy-protocol-trusted",
+            "127.0.0.1",
+            "--upstream-proxy-protocol",
+            "v1",
+            "--active-health-check-interval",
+            "3600",
+        ],
+    )
+    .await;
+    let connections_before = *connections.lock().unwrap();
+
+    let mut client = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let header = v2_header(([198, 51, 100, 9], 4000), ([192, 0, 2, 1], 443));
+    client.write_all(&header).await.unwrap();
+    for path in &["/first", "/second"] {
+        let (status, body) = get(&mut client, path).await;
+        assert!(status.starts_with("http/1.1 200"), "Unexpected status: {}", status);
+        assert_eq!(body, "PROXY TCP4 198.51.100.9 192.0.2.1 4000 443\r\n");
+    }
+    assert_eq!(*connections.lock().unwrap() - connections_before, 2);
+    log::info!("All done :)");
+}
+
+/// Make sure TCP listeners send the PROXY protocol header before relaying the client's bytes, and
+/// that clients outside the trusted addresses don't need to send one
+#[tokio::test]
+async fn test_proxy_protocol_tcp_listener() {
+    init_logging();
+    let mut rng = rand::thread_rng();
+    let upstream_address = format!("127.0.0.1:{}", rng.gen_range(1024, 65535));
+    let mut listener = TcpListener::bind(&upstream_address).await.unwrap();
+    let tcp_address = format!("127.0.0.1:{}", rng.gen_range(1024, 65535));
+    let _balancebeam = BalanceBeam::new_with_args(
+        &[&upstream_address],
+        &[
+            "--tcp-bind",
+            &tcp_address,
+            "--proxy-protocol-trusted",
+            "10.0.0.0/8",
+            "--upstream-proxy-protocol",
+            "v2",
+            "--active-health-check-interval",
+            "3600",
+        ],
+    )
+    .await;
+
+    let mut client = TcpStream::connect(&tcp_address).await.unwrap();
+    let client_port = client.local_addr().unwrap().port();
+    client.write_all(b"hello").await.unwrap();
+    let (mut upstream, _) = timeout(Duration::from_secs(5), listener.accept())
+        .await
+        .expect("Timed out waiting for balancebeam to connect")
+        .unwrap();
+    let tcp_port: u16 = tcp_address.rsplit(':').next().unwrap().parse().unwrap();
+    let mut expected = v2_header(([127, 0, 0, 1], client_port), ([127, 0, 0, 1], tcp_port));
+    expected.extend_from_slice(b"hello");
+    let mut received = vec![0_u8; expected.len()];
+    timeout(Duration::from_secs(5), upstream.read_exact(&mut received))
+        .await
+        .expect("Timed out waiting for the relayed data")
+        .unwrap();
+    assert_eq!(received, expected);
+
+    upstream.write_all(b"world").await.unwrap();
+    let mut reply = [0_u8; 5];
+    timeout(Duration::from_secs(5), client.read_exact(&mut reply))
+        .await
+        .expect("Timed out waiting for the reply")
+        .unwrap();
+    assert_eq!(&reply, b"world");
+    log::info!("All done :)");
+}
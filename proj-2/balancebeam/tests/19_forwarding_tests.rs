@@ -0,0 +1,222 @@
+mod common;
+
+use common::{init_logging, BalanceBeam, EchoServer, Server};
+use rand::Rng;
+use std::time::Duration;
+use tokio::time::delay_for;
+
+/// Sends a GET request with the given headers over a new connection and returns the status code
+/// along with the response body.
+async fn get(balancebeam: &BalanceBeam, path: &str, headers: &[(&str, &str)]) -> (u16, String) {
+    let url = format!("http://{}{}", balancebeam.address, path);
+    let mut request = reqwest::Client::new().get(&url);
+    for (name, value) in headers {
+        request = request.header(*name, *value);
+    }
+    let response = request
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    let status = response.status().as_u16();
+    let body = response
+        .text()
+        .await
+        .expect("Balancebeam replied with a malformed response");
+    (status, body)
+}
+
+/// Waits (for a while) for the access log at `path` to have `count` lines, and returns its lines.
+async fn read_log(path: &std::path::Path, count: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    for _ in 0..50 {
+        let contents = std::fs::read_to_string(path).unwrap_or_default();
+        lines = contents.lines().map(|line| line.to_string()).collect();
+        if lines.len() >= count {
+            break;
+        }
+        delay_for(Duration::from_millis(100)).await;
+    }
+    lines
+}
+
+/// Make sure forwarding headers from clients that aren't trusted proxies are replaced by ones
+/// balancebeam fills in itself:
+///
+/// * Send a request with made-up X-Forwarded-For, X-Forwarded-Host, X-Forwarded-Proto and
+///   Forwarded headers, and make sure the upstream only sees the ones balancebeam added
+#[tokio::test]
+async fn test_untrusted_forwarding_headers() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--active-health-check-interval", "3600"],
+    )
+    .await;
+
+    let (status, body) = get(
+        &balancebeam,
+        "/spoofed",
+        &[
+            ("host", "example.com"),
+            ("x-forwarded-for", "203.0.113.7"),
+            ("x-forwarded-host", "evil.example"),
+            ("x-forwarded-proto", "https"),
+            ("forwarded", "for=203.0.113.7;proto=https"),
+        ],
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert!(body.contains("\nx-forwarded-for: 127.0.0.1\n"), "Unexpected echo: {}", body);
+    assert!(body.contains("\nx-forwarded-host: example.com\n"));
+    assert!(body.contains("\nx-forwarded-proto: http\n"));
+    assert!(body.contains("\nforwarded: for=127.0.0.1;host=example.com;proto=http\n"));
+
+    drop(balancebeam);
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// Make sure requests relayed by trusted proxies are rate limited and logged by the client the
+/// proxies name:
+///
+/// * Send a request through two trusted proxies, and make sure their headers are passed on with
+///   balancebeam's hop added
+/// * Send a request for another client, and make sure it isn't held back by the first client's
+///   rate limit
+/// * Send another request for the first client, and make sure it is rate limited
+/// * Send a request for the first client relayed by an untrusted hop, and make sure the untrusted
+///   hop is taken to be the client
+/// * Make sure the access log names the clients the proxies did
+#[tokio::test]
+async fn test_trusted_proxies() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let mut rng = rand::thread_rng();
+    let path = std::env::temp_dir().join(format!(
+        "balancebeam-test-{}.log",
+        rng.gen_range(0, u32::MAX)
+    ));
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--trusted-proxy",
+            "127.0.0.1",
+            "--trusted-proxy",
+            "10.0.0.0/8",
+            "--max-requests-per-minute",
+            "1",
+            "--access-log",
+            path.to_str().unwrap(),
+            "--access-log-format",
+            "template",
+            "--access-log-template",
+            "$client_ip $status",
+            "--active-health-check-interval",
+            "3600",
+        ],
+    )
+    .await;
+
+    let (status, body) = get(
+        &balancebeam,
+        "/relayed",
+        &[
+            ("host", "example.com"),
+            ("x-forwarded-for", "203.0.113.7, 10.1.2.3"),
+            ("x-forwarded-proto", "https"),
+            ("forwarded", "for=203.0.113.7, for=10.1.2.3"),
+        ],
+    )
+    .await;
+    assert_eq!(status, 200);
+    assert!(
+        body.contains("\nx-forwarded-for: 203.0.113.7, 10.1.2.3, 127.0.0.1\n"),
+        "Unexpected echo: {}",
+        body
+    );
+    assert!(body.contains("\nx-forwarded-proto: https\n"));
+    assert!(body.contains(
+        "\nforwarded: for=203.0.113.7, for=10.1.2.3, for=127.0.0.1;host=example.com;proto=http\n"
+    ));
+
+    let other = [("x-forwarded-for", "2001:db8::1")];
+    assert_eq!(get(&balancebeam, "/other", &other).await.0, 200);
+    let relayed = [("x-forwarded-for", "203.0.113.7")];
+    assert_eq!(get(&balancebeam, "/again", &relayed).await.0, 429);
+    let spoofed = [("x-forwarded-for", "203.0.113.7, 198.51.100.1")];
+    assert_eq!(get(&balancebeam, "/spoofed", &spoofed).await.0, 200);
+
+    assert_eq!(
+        read_log(&path, 4).await,
+        vec![
+            "203.0.113.7 200",
+            "2001:db8::1 200",
+            "203.0.113.7 429",
+            "198.51.100.1 200"
+        ]
+    );
+
+    drop(balancebeam);
+    let _ = std::fs::remove_file(&path);
+    assert_eq!(Box::new(upstream).stop().await, 3);
+    log::info!("All done :)");
+}
+
+/// Make sure only the header the trusted proxies write is trusted to name the client:
+///
+/// * Send a request whose trusted proxy records hops in Forwarded, with an X-Forwarded-For header
+///   the client made up passed along, and make sure the client in Forwarded is logged
+/// * Send another request for the same client with a different made-up X-Forwarded-For, and make
+///   sure it is still held to that client's rate limit
+#[tokio::test]
+async fn test_trusted_forwarded_header() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let mut rng = rand::thread_rng();
+    let path = std::env::temp_dir().join(format!(
+        "balancebeam-test-{}.log",
+        rng.gen_range(0, u32::MAX)
+    ));
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--trusted-proxy",
+            "127.0.0.1",
+            "--trusted-proxy-header",
+            "forwarded",
+            "--max-requests-per-minute",
+            "1",
+            "--access-log",
+            path.to_str().unwrap(),
+            "--access-log-format",
+            "template",
+            "--access-log-template",
+            "$client_ip $status",
+            "--active-health-check-interval",
+            "3600",
+        ],
+    )
+    .await;
+
+    let first = [
+        ("forwarded", "for=\"[2001:db8::1]:4711\""),
+        ("x-forwarded-for", "198.51.100.1"),
+    ];
+    assert_eq!(get(&balancebeam, "/first", &first).await.0, 200);
+    let injected = [
+        ("forwarded", "for=\"[2001:db8::1]\""),
+        ("x-forwarded-for", "198.51.100.2"),
+    ];
+    assert_eq!(get(&balancebeam, "/injected", &injected).await.0, 429);
+
+    assert_eq!(
+        read_log(&path, 2).await,
+        vec!["2001:db8::1 200", "2001:db8::1 429"]
+    );
+
+    drop(balancebeam);
+    let _ = std::fs::remove_file(&path);
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
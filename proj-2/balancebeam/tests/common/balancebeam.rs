@@ -1,13 +1,24 @@
 use rand::Rng;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::process::{Child, Command};
 use tokio::time::delay_for;
 
 pub struct BalanceBeam {
-    #[allow(dead_code)]
     child: Child, // process is killed when dropped (Command::kill_on_drop)
     pub address: String,
+    /// Address of the admin listener, if balancebeam was started with one
+    pub admin_address: Option<String>,
+    /// Process IDs of the processes started by upgrades (SIGUSR2), which are killed when dropped
+    successors: Arc<Mutex<Vec<i32>>>,
+}
+
+/// Picks the process ID out of the line balancebeam logs when it hands its listeners over to an
+/// upgraded process
+fn successor_pid(line: &str) -> Option<i32> {
+    let (_, pid) = line.split_once("Handed listeners over to process ")?;
+    pid.trim().parse().ok()
 }
 
 impl BalanceBeam {
@@ -19,41 +30,74 @@ impl BalanceBeam {
         path
     }
 
+    #[allow(dead_code)]
     pub async fn new(
         upstreams: &[&str],
         active_health_check_interval: Option<usize>,
         max_requests_per_minute: Option<usize>,
+    ) -> BalanceBeam {
+        let mut args = Vec::new();
+        if let Some(active_health_check_interval) = active_health_check_interval {
+            args.push("--active-health-check-interval".to_string());
+            args.push(active_health_check_interval.to_string());
+        }
+        if let Some(max_requests_per_minute) = max_requests_per_minute {
+            args.push("--max-requests-per-minute".to_string());
+            args.push(max_requests_per_minute.to_string());
+        }
+        let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+        BalanceBeam::new_with_args(upstreams, &args).await
+    }
+
+    /// Starts balancebeam with the given upstreams, passing any extra command-line arguments
+    /// (e.g. `&["--balancing-strategy", "round-robin"]`) through unchanged.
+    pub async fn new_with_args(upstreams: &[&str], extra_args: &[&str]) -> BalanceBeam {
+        BalanceBeam::start(upstreams, extra_args, None).await
+    }
+
+    /// Like new_with_args, but also starts the admin listener on a random port.
+    #[allow(dead_code)]
+    pub async fn new_with_admin(upstreams: &[&str], extra_args: &[&str]) -> BalanceBeam {
+        let mut rng = rand::thread_rng();
+        let admin_address = format!("127.0.0.1:{}", rng.gen_range(1024, 65535));
+        BalanceBeam::start(upstreams, extra_args, Some(admin_address)).await
+    }
+
+    async fn start(
+        upstreams: &[&str],
+        extra_args: &[&str],
+        admin_address: Option<String>,
     ) -> BalanceBeam {
         let mut rng = rand::thread_rng();
         let address = format!("127.0.0.1:{}", rng.gen_range(1024, 65535));
         let mut cmd = Command::new(BalanceBeam::target_bin_path());
         cmd.arg("--bind").arg(&address);
+        if let Some(admin_address) = &admin_address {
+            cmd.arg("--admin-bind").arg(admin_address);
+        }
         for upstream in upstreams {
             cmd.arg("--upstream").arg(upstream);
         }
-        if let Some(active_health_check_interval) = active_health_check_interval {
-            cmd.arg("--active-health-check-interval")
-                .arg(active_health_check_interval.to_string());
-        }
-        if let Some(max_requests_per_minute) = max_requests_per_minute {
-            cmd.arg("--max-requests-per-minute")
-                .arg(max_requests_per_minute.to_string());
-        }
+        cmd.args(extra_args);
         cmd.kill_on_drop(true);
         cmd.stdout(std::process::Stdio::piped());
         cmd.stderr(std::process::Stdio::piped());
-        let mut child = cmd.spawn().expect(&format!(
-            "Could not execute balancebeam binary {}",
-            BalanceBeam::target_bin_path().to_str().unwrap()
-        ));
+        let mut child = cmd.spawn().unwrap_or_else(|_| {
+            panic!(
+                "Could not execute balancebeam binary {}",
+                BalanceBeam::target_bin_path().to_str().unwrap()
+            )
+        });
 
         // Print output from the child. We want to intercept and log this output (instead of letting
         // the child inherit stderr and print directly to the terminal) so that the output can be
         // suppressed if the test passes and displayed if it fails.
+        let successors = Arc::new(Mutex::new(Vec::new()));
         let stdout = child
             .stdout
             .take()
             .expect("Child process somehow missing stdout pipe!");
+        let successors_copy = successors.clone();
         tokio::spawn(async move {
             let mut stdout_reader = BufReader::new(stdout).lines();
             while let Some(line) = stdout_reader
@@ -62,12 +106,16 @@ impl BalanceBeam {
                 .expect("I/O error reading from child stdout")
             {
                 println!("Balancebeam output: {}", line);
+                if let Some(pid) = successor_pid(&line) {
+                    successors_copy.lock().unwrap().push(pid);
+                }
             }
         });
         let stderr = child
             .stderr
             .take()
             .expect("Child process somehow missing stderr pipe!");
+        let successors_copy = successors.clone();
         tokio::spawn(async move {
             let mut stderr_reader = BufReader::new(stderr).lines();
             while let Some(line) = stderr_reader
@@ -76,12 +124,43 @@ impl BalanceBeam {
                 .expect("I/O error reading from child stderr")
             {
                 println!("Balancebeam output: {}", line);
+                if let Some(pid) = successor_pid(&line) {
+                    successors_copy.lock().unwrap().push(pid);
+                }
             }
         });
 
         // Hack: wait for executable to start running
         delay_for(Duration::from_secs(1)).await;
-        BalanceBeam { child, address }
+        BalanceBeam {
+            child,
+            address,
+            admin_address,
+            successors,
+        }
+    }
+
+    /// Sends a signal (e.g. SIGHUP to reload the configuration file) to the balancebeam process
+    #[allow(dead_code)]
+    pub fn send_signal(&self, signal: nix::sys::signal::Signal) {
+        let pid = nix::unistd::Pid::from_raw(self.child.id() as i32);
+        nix::sys::signal::kill(pid, signal).expect("Failed to send signal to balancebeam");
+    }
+
+    /// Waits up to `timeout` for the balancebeam process to exit (e.g. after SIGTERM), and returns
+    /// its exit status, or None if it is still running
+    #[allow(dead_code)]
+    pub async fn wait_for_exit(&mut self, timeout: Duration) -> Option<std::process::ExitStatus> {
+        tokio::time::timeout(timeout, &mut self.child)
+            .await
+            .ok()
+            .map(|status| status.expect("Failed to wait for balancebeam to exit"))
+    }
+
+    /// Returns the process IDs of the processes that balancebeam has handed its listeners over to
+    #[allow(dead_code)]
+    pub fn successors(&self) -> Vec<i32> {
+        self.successors.lock().unwrap().clone()
     }
 
     #[allow(dead_code)]
@@ -108,4 +187,48 @@ impl BalanceBeam {
             .text()
             .await
     }
+
+    /// Sends a GET request to the admin listener and returns the status code and body
+    #[allow(dead_code)]
+    pub async fn admin_get(&self, path: &str) -> Result<(u16, String), reqwest::Error> {
+        self.admin_request(reqwest::Method::GET, path, None, None).await
+    }
+
+    /// Sends a request to the admin listener, with a bearer token and body if given, and returns
+    /// the status code and body
+    #[allow(dead_code)]
+    pub async fn admin_request(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        token: Option<&str>,
+        body: Option<&str>,
+    ) -> Result<(u16, String), reqwest::Error> {
+        let admin_address = self
+            .admin_address
+            .as_ref()
+            .expect("balancebeam was started without an admin listener");
+        let client = reqwest::Client::new();
+        let mut request = client.request(method, &format!("http://{}{}", admin_address, path));
+        if let Some(token) = token {
+            request = request.bearer_auth(token);
+        }
+        if let Some(body) = body {
+            request = request
+                .header("Content-Type", "application/json")
+                .body(body.to_string());
+        }
+        let response = request.send().await?;
+        let status = response.status().as_u16();
+        Ok((status, response.text().await?))
+    }
+}
+
+impl Drop for BalanceBeam {
+    fn drop(&mut self) {
+        for pid in self.successors.lock().unwrap().iter() {
+            let pid = nix::unistd::Pid::from_raw(*pid);
+            let _ = nix::sys::signal::kill(pid, nix::sys::signal::Signal::SIGKILL);
+        }
+    }
 }
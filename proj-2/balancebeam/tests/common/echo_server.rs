@@ -7,11 +7,12 @@ use std::sync::{atomic, Arc};
 use tokio::sync::oneshot;
 
 #[derive(Debug)]
-struct ServerState {
+pub(super) struct ServerState {
     pub requests_received: atomic::AtomicUsize,
+    pub connections_received: atomic::AtomicUsize,
 }
 
-async fn echo(
+pub(super) async fn echo(
     server_state: Arc<ServerState>,
     req: Request<Body>,
 ) -> Result<Response<Body>, hyper::Error> {
@@ -27,8 +28,20 @@ async fn echo(
         );
     }
     req_text += "\n";
+    let send_chunked = req.headers().contains_key("x-echo-chunked");
     let mut req_as_bytes = req_text.into_bytes();
     req_as_bytes.extend(hyper::body::to_bytes(req.into_body()).await?);
+    if send_chunked {
+        // Send the echo back in two pieces without a Content-Length, so hyper uses chunked
+        // encoding for the response
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            let second_half = req_as_bytes.split_off(req_as_bytes.len() / 2);
+            let _ = sender.send_data(req_as_bytes.into()).await;
+            let _ = sender.send_data(second_half.into()).await;
+        });
+        return Ok(Response::new(body));
+    }
     Ok(Response::new(Body::from(req_as_bytes)))
 }
 
@@ -40,11 +53,13 @@ pub struct EchoServer {
 }
 
 impl EchoServer {
+    #[allow(dead_code)]
     pub async fn new() -> EchoServer {
         let mut rng = rand::thread_rng();
         EchoServer::new_at_address(format!("127.0.0.1:{}", rng.gen_range(1024, 65535))).await
     }
 
+    #[allow(dead_code)]
     pub async fn new_at_address(bind_addr_string: String) -> EchoServer {
         let bind_addr = bind_addr_string.parse().unwrap();
         // Create a one-shot channel that can be used to tell the server to shut down
@@ -53,11 +68,15 @@ impl EchoServer {
         // Start a separate server task
         let server_state = Arc::new(ServerState {
             requests_received: atomic::AtomicUsize::new(0),
+            connections_received: atomic::AtomicUsize::new(0),
         });
         let server_task_state = server_state.clone();
         let server_task = tokio::spawn(async move {
             let service = make_service_fn(|_| {
                 let server_task_state = server_task_state.clone();
+                server_task_state
+                    .connections_received
+                    .fetch_add(1, atomic::Ordering::SeqCst);
                 async move {
                     Ok::<_, hyper::Error>(service_fn(move |req| {
                         let server_task_state = server_task_state.clone();
@@ -83,6 +102,18 @@ impl EchoServer {
             address: bind_addr_string,
         }
     }
+
+    /// Returns the number of requests the server has received so far
+    #[allow(dead_code)]
+    pub fn requests_received(&self) -> usize {
+        self.state.requests_received.load(atomic::Ordering::SeqCst)
+    }
+
+    /// Returns the number of TCP connections the server has accepted so far
+    #[allow(dead_code)]
+    pub fn connections_received(&self) -> usize {
+        self.state.connections_received.load(atomic::Ordering::SeqCst)
+    }
 }
 
 #[async_trait]
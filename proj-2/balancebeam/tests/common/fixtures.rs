@@ -0,0 +1,19 @@
+use rand::Rng;
+
+/// Returns the path of one of the certificates or keys in tests/tls. Each certificate is
+/// self-signed (so it can serve as its own CA) and only valid for the hostname it is named after.
+#[allow(dead_code)]
+pub fn fixture(name: &str) -> String {
+    format!("{}/tests/tls/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
+/// Returns a path in the temp directory that no other test is using, with the given extension
+#[allow(dead_code)]
+pub fn temp_config_path(extension: &str) -> std::path::PathBuf {
+    let mut rng = rand::thread_rng();
+    std::env::temp_dir().join(format!(
+        "balancebeam-test-{}.{}",
+        rng.gen_range(0, u32::MAX),
+        extension
+    ))
+}
@@ -1,14 +1,22 @@
 mod balancebeam;
 mod echo_server;
 mod error_server;
+mod fixtures;
 mod server;
+mod tls_echo_server;
 
 use std::sync;
 
 pub use balancebeam::BalanceBeam;
+#[allow(unused_imports)]
 pub use echo_server::EchoServer;
+#[allow(unused_imports)]
 pub use error_server::ErrorServer;
+#[allow(unused_imports)]
+pub use fixtures::{fixture, temp_config_path};
 pub use server::Server;
+#[allow(unused_imports)]
+pub use tls_echo_server::TlsEchoServer;
 
 static INIT_TESTS: sync::Once = sync::Once::new();
 
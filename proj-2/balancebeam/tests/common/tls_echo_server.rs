@@ -0,0 +1,127 @@
+use crate::common::echo_server::{echo, ServerState};
+use crate::common::server::Server;
+use async_trait::async_trait;
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use rand::Rng;
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::{atomic, Arc};
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::{
+    AllowAnyAuthenticatedClient, NoClientAuth, RootCertStore, ServerConfig, Session,
+};
+use tokio_rustls::TlsAcceptor;
+
+/// Same as EchoServer, but only speaks HTTPS. If `client_ca` is given, clients must present a
+/// certificate signed by it.
+pub struct TlsEchoServer {
+    shutdown_signal_sender: oneshot::Sender<()>,
+    server_task: tokio::task::JoinHandle<()>,
+    pub address: String,
+    state: Arc<ServerState>,
+}
+
+impl TlsEchoServer {
+    /// Starts a server on a random port, serving the certificate in `cert` with the key in `key`
+    /// (both PEM files).
+    #[allow(dead_code)]
+    pub async fn new(cert: &str, key: &str, client_ca: Option<&str>) -> TlsEchoServer {
+        TlsEchoServer::new_with_alpn(cert, key, client_ca, &[]).await
+    }
+
+    /// Like new, but offers the given protocols (e.g. "h2", "http/1.1") through ALPN. Clients
+    /// that pick h2 are only spoken to in HTTP/2, as servers that offer it do.
+    #[allow(dead_code)]
+    pub async fn new_with_alpn(
+        cert: &str,
+        key: &str,
+        client_ca: Option<&str>,
+        protocols: &[&str],
+    ) -> TlsEchoServer {
+        let mut config = match client_ca {
+            Some(path) => {
+                let mut roots = RootCertStore::empty();
+                roots
+                    .add_pem_file(&mut BufReader::new(File::open(path).unwrap()))
+                    .unwrap();
+                ServerConfig::new(AllowAnyAuthenticatedClient::new(roots))
+            }
+            None => ServerConfig::new(NoClientAuth::new()),
+        };
+        let chain = pemfile::certs(&mut BufReader::new(File::open(cert).unwrap())).unwrap();
+        let mut keys =
+            pemfile::pkcs8_private_keys(&mut BufReader::new(File::open(key).unwrap())).unwrap();
+        config.set_single_cert(chain, keys.remove(0)).unwrap();
+        let protocols: Vec<Vec<u8>> = protocols.iter().map(|p| p.as_bytes().to_vec()).collect();
+        config.set_protocols(&protocols);
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+
+        let mut rng = rand::thread_rng();
+        let address = format!("127.0.0.1:{}", rng.gen_range(1024, 65535));
+        let mut listener = TcpListener::bind(&address).await.unwrap();
+        let (shutdown_tx, mut shutdown_rx) = oneshot::channel::<()>();
+        let server_state = Arc::new(ServerState {
+            requests_received: atomic::AtomicUsize::new(0),
+            connections_received: atomic::AtomicUsize::new(0),
+        });
+        let server_task_state = server_state.clone();
+        let server_task = tokio::spawn(async move {
+            loop {
+                let stream = tokio::select! {
+                    _ = &mut shutdown_rx => break,
+                    accepted = listener.accept() => match accepted {
+                        Ok((stream, _)) => stream,
+                        Err(_) => continue,
+                    },
+                };
+                server_task_state
+                    .connections_received
+                    .fetch_add(1, atomic::Ordering::SeqCst);
+                let acceptor = acceptor.clone();
+                let connection_state = server_task_state.clone();
+                tokio::spawn(async move {
+                    let stream = match acceptor.accept(stream).await {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            log::info!("TLS handshake with TlsEchoServer failed: {}", e);
+                            return;
+                        }
+                    };
+                    let http2 = stream.get_ref().1.get_alpn_protocol() == Some(b"h2");
+                    let service = service_fn(move |req| echo(connection_state.clone(), req));
+                    let _ = Http::new()
+                        .http2_only(http2)
+                        .serve_connection(stream, service)
+                        .await;
+                });
+            }
+        });
+
+        TlsEchoServer {
+            shutdown_signal_sender: shutdown_tx,
+            server_task,
+            state: server_state,
+            address,
+        }
+    }
+}
+
+#[async_trait]
+impl Server for TlsEchoServer {
+    /// Stops accepting connections. Connections that are still open are left to finish on their
+    /// own.
+    async fn stop(self: Box<Self>) -> usize {
+        let _ = self.shutdown_signal_sender.send(());
+        self.server_task
+            .await
+            .expect("TlsEchoServer server task panicked");
+        self.state.requests_received.load(atomic::Ordering::SeqCst)
+    }
+
+    fn address(&self) -> String {
+        self.address.clone()
+    }
+}